@@ -1,6 +1,11 @@
 use std::fs::OpenOptions;
 use std::io::Write;
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use open_rdma_driver::{
+    qp::QpState,
+    types::{Qp, QpAttrBuilder, Qpn},
+    Device,
+};
 
 pub struct SimpleLogger {
     file: std::fs::File,
@@ -37,3 +42,15 @@ impl log::Log for SimpleLogger {
 pub fn init_logging(file_path: &str) -> Result<(), SetLoggerError> {
     log::set_boxed_logger(Box::new(SimpleLogger::new(file_path))).map(|()| log::set_max_level(LevelFilter::Info))
 }
+
+/// Move the created `qp` through INIT, RTR and RTS, so it's ready to send requests.
+pub fn bring_up_qp(dev: &Device, qpn: Qpn, qp: &Qp) {
+    for state in [QpState::Init, QpState::Rtr, QpState::Rts] {
+        let mut builder = QpAttrBuilder::default();
+        let _ = builder.qp_state(state);
+        if matches!(state, QpState::Rtr) {
+            let _ = builder.peer_qpn(qp.peer_qpn).rq_psn(qp.rq_psn).pmtu(qp.pmtu);
+        }
+        dev.modify_qp(qpn, &builder.build().unwrap()).unwrap();
+    }
+}
@@ -9,7 +9,7 @@ use open_rdma_driver::{
 };
 use std::{ffi::c_void, net::Ipv4Addr, thread::sleep, time::Duration};
 
-use crate::common::init_logging;
+use crate::common::{bring_up_qp, init_logging};
 
 const ORDER: usize = 32;
 const SHM_PATH: &str = "/bluesim1\0";
@@ -120,7 +120,8 @@ fn create_and_init_card<'a>(
         .dqp_mac(remote_network.macaddr)
         .build()
         .unwrap();
-    dev.create_qp(&qp).unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    bring_up_qp(&dev, qpn, &qp);
     info!("[{}] QP created", card_id);
 
     (dev, pd, mr, mr_buffer)
@@ -169,6 +170,7 @@ fn main() {
             mr_b.get_key(),
             WorkReqSendFlag::IbvSendSignaled,
             sge0,
+            0,
         )
         .unwrap();
 
@@ -197,6 +199,7 @@ fn main() {
     //         mr_b.get_key(),
     //         WorkReqSendFlag::empty(),
     //         sge0,
+    //         0,
     //     )
     //     .unwrap();
     // let _ = ctx2.wait();
@@ -224,6 +227,7 @@ fn main() {
     //         mr_b.get_key(),
     //         WorkReqSendFlag::empty(),
     //         sge3,
+    //         0,
     //     )
     //     .unwrap();
 
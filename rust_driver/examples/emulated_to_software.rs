@@ -1,7 +1,7 @@
 use std::net::Ipv4Addr;
 
 use buddy_system_allocator::LockedHeap;
-use common::init_logging;
+use common::{bring_up_qp, init_logging};
 use eui48::MacAddress;
 use libc::c_void;
 use log::info;
@@ -114,7 +114,8 @@ fn create_and_init_emulated_card<'a>(
         .dqp_mac(remote_network.macaddr)
         .build()
         .unwrap();
-    dev.create_qp(&qp).unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    bring_up_qp(&dev, qpn, &qp);
     info!("[{}] QP created", card_id);
 
     (dev, pd, mr, mr_buffer)
@@ -163,7 +164,8 @@ fn create_and_init_software_card<'a>(
         .dqp_mac(remote_network.macaddr)
         .build()
         .unwrap();
-    dev.create_qp(&qp).unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    bring_up_qp(&dev, qpn, &qp);
     info!("[{}] QP created", card_id);
 
     (dev, pd, mr, mr_buffer)
@@ -219,6 +221,7 @@ fn main() {
                 mr_b.get_key(),
                 WorkReqSendFlag::IbvSendSignaled,
                 sge0,
+                0,
             )
             .unwrap();
 
@@ -249,6 +252,7 @@ fn main() {
                 mr_b.get_key(),
                 WorkReqSendFlag::IbvSendSignaled,
                 sge0,
+                0,
             )
             .unwrap();
 
@@ -278,6 +282,7 @@ fn main() {
                 mr_a.get_key(),
                 WorkReqSendFlag::IbvSendSignaled,
                 sge0,
+                0,
             )
             .unwrap();
 
@@ -307,6 +312,7 @@ fn main() {
                 mr_a.get_key(),
                 WorkReqSendFlag::IbvSendSignaled,
                 sge0,
+                0,
             )
             .unwrap();
 
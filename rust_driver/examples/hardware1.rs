@@ -12,7 +12,7 @@ use std::{
     net::Ipv4Addr, time::{Duration, Instant},
 };
 
-use crate::common::init_logging;
+use crate::common::{bring_up_qp, init_logging};
 
 const BUFFER_LENGTH: usize = 1024 * 1024 * 128;
 const SEND_CNT: usize = 1024 * 1024 * 128;
@@ -72,7 +72,8 @@ fn create_and_init_card<'a>(
         .peer_qpn(qpn)
         .build()
         .unwrap();
-    dev.create_qp(&qp).unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    bring_up_qp(&dev, qpn, &qp);
     info!("[{}] QP created", card_id);
 
     (dev, pd, mr, mr_buffer)
@@ -133,7 +134,7 @@ fn main() {
     // // test write
     let write_start = Instant::now();
     let ctx1 = dev_a
-        .write(dpqn, raddr, rkey, WorkReqSendFlag::IbvSendSignaled, sge0)
+        .write(dpqn, raddr, rkey, WorkReqSendFlag::IbvSendSignaled, sge0, 0)
         .unwrap();
     let start_waiting = Instant::now();
     // info!("===========7====================");
@@ -12,7 +12,7 @@ use open_rdma_driver::{
 };
 use std::{net::Ipv4Addr, thread, time::Duration};
 
-use crate::common::init_logging;
+use crate::common::{bring_up_qp, init_logging};
 
 const BUFFER_LENGTH: usize = 1024 * 1024 * 2;
 
@@ -70,7 +70,8 @@ fn create_and_init_card<'a>(
         .dqp_mac(remote_network.macaddr)
         .build()
         .unwrap();
-    dev.create_qp(&qp).unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    bring_up_qp(&dev, qpn, &qp);
     info!("[{}] QP created", card_id);
 
     (dev, pd, mr, mr_buffer)
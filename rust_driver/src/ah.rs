@@ -0,0 +1,96 @@
+use std::net::Ipv4Addr;
+
+use eui48::MacAddress;
+
+use crate::{types::Qpn, Device, Error};
+
+/// Address handle
+///
+/// The destination of a datagram sent by a UD QP.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct Ah {
+    pub(crate) dqp_ip: Ipv4Addr,
+    pub(crate) dqp_mac: MacAddress,
+    pub(crate) dqpn: Qpn,
+    pub(crate) qkey: u32,
+}
+
+impl Ah {
+    pub(crate) fn new(ip: Ipv4Addr, mac: MacAddress, qpn: Qpn, qkey: u32) -> Result<Self, Error> {
+        // by IB spec, QP0 and QP1 are reserved
+        if qpn.get() < 2 {
+            return Err(Error::Invalid(format!("destination qpn :{qpn:?}")));
+        }
+        if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+            return Err(Error::Invalid(format!("destination ip :{ip:?}")));
+        }
+        if mac.is_nil() || !mac.is_unicast() {
+            return Err(Error::Invalid(format!("destination mac :{mac:?}")));
+        }
+        Ok(Self {
+            dqp_ip: ip,
+            dqp_mac: mac,
+            dqpn: qpn,
+            qkey,
+        })
+    }
+
+    /// The destination QP of the address handle
+    #[must_use]
+    pub fn dqpn(&self) -> Qpn {
+        self.dqpn
+    }
+
+    /// The Q_Key carried by the datagrams sent to the destination
+    #[must_use]
+    pub fn qkey(&self) -> u32 {
+        self.qkey
+    }
+}
+
+impl Device {
+    /// create an address handle, which describes the destination of a UD send
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * the qpn is reserved
+    /// * the ip is not a unicast address
+    /// * the mac is not a unicast address
+    pub fn create_ah(
+        &self,
+        ip: Ipv4Addr,
+        mac: MacAddress,
+        qpn: Qpn,
+        qkey: u32,
+    ) -> Result<Ah, Error> {
+        Ah::new(ip, mac, qpn, qkey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use eui48::MacAddress;
+
+    use crate::types::Qpn;
+
+    use super::Ah;
+
+    #[test]
+    fn test_ah_new() {
+        let ip = Ipv4Addr::new(192, 168, 0, 2);
+        let mac = MacAddress::new([0x02, 0, 0, 0, 0, 0x02]);
+        let ah = Ah::new(ip, mac, Qpn::new(3), 0x1111).unwrap();
+        assert_eq!(ah.dqpn().get(), 3);
+        assert_eq!(ah.qkey(), 0x1111);
+
+        assert!(Ah::new(ip, mac, Qpn::new(1), 0x1111).is_err());
+        assert!(Ah::new(Ipv4Addr::UNSPECIFIED, mac, Qpn::new(3), 0x1111).is_err());
+        assert!(Ah::new(Ipv4Addr::BROADCAST, mac, Qpn::new(3), 0x1111).is_err());
+        assert!(Ah::new(ip, MacAddress::nil(), Qpn::new(3), 0x1111).is_err());
+        assert!(Ah::new(ip, MacAddress::broadcast(), Qpn::new(3), 0x1111).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     cell::{RefCell, RefMut},
     collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
     ops::Bound,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -10,22 +11,26 @@ use std::{
 
 use crate::{
     buf::{PacketBuf, RDMA_ACK_BUFFER_SLOT_SIZE},
+    cq::{WorkCompletion, WorkCompletionOpcode, WorkCompletionStatus},
     device::{
         ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescUpdateErrPsnRecoverPoint,
-        ToHostWorkRbDescAck, ToHostWorkRbDescAethCode, ToHostWorkRbDescRead,
-        ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType,
+        ToHostWorkRbDescAck, ToHostWorkRbDescAethCode, ToHostWorkRbDescAtomic,
+        ToHostWorkRbDescDatagram, ToHostWorkRbDescNakCode, ToHostWorkRbDescRead,
+        ToHostWorkRbDescSent, ToHostWorkRbDescStatus, ToHostWorkRbDescWriteOrReadResp,
+        ToHostWorkRbDescWriteType, ToHostWorkRbDescWriteWithImm,
     },
     op_ctx::OpCtx,
     qp::QpContext,
-    responser::{make_ack, make_read_resp},
-    types::{Msn, Pmtu, Psn, Qpn, PSN_MAX_WINDOW_SIZE},
-    utils::calculate_packet_cnt,
+    responser::{make_ack, make_atomic_ack, make_nack, make_read_resp, make_rnr_nack},
+    retry::{RetryCancel, RetryEvent, RetryGoBackN, RetryRnr, RetrySelective},
+    types::{Imm, ImmNotification, Msn, Pmtu, Psn, QpType, Qpn, PSN_MAX_WINDOW_SIZE},
+    utils::{calculate_packet_cnt, rnr_timer_to_duration},
     CtrlDescriptorSender, ThreadSafeHashmap, WorkDescriptorSender,
 };
 
-use flume::{Receiver, TryRecvError};
+use flume::{Receiver, Sender, TryRecvError};
 
-use log::{error, info};
+use log::{error, info, warn};
 use parking_lot::RwLock;
 
 const MAX_MSN_WINDOW_PER_QP: usize = 16;
@@ -44,6 +49,8 @@ pub(crate) struct PacketCheckerContext {
     pub(crate) ctrl_desc_sender: Arc<dyn CtrlDescriptorSender>,
     pub(crate) work_desc_sender: Arc<dyn WorkDescriptorSender>,
     pub(crate) ack_buffers: PacketBuf<RDMA_ACK_BUFFER_SLOT_SIZE>,
+    pub(crate) retry_sender: Sender<RetryEvent>,
+    pub(crate) read_resp_map: ThreadSafeHashmap<(Qpn, Msn), ReadRespContext>,
 }
 
 impl PacketChecker {
@@ -90,24 +97,45 @@ fn working_thread(ctx: &mut PacketCheckerContext, stop_flag: &AtomicBool) {
 
 impl PacketCheckerContext {
     pub(crate) fn handle_check_event(&self, event: PacketCheckEvent) {
+        if !self.is_qp_ready_to_recv(event.qpn()) {
+            warn!("Drop the packet of qp {:?}, which is not ready to receive", event.qpn());
+            return;
+        }
+        self.sync_recv_ctx(event.qpn());
+        if self.nak_failed_request(&event) {
+            return;
+        }
         match event {
+            PacketCheckEvent::Write(event) if event.is_read_resp => self.handle_read_resp(&event),
             PacketCheckEvent::Write(event) => {
                 let qpn = event.common.dqpn;
                 let expected_psn = event.common.expected_psn;
                 let psn = event.psn;
                 let enter_error = expected_psn != psn;
-                let (mut is_normal, pmtu) = if let Some(qp) = self.qp_table.read().get(&qpn) {
-                    (qp.status.load(Ordering::Acquire).is_normal(), qp.pmtu)
-                } else {
+                let (mut is_normal, pmtu, qp_type) =
+                    if let Some(qp) = self.qp_table.read().get(&qpn) {
+                        (qp.status.load(Ordering::Acquire).is_normal(), qp.pmtu, qp.qp_type)
+                    } else {
+                        return;
+                    };
+                if matches!(qp_type, QpType::Uc) {
+                    self.handle_qp_uc(&event, enter_error);
                     return;
-                };
+                }
                 if is_normal && enter_error {
                     // ensure only enter error status once
                     self.enter_qp_error_status(qpn, pmtu, expected_psn, psn);
+                    // the missing packets before a larger psn are reported in `handle_qp_ooo`,
+                    // otherwise ask the requester to retransmit from the expected psn
+                    if !psn.larger_in_psn(expected_psn) {
+                        let msn = event.common.msn;
+                        let code = ToHostWorkRbDescNakCode::PsnSeqErr;
+                        self.send_nak(qpn, msn, expected_psn, None, code);
+                    }
                     is_normal = false;
                 }
                 if is_normal {
-                    self.handle_qp_normal(&event);
+                    self.handle_qp_normal(&event, true);
                 } else {
                     self.handle_qp_ooo(&event, pmtu);
                 }
@@ -125,18 +153,216 @@ impl PacketCheckerContext {
                     }
                 }
             }
-            PacketCheckEvent::Ack(event) => {
-                let code = event.code;
+            PacketCheckEvent::AtomicReq(event) => {
+                // the card has executed the atomic request, respond the original value.
+                // A failed one has been NAKed in `nak_failed_request`
                 let qpn = event.common.dqpn;
-                let msn = event.msn;
-                if matches!(code, ToHostWorkRbDescAethCode::Ack) {
-                    wakeup_user_op_ctx(&self.user_op_ctx_map, qpn, msn);
+                let msn = event.common.msn;
+                self.recv_ctx_map
+                    .set_recent_msn_status(qpn, msn, RecentQpMsnStatus::Finished);
+                let slot = self.ack_buffers.recycle_buf();
+                if let Ok(desc) =
+                    make_atomic_ack(slot, &self.qp_table, qpn, msn, event.psn, event.orig)
+                {
+                    if let Err(e) = self.work_desc_sender.send_work_desc(desc) {
+                        error!("Send atomic ack failed {:?}", e);
+                    }
+                } else {
+                    error!("make atomic ack failed");
+                }
+            }
+            PacketCheckEvent::Ack(event) => self.handle_ack(&event),
+            PacketCheckEvent::Datagram(event) => {
+                // a datagram is unreliable, so it is neither ordered nor acknowledged
+                let src = (event.src_qpn, event.src_ip);
+                self.complete_recv_wqe(event.common.dqpn, event.addr, event.len, None, Some(src));
+            }
+            PacketCheckEvent::Sent(event) => {
+                // nothing acknowledges a datagram, so it is completed once the device sent it
+                let key = (event.common.dqpn, event.common.msn);
+                let Some(ctx) = self.user_op_ctx_map.write().remove(&key) else {
+                    error!("No op ctx found for {:?}", key);
+                    return;
+                };
+                if !event.common.status.is_ok() {
+                    error!("Datagram {:?} is not sent: {:?}", key, event.common.status);
+                    if let Some(handler) = ctx.take_handler() {
+                        handler(WorkCompletionStatus::GeneralError);
+                    }
+                    ctx.set_error(WorkCompletionStatus::GeneralError);
+                    return;
+                }
+                if let Some(handler) = ctx.take_handler() {
+                    handler(WorkCompletionStatus::Success);
+                }
+                if let Err(e) = ctx.set_result(()) {
+                    error!("Set result failed {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// NAK a request failed by the card, e.g. without the access permission.
+    ///
+    /// A send without receive buffer is responded with an RNR NAK instead, and the requester
+    /// retransmits it later.
+    /// Returns `true` if the event is a failed request, which should not be handled anymore.
+    fn nak_failed_request(&self, event: &PacketCheckEvent) -> bool {
+        let Some((msn, psn, status)) = event.failed_request() else {
+            return false;
+        };
+        let qpn = event.qpn();
+        warn!("Request of qp {:?}, msn {:?} failed: {:?}", qpn, msn, status);
+        // an unreliable qp never acknowledges
+        let rnr_timer = self
+            .qp_table
+            .read()
+            .get(&qpn)
+            .and_then(|qp| (!matches!(qp.qp_type, QpType::Uc)).then_some(qp.min_rnr_timer));
+        let Some(rnr_timer) = rnr_timer else {
+            return true;
+        };
+        if let Some(code) = status.nak_code() {
+            self.recv_ctx_map
+                .set_recent_msn_status(qpn, msn, RecentQpMsnStatus::Finished);
+            self.send_nak(qpn, msn, psn, None, code);
+        } else if psn == event.expected_psn() {
+            // the expected psn is not advanced, so only the first packet is NAKed
+            let slot = self.ack_buffers.recycle_buf();
+            match make_rnr_nack(slot, &self.qp_table, qpn, msn, psn, rnr_timer) {
+                Ok(desc) => {
+                    if let Err(e) = self.work_desc_sender.send_work_desc(desc) {
+                        error!("Send rnr nak failed {:?}", e);
+                    }
                 }
+                Err(e) => error!("make rnr nak failed {:?}", e),
             }
+        } else {
+            // the following packets of the message are dropped, and will be retransmitted
         }
+        true
     }
 
-    fn handle_qp_normal(&self, event: &ToHostWorkRbDescWriteOrReadResp) {
+    /// Handle an ACK or NAK of our own request
+    fn handle_ack(&self, event: &ToHostWorkRbDescAck) {
+        let qpn = event.common.dqpn;
+        let msn = event.msn;
+        match event.code {
+            ToHostWorkRbDescAethCode::Ack => self.complete_user_op_ctx(qpn, msn),
+            ToHostWorkRbDescAethCode::Nak => match ToHostWorkRbDescNakCode::try_from(event.value) {
+                Ok(ToHostWorkRbDescNakCode::PsnSeqErr) => {
+                    // the responder has lost some packets. Retransmit the range only if the NRETH
+                    // bounds it, otherwise retransmit from the NAKed psn
+                    let retry = if event.last_retry_psn.larger_in_psn(event.psn) {
+                        RetryEvent::Selective(RetrySelective::new(
+                            qpn,
+                            event.psn,
+                            event.last_retry_psn,
+                        ))
+                    } else {
+                        RetryEvent::GoBackN(RetryGoBackN::new(qpn, event.psn))
+                    };
+                    if let Err(e) = self.retry_sender.send(retry) {
+                        error!("Send retry of nak failed {:?}", e);
+                    }
+                }
+                Ok(
+                    ToHostWorkRbDescNakCode::InvalidRequest
+                    | ToHostWorkRbDescNakCode::InvalidRdRequest,
+                ) => self.fail_user_op_ctx(qpn, msn, WorkCompletionStatus::RemoteInvalidRequest),
+                Ok(ToHostWorkRbDescNakCode::RemoteAccessError) => {
+                    self.fail_user_op_ctx(qpn, msn, WorkCompletionStatus::RemoteAccessError);
+                }
+                Ok(ToHostWorkRbDescNakCode::RemoteOperationalError) => {
+                    self.fail_user_op_ctx(qpn, msn, WorkCompletionStatus::RemoteOperationalError);
+                }
+                Err(_) => {
+                    warn!("Unknown NAK code {} of qp {:?}, msn {:?}", event.value, qpn, msn);
+                }
+            },
+            ToHostWorkRbDescAethCode::Rnr => {
+                // the responder has no receive buffer, retransmit after the RNR timer
+                let timer = rnr_timer_to_duration(event.value);
+                let rnr = RetryRnr::new(qpn, msn, event.psn, timer);
+                if let Err(e) = self.retry_sender.send(RetryEvent::Rnr(rnr)) {
+                    error!("Send rnr retry failed {:?}", e);
+                }
+            }
+            ToHostWorkRbDescAethCode::Rsvd => {
+                warn!("Ignore the {:?} of qp {:?}, msn {:?}", event.code, qpn, msn);
+            }
+        }
+    }
+
+    /// Track a response packet of our own read, which is completed once all its packets landed.
+    ///
+    /// The read responses are not in the psn space of the received requests, so they never
+    /// change the status of the qp. A missing packet is fetched again by the retry monitor,
+    /// which reissues the read for the remaining bytes on timeout.
+    fn handle_read_resp(&self, event: &ToHostWorkRbDescWriteOrReadResp) {
+        let key = (event.common.dqpn, event.common.msn);
+        let is_complete = {
+            let mut guard = self.read_resp_map.write();
+            let Some(ctx) = guard.get_mut(&key) else {
+                // a late response of a finished read, or a duplicated one of a reissued read
+                return;
+            };
+            ctx.insert(event.addr);
+            ctx.is_complete() && guard.remove(&key).is_some()
+        };
+        if is_complete {
+            self.complete_user_op_ctx(key.0, key.1);
+        }
+    }
+
+    /// Complete a request which is acknowledged or responded, and stop retransmitting it.
+    ///
+    /// The acknowledgement is cumulative, so the retransmission of the earlier requests of the
+    /// qp is stopped as well.
+    fn complete_user_op_ctx(&self, qpn: Qpn, msn: Msn) {
+        let cancel = RetryEvent::Cancel(RetryCancel::cumulative(qpn, msn));
+        if let Err(e) = self.retry_sender.send(cancel) {
+            error!("Send retry cancel failed {:?}", e);
+        }
+        wakeup_user_op_ctx(&self.user_op_ctx_map, qpn, msn);
+    }
+
+    /// Fail a request which is NAKed by the responder, it will never be retransmitted
+    fn fail_user_op_ctx(&self, qpn: Qpn, msn: Msn, status: WorkCompletionStatus) {
+        let cancel = RetryEvent::Cancel(RetryCancel::new(qpn, msn));
+        if let Err(e) = self.retry_sender.send(cancel) {
+            error!("Send retry cancel failed {:?}", e);
+        }
+        let _: Option<ReadRespContext> = self.read_resp_map.write().remove(&(qpn, msn));
+        let user_op_ctx = self.user_op_ctx_map.write().remove(&(qpn, msn));
+        if let Some(ctx) = user_op_ctx {
+            if let Some(handler) = ctx.take_handler() {
+                handler(status);
+            }
+            ctx.set_error(status);
+        } else {
+            error!("No op ctx found for {:?}", (qpn, msn));
+        }
+    }
+
+    fn is_qp_ready_to_recv(&self, qpn: Qpn) -> bool {
+        self.qp_table
+            .read()
+            .get(&qpn)
+            .is_some_and(|qp| qp.state.load(Ordering::Acquire).can_recv())
+    }
+
+    /// Drop the receiving contexts of a qp if its expected psn is changed
+    fn sync_recv_ctx(&self, qpn: Qpn) {
+        if let Some(qp) = self.qp_table.read().get(&qpn) {
+            if qp.recv_ctx_outdated.swap(false, Ordering::AcqRel) {
+                self.recv_ctx_map.reset_per_qp_ctx(qpn, qp.rq_psn);
+            }
+        }
+    }
+
+    /// Handle an in-order packet. An unreliable qp sends no ack.
+    fn handle_qp_normal(&self, event: &ToHostWorkRbDescWriteOrReadResp, is_reliable: bool) {
         let qpn = event.common.dqpn;
         let msn = event.common.msn;
 
@@ -149,21 +375,25 @@ impl PacketCheckerContext {
                 }
             }
             ToHostWorkRbDescWriteType::Last => {
-                self.recv_ctx_map.remove_ctx(qpn, msn);
-                #[allow(clippy::else_if_without_else)]
-                if event.is_read_resp {
-                    wakeup_user_op_ctx(&self.user_op_ctx_map, qpn, msn);
-                } else if !event.can_auto_ack {
+                let ctx = self.recv_ctx_map.remove_ctx(qpn, msn);
+                // A retransmitted last packet has no context, so it won't be completed twice.
+                if let Some(mut ctx) = ctx {
+                    ctx.set_last_packet(event);
+                    self.finish_recv_ctx(qpn, &ctx, event.imm);
+                }
+                if !event.can_auto_ack && is_reliable {
                     self.send_ack(qpn, msn, event.psn);
                 }
             }
             ToHostWorkRbDescWriteType::Only => {
+                let status = self.recv_ctx_map.query_recent_msn_status(qpn, msn);
                 self.recv_ctx_map
                     .set_recent_msn_status(qpn, msn, RecentQpMsnStatus::Finished);
-                #[allow(clippy::else_if_without_else)]
-                if event.is_read_resp {
-                    wakeup_user_op_ctx(&self.user_op_ctx_map, qpn, msn);
-                } else if !event.can_auto_ack {
+                if !matches!(status, RecentQpMsnStatus::Finished) {
+                    let ctx = RecvContext::from(event);
+                    self.finish_recv_ctx(qpn, &ctx, event.imm);
+                }
+                if !event.can_auto_ack && is_reliable {
                     self.send_ack(qpn, msn, event.psn);
                 }
             }
@@ -171,6 +401,48 @@ impl PacketCheckerContext {
         };
     }
 
+    /// Handle a packet of a UC qp.
+    ///
+    /// The lost packets are never retransmitted, so a message with a psn gap is silently dropped.
+    /// The packets of a UC qp arrive in order, so a new message also drops the one in progress.
+    fn handle_qp_uc(&self, event: &ToHostWorkRbDescWriteOrReadResp, is_gap: bool) {
+        let qpn = event.common.dqpn;
+        let is_start = matches!(
+            event.write_type,
+            ToHostWorkRbDescWriteType::First | ToHostWorkRbDescWriteType::Only
+        );
+        if is_gap || is_start {
+            self.recv_ctx_map
+                .reset_per_qp_ctx(qpn, event.psn.wrapping_add(1));
+        }
+        if is_gap && !is_start {
+            warn!(
+                "Drop the incomplete message of qp {:?}, expected psn {:?}, received psn {:?}",
+                qpn, event.common.expected_psn, event.psn
+            );
+            return;
+        }
+        self.handle_qp_normal(event, false);
+    }
+
+    fn send_nak(
+        &self,
+        qpn: Qpn,
+        msn: Msn,
+        psn: Psn,
+        last_retry_psn: Option<Psn>,
+        code: ToHostWorkRbDescNakCode,
+    ) {
+        let slot = self.ack_buffers.recycle_buf();
+        if let Ok(desc) = make_nack(slot, &self.qp_table, qpn, msn, psn, last_retry_psn, code) {
+            if let Err(e) = self.work_desc_sender.send_work_desc(desc) {
+                error!("Send nak failed {:?}", e);
+            }
+        } else {
+            error!("make nak failed");
+        }
+    }
+
     fn send_ack(&self, qpn: Qpn, msn: Msn, psn: Psn) {
         let slot = self.ack_buffers.recycle_buf();
         if let Ok(desc) = make_ack(slot, &self.qp_table, qpn, msn, psn) {
@@ -182,6 +454,85 @@ impl PacketCheckerContext {
         }
     }
 
+    /// Report a received message to user, if it is a send or a write with immediate.
+    fn finish_recv_ctx(&self, qpn: Qpn, ctx: &RecvContext, imm: Option<u32>) {
+        if ctx.is_send {
+            // the immediate data of a send is reported with the receive buffer
+            self.complete_recv_wqe(qpn, ctx.start_addr, ctx.len_in_bytes, imm, None);
+            return;
+        }
+        if let Some(imm) = imm {
+            self.notify_imm(qpn, imm, ctx.start_addr, ctx.len_in_bytes);
+        }
+    }
+
+    fn notify_imm(&self, qpn: Qpn, imm: u32, addr: u64, len: u32) {
+        if let Some(qp) = self.qp_table.read().get(&qpn) {
+            if let Some(cq) = qp.recv_cq.as_ref() {
+                cq.push(WorkCompletion {
+                    wr_id: 0,
+                    status: WorkCompletionStatus::Success,
+                    opcode: WorkCompletionOpcode::RecvRdmaWithImm,
+                    qpn,
+                    byte_len: len,
+                    imm: Some(Imm::new(imm)),
+                    src_qpn: None,
+                    src_ip: None,
+                    retry_cnt: 0,
+                });
+            }
+            let notification = ImmNotification {
+                qpn,
+                imm: Imm::new(imm),
+                addr,
+                len,
+            };
+            // The qp context holds a receiver, so it only fails when the channel is full
+            if let Err(e) = qp.imm_sender.try_send(notification) {
+                let _: u64 = qp.imm_dropped.fetch_add(1, Ordering::Relaxed);
+                error!("Send imm notification failed {:?}", e);
+            }
+        } else {
+            error!("No qp found for imm notification, qpn={:?}", qpn);
+        }
+    }
+
+    /// Finish the receive buffer consumed by a send message, which starts at `addr`.
+    ///
+    /// The `imm` is the immediate data of the send, and `src` is the source qpn and ip of a datagram.
+    fn complete_recv_wqe(
+        &self,
+        qpn: Qpn,
+        addr: u64,
+        len: u32,
+        imm: Option<u32>,
+        src: Option<(Qpn, Ipv4Addr)>,
+    ) {
+        let qp_table = self.qp_table.read();
+        let qp = qp_table.get(&qpn);
+        let wqe = qp.and_then(|qp_ctx| qp_ctx.take_recv_wqe(addr));
+        if let Some(wqe) = wqe {
+            if let Some(cq) = qp.and_then(|qp_ctx| qp_ctx.recv_cq.as_ref()) {
+                cq.push(WorkCompletion {
+                    wr_id: wqe.wr_id,
+                    status: WorkCompletionStatus::Success,
+                    opcode: WorkCompletionOpcode::Recv,
+                    qpn,
+                    byte_len: len,
+                    imm: imm.map(Imm::new),
+                    src_qpn: src.map(|(src_qpn, _)| src_qpn),
+                    src_ip: src.map(|(_, src_ip)| src_ip),
+                    retry_cnt: 0,
+                });
+            }
+            if let Err(e) = wqe.ctx.set_result(len) {
+                error!("Set result failed {:?}", e);
+            }
+        } else {
+            error!("No recv buffer found for qpn={:?}, addr={:#x}", qpn, addr);
+        }
+    }
+
     // handle qp that out-of-order
     #[allow(clippy::unwrap_used)]
     fn handle_qp_ooo(&self, event: &ToHostWorkRbDescWriteOrReadResp, pmtu: Pmtu) {
@@ -195,6 +546,7 @@ impl PacketCheckerContext {
             .unwrap() // qp_ctx should have created when it enters in error status
             .largest_psn_recved();
         let recved_psn = event.psn;
+        self.report_missing_packets(event, largest_psn_recved);
         match event.write_type {
             ToHostWorkRbDescWriteType::First => {
                 let status = self.recv_ctx_map.query_recent_msn_status(qpn, msn);
@@ -205,6 +557,14 @@ impl PacketCheckerContext {
             }
             ToHostWorkRbDescWriteType::Middle | ToHostWorkRbDescWriteType::Last => {
                 if let Some(mut ctx) = self.recv_ctx_map.get_ctx_mut(qpn, msn) {
+                    if event.imm.is_some() {
+                        ctx.imm = event.imm;
+                    }
+                    if matches!(event.write_type, ToHostWorkRbDescWriteType::Last) {
+                        ctx.set_last_packet(event);
+                        let packet_cnt = ctx.packet_cnt(pmtu);
+                        ctx.recv_map.as_mut().unwrap().set_num_of_packets(packet_cnt);
+                    }
                     let expected_psn = event.common.expected_psn;
                     let range = get_continous_range(largest_psn_recved, expected_psn);
                     let recv_map = ctx.recv_map.as_mut().unwrap();
@@ -217,12 +577,14 @@ impl PacketCheckerContext {
                 // otherwise, we ignore this packet,but we should record its psn
             }
             ToHostWorkRbDescWriteType::Only => {
+                let status = self.recv_ctx_map.query_recent_msn_status(qpn, msn);
                 self.recv_ctx_map
                     .set_recent_msn_status(qpn, msn, RecentQpMsnStatus::Finished);
-                #[allow(clippy::else_if_without_else)]
-                if event.is_read_resp {
-                    wakeup_user_op_ctx(&self.user_op_ctx_map, qpn, msn);
-                } else if !event.can_auto_ack {
+                if !matches!(status, RecentQpMsnStatus::Finished) {
+                    let ctx = RecvContext::from(event);
+                    self.finish_recv_ctx(qpn, &ctx, event.imm);
+                }
+                if !event.can_auto_ack {
                     self.send_ack(qpn, msn, event.psn);
                 }
             }
@@ -237,34 +599,53 @@ impl PacketCheckerContext {
         }
     }
 
+    /// Ask the requester to retransmit the packets skipped by an out-of-order packet.
+    ///
+    /// The packets before the expected psn and the ones up to the largest received psn have
+    /// been seen, so every missing range is reported once, when a packet beyond it arrives.
+    /// A lost retransmission is recovered by the timeout of the requester.
+    fn report_missing_packets(
+        &self,
+        event: &ToHostWorkRbDescWriteOrReadResp,
+        largest_psn_recved: Psn,
+    ) {
+        let expected_psn = event.common.expected_psn;
+        let next_psn = largest_psn_recved.wrapping_add(1);
+        let first_missing = if expected_psn.larger_in_psn(next_psn) {
+            expected_psn
+        } else {
+            next_psn
+        };
+        if event.psn == first_missing || !event.psn.larger_in_psn(first_missing) {
+            return;
+        }
+        let last_missing = event.psn.wrapping_sub(1);
+        let code = ToHostWorkRbDescNakCode::PsnSeqErr;
+        let (qpn, msn) = (event.common.dqpn, event.common.msn);
+        self.send_nak(qpn, msn, first_missing, Some(last_missing), code);
+    }
+
     /// Check if corresponding msn is completed and try to recover the qp status
     #[allow(clippy::unwrap_used)]
     fn check_completed_and_try_recover(&self, qpn: Qpn, msn: Msn) {
         let mut perqp_map = self.recv_ctx_map.get_per_qp_ctx_mut(qpn).unwrap();
-        let (is_read_resp, is_completed, last_psn, recover_psn) =
+        let (is_completed, last_psn, recover_psn) =
             if let Some(ctx) = perqp_map.map.get_mut(&msn) {
                 let recv_map = ctx.recv_map.as_ref().unwrap();
-                (
-                    ctx.is_read_resp,
-                    recv_map.is_complete(),
-                    recv_map.last_psn(),
-                    recv_map.try_get_recover_psn(),
-                )
+                (recv_map.is_complete(), recv_map.last_psn(), recv_map.try_get_recover_psn())
             } else {
-                (false, false, Psn::default(), None)
+                (false, Psn::default(), None)
             };
 
         // decrease borrow
         drop(perqp_map);
 
         if is_completed {
-            self.recv_ctx_map.remove_ctx(qpn, msn);
-            if is_read_resp {
-                wakeup_user_op_ctx(&self.user_op_ctx_map, qpn, msn);
-            } else {
-                // we should manually send ack the packet
-                self.send_ack(qpn, msn, last_psn);
+            if let Some(ctx) = self.recv_ctx_map.remove_ctx(qpn, msn) {
+                self.finish_recv_ctx(qpn, &ctx, ctx.imm);
             }
+            // we should manually send ack the packet
+            self.send_ack(qpn, msn, last_psn);
         }
 
         let perqp_map_len = self.recv_ctx_map.get_per_qp_ctx_mut(qpn).unwrap().map.len();
@@ -302,17 +683,19 @@ impl PacketCheckerContext {
 
         // create context for all msn
 
+        // the packets before the expected psn have been received
+        let last_continous_psn = expected_psn.wrapping_sub(1);
         let mut per_qp_map = self
             .recv_ctx_map
-            .get_or_create_per_qp_ctx_mut(qpn, recved_psn);
+            .get_or_create_per_qp_ctx_mut(qpn, last_continous_psn);
+        per_qp_map.update_largest_psn_recved(last_continous_psn);
         // we know that if we are previous in the normal status,
         // we should have only one or not recv context left.
         debug_assert!(per_qp_map.map.len() <= 1, "Not in normal status");
 
         // the expected_psn is the psn that we should receive **next**
-        let start_psn = expected_psn.wrapping_sub(1);
         for (_, ctx) in per_qp_map.map.iter_mut() {
-            ctx.create_map_on_psn(start_psn, recved_psn, pmtu);
+            ctx.create_map_on_psn(last_continous_psn, recved_psn, pmtu);
         }
     }
 }
@@ -322,7 +705,12 @@ fn wakeup_user_op_ctx(
     qpn: Qpn,
     msn: Msn,
 ) {
-    if let Some(ctx) = user_op_ctx_map.read().get(&(qpn, msn)) {
+    // the finished request is not tracked anymore
+    let user_op_ctx = user_op_ctx_map.write().remove(&(qpn, msn));
+    if let Some(ctx) = user_op_ctx {
+        if let Some(handler) = ctx.take_handler() {
+            handler(WorkCompletionStatus::Success);
+        }
         if let Err(e) = ctx.set_result(()) {
             error!("Set result failed {:?}", e);
         }
@@ -344,8 +732,8 @@ fn try_recover(
             recover_psn,
         });
     if let Ok(ctrl_ctx) = ctrl_desc_sender.send_ctrl_desc(desc) {
-        ctrl_ctx.set_handler(Box::new(move |is_succ| {
-            if is_succ {
+        ctrl_ctx.set_handler(Box::new(move |status| {
+            if matches!(status, WorkCompletionStatus::Success) {
                 if let Some(qp_ctx) = qp_table.read().get(&qpn) {
                     qp_ctx
                         .status
@@ -439,16 +827,24 @@ impl RecvContextMap {
         }
     }
 
-    fn remove_ctx(&self, qpn: Qpn, msn: Msn) {
+    fn remove_ctx(&self, qpn: Qpn, msn: Msn) -> Option<RecvContext> {
         let mut inner = self.0.borrow_mut();
         if let Some(per_qp_map) = inner.get_mut(&qpn) {
-            let _dont_care = per_qp_map.map.remove(&msn);
+            let ctx = per_qp_map.map.remove(&msn);
             per_qp_map.set_recent_msn_status(msn, RecentQpMsnStatus::Finished);
+            ctx
         } else {
             log::error!("No recv ctx found for qpn={:?},msn={:?}", qpn, msn);
+            None
         }
     }
 
+    /// Replace the context of a qp with an empty one, which expects `expected_psn` next
+    fn reset_per_qp_ctx(&self, qpn: Qpn, expected_psn: Psn) {
+        let mut inner = self.0.borrow_mut();
+        let _dont_care = inner.insert(qpn, PerQpContextMap::new(expected_psn.wrapping_sub(1)));
+    }
+
     fn remove_per_qp_ctx(&self, qpn: Qpn) {
         let mut inner = self.0.borrow_mut();
         let _dont_care = inner.remove(&qpn);
@@ -520,25 +916,45 @@ impl RecvContextMap {
 
 #[derive(Debug, Default)]
 pub(crate) struct RecvContext {
-    is_read_resp: bool,
+    is_send: bool,
     start_addr: u64,
     len_in_bytes: u32,
     start_psn: Psn,
+    // The immediate data carried by the last packet, which may arrive before the message completes
+    imm: Option<u32>,
+    // A send packet carries no RETH, so the length of a send message is known by its last packet
+    is_len_known: bool,
     recv_map: Option<Box<SlidingWindow>>,
 }
 
 impl RecvContext {
     pub(crate) fn new_with_recvmap(event: &ToHostWorkRbDescWriteOrReadResp, pmtu: Pmtu) -> Self {
-        let pkt_len = calculate_packet_cnt(pmtu, event.addr, event.len);
-        let mut map = Box::new(SlidingWindow::new(event.psn, pkt_len));
+        let mut ctx = Self::from(event);
+        let mut map = Box::new(SlidingWindow::new(event.psn, ctx.packet_cnt(pmtu)));
         map.insert((event.psn, event.psn));
-        Self {
-            is_read_resp: event.is_read_resp,
-            start_addr: event.addr,
-            len_in_bytes: event.len,
-            start_psn: event.psn,
-            recv_map: Some(map),
+        ctx.recv_map = Some(map);
+        ctx
+    }
+
+    fn packet_cnt(&self, pmtu: Pmtu) -> u32 {
+        if !self.is_len_known {
+            // the window never completes before the last packet arrives
+            return PSN_MAX_WINDOW_SIZE;
+        }
+        // A send message is split by the offset in receive buffer rather than the address
+        let start_addr = if self.is_send { 0 } else { self.start_addr };
+        calculate_packet_cnt(pmtu, start_addr, self.len_in_bytes)
+    }
+
+    /// Learn the length of a send message from its last packet, which ends the message.
+    #[allow(clippy::cast_possible_truncation)] // the message length is from a u32
+    fn set_last_packet(&mut self, event: &ToHostWorkRbDescWriteOrReadResp) {
+        if self.is_len_known {
+            return;
         }
+        let end_addr = event.addr.wrapping_add(u64::from(event.len));
+        self.len_in_bytes = end_addr.wrapping_sub(self.start_addr) as u32;
+        self.is_len_known = true;
     }
 
     pub(crate) fn create_map_on_psn(
@@ -547,8 +963,7 @@ impl RecvContext {
         recved_psn: Psn,
         pmtu: Pmtu,
     ) {
-        let pkt_len = calculate_packet_cnt(pmtu, self.start_addr, self.len_in_bytes);
-        let mut map = Box::new(SlidingWindow::new(self.start_psn, pkt_len));
+        let mut map = Box::new(SlidingWindow::new(self.start_psn, self.packet_cnt(pmtu)));
         map.insert((self.start_psn, last_continous_psn));
         map.insert((recved_psn, recved_psn));
         self.recv_map = Some(map);
@@ -563,15 +978,79 @@ impl RecvContext {
 impl From<&ToHostWorkRbDescWriteOrReadResp> for RecvContext {
     fn from(event: &ToHostWorkRbDescWriteOrReadResp) -> Self {
         RecvContext {
-            is_read_resp: event.is_read_resp,
+            is_send: event.is_send,
             start_addr: event.addr,
             len_in_bytes: event.len,
             start_psn: event.psn,
+            imm: event.imm,
+            is_len_known: !event.is_send
+                || matches!(event.write_type, ToHostWorkRbDescWriteType::Only),
             recv_map: None,
         }
     }
 }
 
+/// The response packets of an outgoing read which have landed in the local buffer.
+///
+/// The responses are split at the pmtu boundaries of the local address, so a packet is
+/// located by its address no matter which psn the responder gave it.
+#[derive(Debug)]
+pub(crate) struct ReadRespContext {
+    laddr: u64,
+    len: u32,
+    pmtu: Pmtu,
+    window: SlidingWindow,
+}
+
+impl ReadRespContext {
+    pub(crate) fn new(laddr: u64, len: u32, pmtu: Pmtu) -> Self {
+        let window = SlidingWindow::new(Psn::default(), calculate_packet_cnt(pmtu, laddr, len));
+        Self {
+            laddr,
+            len,
+            pmtu,
+            window,
+        }
+    }
+
+    /// The index of the packet which starts at `addr`, or `None` if it's out of the buffer
+    #[allow(clippy::arithmetic_side_effects)] // the pmtu is not zero
+    fn packet_index(&self, addr: u64) -> Option<u32> {
+        let offset = addr.checked_sub(self.laddr)?;
+        if offset >= u64::from(self.len) {
+            return None;
+        }
+        let pmtu = u64::from(u32::from(&self.pmtu));
+        u32::try_from(addr / pmtu - self.laddr / pmtu).ok()
+    }
+
+    /// Record the response packet which starts at `addr`
+    pub(crate) fn insert(&mut self, addr: u64) {
+        let Some(index) = self.packet_index(addr) else {
+            warn!("Drop the read response at {addr:#x}, which is out of the local buffer");
+            return;
+        };
+        self.window.insert((Psn::new(index), Psn::new(index)));
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.window.is_complete()
+    }
+
+    /// The length of the bytes which have landed continuously from the start of the buffer
+    #[allow(clippy::arithmetic_side_effects)] // the pmtu is not zero
+    pub(crate) fn received_len(&self) -> u32 {
+        let packets = self.window.continuous_packets();
+        if packets == 0 {
+            return 0;
+        }
+        let pmtu = u64::from(u32::from(&self.pmtu));
+        let end = (self.laddr / pmtu + u64::from(packets)).saturating_mul(pmtu);
+        let received = end.saturating_sub(self.laddr).min(u64::from(self.len));
+        u32::try_from(received).unwrap_or(self.len)
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct SlidingWindow {
     intervals: BTreeMap<u32, u32>,
@@ -660,6 +1139,11 @@ impl SlidingWindow {
         }
     }
 
+    /// Shrink the window to the packets of a message whose length is learnt late.
+    pub(crate) fn set_num_of_packets(&mut self, num_of_packets: u32) {
+        self.num_of_packets = num_of_packets;
+    }
+
     #[allow(clippy::arithmetic_side_effects)]
     pub(crate) fn is_complete(&self) -> bool {
         if self.intervals.is_empty() {
@@ -688,6 +1172,12 @@ impl SlidingWindow {
         self.start_psn.wrapping_add(self.num_of_packets - 1)
     }
 
+    /// The number of packets received continuously from the start
+    #[allow(clippy::arithmetic_side_effects)] // the end offset is less than the packet count
+    pub(crate) fn continuous_packets(&self) -> u32 {
+        self.intervals.get(&0).map_or(0, |end| end + 1)
+    }
+
     pub(crate) fn is_out_of_order(&self) -> bool {
         !self.is_complete() && self.intervals.len() > 1
     }
@@ -703,6 +1193,52 @@ pub(crate) enum PacketCheckEvent {
     Write(ToHostWorkRbDescWriteOrReadResp),
     Ack(ToHostWorkRbDescAck),
     ReadReq(ToHostWorkRbDescRead),
+    AtomicReq(ToHostWorkRbDescAtomic),
+    Datagram(ToHostWorkRbDescDatagram),
+    Sent(ToHostWorkRbDescSent),
+}
+
+impl PacketCheckEvent {
+    fn qpn(&self) -> Qpn {
+        match self {
+            PacketCheckEvent::Write(desc) => desc.common.dqpn,
+            PacketCheckEvent::Ack(desc) => desc.common.dqpn,
+            PacketCheckEvent::ReadReq(desc) => desc.common.dqpn,
+            PacketCheckEvent::AtomicReq(desc) => desc.common.dqpn,
+            PacketCheckEvent::Datagram(desc) => desc.common.dqpn,
+            PacketCheckEvent::Sent(desc) => desc.common.dqpn,
+        }
+    }
+
+    fn expected_psn(&self) -> Psn {
+        match self {
+            PacketCheckEvent::Write(desc) => desc.common.expected_psn,
+            PacketCheckEvent::Ack(desc) => desc.common.expected_psn,
+            PacketCheckEvent::ReadReq(desc) => desc.common.expected_psn,
+            PacketCheckEvent::AtomicReq(desc) => desc.common.expected_psn,
+            PacketCheckEvent::Datagram(desc) => desc.common.expected_psn,
+            PacketCheckEvent::Sent(desc) => desc.common.expected_psn,
+        }
+    }
+
+    /// The msn, psn and status of a request failed by the card
+    fn failed_request(&self) -> Option<(Msn, Psn, ToHostWorkRbDescStatus)> {
+        let (msn, psn, status) = match self {
+            PacketCheckEvent::Write(desc) if !desc.is_read_resp => {
+                (desc.common.msn, desc.psn, &desc.common.status)
+            }
+            // a read request carries no psn, but it is the expected one if it's in order
+            PacketCheckEvent::ReadReq(desc) => {
+                (desc.common.msn, desc.common.expected_psn, &desc.common.status)
+            }
+            PacketCheckEvent::AtomicReq(desc) => (desc.common.msn, desc.psn, &desc.common.status),
+            PacketCheckEvent::Write(_)
+            | PacketCheckEvent::Ack(_)
+            | PacketCheckEvent::Datagram(_)
+            | PacketCheckEvent::Sent(_) => return None,
+        };
+        (!status.is_ok()).then(|| (msn, psn, status.clone()))
+    }
 }
 
 impl From<ToHostWorkRbDescWriteOrReadResp> for PacketCheckEvent {
@@ -711,6 +1247,22 @@ impl From<ToHostWorkRbDescWriteOrReadResp> for PacketCheckEvent {
     }
 }
 
+impl From<ToHostWorkRbDescWriteWithImm> for PacketCheckEvent {
+    fn from(desc: ToHostWorkRbDescWriteWithImm) -> Self {
+        Self::Write(ToHostWorkRbDescWriteOrReadResp {
+            common: desc.common,
+            is_read_resp: false,
+            is_send: false,
+            write_type: desc.write_type,
+            psn: desc.psn,
+            addr: desc.addr,
+            len: desc.len,
+            can_auto_ack: desc.can_auto_ack,
+            imm: Some(desc.imm),
+        })
+    }
+}
+
 impl From<ToHostWorkRbDescAck> for PacketCheckEvent {
     fn from(desc: ToHostWorkRbDescAck) -> Self {
         Self::Ack(desc)
@@ -723,6 +1275,24 @@ impl From<ToHostWorkRbDescRead> for PacketCheckEvent {
     }
 }
 
+impl From<ToHostWorkRbDescAtomic> for PacketCheckEvent {
+    fn from(desc: ToHostWorkRbDescAtomic) -> Self {
+        Self::AtomicReq(desc)
+    }
+}
+
+impl From<ToHostWorkRbDescDatagram> for PacketCheckEvent {
+    fn from(desc: ToHostWorkRbDescDatagram) -> Self {
+        Self::Datagram(desc)
+    }
+}
+
+impl From<ToHostWorkRbDescSent> for PacketCheckEvent {
+    fn from(desc: ToHostWorkRbDescSent) -> Self {
+        Self::Sent(desc)
+    }
+}
+
 impl Default for PacketCheckEvent {
     fn default() -> Self {
         Self::Write(ToHostWorkRbDescWriteOrReadResp::default())
@@ -752,14 +1322,35 @@ mod tests {
 
     use crate::{
         checker::get_continous_range,
+        cq::WorkCompletionStatus,
         device::ToCardCtrlRbDesc,
         op_ctx::{CtrlOpCtx, OpCtx},
-        types::{Msn, Psn, Qpn},
+        types::{Msn, Pmtu, Psn, Qpn},
         CtrlDescriptorSender,
     };
 
     use super::wakeup_user_op_ctx;
 
+    #[test]
+    fn test_read_resp_context() {
+        // the first packet ends at the pmtu boundary of the local address
+        let mut ctx = super::ReadRespContext::new(0x1200, 3000, Pmtu::Mtu1024);
+        assert_eq!(ctx.received_len(), 0);
+        ctx.insert(0x1200);
+        assert_eq!(ctx.received_len(), 512);
+        ctx.insert(0x1800);
+        assert_eq!(ctx.received_len(), 512);
+        // the addresses out of the local buffer are ignored
+        ctx.insert(0x1000);
+        ctx.insert(0x1db8);
+        ctx.insert(0x1400);
+        assert_eq!(ctx.received_len(), 2560);
+        assert!(!ctx.is_complete());
+        ctx.insert(0x1c00);
+        assert!(ctx.is_complete());
+        assert_eq!(ctx.received_len(), 3000);
+    }
+
     #[test]
     fn test_sliding_window() {
         let start = 0;
@@ -898,7 +1489,7 @@ mod tests {
         }
 
         let handler = ctx.take_handler().unwrap();
-        handler(true);
+        handler(WorkCompletionStatus::Success);
         let guard = qp_table.read();
         let status = guard
             .get(&qpn)
@@ -924,6 +1515,7 @@ mod tests {
         wakeup_user_op_ctx(&user_op_ctx_map, qpn, msn);
         sleep(Duration::from_millis(10));
         assert!(flag.load(Ordering::Acquire));
+        assert!(user_op_ctx_map.read().is_empty());
     }
 
     #[test]
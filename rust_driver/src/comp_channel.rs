@@ -0,0 +1,162 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use crate::{cq::WeakCq, Cq, Device, Error};
+
+/// Completion channel
+///
+/// A completion channel owns an eventfd, which becomes readable when a CQ bound to the channel
+/// gets a new completion after `Cq::req_notify`. User can register the fd to an event loop,
+/// e.g. epoll or mio, and call `get_cq_event` to find the notified CQ when the fd is readable.
+#[derive(Debug, Clone)]
+pub struct CompChannel(Arc<CompChannelInner>);
+
+#[derive(Debug)]
+struct CompChannelInner {
+    // The eventfd is in semaphore mode, so every read consumes exactly one event
+    eventfd: File,
+    // the notified CQs, which may be dropped before their events are read
+    events: Mutex<VecDeque<WeakCq>>,
+}
+
+impl CompChannel {
+    pub(crate) fn new() -> Result<Self, Error> {
+        // SAFETY: no pointer is passed to `eventfd`
+        let fd = unsafe {
+            libc::eventfd(
+                0,
+                libc::EFD_NONBLOCK | libc::EFD_CLOEXEC | libc::EFD_SEMAPHORE,
+            )
+        };
+        if fd < 0_i32 {
+            return Err(Error::Device(Box::new(io::Error::last_os_error())));
+        }
+        // SAFETY: the fd is just created and owned by nobody else
+        let eventfd = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        Ok(Self(Arc::new(CompChannelInner {
+            eventfd,
+            events: Mutex::new(VecDeque::new()),
+        })))
+    }
+
+    /// Get a CQ which has a new completion since it was armed by `Cq::req_notify`
+    ///
+    /// Returns `None` if there is no event. The CQ is disarmed after the event,
+    /// so user should arm it again before polling the remaining completions.
+    /// The events of the dropped CQs are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if failed to read the eventfd.
+    pub fn get_cq_event(&self) -> Result<Option<Cq>, Error> {
+        let mut buf = [0u8; 8];
+        loop {
+            match (&self.0.eventfd).read(&mut buf) {
+                Ok(_) => {
+                    if let Some(cq) = self.0.events.lock().pop_front().and_then(|cq| cq.upgrade()) {
+                        return Ok(Some(cq));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(Error::Device(Box::new(e))),
+            }
+        }
+    }
+
+    /// Notify the user that `cq` has a new completion
+    pub(crate) fn notify(&self, cq: WeakCq) {
+        // the event is queued before the eventfd is signaled, so a reader always finds it
+        self.0.events.lock().push_back(cq);
+        if let Err(e) = (&self.0.eventfd).write_all(&1_u64.to_ne_bytes()) {
+            log::error!("Failed to signal the completion channel: {:?}", e);
+        }
+    }
+}
+
+impl AsFd for CompChannel {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.eventfd.as_fd()
+    }
+}
+
+impl AsRawFd for CompChannel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.eventfd.as_raw_fd()
+    }
+}
+
+impl Device {
+    /// create a completion channel, which notifies the completions of its CQs through an eventfd
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if failed to create the eventfd.
+    pub fn create_comp_channel(&self) -> Result<CompChannel, Error> {
+        CompChannel::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::fd::AsRawFd, sync::Arc};
+
+    use crate::cq::{Cq, WorkCompletion};
+
+    use super::CompChannel;
+
+    fn is_readable(channel: &CompChannel) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: channel.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pollfd` is a valid pointer to one `pollfd`
+        let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        ret == 1 && pollfd.revents & libc::POLLIN != 0
+    }
+
+    #[test]
+    fn test_comp_channel_notify() {
+        let channel = CompChannel::new().unwrap();
+        let cq = Cq::new_with_channel(4, Some(channel.clone()));
+
+        // a CQ not armed doesn't notify
+        cq.push(WorkCompletion::default());
+        assert!(!is_readable(&channel));
+        assert!(channel.get_cq_event().unwrap().is_none());
+
+        // only the first completion after arming notifies
+        cq.req_notify().unwrap();
+        cq.push(WorkCompletion::default());
+        cq.push(WorkCompletion::default());
+        assert!(is_readable(&channel));
+        let notified = channel.get_cq_event().unwrap().unwrap();
+        assert_eq!(notified.depth(), 4);
+        assert!(!is_readable(&channel));
+        assert!(channel.get_cq_event().unwrap().is_none());
+
+        let mut wc = [WorkCompletion::default(); 4];
+        assert_eq!(notified.poll(&mut wc).unwrap(), 3);
+
+        // a pending event doesn't keep its CQ alive, and is skipped once the CQ is dropped
+        let dropped = Cq::new_with_channel(4, Some(channel.clone()));
+        dropped.req_notify().unwrap();
+        dropped.push(WorkCompletion::default());
+        cq.req_notify().unwrap();
+        cq.push(WorkCompletion::default());
+        drop(dropped);
+        assert_eq!(Arc::strong_count(&channel.0), 2);
+        assert!(channel.get_cq_event().unwrap().is_some());
+        assert!(!is_readable(&channel));
+
+        // a CQ without a channel can't be armed
+        assert!(Cq::new(4).req_notify().is_err());
+    }
+}
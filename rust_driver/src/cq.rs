@@ -0,0 +1,285 @@
+use std::{
+    collections::VecDeque,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    comp_channel::CompChannel,
+    device::ToCardWorkRbDescOpcode,
+    types::{Imm, Qpn},
+    Device, Error,
+};
+
+/// The status of a work completion
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WorkCompletionStatus {
+    /// The work request is finished successfully
+    #[default]
+    Success,
+    /// The work request is not acknowledged by the peer after the max retry
+    RetryExceeded,
+    /// The peer keeps responding RNR NAK to the work request after the max RNR retry
+    RnrRetryExceeded,
+    /// The work request is flushed because the QP is moved to `Reset` or `Error`
+    FlushError,
+    /// The peer NAKs the work request as an invalid request, e.g. an unsupported opcode
+    RemoteInvalidRequest,
+    /// The peer NAKs the work request as a protection error, e.g. an invalid rkey or remote address
+    RemoteAccessError,
+    /// The peer NAKs the work request because it failed to execute it
+    RemoteOperationalError,
+    /// The operation failed for other reasons, e.g. the card returns a failure
+    GeneralError,
+    /// The work request is not finished before the device is closed
+    DeviceClosed,
+}
+
+/// The opcode of a work completion
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WorkCompletionOpcode {
+    /// Send
+    #[default]
+    Send,
+    /// RDMA write, including RDMA write with immediate
+    RdmaWrite,
+    /// RDMA read
+    RdmaRead,
+    /// Atomic compare and swap
+    CompareSwap,
+    /// Atomic fetch and add
+    FetchAdd,
+    /// A send is received into a posted receive buffer
+    Recv,
+    /// A RDMA write with immediate is received
+    RecvRdmaWithImm,
+}
+
+impl From<ToCardWorkRbDescOpcode> for WorkCompletionOpcode {
+    fn from(opcode: ToCardWorkRbDescOpcode) -> Self {
+        match opcode {
+            ToCardWorkRbDescOpcode::Write | ToCardWorkRbDescOpcode::WriteWithImm => {
+                WorkCompletionOpcode::RdmaWrite
+            }
+            ToCardWorkRbDescOpcode::Read | ToCardWorkRbDescOpcode::ReadResp => {
+                WorkCompletionOpcode::RdmaRead
+            }
+            ToCardWorkRbDescOpcode::Send => WorkCompletionOpcode::Send,
+            ToCardWorkRbDescOpcode::AtomicCmpSwap => WorkCompletionOpcode::CompareSwap,
+            ToCardWorkRbDescOpcode::AtomicFetchAdd => WorkCompletionOpcode::FetchAdd,
+        }
+    }
+}
+
+/// Work completion
+///
+/// The completion of a work request, polled from a completion queue.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WorkCompletion {
+    /// The id of work request given by user.
+    ///
+    /// A received RDMA write with immediate doesn't consume a receive buffer, so its `wr_id` is 0.
+    pub wr_id: u64,
+    /// The status of the work request
+    pub status: WorkCompletionStatus,
+    /// The opcode of the work request
+    pub opcode: WorkCompletionOpcode,
+    /// The local QP of the work request
+    pub qpn: Qpn,
+    /// The length of the received message. It is only valid for receive completions.
+    pub byte_len: u32,
+    /// The immediate data of a received send or RDMA write with immediate
+    pub imm: Option<Imm>,
+    /// The source QP of a datagram received by a UD QP
+    pub src_qpn: Option<Qpn>,
+    /// The source IP of a datagram received by a UD QP
+    pub src_ip: Option<Ipv4Addr>,
+    /// The number of times the request was retransmitted. It is only valid for send completions.
+    pub retry_cnt: u32,
+}
+
+/// Completion queue
+///
+/// The QPs bound to a completion queue report their work completions to it,
+/// and user polls the completions in the order they are finished.
+/// A CQ bound to a `CompChannel` can also notify user of the new completions.
+#[derive(Debug, Clone)]
+pub struct Cq(Arc<CqInner>);
+
+/// A CQ held by its `CompChannel`, which doesn't keep the CQ alive.
+///
+/// The CQ holds the channel, so the channel holding the CQ back would leak both of them.
+#[derive(Debug)]
+pub(crate) struct WeakCq(Weak<CqInner>);
+
+impl WeakCq {
+    /// Get the CQ, or `None` if it is dropped
+    pub(crate) fn upgrade(&self) -> Option<Cq> {
+        self.0.upgrade().map(Cq)
+    }
+}
+
+#[derive(Debug)]
+struct CqInner {
+    depth: usize,
+    queue: Mutex<VecDeque<WorkCompletion>>,
+    channel: Option<CompChannel>,
+    // whether the next completion should be notified to the channel
+    armed: AtomicBool,
+    // set once a completion is dropped for the queue is full
+    overflowed: AtomicBool,
+}
+
+impl Cq {
+    pub(crate) fn new(depth: usize) -> Self {
+        Self::new_with_channel(depth, None)
+    }
+
+    pub(crate) fn new_with_channel(depth: usize, channel: Option<CompChannel>) -> Self {
+        Self(Arc::new(CqInner {
+            depth,
+            queue: Mutex::new(VecDeque::with_capacity(depth)),
+            channel,
+            armed: AtomicBool::new(false),
+            overflowed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Poll at most `wc.len()` work completions from the completion queue.
+    ///
+    /// Returns the number of polled work completions.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the completion queue has overflowed. The completions are lost since
+    /// then, so the CQ stays in the error state and should be destroyed.
+    pub fn poll(&self, wc: &mut [WorkCompletion]) -> Result<usize, Error> {
+        let mut queue = self.0.queue.lock();
+        if self.0.overflowed.load(Ordering::Acquire) {
+            return Err(Error::CqOverflow);
+        }
+        let cnt = wc.len().min(queue.len());
+        for (slot, completion) in wc.iter_mut().zip(queue.drain(..cnt)) {
+            *slot = completion;
+        }
+        Ok(cnt)
+    }
+
+    /// The max number of completions that the completion queue can hold
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        self.0.depth
+    }
+
+    /// Request a notification to the completion channel for the next completion.
+    ///
+    /// The notification is one-shot, so the CQ should be armed again after it is notified.
+    /// The completions already in the CQ don't trigger a notification, so user should poll
+    /// the CQ after arming it to avoid missing them.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the CQ is not bound to a completion channel.
+    pub fn req_notify(&self) -> Result<(), Error> {
+        if self.0.channel.is_none() {
+            return Err(Error::Invalid("CQ without completion channel".to_owned()));
+        }
+        self.0.armed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    pub(crate) fn push(&self, wc: WorkCompletion) {
+        {
+            let mut queue = self.0.queue.lock();
+            if queue.len() >= self.0.depth {
+                log::error!("completion queue overflow, drop the completion: {:?}", wc);
+                self.0.overflowed.store(true, Ordering::Release);
+                return;
+            }
+            queue.push_back(wc);
+        }
+        if let Some(channel) = &self.0.channel {
+            if self.0.armed.swap(false, Ordering::AcqRel) {
+                channel.notify(WeakCq(Arc::downgrade(&self.0)));
+            }
+        }
+    }
+}
+
+impl Device {
+    /// create a completion queue which can hold `depth` completions
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `depth` is zero.
+    pub fn create_cq(&self, depth: usize) -> Result<Cq, Error> {
+        if depth == 0 {
+            return Err(Error::Invalid("CQ depth: 0".to_owned()));
+        }
+        Ok(Cq::new(depth))
+    }
+
+    /// create a completion queue bound to a completion channel
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `depth` is zero.
+    pub fn create_cq_with_channel(&self, depth: usize, channel: &CompChannel) -> Result<Cq, Error> {
+        if depth == 0 {
+            return Err(Error::Invalid("CQ depth: 0".to_owned()));
+        }
+        Ok(Cq::new_with_channel(depth, Some(channel.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cq, WorkCompletion, WorkCompletionOpcode};
+    use crate::Error;
+
+    #[test]
+    fn test_cq_poll() {
+        let cq = Cq::new(2);
+        for wr_id in 0..2 {
+            cq.push(WorkCompletion {
+                wr_id,
+                opcode: WorkCompletionOpcode::RdmaWrite,
+                ..Default::default()
+            });
+        }
+
+        let mut wc = [WorkCompletion::default(); 1];
+        assert_eq!(cq.poll(&mut wc).unwrap(), 1);
+        assert_eq!(wc[0].wr_id, 0);
+        assert_eq!(wc[0].opcode, WorkCompletionOpcode::RdmaWrite);
+
+        let mut wc = [WorkCompletion::default(); 4];
+        assert_eq!(cq.poll(&mut wc).unwrap(), 1);
+        assert_eq!(wc[0].wr_id, 1);
+        assert_eq!(cq.poll(&mut wc).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cq_overflow() {
+        let cq = Cq::new(2);
+        for wr_id in 0..3 {
+            cq.push(WorkCompletion {
+                wr_id,
+                ..Default::default()
+            });
+        }
+
+        // the third completion overflows, and the CQ stays in the error state
+        let mut wc = [WorkCompletion::default(); 4];
+        assert!(matches!(cq.poll(&mut wc), Err(Error::CqOverflow)));
+        assert!(matches!(cq.poll(&mut wc), Err(Error::CqOverflow)));
+    }
+}
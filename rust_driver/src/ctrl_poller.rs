@@ -7,8 +7,9 @@ use core_affinity::CoreId;
 use log::{error,info};
 
 use crate::{
+    cq::WorkCompletionStatus,
     device::{
-        CtrlRbDescOpcode, ToHostCtrlRbDesc, ToHostRb
+        CtrlRbDescOpcode, DeviceError, ToHostCtrlRbDesc, ToHostRb, TO_HOST_RB_POLL_TIMEOUT
     },
     op_ctx::CtrlOpCtx, ThreadSafeHashmap,
 };
@@ -50,8 +51,9 @@ impl ControlPoller {
 impl ControlPollerContext {
     pub(crate) fn poll_ctrl_thread(ctx: &Self, stop_flag: &AtomicBool) {
         while !stop_flag.load(Ordering::Relaxed) {
-            let desc = match ctx.to_host_ctrl_rb.pop() {
+            let desc = match ctx.to_host_ctrl_rb.pop_timeout(TO_HOST_RB_POLL_TIMEOUT) {
                 Ok(desc) => desc,
+                Err(DeviceError::Timeout) => continue,
                 Err(e) => {
                     error!("failed to fetch descriptor from ctrl rb : {:?}", e);
                     return;
@@ -70,7 +72,11 @@ impl ControlPollerContext {
 
         if let Some(ctx) = ctx_map.get(&desc.common.op_id) {
             if let Some(handler) = ctx.take_handler(){
-                handler(desc.common.is_success);
+                handler(if desc.common.is_success {
+                    WorkCompletionStatus::Success
+                } else {
+                    WorkCompletionStatus::GeneralError
+                });
             }
             if let Err(e) = ctx.set_result(desc.common.is_success) {
                 error!("Set result failed {:?}", e);
@@ -42,6 +42,34 @@ pub(super) const CSR_ADDR_META_REPORT_QUEUE_ADDR_LOW: usize =
 pub(super) const CSR_ADDR_META_REPORT_QUEUE_ADDR_HIGH: usize =
     generate_csr_addr(false, 1, CsrIndex::BaseAddrHigh);
 
+/// The read only registers reporting the max capabilities of the card
+enum CapCsrIndex {
+    Qp = 0x0,
+    Mr = 0x1,
+    MrPgtEntries = 0x2,
+    Sge = 0x3,
+    MsgSize = 0x4,
+    Pmtu = 0x5,
+}
+
+/// The capability registers are placed after the registers of all the queues
+const fn generate_cap_csr_addr(reg_index: CapCsrIndex) -> usize {
+    let mut a = 0b1_0000;
+    a <<= 10_i32;
+    a |= reg_index as usize & 0x3FF;
+    a <<= 2_i32;
+    a
+}
+
+pub(super) const CSR_ADDR_CAP_MAX_QP: usize = generate_cap_csr_addr(CapCsrIndex::Qp);
+pub(super) const CSR_ADDR_CAP_MAX_MR: usize = generate_cap_csr_addr(CapCsrIndex::Mr);
+pub(super) const CSR_ADDR_CAP_MAX_MR_PGT_ENTRIES: usize =
+    generate_cap_csr_addr(CapCsrIndex::MrPgtEntries);
+pub(super) const CSR_ADDR_CAP_MAX_SGE: usize = generate_cap_csr_addr(CapCsrIndex::Sge);
+pub(super) const CSR_ADDR_CAP_MAX_MSG_SIZE: usize = generate_cap_csr_addr(CapCsrIndex::MsgSize);
+// holds the value of `Pmtu`
+pub(super) const CSR_ADDR_CAP_MAX_PMTU: usize = generate_cap_csr_addr(CapCsrIndex::Pmtu);
+
 pub(super) const RINGBUF_DEPTH: usize = 128;
 pub(super) const RINGBUF_ELEM_SIZE: usize = 32;
 pub(super) const RINGBUF_PAGE_SIZE: usize = 4096;
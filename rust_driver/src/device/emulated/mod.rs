@@ -13,7 +13,7 @@ use super::{
     DeviceAdaptor, DeviceError, ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDesc, ToHostCtrlRbDesc,
     ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescError,
 };
-use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+use std::{fmt::Debug, net::SocketAddr, sync::Arc, time::Duration};
 
 use super::scheduler::DescriptorScheduler;
 
@@ -221,11 +221,18 @@ impl<Strat: SchedulerStrategy> DeviceAdaptor for Arc<EmulatedDevice<Strat>> {
     fn use_hugepage(&self) -> bool {
         false
     }
+
+    fn close(&self) {
+        self.scheduler.stop();
+    }
 }
 
 #[allow(clippy::unwrap_used,clippy::unwrap_in_result)]
 impl<Strat: SchedulerStrategy> ToCardRb<ToCardCtrlRbDesc> for EmulatedDevice<Strat> {
     fn push(&self, desc: ToCardCtrlRbDesc) -> Result<(), DeviceError> {
+        if desc.is_software_only() {
+            return Err(DeviceError::Device(format!("{desc:?} is not supported by the card")));
+        }
         let mut guard = self.to_card_ctrl_rb.lock();
         let mut writer = guard.write();
 
@@ -248,6 +255,11 @@ impl<Strat: SchedulerStrategy> ToHostRb<ToHostCtrlRbDesc> for EmulatedDevice<Str
         debug!("{:?}", &desc);
         Ok(desc)
     }
+
+    fn pop_timeout(&self, timeout: Duration) -> Result<ToHostCtrlRbDesc, DeviceError> {
+        self.to_host_ctrl_rb.lock().wait_readable(timeout)?;
+        ToHostRb::<ToHostCtrlRbDesc>::pop(self)
+    }
 }
 
 // fn push_to_card_work_rb_desc(
@@ -297,4 +309,9 @@ impl<Strat: SchedulerStrategy> ToHostRb<ToHostWorkRbDesc> for EmulatedDevice<Str
             }
         }
     }
+
+    fn pop_timeout(&self, timeout: Duration) -> Result<ToHostWorkRbDesc, DeviceError> {
+        self.to_host_work_rb.lock().wait_readable(timeout)?;
+        ToHostRb::<ToHostWorkRbDesc>::pop(self)
+    }
 }
@@ -17,7 +17,7 @@ use super::{
     ToCardCtrlRbDesc, ToCardRb, ToCardWorkRbDesc, ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc,
     ToHostWorkRbDescError,
 };
-use std::{fs::{File, OpenOptions}, path::Path, sync::Arc};
+use std::{fs::{File, OpenOptions}, path::Path, sync::Arc, time::Duration};
 
 mod csr_cli;
 mod ib_verbs;
@@ -220,11 +220,18 @@ impl<Strat: SchedulerStrategy> DeviceAdaptor for HardwareDevice<Strat> {
     fn use_hugepage(&self) -> bool {
         true
     }
+
+    fn close(&self) {
+        self.0.scheduler.stop();
+    }
 }
 
 #[allow(clippy::unwrap_used, clippy::unwrap_in_result)]
 impl ToCardRb<ToCardCtrlRbDesc> for Mutex<ToCardCtrlRb> {
     fn push(&self, desc: ToCardCtrlRbDesc) -> Result<(), DeviceError> {
+        if desc.is_software_only() {
+            return Err(DeviceError::Device(format!("{desc:?} is not supported by the card")));
+        }
         let mut guard = self.lock();
         let mut writer = guard.write();
 
@@ -247,6 +254,11 @@ impl ToHostRb<ToHostCtrlRbDesc> for Mutex<ToHostCtrlRb> {
         debug!("{:?}", &desc);
         Ok(desc)
     }
+
+    fn pop_timeout(&self, timeout: Duration) -> Result<ToHostCtrlRbDesc, DeviceError> {
+        self.lock().wait_readable(timeout)?;
+        self.pop()
+    }
 }
 
 impl ToHostRb<ToHostWorkRbDesc> for Mutex<ToHostWorkRb> {
@@ -274,4 +286,9 @@ impl ToHostRb<ToHostWorkRbDesc> for Mutex<ToHostWorkRb> {
             }
         }
     }
+
+    fn pop_timeout(&self, timeout: Duration) -> Result<ToHostWorkRbDesc, DeviceError> {
+        self.lock().wait_readable(timeout)?;
+        self.pop()
+    }
 }
@@ -51,7 +51,9 @@ bitfield! {
     pub get_pmtu, set_pmtu: 146, 144;                                               // 3bits
     _reserverd2, _: 151, 147;                                                   // 5bits
     pub get_peer_qpn, set_peer_qpn: 175, 152;                                      // 24bits
-    _reserverd1, _: 255, 176;                                                   // 80bits
+    pub get_expected_psn, set_expected_psn: 199, 176;                              // 24bits
+    pub get_qkey, set_qkey: 231, 200;                                              // 32bits
+    _reserverd1, _: 255, 232;                                                   // 24bits
 }
 
 bitfield! {
@@ -135,7 +137,10 @@ bitfield! {
     pub get_dqpn, set_dqpn: 151, 128;         // 24bits
     _reserved2 , _: 159, 152;             // 8bits
     pub get_imm, set_imm: 191, 160;           // 32bits
-    _reserved1 , _: 255, 192;             // 64bits
+    // the DETH of a UD send, in the reserved bits of the card, only read by the software device
+    pub get_qkey, set_qkey: 223, 192;         // 32bits
+    pub get_sqpn, set_sqpn: 247, 224;         // 24bits
+    _reserved1 , _: 255, 248;             // 8bits
 }
 
 bitfield! {
@@ -1,7 +1,12 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{fmt::Debug, sync::Arc, time::Duration};
 
 use thiserror::Error;
 
+use crate::{
+    types::{DeviceAttr, Pmtu, PAGE_SIZE},
+    MAX_MSG_SIZE, MAX_SGE_CNT,
+};
+
 mod constants;
 mod emulated;
 mod hardware;
@@ -15,8 +20,48 @@ pub(crate) use types::ToCardWorkRbDesc;
 
 
 pub(crate) use self::{
-    emulated::EmulatedDevice, hardware::HardwareDevice, software::SoftwareDevice, types::*,
+    emulated::EmulatedDevice,
+    hardware::HardwareDevice,
+    software::{software_device_attr, SoftwareDevice},
+    types::*,
 };
+pub use software::{LoopbackFabric, NetImpairment, NetImpairmentBuilder};
+
+/// The number of QPs supported by the card, QP0 and QP1 included.
+pub(crate) const QP_MAX_CNT: usize = 1024;
+
+/// Read the capabilities of a card from its capability registers.
+///
+/// The limits of the driver bound the ones of the card, e.g. a descriptor holds `MAX_SGE_CNT`
+/// SGEs at most. The page size and the depth of the ring buffers are chosen by the driver.
+fn read_card_device_attr<F>(read_csr: F) -> Result<DeviceAttr, DeviceError>
+where
+    F: Fn(usize) -> Result<u32, DeviceError>,
+{
+    let read = |addr, name| match read_csr(addr)? {
+        0 => Err(DeviceError::Device(format!("capability {name} is not reported"))),
+        value => Ok(value),
+    };
+    let max_pmtu = match read(constants::CSR_ADDR_CAP_MAX_PMTU, "max pmtu")? {
+        1 => Pmtu::Mtu256,
+        2 => Pmtu::Mtu512,
+        3 => Pmtu::Mtu1024,
+        4 => Pmtu::Mtu2048,
+        5 => Pmtu::Mtu4096,
+        value => return Err(DeviceError::Device(format!("invalid max pmtu: {value}"))),
+    };
+    Ok(DeviceAttr {
+        max_qp: read(constants::CSR_ADDR_CAP_MAX_QP, "max qp")? as usize,
+        max_mr: read(constants::CSR_ADDR_CAP_MAX_MR, "max mr")? as usize,
+        max_mr_pgt_entries: read(constants::CSR_ADDR_CAP_MAX_MR_PGT_ENTRIES, "max mr pgt entries")?
+            as usize,
+        page_size: PAGE_SIZE,
+        max_sge: (read(constants::CSR_ADDR_CAP_MAX_SGE, "max sge")? as usize).min(MAX_SGE_CNT),
+        max_msg_size: read(constants::CSR_ADDR_CAP_MAX_MSG_SIZE, "max msg size")?.min(MAX_MSG_SIZE),
+        max_pmtu,
+        ringbuf_depth: Some(constants::RINGBUF_DEPTH),
+    })
+}
 
 /// Public interface for a device. Can be a real hardware device or a software emulation.
 pub(crate) trait DeviceAdaptor: Send + Sync {
@@ -32,6 +77,16 @@ pub(crate) trait DeviceAdaptor: Send + Sync {
     fn get_phys_addr(&self, virt_addr: usize) -> Result<usize, DeviceError>;
 
     fn use_hugepage(&self) -> bool;
+
+    /// The capabilities of the device, which are read from the capability registers of a card.
+    fn device_attr(&self) -> Result<DeviceAttr, DeviceError> {
+        read_card_device_attr(|addr| self.read_csr(addr))
+    }
+
+    /// Stop the background threads of the adaptor and wait for them to exit.
+    ///
+    /// The ring buffers must not be used after the adaptor is closed.
+    fn close(&self) {}
 }
 
 /// Generic interface for a to-card ring buffer.
@@ -39,9 +94,15 @@ pub(crate) trait ToCardRb<D> {
     fn push(&self, desc: D) -> Result<(), DeviceError>;
 }
 
+/// How long the pollers wait for a descriptor before checking whether they should stop.
+pub(crate) const TO_HOST_RB_POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
 /// Generic interface for a to-host ring buffer.
 pub(crate) trait ToHostRb<D> {
     fn pop(&self) -> Result<D, DeviceError>;
+
+    /// Like `pop`, but return `DeviceError::Timeout` if there is no descriptor after `timeout`.
+    fn pop_timeout(&self, timeout: Duration) -> Result<D, DeviceError>;
 }
 
 /// An error indicating that a ring buffer overflowed.
@@ -52,3 +113,41 @@ pub(crate) struct Overflowed;
 #[derive(Debug, Error)]
 #[error("net socket failed to bind the port")]
 pub(crate) struct PortBindFailed;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{constants, read_card_device_attr, DeviceError};
+    use crate::types::Pmtu;
+
+    #[test]
+    fn test_read_card_device_attr() {
+        let mut csrs = HashMap::from([
+            (constants::CSR_ADDR_CAP_MAX_QP, 256),
+            (constants::CSR_ADDR_CAP_MAX_MR, 32),
+            (constants::CSR_ADDR_CAP_MAX_MR_PGT_ENTRIES, 512),
+            (constants::CSR_ADDR_CAP_MAX_SGE, 8),
+            (constants::CSR_ADDR_CAP_MAX_MSG_SIZE, u32::MAX),
+            (constants::CSR_ADDR_CAP_MAX_PMTU, 3),
+        ]);
+        let read = |csrs: &HashMap<usize, u32>| {
+            read_card_device_attr(|addr| {
+                csrs.get(&addr)
+                    .copied()
+                    .ok_or_else(|| DeviceError::Device(format!("csr {addr}")))
+            })
+        };
+        let attr = read(&csrs).unwrap();
+        assert_eq!((attr.max_qp, attr.max_mr, attr.max_mr_pgt_entries), (256, 32, 512));
+        assert!(matches!(attr.max_pmtu, Pmtu::Mtu1024));
+        // bounded by the limits of the driver
+        assert_eq!((attr.max_sge, attr.max_msg_size), (4, 1 << 31));
+
+        let _: Option<u32> = csrs.insert(constants::CSR_ADDR_CAP_MAX_PMTU, 6);
+        assert!(read(&csrs).is_err());
+        let _: Option<u32> = csrs.insert(constants::CSR_ADDR_CAP_MAX_PMTU, 5);
+        let _: Option<u32> = csrs.insert(constants::CSR_ADDR_CAP_MAX_QP, 0);
+        assert!(read(&csrs).is_err());
+    }
+}
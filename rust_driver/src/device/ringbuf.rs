@@ -16,6 +16,11 @@ pub(super) trait CsrReaderProxy {
     fn read_head(&self) -> Result<u32, DeviceError>;
 }
 
+/// The times of yielding before `Ringbuf::wait_readable` starts sleeping between the reads of the head
+const WAIT_READABLE_SPIN_CNT: u32 = 64;
+/// The interval of reading the head once `Ringbuf::wait_readable` has yielded enough
+const WAIT_READABLE_SLEEP: Duration = Duration::from_millis(1);
+
 /// The Ringbuf is a circular buffer used comunicate between the host and the card.
 #[derive(Debug)]
 pub(super) struct Ringbuf<T, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_SIZE: usize> {
@@ -144,6 +149,31 @@ impl<T: CsrReaderProxy, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_S
             proxy: &self.proxy,
         })
     }
+
+    /// Wait until there is at least one descriptor to read.
+    ///
+    /// Return `DeviceError::Timeout` if the ring buffer is still empty after `timeout`.
+    pub(super) fn wait_readable(&mut self, timeout: Duration) -> Result<(), DeviceError> {
+        let start = std::time::Instant::now();
+        let mut spin_cnt = 0_u32;
+        while Self::is_empty(self.head, self.tail) {
+            self.head = self.proxy.read_head()? as usize;
+            if !Self::is_empty(self.head, self.tail) {
+                break;
+            }
+            if start.elapsed() > timeout {
+                return Err(DeviceError::Timeout);
+            }
+            // back off, a descriptor usually arrives soon after another one
+            if spin_cnt < WAIT_READABLE_SPIN_CNT {
+                spin_cnt = spin_cnt.wrapping_add(1);
+                std::thread::yield_now();
+            } else {
+                std::thread::sleep(WAIT_READABLE_SLEEP);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a, T: CsrWriterProxy, const DEPTH: usize, const ELEM_SIZE: usize, const PAGE_SIZE: usize>
@@ -27,7 +27,7 @@ use crate::{
 
 const SCHEDULER_SIZE_U32: u32 = 1024 * 1024 * 2; // 32KB
 const SCHEDULER_SIZE: usize = SCHEDULER_SIZE_U32 as usize;
-const MAX_SGL_LENGTH: usize = 1;
+const MAX_SGL_LENGTH: usize = 4;
 
 pub(crate) mod round_robin;
 pub(crate) mod testing;
@@ -127,8 +127,10 @@ pub(crate) struct DescriptorScheduler<Strat: SchedulerStrategy> {
     sender: Sender<Box<ToCardWorkRbDesc>>,
     receiver: Receiver<Box<ToCardWorkRbDesc>>,
     strategy: Strat,
-    thread_handler: Option<std::thread::JoinHandle<()>>,
+    thread_handler: Mutex<Option<std::thread::JoinHandle<()>>>,
     stop_flag: Arc<AtomicBool>,
+    // whether the descriptors are sent to the software device instead of the card
+    is_software: bool,
 }
 
 /// A batch of descriptors.
@@ -154,15 +156,89 @@ struct SGList {
 }
 
 impl SGList {
-    pub(crate) fn new_from_sge(sge: DescSge) -> Self {
-        let mut sge_list = Self {
-            data: [DescSge::default(); MAX_SGL_LENGTH],
-            cur_level: 0,
-            len: 1,
-        };
-        sge_list.data[0] = sge;
+    pub(crate) fn new_from_sges(
+        sge0: DescSge,
+        sge1: Option<DescSge>,
+        sge2: Option<DescSge>,
+        sge3: Option<DescSge>,
+    ) -> Self {
+        let mut sge_list = Self::default();
+        for sge in [Some(sge0), sge1, sge2, sge3].into_iter().flatten() {
+            sge_list.push(sge);
+        }
         sge_list
     }
+
+    // We allow indexing_slicing because the sge list is built from at most `MAX_SGL_LENGTH` sges
+    #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
+    fn push(&mut self, sge: DescSge) {
+        self.data[self.len as usize] = sge;
+        self.len += 1;
+    }
+
+    /// Get the sge of `level`, or `None` if it is beyond the length of the list
+    fn get(&self, level: usize) -> Option<DescSge> {
+        #[allow(clippy::cast_possible_truncation)] // level is less than `MAX_SGL_LENGTH`
+        self.data
+            .get(level)
+            .copied()
+            .filter(|_| (level as u32) < self.len)
+    }
+
+    /// The total length of the buffers in the sge list
+    fn total_len(&self) -> u32 {
+        self.data
+            .iter()
+            .take(self.len as usize)
+            .map(|sge| sge.len)
+            .sum()
+    }
+
+    /// Cut a buffer of `length` from the sge list
+    ///
+    /// The function iterate from `cur_level` of the sge list. If current level is not enough,
+    /// the rest of it is cut and it will move to the next level.
+    ///
+    /// Return `DeviceError::Scheduler` if the rest of the sge list is shorter than `length`.
+    // We allow indexing_slicing because
+    // * `new_sgl_level` will always smaller than `self` sgl level, which is less than `MAX_SGL_LENGTH`
+    // * `current_level` won't be greater than `self.len`, which is less than `MAX_SGL_LENGTH`
+    #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
+    pub(crate) fn cut(&mut self, mut length: u32) -> Result<SGList, DeviceError> {
+        let mut current_level = self.cur_level as usize;
+        let mut new_sgl = SGList::default();
+        let mut new_sgl_level: usize = 0;
+        #[allow(clippy::cast_possible_truncation)]
+        while (current_level as u32) < self.len {
+            // if we can cut from current level, just cut and return
+            if self.data[current_level].len >= length {
+                let addr = self.data[current_level].addr;
+                new_sgl.data[new_sgl_level] = DescSge {
+                    addr,
+                    len: length,
+                    key: self.data[current_level].key,
+                };
+                new_sgl.len = new_sgl_level as u32 + 1;
+                self.data[current_level].addr =
+                    self.data[current_level].addr.wrapping_add(u64::from(length));
+                self.data[current_level].len = self.data[current_level].len.wrapping_sub(length);
+                if self.data[current_level].len == 0 {
+                    current_level += 1;
+                }
+                self.cur_level = current_level as u32;
+                return Ok(new_sgl);
+            }
+            // otherwise take the whole level and check next level
+            new_sgl.data[new_sgl_level] = self.data[current_level];
+            new_sgl_level += 1;
+            length = length.wrapping_sub(self.data[current_level].len);
+            self.data[current_level].len = 0;
+            current_level += 1;
+        }
+        Err(DeviceError::Scheduler(format!(
+            "the sge list is {length} bytes shorter than required"
+        )))
+    }
 }
 
 impl Default for SGList {
@@ -209,9 +285,13 @@ impl<Strat: SchedulerStrategy> DescriptorScheduler<Strat> {
                 };
                 if let Some(desc) = desc {
                     let dqpn = get_to_card_desc_common(&desc).dqpn;
-                    let splited_descs = split_descriptor(desc);
-                    if let Err(e) = strategy.push(dqpn, splited_descs.into_iter()) {
-                        error!("failed to push descriptors: {:?}", e);
+                    match split_descriptor(desc) {
+                        Ok(splited_descs) => {
+                            if let Err(e) = strategy.push(dqpn, splited_descs.into_iter()) {
+                                error!("failed to push descriptors: {:?}", e);
+                            }
+                        }
+                        Err(e) => error!("failed to split descriptor: {:?}", e),
                     }
                 }
 
@@ -242,9 +322,10 @@ impl<Strat: SchedulerStrategy> DescriptorScheduler<Strat> {
         Self {
             sender,
             strategy: strategy_clone,
-            thread_handler: Some(thread_handler),
+            thread_handler: Mutex::new(Some(thread_handler)),
             receiver,
             stop_flag,
+            is_software: false,
         }
     }
 
@@ -263,9 +344,13 @@ impl<Strat: SchedulerStrategy> DescriptorScheduler<Strat> {
                 };
                 if let Some(desc) = desc {
                     let dqpn = get_to_card_desc_common(&desc).dqpn;
-                    let splited_descs = split_descriptor(desc);
-                    if let Err(e) = strategy.push(dqpn, splited_descs.into_iter()) {
-                        error!("failed to push descriptors: {:?}", e);
+                    match split_descriptor(desc) {
+                        Ok(splited_descs) => {
+                            if let Err(e) = strategy.push(dqpn, splited_descs.into_iter()) {
+                                error!("failed to push descriptors: {:?}", e);
+                            }
+                        }
+                        Err(e) => error!("failed to split descriptor: {:?}", e),
                     }
                 }
 
@@ -286,17 +371,22 @@ impl<Strat: SchedulerStrategy> DescriptorScheduler<Strat> {
         Self {
             sender,
             strategy: strategy_clone,
-            thread_handler: Some(thread_handler),
+            thread_handler: Mutex::new(Some(thread_handler)),
             receiver,
             stop_flag,
+            is_software: true,
         }
     }
 }
 
-impl<Strat: SchedulerStrategy> Drop for DescriptorScheduler<Strat> {
-    fn drop(&mut self) {
+impl<Strat: SchedulerStrategy> DescriptorScheduler<Strat> {
+    /// Stop the scheduling thread and wait for it to exit.
+    ///
+    /// The descriptors pushed after the scheduler is stopped are never sent.
+    pub(crate) fn stop(&self) {
         self.stop_flag.store(true, Ordering::Relaxed);
-        if let Some(thread) = self.thread_handler.take() {
+        let thread = self.thread_handler.lock().take();
+        if let Some(thread) = thread {
             if let Err(e) = thread.join() {
                 panic!(
                     "{}",
@@ -307,8 +397,18 @@ impl<Strat: SchedulerStrategy> Drop for DescriptorScheduler<Strat> {
     }
 }
 
+impl<Strat: SchedulerStrategy> Drop for DescriptorScheduler<Strat> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
 impl<Strat: SchedulerStrategy> ToCardRb<Box<ToCardWorkRbDesc>> for DescriptorScheduler<Strat> {
     fn push(&self, desc: Box<ToCardWorkRbDesc>) -> Result<(), DeviceError> {
+        if !self.is_software && desc.is_software_only() {
+            return Err(DeviceError::Device(format!("{desc:?} is not supported by the card")));
+        }
+        check_sgl_length(&desc)?;
         self.sender
             .send(desc)
             .map_err(|e| DeviceError::Scheduler(e.to_string()))
@@ -324,8 +424,13 @@ impl SealedDesc {
     pub fn get_dqpn(&self) -> Qpn {
         match &*self.0 {
             ToCardWorkRbDesc::Read(desc) => desc.common.dqpn,
-            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => desc.common.dqpn,
+            ToCardWorkRbDesc::Write(desc)
+            | ToCardWorkRbDesc::ReadResp(desc)
+            | ToCardWorkRbDesc::Send(desc) => desc.common.dqpn,
             ToCardWorkRbDesc::WriteWithImm(desc) => desc.common.dqpn,
+            ToCardWorkRbDesc::AtomicCmpSwap(desc) | ToCardWorkRbDesc::AtomicFetchAdd(desc) => {
+                desc.common.dqpn
+            }
         }
     }
 
@@ -333,8 +438,13 @@ impl SealedDesc {
     pub fn get_psn(&self) -> Psn {
         match &*self.0 {
             ToCardWorkRbDesc::Read(desc) => desc.common.psn,
-            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => desc.common.psn,
+            ToCardWorkRbDesc::Write(desc)
+            | ToCardWorkRbDesc::ReadResp(desc)
+            | ToCardWorkRbDesc::Send(desc) => desc.common.psn,
             ToCardWorkRbDesc::WriteWithImm(desc) => desc.common.psn,
+            ToCardWorkRbDesc::AtomicCmpSwap(desc) | ToCardWorkRbDesc::AtomicFetchAdd(desc) => {
+                desc.common.psn
+            }
         }
     }
 }
@@ -357,100 +467,132 @@ fn get_first_schedule_segment_length(va: u64) -> u32 {
 fn get_to_card_desc_common(desc: &ToCardWorkRbDesc) -> &ToCardWorkRbDescCommon {
     match desc {
         ToCardWorkRbDesc::Read(req) => &req.common,
-        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => &req.common,
+        ToCardWorkRbDesc::Write(req)
+        | ToCardWorkRbDesc::ReadResp(req)
+        | ToCardWorkRbDesc::Send(req) => &req.common,
         ToCardWorkRbDesc::WriteWithImm(req) => &req.common,
+        ToCardWorkRbDesc::AtomicCmpSwap(req) | ToCardWorkRbDesc::AtomicFetchAdd(req) => &req.common,
     }
 }
 
-// We allow indexing_slicing because
-// * `new_sgl_level` will always smaller than `origin_sgl` sgl level, which is less than `MAX_SGL_LENGTH`
-// * `current_level` won't be greater than `origin_sgl.len`, which is less than `MAX_SGL_LENGTH`
-#[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
-fn cut_from_sgl(mut length: u32, origin_sgl: &mut SGList) -> SGList {
-    let mut current_level = origin_sgl.cur_level as usize;
-    let mut new_sgl = SGList::default();
-    let mut new_sgl_level: usize = 0;
-    #[allow(clippy::cast_possible_truncation)]
-    while (current_level as u32) < origin_sgl.len {
-        // if we can cut from current level, just cut and return
-        if origin_sgl.data[current_level].len >= length {
-            let addr = origin_sgl.data[current_level].addr;
-            new_sgl.data[new_sgl_level] = DescSge {
-                addr,
-                len: length,
-                key: origin_sgl.data[current_level].key,
-            };
-            new_sgl.len = new_sgl_level as u32 + 1;
-            origin_sgl.data[current_level].addr = origin_sgl.data[current_level]
-                .addr
-                .wrapping_add(u64::from(length));
-            origin_sgl.data[current_level].len =
-                origin_sgl.data[current_level].len.wrapping_sub(length);
-            if origin_sgl.data[current_level].len == 0 {
-                current_level += 1;
-            }
-            origin_sgl.cur_level = current_level as u32;
-            return new_sgl;
-        }
-        // otherwise check next level
-        let addr = origin_sgl.data[current_level].addr as *mut u8;
-        new_sgl.data[new_sgl_level] = DescSge {
-            addr: addr as u64,
-            len: origin_sgl.data[current_level].len,
-            key: origin_sgl.data[current_level].key,
-        };
-        new_sgl_level += 1;
-        length = length.wrapping_sub(origin_sgl.data[current_level].len);
-        origin_sgl.data[current_level].len = 0;
-        current_level += 1;
-    }
-    unreachable!("The length is too long");
-}
-
 fn get_total_len(desc: &ToCardWorkRbDesc) -> u32 {
     match desc {
         ToCardWorkRbDesc::Read(req) => req.common.total_len,
-        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => req.common.total_len,
+        ToCardWorkRbDesc::Write(req)
+        | ToCardWorkRbDesc::ReadResp(req)
+        | ToCardWorkRbDesc::Send(req) => req.common.total_len,
         ToCardWorkRbDesc::WriteWithImm(req) => req.common.total_len,
+        ToCardWorkRbDesc::AtomicCmpSwap(req) | ToCardWorkRbDesc::AtomicFetchAdd(req) => {
+            req.common.total_len
+        }
+    }
+}
+
+/// Check that the sge list of a whole message covers its `total_len`, so it can be cut into
+/// segments.
+///
+/// A descriptor sliced for the selective retransmission carries a part of the message, and is
+/// checked when it is cut.
+fn check_sgl_length(desc: &ToCardWorkRbDesc) -> Result<(), DeviceError> {
+    let (is_first, is_last, sg_list) = match desc {
+        ToCardWorkRbDesc::Read(_)
+        | ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | ToCardWorkRbDesc::AtomicFetchAdd(_) => return Ok(()),
+        ToCardWorkRbDesc::Write(req)
+        | ToCardWorkRbDesc::ReadResp(req)
+        | ToCardWorkRbDesc::Send(req) => (
+            req.is_first,
+            req.is_last,
+            SGList::new_from_sges(req.sge0, req.sge1, req.sge2, req.sge3),
+        ),
+        ToCardWorkRbDesc::WriteWithImm(req) => (
+            req.is_first,
+            req.is_last,
+            SGList::new_from_sges(req.sge0, req.sge1, req.sge2, req.sge3),
+        ),
+    };
+    let total_len = get_total_len(desc);
+    if is_first && is_last && sg_list.total_len() < total_len {
+        return Err(DeviceError::Scheduler(format!(
+            "the sge list is {} bytes, but the message is {total_len} bytes",
+            sg_list.total_len()
+        )));
     }
+    Ok(())
 }
 
 /// Split the descriptor into multiple descriptors if it is greater than the `SCHEDULER_SIZE` size.
+///
+/// Return `DeviceError::Scheduler` if the sge list of the descriptor is shorter than its payload.
 #[allow(clippy::linkedlist)]
-pub(crate) fn split_descriptor(desc: Box<ToCardWorkRbDesc>) -> LinkedList<SealedDesc> {
-    let is_read = matches!(*desc, ToCardWorkRbDesc::Read(_));
+pub(crate) fn split_descriptor(
+    desc: Box<ToCardWorkRbDesc>,
+) -> Result<LinkedList<SealedDesc>, DeviceError> {
+    // Read and atomic requests have no payload to split
+    let is_read_or_atomic = matches!(
+        *desc,
+        ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::AtomicCmpSwap(_)
+            | ToCardWorkRbDesc::AtomicFetchAdd(_)
+    );
     let total_len = get_total_len(&desc);
     #[allow(clippy::cast_possible_truncation)]
-    if is_read || total_len < SCHEDULER_SIZE as u32 {
+    if is_read_or_atomic || total_len < SCHEDULER_SIZE as u32 {
         let mut list = LinkedList::new();
         list.push_back(SealedDesc(desc));
-        return list;
+        return Ok(list);
     }
 
-    let (raddr, pmtu, psn, sge) = match &*desc {
-        ToCardWorkRbDesc::Read(_) => unreachable!(),
-        ToCardWorkRbDesc::Write(req) | ToCardWorkRbDesc::ReadResp(req) => {
-            (req.common.raddr, req.common.pmtu, req.common.psn, req.sge0)
-        }
-        ToCardWorkRbDesc::WriteWithImm(req) => {
-            (req.common.raddr, req.common.pmtu, req.common.psn, req.sge0)
+    // A descriptor sliced for the selective retransmission may be neither first nor last
+    let (raddr, pmtu, psn, is_first, is_last, mut sg_list) = match &*desc {
+        ToCardWorkRbDesc::Read(_)
+        | ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | ToCardWorkRbDesc::AtomicFetchAdd(_) => unreachable!(),
+        ToCardWorkRbDesc::Write(req)
+        | ToCardWorkRbDesc::ReadResp(req)
+        | ToCardWorkRbDesc::Send(req) => {
+            (
+                req.common.raddr,
+                req.common.pmtu,
+                req.common.psn,
+                req.is_first,
+                req.is_last,
+                SGList::new_from_sges(req.sge0, req.sge1, req.sge2, req.sge3),
+            )
         }
+        ToCardWorkRbDesc::WriteWithImm(req) => (
+            req.common.raddr,
+            req.common.pmtu,
+            req.common.psn,
+            req.is_first,
+            req.is_last,
+            SGList::new_from_sges(req.sge0, req.sge1, req.sge2, req.sge3),
+        ),
     };
 
-    let mut sg_list = SGList::new_from_sge(sge);
-
+    // the first slice of a selective retransmission carries less data than its `total_len`
+    let data_length = sg_list.total_len();
     let mut descs = LinkedList::new();
-    let mut this_length = get_first_schedule_segment_length(raddr);
-    let mut remain_data_length = total_len;
+    let mut this_length = get_first_schedule_segment_length(raddr).min(data_length);
+    let mut remain_data_length = data_length;
     let mut current_va = raddr;
     let mut base_psn = psn;
     while remain_data_length > 0 {
         let mut new_desc = desc.clone();
-        let new_sge = cut_from_sgl(this_length, &mut sg_list).data[0];
+        let new_sgl = sg_list.cut(this_length)?;
+        // the length of a segment is greater than zero, so there is at least one sge
+        let new_sge0 = new_sgl.get(0).unwrap_or_default();
         match &mut *new_desc {
-            ToCardWorkRbDesc::Read(_) => unreachable!(),
-            ToCardWorkRbDesc::Write(ref mut req) | ToCardWorkRbDesc::ReadResp(ref mut req) => {
-                req.sge0 = new_sge;
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::AtomicCmpSwap(_)
+            | ToCardWorkRbDesc::AtomicFetchAdd(_) => unreachable!(),
+            ToCardWorkRbDesc::Write(ref mut req)
+            | ToCardWorkRbDesc::ReadResp(ref mut req)
+            | ToCardWorkRbDesc::Send(ref mut req) => {
+                req.sge0 = new_sge0;
+                req.sge1 = new_sgl.get(1);
+                req.sge2 = new_sgl.get(2);
+                req.sge3 = new_sgl.get(3);
                 req.common.total_len = this_length;
                 req.common.raddr = current_va;
                 req.common.psn = base_psn;
@@ -458,7 +600,10 @@ pub(crate) fn split_descriptor(desc: Box<ToCardWorkRbDesc>) -> LinkedList<Sealed
                 req.is_last = false;
             }
             ToCardWorkRbDesc::WriteWithImm(ref mut req) => {
-                req.sge0 = new_sge;
+                req.sge0 = new_sge0;
+                req.sge1 = new_sgl.get(1);
+                req.sge2 = new_sgl.get(2);
+                req.sge3 = new_sgl.get(3);
                 req.common.total_len = this_length;
                 req.common.raddr = current_va;
                 req.common.psn = base_psn;
@@ -479,31 +624,135 @@ pub(crate) fn split_descriptor(desc: Box<ToCardWorkRbDesc>) -> LinkedList<Sealed
     // The above code guarantee there at least 2 descriptors in the list
     if let Some(req) = descs.front_mut() {
         match &mut *req.0 {
-            ToCardWorkRbDesc::Read(_) => unreachable!(),
-            ToCardWorkRbDesc::Write(ref mut req) | ToCardWorkRbDesc::ReadResp(ref mut req) => {
-                req.is_first = true;
-                req.common.total_len = total_len;
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::AtomicCmpSwap(_)
+            | ToCardWorkRbDesc::AtomicFetchAdd(_) => unreachable!(),
+            ToCardWorkRbDesc::Write(ref mut req)
+            | ToCardWorkRbDesc::ReadResp(ref mut req)
+            | ToCardWorkRbDesc::Send(ref mut req) => {
+                req.is_first = is_first;
+                if is_first {
+                    req.common.total_len = total_len;
+                }
             }
             ToCardWorkRbDesc::WriteWithImm(ref mut req) => {
-                req.is_first = true;
-                req.common.total_len = total_len;
+                req.is_first = is_first;
+                if is_first {
+                    req.common.total_len = total_len;
+                }
             }
         }
     }
 
     if let Some(req) = descs.back_mut() {
         match &mut *req.0 {
-            ToCardWorkRbDesc::Read(_) => unreachable!(),
-            ToCardWorkRbDesc::Write(ref mut req) | ToCardWorkRbDesc::ReadResp(ref mut req) => {
-                req.is_last = true;
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::AtomicCmpSwap(_)
+            | ToCardWorkRbDesc::AtomicFetchAdd(_) => unreachable!(),
+            ToCardWorkRbDesc::Write(ref mut req)
+            | ToCardWorkRbDesc::ReadResp(ref mut req)
+            | ToCardWorkRbDesc::Send(ref mut req) => {
+                req.is_last = is_last;
             }
             ToCardWorkRbDesc::WriteWithImm(ref mut req) => {
-                req.is_last = true;
+                req.is_last = is_last;
             }
         }
     }
 
-    descs
+    Ok(descs)
+}
+
+/// Cut the packets from `from` to `to` out of a descriptor, for the selective retransmission.
+///
+/// The range is clamped to the packets of the descriptor, and `None` is returned if they don't
+/// overlap. A read or atomic request has only one packet, so it is returned as a whole.
+/// Only the slice containing the first packet keeps the `total_len` of the whole message.
+///
+/// Return `DeviceError::Scheduler` if the sge list of the descriptor is shorter than the slice.
+#[allow(clippy::arithmetic_side_effects)] // the offsets are bounded by the total length
+pub(crate) fn slice_descriptor(
+    desc: &ToCardWorkRbDesc,
+    from: Psn,
+    to: Psn,
+) -> Result<Option<Box<ToCardWorkRbDesc>>, DeviceError> {
+    let common = get_to_card_desc_common(desc);
+    let (psn, pmtu, raddr, total_len) = (common.psn, common.pmtu, common.raddr, common.total_len);
+    let packet_cnt = match desc {
+        ToCardWorkRbDesc::Read(_)
+        | ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | ToCardWorkRbDesc::AtomicFetchAdd(_) => 1,
+        ToCardWorkRbDesc::Write(_)
+        | ToCardWorkRbDesc::WriteWithImm(_)
+        | ToCardWorkRbDesc::ReadResp(_)
+        | ToCardWorkRbDesc::Send(_) => calculate_packet_cnt(pmtu, raddr, total_len),
+    };
+    let last_psn = psn.wrapping_add(packet_cnt - 1);
+    if !to.larger_in_psn(psn) || !last_psn.larger_in_psn(from) {
+        return Ok(None);
+    }
+    let start_idx = if from.larger_in_psn(psn) {
+        from.wrapping_abs(psn)
+    } else {
+        0
+    };
+    let end_idx = if last_psn.larger_in_psn(to) {
+        to.wrapping_abs(psn)
+    } else {
+        packet_cnt - 1
+    };
+    if start_idx == 0 && end_idx == packet_cnt - 1 {
+        return Ok(Some(Box::new(desc.clone())));
+    }
+
+    // the offset of the `idx`th packet in the message
+    let pmtu = u32::from(&pmtu);
+    let first_packet_length = get_first_packet_max_length(raddr, pmtu).min(total_len);
+    let offset_of = |idx: u32| {
+        if idx == 0 {
+            0
+        } else {
+            (first_packet_length + (idx - 1) * pmtu).min(total_len)
+        }
+    };
+    let start_offset = offset_of(start_idx);
+    let length = offset_of(end_idx + 1) - start_offset;
+    let is_first = start_idx == 0;
+    let is_last = end_idx == packet_cnt - 1;
+
+    let mut new_desc = Box::new(desc.clone());
+    let (new_common, sge0, sge1, sge2, sge3) = match &mut *new_desc {
+        ToCardWorkRbDesc::Read(_)
+        | ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | ToCardWorkRbDesc::AtomicFetchAdd(_) => unreachable!(),
+        ToCardWorkRbDesc::Write(ref mut req)
+        | ToCardWorkRbDesc::ReadResp(ref mut req)
+        | ToCardWorkRbDesc::Send(ref mut req) => {
+            req.is_first = is_first;
+            req.is_last = is_last;
+            (&mut req.common, &mut req.sge0, &mut req.sge1, &mut req.sge2, &mut req.sge3)
+        }
+        ToCardWorkRbDesc::WriteWithImm(ref mut req) => {
+            req.is_first = is_first;
+            req.is_last = is_last;
+            (&mut req.common, &mut req.sge0, &mut req.sge1, &mut req.sge2, &mut req.sge3)
+        }
+    };
+    let mut sg_list = SGList::new_from_sges(*sge0, *sge1, *sge2, *sge3);
+    if start_offset > 0 {
+        let _: SGList = sg_list.cut(start_offset)?;
+    }
+    let new_sgl = sg_list.cut(length)?;
+    *sge0 = new_sgl.get(0).unwrap_or_default();
+    *sge1 = new_sgl.get(1);
+    *sge2 = new_sgl.get(2);
+    *sge3 = new_sgl.get(3);
+    new_common.psn = psn.wrapping_add(start_idx);
+    new_common.raddr = raddr.wrapping_add(u64::from(start_offset));
+    if !is_first {
+        new_common.total_len = length;
+    }
+    Ok(Some(new_desc))
 }
 
 /// Recalculate the PSN of the descriptor
@@ -540,7 +789,7 @@ mod test {
         ToCardWorkRbDescWrite, ToCardWorkRbDescWriteWithImm,
     };
 
-    use crate::types::{Key, Msn, Qpn, WorkReqSendFlag};
+    use crate::types::{Key, Msn, Psn, Qpn, WorkReqSendFlag};
     use crate::utils::Buffer;
     use crate::SealedDesc;
 
@@ -564,16 +813,9 @@ mod test {
 
         pub(crate) fn build(&self) -> SGList {
             let mut sg_list = SGList::default();
+            assert!(self.sg_list.len() <= MAX_SGL_LENGTH, "too many sges");
             for sge in self.sg_list.iter() {
-                sg_list.data[sg_list.len as usize] = *sge;
-                sg_list.len += 1;
-            }
-            while sg_list.len < MAX_SGL_LENGTH.try_into().unwrap() {
-                sg_list.data[sg_list.len as usize] = DescSge {
-                    addr: 0,
-                    len: 0,
-                    key: Key::default(),
-                };
+                sg_list.push(*sge);
             }
             sg_list
         }
@@ -593,14 +835,194 @@ mod test {
         let mut sgl = SGListBuilder::new()
             .with_sge(0, 1024, Key::default())
             .build();
-        let new_sgl = super::cut_from_sgl(512, &mut sgl);
+        let new_sgl = sgl.cut(512).unwrap();
         assert_eq!(new_sgl.len, 1);
         assert_eq!(new_sgl.data[0].len, 512);
         assert_eq!(sgl.data[0].len, 512);
         assert_eq!(sgl.data[0].addr, 512);
     }
 
-    #[allow(dead_code)]
+    #[test]
+    fn test_cut_from_sgl_across_sges() {
+        let mut sgl = SGListBuilder::new()
+            .with_sge(0x1000, 256, Key::new(1))
+            .with_sge(0x2000, 512, Key::new(2))
+            .with_sge(0x3000, 256, Key::new(3))
+            .build();
+        // cut the whole first sge and a part of the second one
+        let new_sgl = sgl.cut(384).unwrap();
+        assert_eq!(new_sgl.len, 2);
+        assert_eq!(new_sgl.data[0].addr, 0x1000);
+        assert_eq!(new_sgl.data[0].len, 256);
+        assert_eq!(new_sgl.data[1].addr, 0x2000);
+        assert_eq!(new_sgl.data[1].len, 128);
+        assert_eq!(new_sgl.data[1].key.get(), 2);
+        assert_eq!(sgl.cur_level, 1);
+
+        // cut the rest of the second sge and the whole third one
+        let new_sgl = sgl.cut(640).unwrap();
+        assert_eq!(new_sgl.len, 2);
+        assert_eq!(new_sgl.data[0].addr, 0x2000 + 128);
+        assert_eq!(new_sgl.data[0].len, 384);
+        assert_eq!(new_sgl.data[1].addr, 0x3000);
+        assert_eq!(new_sgl.data[1].len, 256);
+        assert_eq!(sgl.cur_level, 3);
+
+        // nothing is left to cut
+        assert!(sgl.cut(1).is_err());
+    }
+
+    #[test]
+    fn test_split_descriptor_across_sges() {
+        // the scheduler size is 32 units: 29 - 32, 32 - 64, 64 - 65
+        let unit = super::SCHEDULER_SIZE_U32 / 32;
+        let va = u64::from(29 * unit);
+        let sge_len = 12 * unit;
+        let desc = Box::new(ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+            common: ToCardWorkRbDescCommon {
+                total_len: sge_len * 3,
+                raddr: va,
+                dqpn: Qpn::new(2),
+                pmtu: crate::types::Pmtu::Mtu4096,
+                ..Default::default()
+            },
+            sge0: DescSge {
+                addr: 0x1000_0000,
+                len: sge_len,
+                key: Key::new(1),
+            },
+            sge1: Some(DescSge {
+                addr: 0x2000_0000,
+                len: sge_len,
+                key: Key::new(2),
+            }),
+            sge2: Some(DescSge {
+                addr: 0x3000_0000,
+                len: sge_len,
+                key: Key::new(3),
+            }),
+            is_first: true,
+            is_last: true,
+            ..Default::default()
+        }));
+        let descs = convert_list_to_vec(super::split_descriptor(desc).unwrap());
+        assert_eq!(descs.len(), 3);
+        let segments: Vec<_> = descs
+            .into_iter()
+            .map(|desc| match *desc.into_desc() {
+                ToCardWorkRbDesc::Write(req) => req,
+                ToCardWorkRbDesc::Read(_)
+                | ToCardWorkRbDesc::WriteWithImm(_)
+                | ToCardWorkRbDesc::ReadResp(_)
+                | ToCardWorkRbDesc::Send(_)
+                | ToCardWorkRbDesc::AtomicCmpSwap(_)
+                | ToCardWorkRbDesc::AtomicFetchAdd(_) => panic!("unexpected descriptor"),
+            })
+            .collect();
+
+        // the first segment is 3 units, which is in the first sge
+        assert_eq!(segments[0].sge0.addr, 0x1000_0000);
+        assert_eq!(segments[0].sge0.len, 3 * unit);
+        assert!(segments[0].sge1.is_none());
+        assert!(segments[0].is_first);
+
+        // the second segment is 32 units: 9 of sge0, the whole sge1 and 11 of sge2
+        assert_eq!(segments[1].common.raddr, u64::from(32 * unit));
+        assert_eq!(segments[1].sge0.addr, 0x1000_0000 + u64::from(3 * unit));
+        assert_eq!(segments[1].sge0.len, 9 * unit);
+        let sge1 = segments[1].sge1.unwrap();
+        assert_eq!((sge1.addr, sge1.len, sge1.key.get()), (0x2000_0000, sge_len, 2));
+        let sge2 = segments[1].sge2.unwrap();
+        assert_eq!((sge2.addr, sge2.len, sge2.key.get()), (0x3000_0000, 11 * unit, 3));
+        assert!(segments[1].sge3.is_none());
+
+        // the last segment is the rest 1 unit of sge2
+        assert_eq!(segments[2].common.raddr, u64::from(64 * unit));
+        assert_eq!(segments[2].sge0.addr, 0x3000_0000 + u64::from(11 * unit));
+        assert_eq!(segments[2].sge0.len, unit);
+        assert_eq!(segments[2].sge0.key.get(), 3);
+        assert!(segments[2].sge1.is_none());
+        assert!(segments[2].is_last);
+    }
+
+    #[test]
+    fn test_slice_descriptor() {
+        // 10 packets: 0x800 bytes in the first packet, 8 full packets and 0x800 bytes in the last
+        let pmtu = 4096;
+        let desc = ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+            common: ToCardWorkRbDescCommon {
+                total_len: 9 * pmtu,
+                raddr: 0x800,
+                psn: Psn::new(100),
+                pmtu: crate::types::Pmtu::Mtu4096,
+                ..Default::default()
+            },
+            sge0: DescSge {
+                addr: 0x1000_0000,
+                len: 4 * pmtu,
+                key: Key::new(1),
+            },
+            sge1: Some(DescSge {
+                addr: 0x2000_0000,
+                len: 5 * pmtu,
+                key: Key::new(2),
+            }),
+            is_first: true,
+            is_last: true,
+            ..Default::default()
+        });
+        let slice = |from: u32, to: u32| {
+            super::slice_descriptor(&desc, Psn::new(from), Psn::new(to))
+                .unwrap()
+                .map(|desc| match *desc {
+                    ToCardWorkRbDesc::Write(req) => req,
+                    ToCardWorkRbDesc::Read(_)
+                    | ToCardWorkRbDesc::WriteWithImm(_)
+                    | ToCardWorkRbDesc::ReadResp(_)
+                    | ToCardWorkRbDesc::Send(_)
+                    | ToCardWorkRbDesc::AtomicCmpSwap(_)
+                    | ToCardWorkRbDesc::AtomicFetchAdd(_) => panic!("unexpected descriptor"),
+                })
+        };
+
+        // the middle packets 103 - 105 cross the two sges
+        let middle = slice(103, 105).unwrap();
+        assert_eq!(middle.common.psn, Psn::new(103));
+        assert_eq!(middle.common.raddr, u64::from(3 * pmtu));
+        assert_eq!(middle.common.total_len, 3 * pmtu);
+        assert_eq!((middle.sge0.addr, middle.sge0.len), (0x1000_0000 + 0x2800, 0x1800));
+        let sge1 = middle.sge1.unwrap();
+        assert_eq!((sge1.addr, sge1.len), (0x2000_0000, 0x1800));
+        assert!(!middle.is_first && !middle.is_last);
+
+        // the first slice keeps the total length of the message
+        let first = slice(90, 100).unwrap();
+        assert_eq!(first.common.psn, Psn::new(100));
+        assert_eq!(first.common.total_len, 9 * pmtu);
+        assert_eq!(first.sge0.len, 0x800);
+        assert!(first.is_first && !first.is_last);
+
+        // the last packet only has the rest 0x800 bytes
+        let last = slice(109, 120).unwrap();
+        assert_eq!(last.common.raddr, u64::from(9 * pmtu));
+        assert_eq!(last.common.total_len, 0x800);
+        assert_eq!((last.sge0.addr, last.sge0.len), (0x2000_0000 + 0x4800, 0x800));
+        assert!(!last.is_first && last.is_last);
+
+        // a range out of the message
+        assert!(slice(110, 120).is_none());
+        assert!(slice(90, 99).is_none());
+        assert!(super::check_sgl_length(&desc).is_ok());
+
+        // the sge list is shorter than the message
+        let mut short = desc.clone();
+        if let ToCardWorkRbDesc::Write(ref mut req) = short {
+            req.sge1 = None;
+        }
+        assert!(super::check_sgl_length(&short).is_err());
+        assert!(super::slice_descriptor(&short, Psn::new(103), Psn::new(105)).is_err());
+    }
+
     fn convert_list_to_vec<T>(list: LinkedList<T>) -> Vec<T> {
         let mut vec = Vec::new();
         for i in list {
@@ -113,6 +113,8 @@ mod tests {
                 qp_type: QpType::Rc,
                 psn: Psn::new(1234),
                 msn: Msn::new(0),
+                qkey: 0,
+                sqpn: Qpn::new(qpn),
             },
             is_last: true,
             is_first: true,
@@ -3,7 +3,8 @@ use thiserror::Error;
 
 use crate::{
     device::{
-        CtrlRbDescOpcode, ToCardCtrlRbDesc, ToCardWorkRbDesc, ToHostCtrlRbDesc, ToHostCtrlRbDescCommon, ToHostWorkRbDesc, ToHostWorkRbDescAck, ToHostWorkRbDescAethCode, ToHostWorkRbDescCommon, ToHostWorkRbDescOpcode, ToHostWorkRbDescRead, ToHostWorkRbDescStatus, ToHostWorkRbDescTransType, ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType, ToHostWorkRbDescWriteWithImm
+        layout::NReth,
+        CtrlRbDescOpcode, DescSge, ToCardCtrlRbDesc, ToCardWorkRbDesc, ToHostCtrlRbDesc, ToHostCtrlRbDescCommon, ToHostWorkRbDesc, ToHostWorkRbDescAck, ToHostWorkRbDescAtomic, ToHostWorkRbDescCommon, ToHostWorkRbDescDatagram, ToHostWorkRbDescOpcode, ToHostWorkRbDescRead, ToHostWorkRbDescSent, ToHostWorkRbDescStatus, ToHostWorkRbDescTransType, ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType, ToHostWorkRbDescWriteWithImm
     },
     types::{MemAccessTypeFlag, Msn, Pmtu, Psn, QpType},
     utils::get_first_packet_max_length,
@@ -12,29 +13,193 @@ use crate::{
 use super::{
     net_agent::{NetAgentError, NetReceiveLogic, NetSendAgent},
     types::{
-        Key, Metadata, PDHandle, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
-        RdmaMessageMetaCommon, RethHeader, ToCardDescriptor, ToCardReadDescriptor,
-        ToCardWriteDescriptor,
+        AtomicEthHeader, DatagramMeta, Key, Metadata, PDHandle, PKey, PayloadInfo, Qpn, RdmaGeneralMeta,
+        RdmaMessage, RdmaMessageMetaCommon, RethHeader, SGListElementWithKey,
+        ToCardAtomicDescriptor, ToCardDescriptor, ToCardReadDescriptor, ToCardWriteDescriptor,
     },
 };
 use std::{
-    collections::HashMap,
-    sync::{Arc, PoisonError, RwLock},
+    collections::{HashMap, VecDeque},
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
 };
 
+/// The number of executed atomic requests remembered by a queue pair.
+///
+/// A retransmitted atomic request should be responded with the original value, instead of being executed again.
+const MAX_EXECUTED_ATOMIC_PER_QP: usize = 16;
+
+/// The length of the operand of an atomic operation
+const ATOMIC_OPERAND_SIZE: u32 = 8;
+
+/// The length of the NRETH behind the AETH of an acknowledge
+const NRETH_SIZE: usize = 4;
+
+#[allow(dead_code)]
 #[derive(Debug,Clone)]
 struct QueuePairInner {
     pmtu: Pmtu,
     qp_type: QpType,
     qp_access_flags: MemAccessTypeFlag,
     pdkey: PDHandle,
+    rq_psn: Psn,
+    qkey: u32,
 }
 
 /// The hardware queue pair context
 #[derive(Debug)]
 struct QueuePair {
-    #[allow(dead_code)]
-    inner: QueuePairInner,
+    inner: RwLock<QueuePairInner>,
+    // The psn of the next request packet in order
+    expected_psn: Mutex<Psn>,
+    recv_queue: Mutex<RecvQueue>,
+    // The local buffers of the outgoing atomic requests, to which the original values are written back.
+    pending_atomic: Mutex<HashMap<Msn, SGListElementWithKey>>,
+    // (psn, original value) of the recently executed atomic requests
+    executed_atomic: Mutex<VecDeque<(Psn, u64)>>,
+}
+
+impl QueuePair {
+    fn new(inner: QueuePairInner) -> Self {
+        Self {
+            expected_psn: Mutex::new(inner.rq_psn),
+            inner: RwLock::new(inner),
+            recv_queue: Mutex::new(RecvQueue::default()),
+            pending_atomic: Mutex::new(HashMap::new()),
+            executed_atomic: Mutex::new(VecDeque::new()),
+        }
+    }
+}
+
+/// A receive buffer bound to an incoming send message
+#[derive(Debug, Clone, Copy)]
+struct RecvBinding {
+    msn: Msn,
+    sge: DescSge,
+    // The psn of the first packet of the message
+    first_psn: Psn,
+    // The psn of the last packet of the message, known once the last packet arrives
+    last_psn: Option<Psn>,
+}
+
+impl RecvBinding {
+    /// The offset in the receive buffer of the payload of packet `psn`.
+    ///
+    /// A send message is split from the start of the receive buffer, so every packet but the last
+    /// one carries a full pmtu.
+    #[allow(clippy::arithmetic_side_effects)] // a 24 bits psn distance times a pmtu fits in u64
+    fn offset(&self, psn: Psn, pmtu: u32) -> u64 {
+        u64::from(psn.wrapping_sub(self.first_psn.get()).get()) * u64::from(pmtu)
+    }
+}
+
+/// The hardware receive queue, which holds the buffers posted by the `PostRecv` descriptor
+#[derive(Debug, Default)]
+struct RecvQueue {
+    posted: VecDeque<DescSge>,
+    // The buffers bound to the messages in progress
+    consumed: VecDeque<RecvBinding>,
+}
+
+impl RecvQueue {
+    /// Get the buffer bound to the message `msn`, to which the packet `psn` belongs.
+    ///
+    /// The first packet of a message binds the head of the posted buffers to it. Return `None` if
+    /// no buffer is posted, or if the packet arrives before the first packet of its message.
+    fn get_or_consume(
+        &mut self,
+        msn: Msn,
+        psn: Psn,
+        write_type: ToHostWorkRbDescWriteType,
+    ) -> Option<RecvBinding> {
+        let is_last = matches!(
+            write_type,
+            ToHostWorkRbDescWriteType::Last | ToHostWorkRbDescWriteType::Only
+        );
+        if let Some(binding) = self.consumed.iter_mut().find(|binding| binding.msn == msn) {
+            if is_last {
+                binding.last_psn = Some(psn);
+            }
+            return Some(*binding);
+        }
+        if !matches!(
+            write_type,
+            ToHostWorkRbDescWriteType::First | ToHostWorkRbDescWriteType::Only
+        ) {
+            return None;
+        }
+        let binding = RecvBinding {
+            msn,
+            sge: self.posted.pop_front()?,
+            first_psn: psn,
+            last_psn: is_last.then_some(psn),
+        };
+        self.consumed.push_back(binding);
+        Some(binding)
+    }
+
+    /// Get the buffer bound to the message `msn` of a RC qp, without binding a new one.
+    fn get(&self, msn: Msn) -> Option<RecvBinding> {
+        self.consumed
+            .iter()
+            .find(|binding| binding.msn == msn)
+            .copied()
+    }
+
+    /// Release the buffers of the messages whose packets are all received.
+    ///
+    /// The expected psn only advances in order, so a message is complete once the expected psn
+    /// has passed its last packet.
+    fn release_completed(&mut self, expected_psn: Psn) {
+        self.consumed.retain(|binding| {
+            binding.last_psn.map_or(true, |last_psn| {
+                last_psn == expected_psn || !expected_psn.larger_in_psn(last_psn)
+            })
+        });
+    }
+
+    /// Get the buffer bound to the message `msn` of a UC qp.
+    ///
+    /// A UC message with a psn gap is dropped, so its buffer is returned to the head of the posted
+    /// buffers and reused by the next message. The last packet releases the binding since there
+    /// is no retransmission.
+    fn get_or_consume_uc(
+        &mut self,
+        msn: Msn,
+        psn: Psn,
+        write_type: ToHostWorkRbDescWriteType,
+        is_gap: bool,
+    ) -> Option<RecvBinding> {
+        if is_gap {
+            while let Some(binding) = self.consumed.pop_back() {
+                self.posted.push_front(binding.sge);
+            }
+        }
+        let binding = self.get_or_consume(msn, psn, write_type);
+        if matches!(
+            write_type,
+            ToHostWorkRbDescWriteType::Last | ToHostWorkRbDescWriteType::Only
+        ) {
+            self.consumed.retain(|consumed| consumed.msn != msn);
+        }
+        binding
+    }
+}
+
+/// Where the payload of a send packet lands
+#[derive(Debug)]
+enum RecvPlacement {
+    /// The address to write and the status of validating the receive buffer
+    Buffer(u64, ToHostWorkRbDescStatus),
+    /// A retransmitted packet of a completed message, which is acknowledged without being written
+    Duplicate,
+    /// A new message finds no receive buffer posted
+    NoBuffer,
+    /// The packet arrives before the first packet of its message, so it can't be placed
+    Unplaced,
 }
 
 /// The hardware memory region context
@@ -69,6 +234,8 @@ pub(crate) enum BlueRdmaLogicError {
     NetAgentError(#[from] NetAgentError),
     #[error("Raw packet length is too long. Pmtu is `{0}`, length is `{1}`")]
     RawPacketLengthTooLong(u32, u32),
+    #[error("Datagram length is too long. Pmtu is `{0}`, length is `{1}`")]
+    DatagramLengthTooLong(u32, u32),
     #[error("Poison error")]
     Poison,
     #[error("Unreachable")]
@@ -118,6 +285,7 @@ impl BlueRDMALogic {
         mut meta_data: RdmaGeneralMeta,
     ) -> Result<(), BlueRdmaLogicError> {
         // RdmaWriteOnly or RdmaWriteOnlyWithImmediate
+        let sge_total_length = req.sg_list.get_total_length();
         let payload = req.sg_list.cut_all_levels();
 
         // if it's a RdmaWriteOnlyWithImmediate, add the immediate data
@@ -127,7 +295,7 @@ impl BlueRDMALogic {
         meta_data.reth.len = if meta_data.common_meta.opcode.is_first() {
             req.common.total_len
         } else {
-            req.sg_list.get_total_length()
+            sge_total_length
         };
 
         let msg = RdmaMessage {
@@ -139,6 +307,56 @@ impl BlueRDMALogic {
         Ok(())
     }
 
+    /// Nothing acknowledges a datagram, so the driver completes it on this report
+    fn report_datagram_sent(&self, sqpn: crate::types::Qpn, msn: Msn, is_sent: bool) {
+        let status = if is_sent {
+            ToHostWorkRbDescStatus::Normal
+        } else {
+            ToHostWorkRbDescStatus::Unknown
+        };
+        let descriptor = ToHostWorkRbDesc::Sent(ToHostWorkRbDescSent {
+            common: ToHostWorkRbDescCommon {
+                status,
+                trans: ToHostWorkRbDescTransType::Ud,
+                dqpn: sqpn,
+                msn,
+                expected_psn: Psn::default(),
+            },
+        });
+        #[allow(clippy::unwrap_used)] // if the pipe in software is broken, we should panic.
+        {
+            self.to_host_data_descriptor_queue.send(descriptor).unwrap();
+        }
+    }
+
+    fn send_datagram_packet(
+        &self,
+        mut req: ToCardWriteDescriptor,
+        mut common_meta: RdmaMessageMetaCommon,
+    ) -> Result<(), BlueRdmaLogicError> {
+        // a datagram is always sent in a single packet
+        let total_length = req.sg_list.get_total_length();
+        let pmtu = u32::from(&req.common.pmtu);
+        if total_length > pmtu {
+            return Err(BlueRdmaLogicError::DatagramLengthTooLong(
+                pmtu,
+                total_length,
+            ));
+        }
+        common_meta.opcode = ToHostWorkRbDescOpcode::SendOnly;
+        let msg = RdmaMessage {
+            meta_data: Metadata::Datagram(DatagramMeta {
+                common_meta,
+                qkey: req.common.qkey,
+                src_qpn: Qpn::new(req.common.sqpn.get()),
+            }),
+            payload: req.sg_list.cut_all_levels(),
+        };
+
+        self.net_send_agent.send(req.common.dqp_ip, 4791, &msg)?;
+        Ok(())
+    }
+
     fn send_read_packet(
         &self,
         req: &ToCardReadDescriptor,
@@ -169,8 +387,49 @@ impl BlueRDMALogic {
         Ok(())
     }
 
+    fn send_atomic_packet(
+        &self,
+        req: &ToCardAtomicDescriptor,
+        mut common_meta: RdmaMessageMetaCommon,
+    ) -> Result<(), BlueRdmaLogicError> {
+        common_meta.opcode = req.opcode.clone();
+        // remember the local buffer, the original value will be written back when the ack arrives
+        {
+            let qp_table = self.qp_table.read()?;
+            if let Some(qp) = qp_table.get(&common_meta.dqpn) {
+                let _: Option<SGListElementWithKey> = qp
+                    .pending_atomic
+                    .lock()?
+                    .insert(req.common.msn, req.sge.data[0]);
+            }
+        }
+
+        let msg = RdmaMessage {
+            meta_data: Metadata::Atomic(AtomicEthHeader {
+                common_meta,
+                va: req.common.raddr,
+                rkey: Key::new(req.common.rkey.get()),
+                swap_add: req.swap_add,
+                compare: req.compare,
+            }),
+            payload: PayloadInfo::new(),
+        };
+
+        self.net_send_agent.send(req.common.dqp_ip, 4791, &msg)?;
+        Ok(())
+    }
+
     /// Convert a `ToCardWorkRbDesc` to a `RdmaMessage` and call the `net_send_agent` to send through the network.
+    ///
+    /// All the packets of the descriptor are handed to the network when it returns.
     pub(crate) fn send(&self, desc: Box<ToCardWorkRbDesc>) -> Result<(), BlueRdmaLogicError> {
+        let result = self.send_descriptor(desc);
+        let flushed = self.net_send_agent.flush();
+        result?;
+        Ok(flushed?)
+    }
+
+    fn send_descriptor(&self, desc: Box<ToCardWorkRbDesc>) -> Result<(), BlueRdmaLogicError> {
         let desc = ToCardDescriptor::from(desc);
         // if it's a raw packet, send it directly
         if desc.is_raw_packet() {
@@ -193,6 +452,13 @@ impl BlueRDMALogic {
 
         #[allow(clippy::arithmetic_side_effects)]
         match desc {
+            ToCardDescriptor::Write(req) if matches!(req.common.qp_type, QpType::Ud) => {
+                let sqpn = crate::types::Qpn::new(req.common.sqpn.get());
+                let msn = req.common.msn;
+                let result = self.send_datagram_packet(req, common_meta);
+                self.report_datagram_sent(sqpn, msn, result.is_ok());
+                result?;
+            }
             ToCardDescriptor::Write(mut req) => {
                 log::info!("{:?}", req);
                 let pmtu = u32::from(&req.common.pmtu);
@@ -279,6 +545,9 @@ impl BlueRDMALogic {
             ToCardDescriptor::Read(req) => {
                 self.send_read_packet(&req, common_meta)?;
             }
+            ToCardDescriptor::Atomic(req) => {
+                self.send_atomic_packet(&req, common_meta)?;
+            }
         }
         Ok(())
     }
@@ -295,17 +564,22 @@ impl BlueRDMALogic {
                     qp_type: desc.qp_type,
                     qp_access_flags: desc.rq_acc_flags,
                     pdkey: PDHandle::new(desc.pd_hdl),
+                    rq_psn: desc.expected_psn,
+                    qkey: desc.qkey,
                 };
                 let is_success = if desc.is_valid {
-                    // create
-                    let _result = qp_table
-                        .entry(qpn)
-                        .and_modify(|existing_qp| {
-                            *existing_qp = Arc::new(QueuePair {
-                                inner: qp_inner.clone(),
-                            });
-                        })
-                        .or_insert(Arc::new(QueuePair { inner: qp_inner }));
+                    if let Some(existing_qp) = qp_table.get(&qpn) {
+                        // modify, the queues are kept
+                        let mut inner = existing_qp.inner.write()?;
+                        if inner.rq_psn != qp_inner.rq_psn {
+                            *existing_qp.expected_psn.lock()? = qp_inner.rq_psn;
+                        }
+                        *inner = qp_inner;
+                    } else {
+                        // create
+                        let _: Option<Arc<QueuePair>> =
+                            qp_table.insert(qpn, Arc::new(QueuePair::new(qp_inner)));
+                    }
                     true
                 } else {
                     // delete
@@ -351,7 +625,25 @@ impl BlueRDMALogic {
                 (desc.common.op_id,true)
             }
             ToCardCtrlRbDesc::UpdateErrorPsnRecoverPoint(desc) => {
-                (desc.common.op_id,true)
+                let qp_table = self.qp_table.read()?;
+                let is_success = if let Some(qp) = qp_table.get(&Qpn::new(desc.qpn.get())) {
+                    *qp.expected_psn.lock()? = desc.recover_psn;
+                    qp.recv_queue.lock()?.release_completed(desc.recover_psn);
+                    true
+                } else {
+                    false
+                };
+                (desc.common.op_id, is_success)
+            }
+            ToCardCtrlRbDesc::PostRecv(desc) => {
+                let qp_table = self.qp_table.read()?;
+                let is_success = if let Some(qp) = qp_table.get(&Qpn::new(desc.qpn.get())) {
+                    qp.recv_queue.lock()?.posted.push_back(desc.sge);
+                    true
+                } else {
+                    false
+                };
+                (desc.common.op_id, is_success)
             }
         };
         let resp_desc = ToHostCtrlRbDesc{
@@ -405,6 +697,288 @@ impl BlueRDMALogic {
         }
         Ok(ToHostWorkRbDescStatus::Normal)
     }
+
+    /// Find the receive buffer of a send packet and validate the region to be written.
+    ///
+    /// A send packet carries no RETH, so the payload is placed by the buffer bound to its message.
+    /// Return `None` if the qp does not exist.
+    /// `uc_expected_psn` is the expected psn before receiving the packet if the qp is a UC qp.
+    fn consume_recv_buffer(
+        &self,
+        header: &RdmaGeneralMeta,
+        msn: Msn,
+        uc_expected_psn: Option<Psn>,
+    ) -> Result<Option<RecvPlacement>, BlueRdmaLogicError> {
+        let psn = header.common_meta.psn;
+        let write_type = header
+            .common_meta
+            .opcode
+            .write_type()
+            .unwrap_or(ToHostWorkRbDescWriteType::Only);
+        let (binding, pmtu) = {
+            let qp_table = self.qp_table.read()?;
+            let Some(qp) = qp_table.get(&header.common_meta.dqpn) else {
+                return Ok(None);
+            };
+            let pmtu = u32::from(&qp.inner.read()?.pmtu);
+            // the expected psn is locked before the receive queue, like `check_expected_psn`
+            let rc_expected_psn = *qp.expected_psn.lock()?;
+            let mut recv_queue = qp.recv_queue.lock()?;
+            let binding = if let Some(expected_psn) = uc_expected_psn {
+                recv_queue.get_or_consume_uc(msn, psn, write_type, expected_psn != psn)
+            } else {
+                let is_duplicate = psn != rc_expected_psn && rc_expected_psn.larger_in_psn(psn);
+                if is_duplicate {
+                    // The buffer of a completed message has been released
+                    let Some(binding) = recv_queue.get(msn) else {
+                        let placement = match write_type {
+                            ToHostWorkRbDescWriteType::Last | ToHostWorkRbDescWriteType::Only => {
+                                RecvPlacement::Duplicate
+                            }
+                            ToHostWorkRbDescWriteType::First
+                            | ToHostWorkRbDescWriteType::Middle => RecvPlacement::Unplaced,
+                        };
+                        return Ok(Some(placement));
+                    };
+                    Some(binding)
+                } else {
+                    recv_queue.get_or_consume(msn, psn, write_type)
+                }
+            };
+            (binding, pmtu)
+        };
+        let Some(binding) = binding else {
+            let placement = match write_type {
+                ToHostWorkRbDescWriteType::First | ToHostWorkRbDescWriteType::Only => {
+                    RecvPlacement::NoBuffer
+                }
+                ToHostWorkRbDescWriteType::Middle | ToHostWorkRbDescWriteType::Last => {
+                    RecvPlacement::Unplaced
+                }
+            };
+            return Ok(Some(placement));
+        };
+
+        let sge = binding.sge;
+        let offset = binding.offset(psn, pmtu);
+        let len = header.reth.len;
+        if offset.saturating_add(u64::from(len)) > u64::from(sge.len) {
+            return Ok(Some(RecvPlacement::Buffer(
+                sge.addr,
+                ToHostWorkRbDescStatus::InvMrRegion,
+            )));
+        }
+        let va = sge.addr.wrapping_add(offset);
+        let status = self.validate_rkey(sge.key.into(), header.needed_permissions(), va, len)?;
+        Ok(Some(RecvPlacement::Buffer(va, status)))
+    }
+
+    /// Take the head of the posted receive buffers for a datagram and validate the region to be written.
+    ///
+    /// Return the address to write and the status, or `None` if the datagram should be dropped.
+    fn consume_datagram_buffer(
+        &self,
+        header: &DatagramMeta,
+        len: u32,
+    ) -> Result<Option<(u64, ToHostWorkRbDescStatus)>, BlueRdmaLogicError> {
+        let sge = {
+            let qp_table = self.qp_table.read()?;
+            let Some(qp) = qp_table.get(&header.common_meta.dqpn) else {
+                return Ok(None);
+            };
+            let inner = qp.inner.read()?;
+            if !matches!(inner.qp_type, QpType::Ud) || inner.qkey != header.qkey {
+                log::warn!(
+                    "Q_Key mismatch or not a UD qp: {:?}, drop the datagram",
+                    header.common_meta.dqpn
+                );
+                return Ok(None);
+            }
+            // datagrams are not retransmitted, so the buffer is consumed directly
+            let mut recv_queue = qp.recv_queue.lock()?;
+            recv_queue.posted.pop_front()
+        };
+        let Some(sge) = sge else {
+            log::warn!(
+                "No receive buffer for {:?}, drop the datagram",
+                header.common_meta.dqpn
+            );
+            return Ok(None);
+        };
+        if len > sge.len {
+            return Ok(Some((sge.addr, ToHostWorkRbDescStatus::InvMrRegion)));
+        }
+        let status = self.validate_rkey(
+            sge.key.into(),
+            MemAccessTypeFlag::IbvAccessLocalWrite,
+            sge.addr,
+            len,
+        )?;
+        Ok(Some((sge.addr, status)))
+    }
+
+    /// Check the psn of a request packet against the expected psn of its qp.
+    ///
+    /// Return the expected psn before receiving the packet, which is reported to the host,
+    /// or `None` if the qp does not exist. The expected psn advances only if the packet is in order.
+    /// A request which is not accepted, e.g. for no receive buffer, will be retransmitted by the
+    /// requester, so it doesn't advance the expected psn.
+    fn check_expected_psn(
+        &self,
+        qpn: Qpn,
+        psn: Psn,
+        is_accepted: bool,
+    ) -> Result<Option<Psn>, BlueRdmaLogicError> {
+        let qp_table = self.qp_table.read()?;
+        let Some(qp) = qp_table.get(&qpn) else {
+            return Ok(None);
+        };
+        let mut expected_psn = qp.expected_psn.lock()?;
+        let current = *expected_psn;
+        if current == psn && is_accepted {
+            *expected_psn = psn.wrapping_add(1);
+            qp.recv_queue.lock()?.release_completed(*expected_psn);
+        }
+        Ok(Some(current))
+    }
+
+    /// Check the psn of a request packet of a UC qp.
+    ///
+    /// Return the expected psn before receiving the packet, or `None` if the qp is not a UC qp.
+    /// The lost packets of a UC qp are never retransmitted, so the expected psn always follows
+    /// the received packet.
+    fn check_uc_expected_psn(
+        &self,
+        qpn: Qpn,
+        psn: Psn,
+    ) -> Result<Option<Psn>, BlueRdmaLogicError> {
+        let qp_table = self.qp_table.read()?;
+        let Some(qp) = qp_table.get(&qpn) else {
+            return Ok(None);
+        };
+        if !matches!(qp.inner.read()?.qp_type, QpType::Uc) {
+            return Ok(None);
+        }
+        let mut expected_psn = qp.expected_psn.lock()?;
+        let current = *expected_psn;
+        *expected_psn = psn.wrapping_add(1);
+        Ok(Some(current))
+    }
+
+    /// Execute an atomic request on the local memory.
+    ///
+    /// Return the status, the original value of the memory and the expected psn before receiving
+    /// the request, or `None` if the request is dropped. Only the request of the expected psn is
+    /// executed. A retransmitted one is not executed again, and the original value of its first
+    /// execution is returned. A request after a lost packet is dropped and retransmitted later.
+    fn execute_atomic(
+        &self,
+        header: &AtomicEthHeader,
+    ) -> Result<Option<(ToHostWorkRbDescStatus, u64, Psn)>, BlueRdmaLogicError> {
+        let qpn = header.common_meta.dqpn;
+        let qp = {
+            let qp_table = self.qp_table.read()?;
+            let Some(qp) = qp_table.get(&qpn) else {
+                return Ok(None);
+            };
+            Arc::clone(qp)
+        };
+        if !matches!(qp.inner.read()?.qp_type, QpType::Rc) {
+            log::warn!("Atomic request to a non-RC qp: {:?}, drop it", qpn);
+            return Ok(None);
+        }
+        // hold the lock, so that a duplicated request is not executed concurrently
+        let mut executed = qp.executed_atomic.lock()?;
+        let psn = header.common_meta.psn;
+        let Some(expected_psn) = self.check_expected_psn(qpn, psn, true)? else {
+            return Ok(None);
+        };
+        if psn != expected_psn {
+            // a duplicated request is answered with the original value of its first execution
+            let orig = executed
+                .iter()
+                .find(|(executed_psn, _)| *executed_psn == psn && expected_psn.larger_in_psn(psn))
+                .map(|(_, orig)| *orig);
+            let Some(orig) = orig else {
+                log::warn!("Atomic request of {:?} is not expected: {:?}, drop it", psn, expected_psn);
+                return Ok(None);
+            };
+            return Ok(Some((ToHostWorkRbDescStatus::Normal, orig, expected_psn)));
+        }
+
+        // the target should be aligned to 8 bytes
+        if header.va & u64::from(ATOMIC_OPERAND_SIZE - 1) != 0 {
+            return Ok(Some((ToHostWorkRbDescStatus::InvMrRegion, 0, expected_psn)));
+        }
+        let status = self.validate_rkey(
+            header.rkey,
+            MemAccessTypeFlag::IbvAccessRemoteAtomic,
+            header.va,
+            ATOMIC_OPERAND_SIZE,
+        )?;
+        if !status.is_ok() {
+            return Ok(Some((status, 0, expected_psn)));
+        }
+
+        // SAFETY: the target is a valid and aligned region in a memory region
+        let target = unsafe { &*(header.va as *const AtomicU64) };
+        let orig = if header.is_compare_swap() {
+            match target.compare_exchange(
+                header.compare,
+                header.swap_add,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(orig) | Err(orig) => orig,
+            }
+        } else {
+            target.fetch_add(header.swap_add, Ordering::SeqCst)
+        };
+
+        if executed.len() >= MAX_EXECUTED_ATOMIC_PER_QP {
+            let _: Option<(Psn, u64)> = executed.pop_front();
+        }
+        executed.push_back((psn, orig));
+        Ok(Some((ToHostWorkRbDescStatus::Normal, orig, expected_psn)))
+    }
+
+    /// Write the original value in an atomic ack back to the local buffer of the request.
+    ///
+    /// Return `None` if there is no pending request of `msn`, which means the ack is duplicated.
+    fn write_back_atomic_orig(
+        &self,
+        qpn: Qpn,
+        msn: Msn,
+        orig: u64,
+    ) -> Result<Option<ToHostWorkRbDescStatus>, BlueRdmaLogicError> {
+        let sge = {
+            let qp_table = self.qp_table.read()?;
+            let Some(qp) = qp_table.get(&qpn) else {
+                return Ok(None);
+            };
+            let mut pending_atomic = qp.pending_atomic.lock()?;
+            pending_atomic.remove(&msn)
+        };
+        let Some(sge) = sge else {
+            return Ok(None);
+        };
+        if sge.len < ATOMIC_OPERAND_SIZE {
+            return Ok(Some(ToHostWorkRbDescStatus::InvMrRegion));
+        }
+        let status = self.validate_rkey(
+            sge.key,
+            MemAccessTypeFlag::IbvAccessLocalWrite,
+            sge.addr,
+            ATOMIC_OPERAND_SIZE,
+        )?;
+        if status.is_ok() {
+            // SAFETY: the buffer is validated to be in a memory region
+            unsafe {
+                (sge.addr as *mut u64).write_unaligned(orig);
+            }
+        }
+        Ok(Some(status))
+    }
 }
 
 unsafe impl Send for BlueRDMALogic {}
@@ -422,23 +996,81 @@ fn recv_default_meta(message: &RdmaMessage) -> ToHostWorkRbDescCommon {
 }
 
 impl NetReceiveLogic<'_> for BlueRDMALogic {
-    fn recv(&self, message: &mut RdmaMessage) {
+    fn recv(&self, message: &mut RdmaMessage, src_addr: Ipv4Addr) {
         let meta = &message.meta_data;
         let mut common = recv_default_meta(message);
         let descriptor = match meta {
             Metadata::General(header) => {
-                // validate the rkey
-                let reky = header.reth.rkey;
-                let needed_permissions = header.needed_permissions();
-                let va = header.reth.va;
-                let len = header.reth.len;
-                let Ok(status) = self.validate_rkey(reky, needed_permissions, va, len) else {
-                    log::error!("Failed to validate the rkey");
+                // the acks and atomics are parsed into their own headers, so it's malformed
+                if matches!(
+                    header.common_meta.opcode,
+                    ToHostWorkRbDescOpcode::Acknowledge
+                        | ToHostWorkRbDescOpcode::AtomicAcknowledge
+                        | ToHostWorkRbDescOpcode::CompareSwap
+                        | ToHostWorkRbDescOpcode::FetchAdd
+                ) {
+                    log::error!("Unexpected general packet: {:?}, drop it", header.common_meta);
                     return;
+                }
+                let is_read_resp = header.common_meta.opcode.is_resp();
+                // the read responses are in the psn space of our own requests
+                let uc_expected_psn = if is_read_resp {
+                    None
+                } else {
+                    match self
+                        .check_uc_expected_psn(header.common_meta.dqpn, header.common_meta.psn)
+                    {
+                        Ok(uc_expected_psn) => uc_expected_psn,
+                        Err(e) => {
+                            log::error!("Failed to check the psn: {:?}", e);
+                            return;
+                        }
+                    }
+                };
+                let (va, status, is_duplicate) = if header.is_send() {
+                    // a send packet is written to the receive buffer posted by user
+                    let placement = match self.consume_recv_buffer(header, common.msn, uc_expected_psn) {
+                        Ok(Some(placement)) => placement,
+                        Ok(None) => {
+                            log::warn!("Unknown {:?}, drop the packet", header.common_meta.dqpn);
+                            return;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to consume the receive buffer: {:?}", e);
+                            return;
+                        }
+                    };
+                    match placement {
+                        RecvPlacement::Buffer(va, status) => (va, status, false),
+                        RecvPlacement::Duplicate => (0, ToHostWorkRbDescStatus::Normal, true),
+                        // the driver responds an RNR NAK, and the requester will retry later
+                        RecvPlacement::NoBuffer if uc_expected_psn.is_none() => {
+                            (0, ToHostWorkRbDescStatus::NoRecvBuf, false)
+                        }
+                        RecvPlacement::NoBuffer | RecvPlacement::Unplaced => {
+                            // the requester retransmits it after the driver finds it missing
+                            log::warn!(
+                                "No receive buffer for {:?}, drop the send packet",
+                                header.common_meta.dqpn
+                            );
+                            return;
+                        }
+                    }
+                } else {
+                    // validate the rkey
+                    let reky = header.reth.rkey;
+                    let needed_permissions = header.needed_permissions();
+                    let va = header.reth.va;
+                    let len = header.reth.len;
+                    let Ok(status) = self.validate_rkey(reky, needed_permissions, va, len) else {
+                        log::error!("Failed to validate the rkey");
+                        return;
+                    };
+                    (va, status, false)
                 };
 
                 // Copy the payload to the memory
-                if status.is_ok() && header.has_payload() {
+                if status.is_ok() && header.has_payload() && !is_duplicate {
                     message.payload.copy_to(va as *mut u8);
                 }
 
@@ -451,11 +1083,39 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                     .unwrap_or(ToHostWorkRbDescWriteType::Only);
 
                 common.status = status;
-                let is_read_resp = header.common_meta.opcode.is_resp();
+                let is_send = header.is_send();
+                #[allow(clippy::else_if_without_else)]
+                if let Some(expected_psn) = uc_expected_psn {
+                    common.trans = ToHostWorkRbDescTransType::Uc;
+                    common.expected_psn = expected_psn;
+                } else if !is_read_resp {
+                    let is_accepted = !matches!(common.status, ToHostWorkRbDescStatus::NoRecvBuf);
+                    match self.check_expected_psn(
+                        header.common_meta.dqpn,
+                        header.common_meta.psn,
+                        is_accepted,
+                    ) {
+                        Ok(Some(expected_psn)) => common.expected_psn = expected_psn,
+                        Ok(None) => {
+                            log::warn!("Unknown {:?}, drop the packet", header.common_meta.dqpn);
+                            return;
+                        }
+                        Err(e) => {
+                            log::error!("Failed to check the psn: {:?}", e);
+                            return;
+                        }
+                    }
+                }
 
                 // Write a descriptor to host
                 match header.common_meta.opcode {
-                    ToHostWorkRbDescOpcode::RdmaWriteFirst
+                    ToHostWorkRbDescOpcode::SendFirst
+                    | ToHostWorkRbDescOpcode::SendMiddle
+                    | ToHostWorkRbDescOpcode::SendLast
+                    | ToHostWorkRbDescOpcode::SendLastWithImmediate
+                    | ToHostWorkRbDescOpcode::SendOnly
+                    | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+                    | ToHostWorkRbDescOpcode::RdmaWriteFirst
                     | ToHostWorkRbDescOpcode::RdmaWriteMiddle
                     | ToHostWorkRbDescOpcode::RdmaWriteLast
                     | ToHostWorkRbDescOpcode::RdmaWriteOnly
@@ -466,11 +1126,14 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                         ToHostWorkRbDesc::WriteOrReadResp(ToHostWorkRbDescWriteOrReadResp {
                             common,
                             is_read_resp,
+                            is_send,
                             write_type,
                             psn: header.common_meta.psn,
-                            addr: header.reth.va,
+                            addr: va,
                             len: header.reth.len,
                             can_auto_ack: false,
+                            // only the send packets carry the immediate here
+                            imm: header.imm,
                         })
                     }
                     ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate
@@ -486,6 +1149,7 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                             addr: header.reth.va,
                             len: header.reth.len,
                             key: header.reth.rkey.into(),
+                            can_auto_ack: false,
                         })
                     }
                     ToHostWorkRbDescOpcode::RdmaReadRequest => {
@@ -502,29 +1166,102 @@ impl NetReceiveLogic<'_> for BlueRDMALogic {
                             rkey: sec_reth.rkey.into(),
                         })
                     }
-                    ToHostWorkRbDescOpcode::Acknowledge => {
-                        unimplemented!()
+                    ToHostWorkRbDescOpcode::Acknowledge
+                    | ToHostWorkRbDescOpcode::AtomicAcknowledge
+                    | ToHostWorkRbDescOpcode::CompareSwap
+                    | ToHostWorkRbDescOpcode::FetchAdd => {
+                        // dropped above
+                        return;
                     }
                 }
             }
-            Metadata::Acknowledge(header) => {
-                common.status = ToHostWorkRbDescStatus::Normal;
-                match header.aeth_code {
-                    ToHostWorkRbDescAethCode::Ack => ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
-                        common,
-                        #[allow(clippy::cast_possible_truncation)]
-                        msn: crate::types::Msn::new(header.msn as u16), // msn is u16 currently. So we can just truncate it.
-                        value: header.aeth_value,
-                        psn: crate::types::Psn::new(header.common_meta.psn.get()),
-                        code: ToHostWorkRbDescAethCode::Ack,
-                    }),
-                    ToHostWorkRbDescAethCode::Rnr
-                    | ToHostWorkRbDescAethCode::Rsvd
-                    | ToHostWorkRbDescAethCode::Nak => {
-                        // just ignore
-                        unimplemented!()
+            Metadata::Atomic(header) => {
+                let (status, orig, expected_psn) = match self.execute_atomic(header) {
+                    Ok(Some(result)) => result,
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::error!("Failed to execute the atomic request: {:?}", e);
+                        return;
                     }
+                };
+                common.status = status;
+                common.expected_psn = expected_psn;
+                ToHostWorkRbDesc::Atomic(ToHostWorkRbDescAtomic {
+                    common,
+                    psn: header.common_meta.psn,
+                    orig,
+                })
+            }
+            Metadata::Datagram(header) => {
+                #[allow(clippy::cast_possible_truncation)]
+                let len = message.payload.get_length() as u32;
+                let (addr, status) = match self.consume_datagram_buffer(header, len) {
+                    Ok(Some(result)) => result,
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::error!("Failed to consume the receive buffer: {:?}", e);
+                        return;
+                    }
+                };
+                // the GRH is not written, the source is reported in the descriptor instead
+                if status.is_ok() {
+                    message.payload.copy_to(addr as *mut u8);
                 }
+                common.status = status;
+                common.trans = ToHostWorkRbDescTransType::Ud;
+                ToHostWorkRbDesc::Datagram(ToHostWorkRbDescDatagram {
+                    common,
+                    addr,
+                    len,
+                    src_qpn: crate::types::Qpn::new(header.src_qpn.get()),
+                    src_ip: src_addr,
+                })
+            }
+            Metadata::AtomicAcknowledge(header) => {
+                #[allow(clippy::cast_possible_truncation)]
+                let msn = Msn::new(header.aeth.msn as u16); // msn is u16 currently. So we can just truncate it.
+                let qpn = header.aeth.common_meta.dqpn;
+                common.status = match self.write_back_atomic_orig(qpn, msn, header.orig) {
+                    Ok(Some(status)) => status,
+                    Ok(None) => {
+                        log::warn!("No pending atomic request for {:?}, drop the ack", msn);
+                        return;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to write back the atomic result: {:?}", e);
+                        return;
+                    }
+                };
+                ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
+                    common,
+                    msn,
+                    value: header.aeth.aeth_value,
+                    psn: crate::types::Psn::new(header.aeth.common_meta.psn.get()),
+                    code: header.aeth.aeth_code,
+                    last_retry_psn: crate::types::Psn::new(header.aeth.common_meta.psn.get()),
+                })
+            }
+            Metadata::Acknowledge(header) => {
+                common.status = ToHostWorkRbDescStatus::Normal;
+                let psn = crate::types::Psn::new(header.common_meta.psn.get());
+                // The NRETH is in the payload, which tells the last psn to retry of a NAK
+                let last_retry_psn = message
+                    .payload
+                    .direct_data_ptr(false)
+                    .and_then(|data| data.get(..NRETH_SIZE))
+                    .map_or(psn.wrapping_sub(1), |nreth| {
+                        Psn::from_be(NReth(nreth).get_last_retry_psn())
+                    });
+                // The code of a NAK or RNR NAK is in the value, which is handled by the driver
+                ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
+                    common,
+                    #[allow(clippy::cast_possible_truncation)]
+                    msn: crate::types::Msn::new(header.msn as u16), // msn is u16 currently. So we can just truncate it.
+                    value: header.aeth_value,
+                    psn,
+                    code: header.aeth_code,
+                    last_retry_psn,
+                })
             }
         };
 
@@ -544,6 +1281,7 @@ fn to_host_ctrl_opcode(desc : &ToCardCtrlRbDesc) -> CtrlRbDescOpcode {
         ToCardCtrlRbDesc::SetNetworkParam(_) => CtrlRbDescOpcode::SetNetworkParam,
         ToCardCtrlRbDesc::SetRawPacketReceiveMeta(_) => CtrlRbDescOpcode::SetRawPacketReceiveMeta,
         ToCardCtrlRbDesc::UpdateErrorPsnRecoverPoint(_) => CtrlRbDescOpcode::UpdateErrorPsnRecoverPoint,
+        ToCardCtrlRbDesc::PostRecv(_) => CtrlRbDescOpcode::PostRecv,
     }
 }
 
@@ -551,51 +1289,126 @@ fn to_host_ctrl_opcode(desc : &ToCardCtrlRbDesc) -> CtrlRbDescOpcode {
 mod tests {
     use std::{net::Ipv4Addr, sync::Arc};
 
-    use flume::unbounded;
+    use flume::{unbounded, Receiver};
 
     use crate::{
         device::{
             software::{
-                net_agent::{NetAgentError, NetSendAgent},
-                types::{Key, PayloadInfo, Qpn, RdmaMessage},
+                net_agent::{NetAgentError, NetReceiveLogic, NetSendAgent},
+                types::{
+                    AethHeader, AtomicEthHeader, DatagramMeta, Key, Metadata, PKey, PayloadInfo,
+                    Qpn, RdmaGeneralMeta, RdmaMessage, RdmaMessageMetaCommon, RethHeader,
+                },
             },
-            ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescQpManagement,
-            ToCardCtrlRbDescUpdateMrTable,
+            DescSge, ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescPostRecv,
+            ToCardCtrlRbDescQpManagement,
+            ToCardCtrlRbDescUpdateErrPsnRecoverPoint, ToCardCtrlRbDescUpdateMrTable,
+            ToHostCtrlRbDesc, ToHostWorkRbDesc, ToHostWorkRbDescAethCode, ToHostWorkRbDescNakCode,
+            ToHostWorkRbDescOpcode, ToHostWorkRbDescStatus, ToHostWorkRbDescTransType,
         },
-        types::{MemAccessTypeFlag, Pmtu, QpType},
+        types::{MemAccessTypeFlag, Pmtu, Psn, QpType},
     };
 
     use super::BlueRDMALogic;
 
+    #[derive(Debug)]
+    struct DummpyProxy;
+
+    impl NetSendAgent for DummpyProxy {
+        fn send(
+            &self,
+            _: Ipv4Addr,
+            _: u16,
+            _message: &RdmaMessage,
+        ) -> Result<(), NetAgentError> {
+            Ok(())
+        }
+
+        fn send_raw(
+            &self,
+            _: Ipv4Addr,
+            _: u16,
+            _payload: &PayloadInfo,
+        ) -> Result<(), NetAgentError> {
+            Ok(())
+        }
+
+        fn send_packet(&self, _: Ipv4Addr, _: u16, _: &[u8]) -> Result<(), NetAgentError> {
+            Ok(())
+        }
+    }
+
+    /// Create a logic whose packets are sent to nowhere, with the receivers of its descriptors.
+    fn new_logic() -> (
+        BlueRDMALogic,
+        Receiver<ToHostCtrlRbDesc>,
+        Receiver<ToHostWorkRbDesc>,
+    ) {
+        let (ctrl_sender, ctrl_receiver) = unbounded();
+        let (work_sender, work_receiver) = unbounded();
+        let logic = BlueRDMALogic::new(Arc::new(DummpyProxy), ctrl_sender, work_sender);
+        (logic, ctrl_receiver, work_receiver)
+    }
+
+    /// Create the QP 3, which is connected to the QP 3 of the peer.
+    fn update_qp(
+        logic: &BlueRDMALogic,
+        qp_type: QpType,
+        rq_acc_flags: MemAccessTypeFlag,
+        pmtu: Pmtu,
+        expected_psn: u32,
+        qkey: u32,
+    ) {
+        logic
+            .update(ToCardCtrlRbDesc::QpManagement(ToCardCtrlRbDescQpManagement {
+                common: ToCardCtrlRbDescCommon { op_id: 0 },
+                is_valid: true,
+                qpn: crate::Qpn::new(3),
+                pd_hdl: 1,
+                qp_type,
+                rq_acc_flags,
+                pmtu,
+                peer_qpn: crate::Qpn::new(3),
+                expected_psn: Psn::new(expected_psn),
+                qkey,
+            }))
+            .unwrap();
+    }
+
+    /// Register the `len` bytes at `addr` as the MR of key 0x1000.
+    fn update_mr(logic: &BlueRDMALogic, addr: u64, len: u32, acc_flags: MemAccessTypeFlag) {
+        logic
+            .update(ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
+                common: ToCardCtrlRbDescCommon { op_id: 0 },
+                addr,
+                len,
+                key: crate::types::Key::new(0x1000),
+                pd_hdl: 1,
+                acc_flags,
+                pgt_offset: 0,
+            }))
+            .unwrap();
+    }
+
+    /// Post a receive buffer of `len` bytes at `addr` in the MR of key 0x1000 to the QP 3.
+    fn post_recv(logic: &BlueRDMALogic, addr: u64, len: u32) {
+        logic
+            .update(ToCardCtrlRbDesc::PostRecv(ToCardCtrlRbDescPostRecv {
+                common: ToCardCtrlRbDescCommon { op_id: 0 },
+                qpn: crate::Qpn::new(3),
+                sge: DescSge {
+                    addr,
+                    len,
+                    key: crate::types::Key::new(0x1000),
+                },
+            }))
+            .unwrap();
+    }
+
     // test update mr table, qp table
     #[test]
     fn test_logic_update() {
-        #[derive(Debug)]
-        struct DummpyProxy;
-
-        impl NetSendAgent for DummpyProxy {
-            fn send(
-                &self,
-                _: Ipv4Addr,
-                _: u16,
-                _message: &RdmaMessage,
-            ) -> Result<(), NetAgentError> {
-                Ok(())
-            }
-
-            fn send_raw(
-                &self,
-                _: Ipv4Addr,
-                _: u16,
-                _payload: &PayloadInfo,
-            ) -> Result<(), NetAgentError> {
-                Ok(())
-            }
-        }
-        let agent = Arc::new(DummpyProxy);
-        let (ctrl_sender, _ctrl_receiver) = unbounded();
-        let (work_sender, _work_receiver) = unbounded();
-        let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent), ctrl_sender, work_sender);
+        let (logic, _ctrl_receiver, _work_receiver) = new_logic();
         // test updating qp
         {
             let desc = ToCardCtrlRbDesc::QpManagement(ToCardCtrlRbDescQpManagement {
@@ -607,12 +1420,14 @@ mod tests {
                 rq_acc_flags: MemAccessTypeFlag::IbvAccessRemoteWrite,
                 pmtu: Pmtu::Mtu1024,
                 peer_qpn: crate::Qpn::new(1234),
+                expected_psn: Psn::new(0),
+                qkey: 0,
             });
             logic.update(desc).unwrap();
             {
                 let guard = logic.qp_table.read().unwrap();
                 let qp_context = guard.get(&Qpn::new(1234)).unwrap();
-                let inner = &qp_context.inner;
+                let inner = qp_context.inner.read().unwrap();
                 assert!(matches!(inner.pmtu, Pmtu::Mtu1024));
                 assert!(matches!(inner.qp_type, QpType::Rc));
                 assert!(inner
@@ -630,12 +1445,14 @@ mod tests {
                 rq_acc_flags: MemAccessTypeFlag::IbvAccessRemoteWrite,
                 pmtu: Pmtu::Mtu2048,
                 peer_qpn: crate::Qpn::new(1234),
+                expected_psn: Psn::new(0),
+                qkey: 0,
             });
             logic.update(desc).unwrap();
             {
                 let guard = logic.qp_table.read().unwrap();
                 let qp_context = guard.get(&Qpn::new(1234)).unwrap();
-                let inner = &qp_context.inner;
+                let inner = qp_context.inner.read().unwrap();
                 assert!(matches!(inner.pmtu, Pmtu::Mtu2048));
                 assert!(matches!(inner.qp_type, QpType::Rc));
                 assert!(inner
@@ -698,4 +1515,509 @@ mod tests {
             }
         }
     }
+
+    fn atomic_message(opcode: ToHostWorkRbDescOpcode, psn: u32, va: u64, compare: u64, swap_add: u64) -> RdmaMessage {
+        RdmaMessage {
+            meta_data: Metadata::Atomic(AtomicEthHeader {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode,
+                    solicited: false,
+                    pkey: PKey::new(1),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(psn),
+                },
+                va,
+                rkey: Key::new(0x1000),
+                swap_add,
+                compare,
+            }),
+            payload: PayloadInfo::new(),
+        }
+    }
+
+    #[test]
+    fn test_logic_atomic() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        let mut buffer = vec![0u64; 4];
+        let addr = buffer.as_mut_ptr() as u64;
+        update_qp(
+            &logic,
+            QpType::Rc,
+            MemAccessTypeFlag::IbvAccessRemoteAtomic,
+            Pmtu::Mtu1024,
+            0,
+            0,
+        );
+        update_mr(&logic, addr, 32, MemAccessTypeFlag::IbvAccessRemoteAtomic);
+
+        let recv_atomic = |opcode, psn, va, compare, swap_add| {
+            let mut message = atomic_message(opcode, psn, va, compare, swap_add);
+            logic.recv(&mut message, Ipv4Addr::LOCALHOST);
+            let ToHostWorkRbDesc::Atomic(desc) = work_receiver.try_recv().unwrap() else {
+                panic!("unexpected descriptor");
+            };
+            (desc.common.status, desc.orig)
+        };
+
+        // compare and swap succeeds
+        let (status, orig) = recv_atomic(ToHostWorkRbDescOpcode::CompareSwap, 0, addr, 0, 10);
+        assert!(status.is_ok());
+        assert_eq!(orig, 0);
+        assert_eq!(buffer[0], 10);
+
+        // compare and swap fails, the memory is untouched
+        let (status, orig) = recv_atomic(ToHostWorkRbDescOpcode::CompareSwap, 1, addr, 0, 20);
+        assert!(status.is_ok());
+        assert_eq!(orig, 10);
+        assert_eq!(buffer[0], 10);
+
+        // fetch and add
+        let (status, orig) = recv_atomic(ToHostWorkRbDescOpcode::FetchAdd, 2, addr, 0, 5);
+        assert!(status.is_ok());
+        assert_eq!(orig, 10);
+        assert_eq!(buffer[0], 15);
+
+        // a retransmitted request is not executed again
+        let (status, orig) = recv_atomic(ToHostWorkRbDescOpcode::FetchAdd, 2, addr, 0, 5);
+        assert!(status.is_ok());
+        assert_eq!(orig, 10);
+        assert_eq!(buffer[0], 15);
+
+        // unaligned address
+        let (status, _) = recv_atomic(ToHostWorkRbDescOpcode::FetchAdd, 3, addr + 4, 0, 5);
+        assert!(matches!(status, ToHostWorkRbDescStatus::InvMrRegion));
+
+        // out of the memory region
+        let (status, _) = recv_atomic(ToHostWorkRbDescOpcode::FetchAdd, 4, addr + 32, 0, 5);
+        assert!(matches!(status, ToHostWorkRbDescStatus::InvMrRegion));
+        assert_eq!(buffer[0], 15);
+
+        // a request after a lost packet is not executed, until it is retransmitted in order
+        let mut message = atomic_message(ToHostWorkRbDescOpcode::FetchAdd, 6, addr, 0, 5);
+        logic.recv(&mut message, Ipv4Addr::LOCALHOST);
+        assert!(work_receiver.try_recv().is_err());
+        assert_eq!(buffer[0], 15);
+        let (status, orig) = recv_atomic(ToHostWorkRbDescOpcode::FetchAdd, 5, addr, 0, 5);
+        assert!(status.is_ok());
+        assert_eq!(orig, 15);
+        assert_eq!(buffer[0], 20);
+
+        // a UC qp doesn't execute atomics
+        update_qp(
+            &logic,
+            QpType::Uc,
+            MemAccessTypeFlag::IbvAccessRemoteAtomic,
+            Pmtu::Mtu1024,
+            6,
+            0,
+        );
+        let mut message = atomic_message(ToHostWorkRbDescOpcode::FetchAdd, 6, addr, 0, 5);
+        logic.recv(&mut message, Ipv4Addr::LOCALHOST);
+        assert!(work_receiver.try_recv().is_err());
+        assert_eq!(buffer[0], 20);
+    }
+
+    #[test]
+    fn test_logic_expected_psn() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        update_qp(
+            &logic,
+            QpType::Rc,
+            MemAccessTypeFlag::IbvAccessRemoteAtomic,
+            Pmtu::Mtu1024,
+            100,
+            0,
+        );
+
+        let recv_expected_psn = |psn| {
+            logic.recv(
+                &mut atomic_message(ToHostWorkRbDescOpcode::FetchAdd, psn, 0, 0, 1),
+                Ipv4Addr::LOCALHOST,
+            );
+            let ToHostWorkRbDesc::Atomic(desc) = work_receiver.try_recv().unwrap() else {
+                panic!("unexpected descriptor");
+            };
+            desc.common.expected_psn.get()
+        };
+
+        assert_eq!(recv_expected_psn(100), 100);
+        // a lost packet doesn't advance the expected psn, and the request after it is dropped
+        logic.recv(
+            &mut atomic_message(ToHostWorkRbDescOpcode::FetchAdd, 102, 0, 0, 1),
+            Ipv4Addr::LOCALHOST,
+        );
+        assert!(work_receiver.try_recv().is_err());
+        assert_eq!(recv_expected_psn(101), 101);
+        assert_eq!(recv_expected_psn(102), 102);
+
+        // the driver moves the expected psn after recovering
+        logic
+            .update(ToCardCtrlRbDesc::UpdateErrorPsnRecoverPoint(
+                ToCardCtrlRbDescUpdateErrPsnRecoverPoint {
+                    common: ToCardCtrlRbDescCommon { op_id: 1 },
+                    qpn: crate::Qpn::new(3),
+                    recover_psn: Psn::new(200),
+                },
+            ))
+            .unwrap();
+        assert_eq!(recv_expected_psn(200), 200);
+        assert_eq!(recv_expected_psn(201), 201);
+    }
+
+    #[test]
+    fn test_logic_recv_datagram() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        let mut buffer = vec![0u8; 64];
+        let addr = buffer.as_mut_ptr() as u64;
+        update_qp(
+            &logic,
+            QpType::Ud,
+            MemAccessTypeFlag::IbvAccessLocalWrite,
+            Pmtu::Mtu1024,
+            0,
+            0x1111,
+        );
+        update_mr(&logic, addr, 64, MemAccessTypeFlag::IbvAccessLocalWrite);
+        post_recv(&logic, addr, 64);
+
+        let data = [7u8; 16];
+        let datagram = |qkey| RdmaMessage {
+            meta_data: Metadata::Datagram(DatagramMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Ud,
+                    opcode: ToHostWorkRbDescOpcode::SendOnly,
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(1234),
+                },
+                qkey,
+                src_qpn: Qpn::new(5),
+            }),
+            payload: PayloadInfo::new_with_data(data.as_ptr(), data.len()),
+        };
+        let src_addr = Ipv4Addr::new(10, 0, 0, 1);
+
+        // a datagram with a wrong qkey is dropped, and the buffer is not consumed
+        logic.recv(&mut datagram(0x2222), src_addr);
+        assert!(work_receiver.try_recv().is_err());
+
+        // the psn of a datagram is not checked
+        logic.recv(&mut datagram(0x1111), src_addr);
+        let ToHostWorkRbDesc::Datagram(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert!(desc.common.status.is_ok());
+        assert_eq!(desc.addr, addr);
+        assert_eq!(desc.len, 16);
+        assert_eq!(desc.src_qpn.get(), 5);
+        assert_eq!(desc.src_ip, src_addr);
+        assert_eq!(buffer[..16], data);
+        assert_eq!(buffer[16], 0);
+
+        // no buffer left
+        logic.recv(&mut datagram(0x1111), src_addr);
+        assert!(work_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_logic_recv_uc() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        let mut buffer = vec![0u8; 128];
+        let addr = buffer.as_mut_ptr() as u64;
+        update_qp(&logic, QpType::Uc, MemAccessTypeFlag::IbvAccessLocalWrite, Pmtu::Mtu1024, 10, 0);
+        update_mr(&logic, addr, 128, MemAccessTypeFlag::IbvAccessLocalWrite);
+        for offset in [0, 64] {
+            post_recv(&logic, addr + offset, 64);
+        }
+
+        let data = [7u8; 16];
+        let send = |opcode, msn, psn| RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Uc,
+                    opcode,
+                    solicited: false,
+                    pkey: PKey::new(msn),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(psn),
+                },
+                reth: RethHeader {
+                    len: 16,
+                    ..RethHeader::default()
+                },
+                imm: None,
+                secondary_reth: None,
+            }),
+            payload: PayloadInfo::new_with_data(data.as_ptr(), data.len()),
+        };
+
+        logic.recv(
+            &mut send(ToHostWorkRbDescOpcode::SendFirst, 1, 10),
+            Ipv4Addr::LOCALHOST,
+        );
+        let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert!(matches!(desc.common.trans, ToHostWorkRbDescTransType::Uc));
+        assert_eq!(desc.common.expected_psn.get(), 10);
+        assert_eq!(desc.addr, addr);
+
+        // psn 11 is lost, so the rest of the message is dropped
+        logic.recv(
+            &mut send(ToHostWorkRbDescOpcode::SendLast, 1, 12),
+            Ipv4Addr::LOCALHOST,
+        );
+        assert!(work_receiver.try_recv().is_err());
+        assert_eq!(buffer[16], 0);
+
+        // the next message reuses the buffer of the dropped one, without a retransmission
+        logic.recv(
+            &mut send(ToHostWorkRbDescOpcode::SendOnly, 2, 13),
+            Ipv4Addr::LOCALHOST,
+        );
+        let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert_eq!(desc.common.expected_psn.get(), 13);
+        assert_eq!(desc.addr, addr);
+
+        logic.recv(
+            &mut send(ToHostWorkRbDescOpcode::SendOnly, 3, 14),
+            Ipv4Addr::LOCALHOST,
+        );
+        let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert_eq!(desc.addr, addr + 64);
+    }
+
+    #[test]
+    fn test_logic_recv_send_by_psn() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        let msg_cnt = 20;
+        let buf_len = 512;
+        let mut buffer = vec![0u8; buf_len * msg_cnt];
+        let addr = buffer.as_mut_ptr() as u64;
+        update_qp(&logic, QpType::Rc, MemAccessTypeFlag::IbvAccessLocalWrite, Pmtu::Mtu256, 10, 0);
+        update_mr(&logic, addr, buffer.len() as u32, MemAccessTypeFlag::IbvAccessLocalWrite);
+        for i in 0..msg_cnt {
+            post_recv(&logic, addr + (i * buf_len) as u64, buf_len as u32);
+        }
+
+        // every message has a full pmtu first packet and a last packet with immediate data
+        let first_data = [1u8; 256];
+        let last_data = [2u8; 16];
+        let send = |opcode, msn: usize, psn: usize| {
+            let (data, imm) = if matches!(opcode, ToHostWorkRbDescOpcode::SendFirst) {
+                (&first_data[..], None)
+            } else {
+                (&last_data[..], Some(msn as u32))
+            };
+            RdmaMessage {
+                meta_data: Metadata::General(RdmaGeneralMeta {
+                    common_meta: RdmaMessageMetaCommon {
+                        tran_type: ToHostWorkRbDescTransType::Rc,
+                        opcode,
+                        solicited: false,
+                        pkey: PKey::new(msn as u16),
+                        dqpn: Qpn::new(3),
+                        ack_req: false,
+                        psn: Psn::new(psn as u32),
+                    },
+                    reth: RethHeader {
+                        len: data.len() as u32,
+                        ..RethHeader::default()
+                    },
+                    imm,
+                    secondary_reth: None,
+                }),
+                payload: PayloadInfo::new_with_data(data.as_ptr(), data.len()),
+            }
+        };
+
+        // the last packet can't be placed before the first one of its message
+        logic.recv(
+            &mut send(ToHostWorkRbDescOpcode::SendLastWithImmediate, 0, 11),
+            Ipv4Addr::LOCALHOST,
+        );
+        assert!(work_receiver.try_recv().is_err());
+
+        // all the messages are in progress at the same time
+        for i in 0..msg_cnt {
+            logic.recv(
+                &mut send(ToHostWorkRbDescOpcode::SendFirst, i, 10 + 2 * i),
+                Ipv4Addr::LOCALHOST,
+            );
+            let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+                panic!("unexpected descriptor");
+            };
+            assert_eq!(desc.addr, addr + (i * buf_len) as u64);
+        }
+        for i in 0..msg_cnt {
+            logic.recv(
+                &mut send(ToHostWorkRbDescOpcode::SendLastWithImmediate, i, 11 + 2 * i),
+                Ipv4Addr::LOCALHOST,
+            );
+            let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+                panic!("unexpected descriptor");
+            };
+            assert!(desc.common.status.is_ok());
+            assert_eq!(desc.addr, addr + (i * buf_len + 256) as u64);
+            assert_eq!(desc.imm, Some(i as u32));
+            assert_eq!(buffer[i * buf_len + 255], 1);
+            assert_eq!(buffer[i * buf_len + 256], 2);
+            assert_eq!(buffer[i * buf_len + 272], 0);
+        }
+
+        // the buffer of a completed message is released, its retransmission is only reported
+        let first_buf = buffer[..buf_len].to_vec();
+        logic.recv(
+            &mut send(ToHostWorkRbDescOpcode::SendLastWithImmediate, 0, 11),
+            Ipv4Addr::LOCALHOST,
+        );
+        let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert!(desc.common.status.is_ok());
+        assert_eq!(desc.common.expected_psn.get(), 12);
+        assert_eq!(buffer[..buf_len], first_buf);
+        logic.recv(
+            &mut send(ToHostWorkRbDescOpcode::SendFirst, 0, 10),
+            Ipv4Addr::LOCALHOST,
+        );
+        assert!(work_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_logic_recv_send_without_recv_buffer() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        let mut buffer = vec![0u8; 64];
+        let addr = buffer.as_mut_ptr() as u64;
+        update_qp(&logic, QpType::Rc, MemAccessTypeFlag::IbvAccessLocalWrite, Pmtu::Mtu1024, 10, 0);
+        update_mr(&logic, addr, 64, MemAccessTypeFlag::IbvAccessLocalWrite);
+
+        let data = [7u8; 16];
+        let send_only = || RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::SendOnly,
+                    solicited: false,
+                    pkey: PKey::new(1),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(10),
+                },
+                reth: RethHeader {
+                    va: 0,
+                    rkey: Key::new(0),
+                    len: 16,
+                },
+                imm: None,
+                secondary_reth: None,
+            }),
+            payload: PayloadInfo::new_with_data(data.as_ptr(), data.len()),
+        };
+
+        // no receive buffer, the request is reported without advancing the expected psn
+        logic.recv(&mut send_only(), Ipv4Addr::LOCALHOST);
+        let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert!(matches!(desc.common.status, ToHostWorkRbDescStatus::NoRecvBuf));
+        assert_eq!(desc.common.expected_psn.get(), 10);
+
+        // the retransmission is accepted once a buffer is posted
+        post_recv(&logic, addr, 64);
+        logic.recv(&mut send_only(), Ipv4Addr::LOCALHOST);
+        let ToHostWorkRbDesc::WriteOrReadResp(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert!(desc.common.status.is_ok());
+        assert_eq!(desc.common.expected_psn.get(), 10);
+        assert_eq!(desc.addr, addr);
+        assert_eq!(buffer[0], 7);
+    }
+
+    #[test]
+    fn test_logic_recv_nak() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        let mut message = RdmaMessage {
+            meta_data: Metadata::Acknowledge(AethHeader {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::Acknowledge,
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(100),
+                },
+                aeth_code: ToHostWorkRbDescAethCode::Nak,
+                aeth_value: ToHostWorkRbDescNakCode::RemoteAccessError as u8,
+                msn: 7,
+            }),
+            payload: PayloadInfo::new(),
+        };
+        logic.recv(&mut message, Ipv4Addr::LOCALHOST);
+        let ToHostWorkRbDesc::Ack(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert!(matches!(desc.code, ToHostWorkRbDescAethCode::Nak));
+        assert_eq!(desc.value, ToHostWorkRbDescNakCode::RemoteAccessError as u8);
+        assert_eq!(desc.psn.get(), 100);
+        assert_eq!(desc.msn.get(), 7);
+        // without the NRETH, all the packets from the NAKed psn are to retry
+        assert_eq!(desc.last_retry_psn.get(), 99);
+
+        // the last retry psn of a sequence error NAK is in the NRETH
+        let nreth = [0x00, 0x01, 0x04, 0x00];
+        if let Metadata::Acknowledge(ref mut header) = message.meta_data {
+            header.aeth_value = ToHostWorkRbDescNakCode::PsnSeqErr as u8;
+        }
+        message.payload = PayloadInfo::new_with_data(nreth.as_ptr(), nreth.len());
+        logic.recv(&mut message, Ipv4Addr::LOCALHOST);
+        let ToHostWorkRbDesc::Ack(desc) = work_receiver.try_recv().unwrap() else {
+            panic!("unexpected descriptor");
+        };
+        assert_eq!(desc.value, ToHostWorkRbDescNakCode::PsnSeqErr as u8);
+        assert_eq!(desc.last_retry_psn.get(), 0x104);
+    }
+
+    #[test]
+    fn test_logic_recv_malformed() {
+        let (logic, _ctrl_receiver, work_receiver) = new_logic();
+        // an atomic or an ack without its own header is dropped
+        for opcode in [
+            ToHostWorkRbDescOpcode::Acknowledge,
+            ToHostWorkRbDescOpcode::AtomicAcknowledge,
+            ToHostWorkRbDescOpcode::CompareSwap,
+            ToHostWorkRbDescOpcode::FetchAdd,
+        ] {
+            let mut message = RdmaMessage {
+                meta_data: Metadata::General(RdmaGeneralMeta {
+                    common_meta: RdmaMessageMetaCommon {
+                        tran_type: ToHostWorkRbDescTransType::Rc,
+                        opcode,
+                        solicited: false,
+                        pkey: PKey::new(0),
+                        dqpn: Qpn::new(3),
+                        ack_req: false,
+                        psn: Psn::new(0),
+                    },
+                    reth: RethHeader::default(),
+                    imm: None,
+                    secondary_reth: None,
+                }),
+                payload: PayloadInfo::new(),
+            };
+            logic.recv(&mut message, Ipv4Addr::LOCALHOST);
+            assert!(work_receiver.try_recv().is_err());
+        }
+    }
 }
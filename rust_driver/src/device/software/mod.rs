@@ -5,17 +5,29 @@ use std::{
         atomic::AtomicBool,
         Arc,
     },
+    time::Duration,
 };
 
-use flume::{unbounded, Receiver};
+use flume::{unbounded, Receiver, RecvTimeoutError};
 use log::debug;
+use parking_lot::Mutex;
 
-use crate::SchedulerStrategy;
+use crate::{
+    pcap::PcapWriter,
+    types::{DeviceAttr, Pmtu, PAGE_SIZE},
+    SchedulerStrategy, MAX_MSG_SIZE, MAX_SGE_CNT, MR_PGT_LENGTH, MR_TABLE_SIZE,
+};
 
-use self::net_agent::udp_agent::{UDPReceiveAgent, UDPSendAgent};
+use self::net_agent::{
+    impairment::ImpairedSendAgent,
+    loopback_agent::LoopbackReceiveAgent,
+    udp_agent::{UDPReceiveAgent, UDPSendAgent},
+    NetSendAgent,
+};
 
 use super::{
     scheduler::DescriptorScheduler, DeviceAdaptor, DeviceError, ToCardCtrlRbDesc, ToCardRb,
+    QP_MAX_CNT,
     ToCardWorkRbDesc, ToHostCtrlRbDesc, ToHostRb, ToHostWorkRbDesc,
 };
 
@@ -28,17 +40,31 @@ pub(crate) mod tests;
 mod types;
 
 pub(crate) use logic::BlueRDMALogic;
+pub use net_agent::{
+    impairment::{NetImpairment, NetImpairmentBuilder},
+    loopback_agent::LoopbackFabric,
+};
 
 /// An software device implementation of the device.
 #[allow(dead_code)]
 #[derive(Debug)]
 pub(crate) struct SoftwareDevice<Strat: SchedulerStrategy> {
-    recv_agent: UDPReceiveAgent,
+    recv_agent: Mutex<Option<RecvAgent>>,
+    send_agent: Arc<dyn NetSendAgent + Send + Sync>,
     device: Arc<BlueRDMALogic>,
     stop_flag: Arc<AtomicBool>,
     to_card_work_rb: ToCardWorkRb<Strat>,
     to_host_work_rb: ToHostWorkRb,
     to_host_ctrl_rb: ToHostCtrlRb,
+    attr: DeviceAttr,
+}
+
+/// Keeps the receiving side of the device alive.
+#[allow(dead_code)]
+#[derive(Debug)]
+enum RecvAgent {
+    Udp(UDPReceiveAgent),
+    Loopback(LoopbackReceiveAgent),
 }
 
 #[derive(Debug, Clone)]
@@ -52,16 +78,52 @@ struct ToHostCtrlRb(Receiver<ToHostCtrlRbDesc>);
 
 impl<Strat: SchedulerStrategy> SoftwareDevice<Strat> {
     /// Initializing an software device.
-    pub(crate) fn new(addr: Ipv4Addr, port: u16, strategy: Strat) -> Result<Self, Box<dyn Error>> {
-        let send_agent = UDPSendAgent::new(addr, port)?;
+    pub(crate) fn new(
+        addr: Ipv4Addr,
+        port: u16,
+        strategy: Strat,
+        impairment: Option<NetImpairment>,
+        pcap: Option<Arc<PcapWriter>>,
+        attr: DeviceAttr,
+    ) -> Result<Self, Box<dyn Error>> {
+        let send_agent = UDPSendAgent::new(addr, port, pcap.clone())?;
+        let send_agent = impaired(Arc::new(send_agent), impairment, addr, port);
+        Self::new_with_agents(send_agent, strategy, attr, |device| {
+            Ok(RecvAgent::Udp(UDPReceiveAgent::new(device, addr, port, pcap)?))
+        })
+    }
+
+    /// Initializing an software device attached to the `fabric` at `addr`.
+    pub(crate) fn new_loopback(
+        addr: Ipv4Addr,
+        port: u16,
+        fabric: &LoopbackFabric,
+        strategy: Strat,
+        impairment: Option<NetImpairment>,
+        pcap: Option<Arc<PcapWriter>>,
+        attr: DeviceAttr,
+    ) -> Result<Self, Box<dyn Error>> {
+        let send_agent = Arc::new(fabric.send_agent(addr, port, pcap.clone()));
+        let send_agent = impaired(send_agent, impairment, addr, port);
+        Self::new_with_agents(send_agent, strategy, attr, |device| {
+            Ok(RecvAgent::Loopback(fabric.attach(addr, device, pcap)?))
+        })
+    }
+
+    fn new_with_agents(
+        send_agent: Arc<dyn NetSendAgent + Send + Sync>,
+        strategy: Strat,
+        attr: DeviceAttr,
+        recv_agent: impl FnOnce(Arc<BlueRDMALogic>) -> Result<RecvAgent, Box<dyn Error>>,
+    ) -> Result<Self, Box<dyn Error>> {
         let (ctrl_sender, ctrl_receiver) = unbounded();
         let (work_sender, work_receiver) = unbounded();
         let device = Arc::new(BlueRDMALogic::new(
-            Arc::new(send_agent),
+            Arc::<dyn NetSendAgent + Send + Sync>::clone(&send_agent),
             ctrl_sender,
             work_sender,
         ));
-        let recv_agent = UDPReceiveAgent::new(Arc::<BlueRDMALogic>::clone(&device), addr, port)?;
+        let recv_agent = recv_agent(Arc::<BlueRDMALogic>::clone(&device))?;
 
         let this_device = Arc::<BlueRDMALogic>::clone(&device);
 
@@ -72,16 +134,52 @@ impl<Strat: SchedulerStrategy> SoftwareDevice<Strat> {
         ));
         let to_card_work_rb = ToCardWorkRb(scheduler);
         Ok(Self {
-            recv_agent,
+            recv_agent: Mutex::new(Some(recv_agent)),
+            send_agent,
             device,
             to_card_work_rb,
             to_host_work_rb: ToHostWorkRb(work_receiver),
             to_host_ctrl_rb: ToHostCtrlRb(ctrl_receiver),
             stop_flag,
+            attr,
         })
     }
 }
 
+/// The capabilities of a software device.
+///
+/// Its tables are not limited like the ones of the card, so the sizes of the MR table and the MR
+/// page table are taken from the config if they are set.
+pub(crate) fn software_device_attr(
+    mr_table_size: Option<usize>,
+    mr_pgt_length: Option<usize>,
+) -> DeviceAttr {
+    DeviceAttr {
+        max_qp: QP_MAX_CNT,
+        max_mr: mr_table_size.unwrap_or(MR_TABLE_SIZE),
+        max_mr_pgt_entries: mr_pgt_length.unwrap_or(MR_PGT_LENGTH),
+        page_size: PAGE_SIZE,
+        max_sge: MAX_SGE_CNT,
+        max_msg_size: MAX_MSG_SIZE,
+        max_pmtu: Pmtu::Mtu4096,
+        // the descriptors are passed through unbounded channels
+        ringbuf_depth: None,
+    }
+}
+
+/// Wrap the `send_agent` with an `ImpairedSendAgent` if the `impairment` is set.
+fn impaired(
+    send_agent: Arc<dyn NetSendAgent + Send + Sync>,
+    impairment: Option<NetImpairment>,
+    addr: Ipv4Addr,
+    port: u16,
+) -> Arc<dyn NetSendAgent + Send + Sync> {
+    match impairment {
+        Some(config) => Arc::new(ImpairedSendAgent::new(send_agent, config, addr, port)),
+        None => send_agent,
+    }
+}
+
 impl<Strat: SchedulerStrategy> DeviceAdaptor for SoftwareDevice<Strat> {
     fn to_card_ctrl_rb(&self) -> Arc<dyn ToCardRb<ToCardCtrlRbDesc>> {
         Arc::<BlueRDMALogic>::clone(&self.device)
@@ -114,6 +212,18 @@ impl<Strat: SchedulerStrategy> DeviceAdaptor for SoftwareDevice<Strat> {
     fn use_hugepage(&self) -> bool {
         false
     }
+
+    fn device_attr(&self) -> Result<DeviceAttr, DeviceError> {
+        Ok(self.attr)
+    }
+
+    fn close(&self) {
+        self.to_card_work_rb.0.stop();
+        // stop receiving packets, and detach from the fabric if it is a loopback device
+        drop(self.recv_agent.lock().take());
+        // stop the delivery thread of the delayed packets, if any
+        self.send_agent.close();
+    }
 }
 
 impl ToCardRb<ToCardCtrlRbDesc> for BlueRDMALogic {
@@ -130,6 +240,13 @@ impl ToHostRb<ToHostCtrlRbDesc> for ToHostCtrlRb {
             .recv()
             .map_err(|e| DeviceError::Device(e.to_string()))
     }
+
+    fn pop_timeout(&self, timeout: Duration) -> Result<ToHostCtrlRbDesc, DeviceError> {
+        self.0.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => DeviceError::Timeout,
+            RecvTimeoutError::Disconnected => DeviceError::Device(e.to_string()),
+        })
+    }
 }
 
 impl ToHostRb<ToHostWorkRbDesc> for ToHostWorkRb {
@@ -138,6 +255,13 @@ impl ToHostRb<ToHostWorkRbDesc> for ToHostWorkRb {
             .recv()
             .map_err(|e| DeviceError::Device(e.to_string()))
     }
+
+    fn pop_timeout(&self, timeout: Duration) -> Result<ToHostWorkRbDesc, DeviceError> {
+        self.0.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => DeviceError::Timeout,
+            RecvTimeoutError::Disconnected => DeviceError::Device(e.to_string()),
+        })
+    }
 }
 
 impl<Strat: SchedulerStrategy> ToCardRb<Box<ToCardWorkRbDesc>> for ToCardWorkRb<Strat> {
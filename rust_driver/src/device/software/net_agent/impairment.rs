@@ -0,0 +1,511 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use derive_builder::Builder;
+use flume::{unbounded, RecvTimeoutError, Sender};
+use log::{debug, error};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+use crate::device::software::{
+    packet::ICRC_SIZE,
+    packet_processor::PacketWriter,
+    types::{PayloadInfo, RdmaMessage},
+};
+
+use super::{udp_agent::NET_SERVER_BUF_SIZE, NetAgentError, NetSendAgent};
+
+/// Impairments applied to the packets sent by a software device.
+///
+/// The rates are probabilities in `[0, 1]` and are evaluated per packet. All the random decisions
+/// are drawn from an RNG seeded by `seed`, so the same sequence of packets is always impaired in
+/// the same way.
+#[non_exhaustive]
+#[derive(Builder, Debug, Clone, Copy, Default)]
+#[builder(default, build_fn(validate = "Self::validate"))]
+pub struct NetImpairment {
+    /// The probability of dropping a packet
+    pub loss_rate: f64,
+    /// The probability of sending a packet twice
+    pub duplicate_rate: f64,
+    /// The probability of flipping a bit in the ICRC of a packet, so the receiver drops it
+    pub corrupt_rate: f64,
+    /// The number of packets held back to be reordered. A packet is overtaken by at most
+    /// `reorder_window` later packets of the same descriptor, as the held ones are all sent
+    /// once the device finishes the descriptor.
+    pub reorder_window: usize,
+    /// The delay added to every packet
+    pub delay: Duration,
+    /// The seed of the RNG
+    pub seed: u64,
+}
+
+impl NetImpairmentBuilder {
+    fn validate(&self) -> Result<(), String> {
+        let rates = [
+            ("loss_rate", self.loss_rate),
+            ("duplicate_rate", self.duplicate_rate),
+            ("corrupt_rate", self.corrupt_rate),
+        ];
+        for (name, rate) in rates {
+            if let Some(rate) = rate {
+                if !(0.0_f64..=1.0_f64).contains(&rate) {
+                    return Err(format!("{name} should be in [0, 1], but it's {rate}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A packet held back to be reordered.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct HeldPacket {
+    /// The packets with lower rank are released first. It's the sequence number plus a random
+    /// offset in `[0, reorder_window]`, and the packet is released once the sequence number
+    /// passes it, which bounds how far a packet can be reordered.
+    rank: u64,
+    seq: u64,
+    dest_addr: Ipv4Addr,
+    dest_port: u16,
+    data: Vec<u8>,
+}
+
+/// A packet waiting in the delivery thread until it is due.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct DelayedPacket {
+    due: Instant,
+    seq: u64,
+    dest_addr: Ipv4Addr,
+    dest_port: u16,
+    data: Vec<u8>,
+}
+
+/// The random and reordering state, which is only changed in the sending order.
+#[derive(Debug)]
+struct ImpairState {
+    rng: StdRng,
+    // the sequence number of the next packet
+    seq: u64,
+    held: BinaryHeap<Reverse<HeldPacket>>,
+}
+
+/// A send agent that impairs the packets before passing them to the `inner` agent.
+#[derive(Debug)]
+pub(crate) struct ImpairedSendAgent {
+    inner: Arc<dyn NetSendAgent + Send + Sync>,
+    config: NetImpairment,
+    state: Mutex<ImpairState>,
+    sending_id_counter: AtomicU16,
+    src_addr: Ipv4Addr,
+    src_port: u16,
+    // Only exists when packets are delayed. The packets not due yet are dropped once it's closed.
+    delivery: Mutex<Option<Delivery>>,
+}
+
+/// The thread sending the delayed packets once they are due.
+#[derive(Debug)]
+struct Delivery {
+    sender: Sender<DelayedPacket>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl ImpairedSendAgent {
+    pub(crate) fn new(
+        inner: Arc<dyn NetSendAgent + Send + Sync>,
+        config: NetImpairment,
+        src_addr: Ipv4Addr,
+        src_port: u16,
+    ) -> Self {
+        let delivery = (!config.delay.is_zero()).then(|| {
+            let (sender, receiver) = unbounded::<DelayedPacket>();
+            let thread_inner = Arc::clone(&inner);
+            let thread = thread::spawn(move || {
+                let mut pending = BinaryHeap::new();
+                loop {
+                    // wait for a new packet, or until the earliest one is due
+                    let received = match pending.peek() {
+                        Some(Reverse(DelayedPacket { due, .. })) => receiver.recv_deadline(*due),
+                        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match received {
+                        Ok(packet) => pending.push(Reverse(packet)),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    let now = Instant::now();
+                    while pending.peek().is_some_and(|Reverse(packet)| packet.due <= now) {
+                        let Some(Reverse(packet)) = pending.pop() else {
+                            break;
+                        };
+                        let DelayedPacket {
+                            dest_addr,
+                            dest_port,
+                            ref data,
+                            ..
+                        } = packet;
+                        if let Err(e) = thread_inner.send_packet(dest_addr, dest_port, data) {
+                            error!("impairment: failed to send packet: {e}");
+                        }
+                    }
+                }
+            });
+            Delivery { sender, thread }
+        });
+        Self {
+            inner,
+            config,
+            state: Mutex::new(ImpairState {
+                rng: StdRng::seed_from_u64(config.seed),
+                seq: 0,
+                held: BinaryHeap::new(),
+            }),
+            sending_id_counter: AtomicU16::new(0),
+            src_addr,
+            src_port,
+            delivery: Mutex::new(delivery),
+        }
+    }
+
+    fn impair_and_send(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        packet: &[u8],
+    ) -> Result<(), NetAgentError> {
+        // Draw all the random numbers in the sending order to keep the impairment deterministic.
+        let mut state = self.state.lock();
+        let ImpairState {
+            ref mut rng,
+            ref mut seq,
+            ref mut held,
+        } = *state;
+        if rng.gen::<f64>() < self.config.loss_rate {
+            debug!("impairment: drop a packet to {dest_addr}");
+            return Ok(());
+        }
+        let mut data = packet.to_vec();
+        if rng.gen::<f64>() < self.config.corrupt_rate && data.len() >= ICRC_SIZE {
+            #[allow(clippy::arithmetic_side_effects)] // the length is checked above
+            let idx = rng.gen_range(data.len() - ICRC_SIZE..data.len());
+            if let Some(byte) = data.get_mut(idx) {
+                *byte ^= 1_u8 << rng.gen_range(0_u8..8_u8);
+            }
+        }
+        let copies = if rng.gen::<f64>() < self.config.duplicate_rate {
+            2_usize
+        } else {
+            1_usize
+        };
+
+        let window = self.config.reorder_window as u64;
+        for _ in 0..copies {
+            held.push(Reverse(HeldPacket {
+                rank: seq.wrapping_add(rng.gen_range(0..=window)),
+                seq: *seq,
+                dest_addr,
+                dest_port,
+                data: data.clone(),
+            }));
+            *seq = seq.wrapping_add(1);
+            // the packets passed by the sequence number have been overtaken enough
+            while held.peek().is_some_and(|Reverse(next)| next.rank < *seq) {
+                if let Some(Reverse(next)) = held.pop() {
+                    self.release(next.seq, next.dest_addr, next.dest_port, next.data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a packet out of the reordering, after the delay if any
+    fn release(
+        &self,
+        seq: u64,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        data: Vec<u8>,
+    ) -> Result<(), NetAgentError> {
+        if self.config.delay.is_zero() {
+            return self.inner.send_packet(dest_addr, dest_port, &data);
+        }
+        let due = Instant::now()
+            .checked_add(self.config.delay)
+            .unwrap_or_else(Instant::now);
+        self.delivery
+            .lock()
+            .as_ref()
+            .ok_or(NetAgentError::ImpairmentStopped)?
+            .sender
+            .send(DelayedPacket {
+                due,
+                seq,
+                dest_addr,
+                dest_port,
+                data,
+            })
+            .map_err(|_| NetAgentError::ImpairmentStopped)
+    }
+}
+
+impl Drop for ImpairedSendAgent {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("impairment: failed to send packet: {e}");
+        }
+        self.close();
+    }
+}
+
+impl NetSendAgent for ImpairedSendAgent {
+    fn send(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        let mut buf = [0u8; NET_SERVER_BUF_SIZE];
+        let ip_id = self.sending_id_counter.fetch_add(1, Ordering::Relaxed);
+
+        let total_length = PacketWriter::new(&mut buf)
+            .src_addr(self.src_addr)
+            .src_port(self.src_port)
+            .dest_addr(dest_addr)
+            .dest_port(dest_port)
+            .ip_id(ip_id)
+            .message(message)
+            .write()?;
+        #[allow(clippy::indexing_slicing)]
+        // We are sure that the total_length is less than the buffer size.
+        self.impair_and_send(dest_addr, dest_port, &buf[0..total_length])
+    }
+
+    fn send_raw(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        payload: &PayloadInfo,
+    ) -> Result<(), NetAgentError> {
+        let buf = payload
+            .direct_data_ptr(true)
+            .ok_or(NetAgentError::InvalidRdmaMessage(
+                "PayloadInfo should have at least one item".to_owned(),
+            ))?;
+        self.impair_and_send(dest_addr, dest_port, buf)
+    }
+
+    fn send_packet(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        packet: &[u8],
+    ) -> Result<(), NetAgentError> {
+        self.impair_and_send(dest_addr, dest_port, packet)
+    }
+
+    fn flush(&self) -> Result<(), NetAgentError> {
+        // the held packets are not overtaken anymore
+        let mut state = self.state.lock();
+        while let Some(Reverse(packet)) = state.held.pop() {
+            self.release(packet.seq, packet.dest_addr, packet.dest_port, packet.data)?;
+        }
+        Ok(())
+    }
+
+    fn close(&self) {
+        if let Some(Delivery { sender, thread }) = self.delivery.lock().take() {
+            // the thread exits once the sender is dropped
+            drop(sender);
+            if thread.join().is_err() {
+                error!("impairment: delivery thread panicked");
+            }
+        }
+        self.inner.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use flume::{unbounded, Sender};
+    use parking_lot::Mutex;
+
+    use crate::device::software::{
+        net_agent::{NetAgentError, NetSendAgent},
+        types::{PayloadInfo, RdmaMessage},
+    };
+
+    use super::{ImpairedSendAgent, NetImpairment, NetImpairmentBuilder};
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        packets: Mutex<Vec<Vec<u8>>>,
+        // notified of every packet reaching the wire
+        notify: Option<Sender<Vec<u8>>>,
+    }
+
+    impl NetSendAgent for Recorder {
+        fn send(&self, _: Ipv4Addr, _: u16, _: &RdmaMessage) -> Result<(), NetAgentError> {
+            unreachable!()
+        }
+
+        fn send_raw(&self, _: Ipv4Addr, _: u16, _: &PayloadInfo) -> Result<(), NetAgentError> {
+            unreachable!()
+        }
+
+        fn send_packet(&self, _: Ipv4Addr, _: u16, packet: &[u8]) -> Result<(), NetAgentError> {
+            self.packets.lock().push(packet.to_vec());
+            if let Some(ref notify) = self.notify {
+                notify.send(packet.to_vec()).unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    /// Send packets `[i; 16]` for i in `0..cnt`, and return what reaches the wire.
+    fn run(config: NetImpairment, cnt: u8) -> Vec<Vec<u8>> {
+        let recorder = Arc::new(Recorder::default());
+        let agent = ImpairedSendAgent::new(
+            Arc::<Recorder>::clone(&recorder),
+            config,
+            Ipv4Addr::LOCALHOST,
+            4791,
+        );
+        for i in 0..cnt {
+            agent.send_packet(Ipv4Addr::LOCALHOST, 4791, &[i; 16]).unwrap();
+        }
+        agent.flush().unwrap();
+        let packets = recorder.packets.lock().clone();
+        packets
+    }
+
+    #[test]
+    fn test_impairment_loss_and_duplication() {
+        let config = NetImpairmentBuilder::default().loss_rate(1.0).build().unwrap();
+        assert!(run(config, 8).is_empty());
+
+        let config = NetImpairmentBuilder::default().duplicate_rate(1.0).build().unwrap();
+        let packets = run(config, 4);
+        let expected: Vec<Vec<u8>> = (0..4).flat_map(|i| [[i; 16].to_vec(), [i; 16].to_vec()]).collect();
+        assert_eq!(packets, expected);
+
+        // the same seed drops the same packets
+        let config = NetImpairmentBuilder::default()
+            .loss_rate(0.5)
+            .seed(42)
+            .build()
+            .unwrap();
+        let packets = run(config, 64);
+        assert!(!packets.is_empty() && packets.len() < 64);
+        assert_eq!(packets, run(config, 64));
+    }
+
+    #[test]
+    fn test_impairment_rates() {
+        assert!(NetImpairmentBuilder::default().loss_rate(1.5).build().is_err());
+        assert!(NetImpairmentBuilder::default().duplicate_rate(-0.1).build().is_err());
+        assert!(NetImpairmentBuilder::default().corrupt_rate(f64::NAN).build().is_err());
+        assert!(NetImpairmentBuilder::default()
+            .loss_rate(1.0)
+            .duplicate_rate(0.0)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_impairment_corruption() {
+        let config = NetImpairmentBuilder::default().corrupt_rate(1.0).build().unwrap();
+        for (i, packet) in run(config, 8).into_iter().enumerate() {
+            let diff: Vec<usize> = packet
+                .iter()
+                .enumerate()
+                .filter(|(_, byte)| **byte as usize != i)
+                .map(|(idx, _)| idx)
+                .collect();
+            // only one bit of the ICRC is flipped
+            assert_eq!(diff.len(), 1);
+            assert!(diff[0] >= 12);
+            assert_eq!((packet[diff[0]] ^ i as u8).count_ones(), 1);
+        }
+    }
+
+    #[test]
+    fn test_impairment_reorder_and_delay() {
+        let config = NetImpairmentBuilder::default()
+            .reorder_window(4)
+            .seed(7)
+            .build()
+            .unwrap();
+        let packets = run(config, 32);
+        let sent: Vec<Vec<u8>> = (0..32).map(|i| [i; 16].to_vec()).collect();
+        assert_ne!(packets, sent);
+        let mut sorted = packets.clone();
+        sorted.sort();
+        assert_eq!(sorted, sent);
+        for (pos, packet) in packets.iter().enumerate() {
+            // a packet is overtaken by at most `reorder_window` packets
+            assert!(pos <= packet[0] as usize + 4);
+        }
+        assert_eq!(packets, run(config, 32));
+
+        let delay = Duration::from_millis(100);
+        let config = NetImpairmentBuilder::default().delay(delay).build().unwrap();
+        let (sender, receiver) = unbounded();
+        let recorder = Arc::new(Recorder {
+            packets: Mutex::default(),
+            notify: Some(sender),
+        });
+        let agent = ImpairedSendAgent::new(
+            Arc::<Recorder>::clone(&recorder),
+            config,
+            Ipv4Addr::LOCALHOST,
+            4791,
+        );
+        for i in 0..4 {
+            let sent = Instant::now();
+            agent.send_packet(Ipv4Addr::LOCALHOST, 4791, &[i; 16]).unwrap();
+            // wait for the delivery, the timeout only guards against a hang
+            let packet = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(sent.elapsed() >= delay);
+            assert_eq!(packet, [i; 16].to_vec());
+        }
+    }
+
+    #[test]
+    fn test_impairment_close() {
+        let config = NetImpairmentBuilder::default()
+            .delay(Duration::from_secs(3600))
+            .build()
+            .unwrap();
+        let recorder = Arc::new(Recorder::default());
+        let agent = ImpairedSendAgent::new(
+            Arc::<Recorder>::clone(&recorder),
+            config,
+            Ipv4Addr::LOCALHOST,
+            4791,
+        );
+        agent.send_packet(Ipv4Addr::LOCALHOST, 4791, &[0; 16]).unwrap();
+        // the delivery thread is joined, and the packet not due yet is dropped
+        agent.close();
+        assert!(agent.delivery.lock().is_none());
+        assert!(recorder.packets.lock().is_empty());
+        assert!(matches!(
+            agent.send_packet(Ipv4Addr::LOCALHOST, 4791, &[1; 16]),
+            Err(NetAgentError::ImpairmentStopped)
+        ));
+    }
+}
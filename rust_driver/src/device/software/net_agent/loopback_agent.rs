@@ -0,0 +1,369 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{atomic::AtomicU16, Arc, Mutex, PoisonError},
+    thread,
+};
+
+use flume::{unbounded, Sender};
+use log::{debug, error, info};
+
+use crate::{
+    device::software::{
+        packet_processor::PacketWriter,
+        types::{PayloadInfo, RdmaMessage},
+    },
+    pcap::PcapWriter,
+};
+
+use super::{
+    recv_packet, udp_agent::NET_SERVER_BUF_SIZE, NetAgentError, NetReceiveLogic, NetSendAgent,
+};
+
+type Endpoints = Arc<Mutex<HashMap<Ipv4Addr, Endpoint>>>;
+
+/// A device attached to the fabric.
+#[derive(Debug, Clone)]
+struct Endpoint {
+    receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+    pcap: Option<Arc<PcapWriter>>,
+}
+
+/// A packet travelling through the fabric.
+#[derive(Debug)]
+struct Packet {
+    dest_addr: Ipv4Addr,
+    data: Vec<u8>,
+}
+
+/// An in-process network that connects software devices within one process.
+///
+/// All the packets sent through the fabric are delivered by a single thread in the order they are
+/// sent, so the behavior of the devices attached to the same fabric is deterministic. Packets sent
+/// to an address without an attached device are dropped, like on a real network.
+#[derive(Clone)]
+pub struct LoopbackFabric {
+    endpoints: Endpoints,
+    delivery: Arc<Delivery>,
+}
+
+/// The delivery thread of a fabric, shared by the fabric and its send agents.
+///
+/// The thread is stopped and joined when the last of them is dropped.
+#[derive(Debug)]
+struct Delivery {
+    sender: Option<Sender<Packet>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        // the thread exits once all the senders are dropped
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            // A device may be dropped by the delivery thread itself, which can not join itself.
+            if thread.thread().id() != thread::current().id() && thread.join().is_err() {
+                error!("loopback fabric: delivery thread panicked");
+            }
+        }
+    }
+}
+
+impl LoopbackFabric {
+    /// Create a new fabric and start its delivery thread.
+    ///
+    /// The thread is stopped and joined once the fabric and all the devices attached to it are
+    /// dropped.
+    #[must_use]
+    pub fn new() -> Self {
+        let endpoints: Endpoints = Arc::default();
+        let (sender, receiver) = unbounded::<Packet>();
+        let thread_endpoints = Arc::clone(&endpoints);
+        let thread = thread::spawn(move || {
+            while let Ok(mut packet) = receiver.recv() {
+                // Do not hold the lock while processing, the receiver may send packets itself.
+                let endpoint = thread_endpoints
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get(&packet.dest_addr)
+                    .cloned();
+                match endpoint {
+                    Some(endpoint) => recv_packet(
+                        &*endpoint.receiver,
+                        &mut packet.data,
+                        endpoint.pcap.as_deref(),
+                    ),
+                    None => debug!("loopback fabric: no device at {}", packet.dest_addr),
+                }
+            }
+        });
+        Self {
+            endpoints,
+            delivery: Arc::new(Delivery {
+                sender: Some(sender),
+                thread: Some(thread),
+            }),
+        }
+    }
+
+    /// Attach a receiver to the fabric at `addr`.
+    pub(crate) fn attach(
+        &self,
+        addr: Ipv4Addr,
+        receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
+        pcap: Option<Arc<PcapWriter>>,
+    ) -> Result<LoopbackReceiveAgent, NetAgentError> {
+        let mut guard = self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if guard.contains_key(&addr) {
+            return Err(NetAgentError::AddrInUse(addr));
+        }
+        let _: Option<_> = guard.insert(addr, Endpoint { receiver, pcap });
+        info!("loopback device attached at {}", addr);
+        Ok(LoopbackReceiveAgent {
+            endpoints: Arc::clone(&self.endpoints),
+            addr,
+        })
+    }
+
+    /// Create an agent that sends packets through the fabric with `src_addr` and `src_port`.
+    pub(crate) fn send_agent(
+        &self,
+        src_addr: Ipv4Addr,
+        src_port: u16,
+        pcap: Option<Arc<PcapWriter>>,
+    ) -> LoopbackSendAgent {
+        LoopbackSendAgent {
+            delivery: Arc::clone(&self.delivery),
+            sending_id_counter: AtomicU16::new(0),
+            src_addr,
+            src_port,
+            pcap,
+        }
+    }
+}
+
+impl Default for LoopbackFabric {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for LoopbackFabric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addrs: Vec<Ipv4Addr> = self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .copied()
+            .collect();
+        f.debug_struct("LoopbackFabric")
+            .field("endpoints", &addrs)
+            .finish()
+    }
+}
+
+/// The receiving side of a device attached to a `LoopbackFabric`.
+///
+/// The device is detached from the fabric when the agent is dropped.
+#[derive(Debug)]
+pub(crate) struct LoopbackReceiveAgent {
+    endpoints: Endpoints,
+    addr: Ipv4Addr,
+}
+
+impl Drop for LoopbackReceiveAgent {
+    fn drop(&mut self) {
+        let _: Option<_> = self
+            .endpoints
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.addr);
+    }
+}
+
+/// A client that sends messages through a `LoopbackFabric`.
+#[derive(Debug)]
+pub(crate) struct LoopbackSendAgent {
+    delivery: Arc<Delivery>,
+    sending_id_counter: AtomicU16,
+    src_addr: Ipv4Addr,
+    src_port: u16,
+    pcap: Option<Arc<PcapWriter>>,
+}
+
+impl NetSendAgent for LoopbackSendAgent {
+    fn send(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        message: &RdmaMessage,
+    ) -> Result<(), NetAgentError> {
+        let mut buf = [0u8; NET_SERVER_BUF_SIZE];
+        let ip_id = self
+            .sending_id_counter
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        let total_length = PacketWriter::new(&mut buf)
+            .src_addr(self.src_addr)
+            .src_port(self.src_port)
+            .dest_addr(dest_addr)
+            .dest_port(dest_port)
+            .ip_id(ip_id)
+            .message(message)
+            .write()?;
+        #[allow(clippy::indexing_slicing)]
+        // We are sure that the total_length is less than the buffer size.
+        self.send_packet(dest_addr, dest_port, &buf[0..total_length])
+    }
+
+    fn send_raw(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        payload: &PayloadInfo,
+    ) -> Result<(), NetAgentError> {
+        let buf = payload
+            .direct_data_ptr(true)
+            .ok_or(NetAgentError::InvalidRdmaMessage(
+                "PayloadInfo should have at least one item".to_owned(),
+            ))?;
+        self.send_packet(dest_addr, dest_port, buf)
+    }
+
+    fn send_packet(
+        &self,
+        dest_addr: Ipv4Addr,
+        _dest_port: u16,
+        packet: &[u8],
+    ) -> Result<(), NetAgentError> {
+        if let Some(ref pcap) = self.pcap {
+            pcap.write_ip(packet);
+        }
+        self.delivery
+            .sender
+            .as_ref()
+            .ok_or(NetAgentError::FabricClosed)?
+            .send(Packet {
+                dest_addr,
+                data: packet.to_vec(),
+            })
+            .map_err(|_| NetAgentError::FabricClosed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        device::{
+            software::{
+                net_agent::{NetAgentError, NetReceiveLogic, NetSendAgent},
+                types::{
+                    Key, Metadata, PKey, PayloadInfo, Qpn, RdmaGeneralMeta, RdmaMessage,
+                    RdmaMessageMetaCommon, RethHeader,
+                },
+            },
+            ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType,
+        },
+        types::Psn,
+    };
+
+    use super::LoopbackFabric;
+
+    #[derive(Debug, Default)]
+    struct PsnRecorder {
+        psns: Mutex<Vec<u32>>,
+    }
+
+    impl NetReceiveLogic<'_> for PsnRecorder {
+        fn recv(&self, msg: &mut RdmaMessage, _: Ipv4Addr) {
+            let psn = msg.meta_data.common_meta().psn.get();
+            self.psns.lock().unwrap().push(psn);
+        }
+    }
+
+    fn write_message(psn: u32, data: &[u8]) -> RdmaMessage {
+        let mut payload = PayloadInfo::new();
+        payload.add(data.as_ptr(), data.len());
+        RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta {
+                common_meta: RdmaMessageMetaCommon {
+                    tran_type: ToHostWorkRbDescTransType::Rc,
+                    opcode: ToHostWorkRbDescOpcode::RdmaWriteOnly,
+                    solicited: false,
+                    pkey: PKey::new(0),
+                    dqpn: Qpn::new(3),
+                    ack_req: false,
+                    psn: Psn::new(psn),
+                },
+                reth: RethHeader {
+                    va: 0x1000,
+                    rkey: Key::new(0x1234),
+                    len: data.len() as u32,
+                },
+                imm: None,
+                secondary_reth: None,
+            }),
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_loopback_fabric_delivery_order() {
+        let fabric = LoopbackFabric::new();
+        let dest = Ipv4Addr::new(10, 0, 0, 3);
+        let recorder = Arc::new(PsnRecorder::default());
+        let _agent = fabric
+            .attach(dest, Arc::<PsnRecorder>::clone(&recorder), None)
+            .unwrap();
+        assert!(matches!(
+            fabric.attach(dest, Arc::new(PsnRecorder::default()), None),
+            Err(NetAgentError::AddrInUse(addr)) if addr == dest
+        ));
+
+        let sender1 = fabric.send_agent(Ipv4Addr::new(10, 0, 0, 1), 4791, None);
+        let sender2 = fabric.send_agent(Ipv4Addr::new(10, 0, 0, 2), 4791, None);
+        let data = [0xa5u8; 64];
+        for psn in 0..16 {
+            let sender = if psn % 2 == 0 { &sender1 } else { &sender2 };
+            sender.send(dest, 4791, &write_message(psn, &data)).unwrap();
+        }
+        // packets to an address without a device are dropped
+        sender1
+            .send(Ipv4Addr::new(10, 0, 0, 4), 4791, &write_message(16, &data))
+            .unwrap();
+        sender1.send(dest, 4791, &write_message(17, &data)).unwrap();
+
+        for _ in 0..100 {
+            if recorder.psns.lock().unwrap().len() == 17 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let expected: Vec<u32> = (0..16).chain(std::iter::once(17)).collect();
+        assert_eq!(*recorder.psns.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_loopback_fabric_join_on_drop() {
+        let fabric = LoopbackFabric::new();
+        let endpoints = Arc::clone(&fabric.endpoints);
+        let sender = fabric.send_agent(Ipv4Addr::new(10, 0, 0, 1), 4791, None);
+        drop(fabric);
+        // the send agent keeps the delivery thread running
+        assert_eq!(Arc::strong_count(&endpoints), 2);
+        drop(sender);
+        // the thread has been joined, so its endpoints are dropped
+        assert_eq!(Arc::strong_count(&endpoints), 1);
+    }
+}
@@ -1,18 +1,24 @@
-use std::{fmt::Debug, net::Ipv4Addr};
+use std::{fmt::Debug, mem::size_of, net::Ipv4Addr};
 
+use log::error;
 use thiserror::Error;
 
+use crate::pcap::PcapWriter;
+
 use super::{
-    packet::PacketError,
-    packet_processor::PacketProcessorError,
+    packet::{CommonPacketHeader, IpUdpHeaders, PacketError, ICRC_SIZE},
+    packet_processor::{is_icrc_valid, PacketProcessor, PacketProcessorError},
     types::{PayloadInfo, RdmaMessage},
 };
 use std::io;
 
+pub(crate) mod impairment;
+pub(crate) mod loopback_agent;
 pub(crate) mod udp_agent;
 
 pub(crate) trait NetReceiveLogic<'a>: Send + Sync + Debug {
-    fn recv(&self, message: &mut RdmaMessage);
+    /// Handle a message from the `src_addr`
+    fn recv(&self, message: &mut RdmaMessage, src_addr: Ipv4Addr);
 }
 
 pub(crate) trait NetSendAgent: Debug {
@@ -29,6 +35,22 @@ pub(crate) trait NetSendAgent: Debug {
         dest_port: u16,
         payload: &PayloadInfo,
     ) -> Result<(), NetAgentError>;
+
+    /// Send an IP packet whose headers are already filled.
+    fn send_packet(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        packet: &[u8],
+    ) -> Result<(), NetAgentError>;
+
+    /// Send the packets held back by the agent, which is called once a descriptor is sent.
+    fn flush(&self) -> Result<(), NetAgentError> {
+        Ok(())
+    }
+
+    /// Stop the threads of the agent and wait for them to exit. The packets not sent yet are dropped.
+    fn close(&self) {}
 }
 
 #[derive(Error, Debug)]
@@ -46,4 +68,53 @@ pub(crate) enum NetAgentError {
     WrongBytesSending(usize, usize),
     #[error("Invalid RDMA message :{0}")]
     InvalidRdmaMessage(String),
+    #[error("address {0} is in use")]
+    AddrInUse(Ipv4Addr),
+    #[error("loopback fabric is closed")]
+    FabricClosed,
+    #[error("impairment delivery thread stopped")]
+    ImpairmentStopped,
+}
+
+/// Check a packet received from the network, and pass its message to the `receiver`.
+///
+/// The `received_data` is an IP packet, whose UDP payload ends with the ICRC.
+/// It's recorded to the `pcap` before checking, if any.
+pub(crate) fn recv_packet(
+    receiver: &dyn for<'a> NetReceiveLogic<'a>,
+    received_data: &mut [u8],
+    pcap: Option<&PcapWriter>,
+) {
+    if let Some(pcap) = pcap {
+        pcap.write_ip(received_data);
+    }
+    let length = received_data.len();
+    #[allow(clippy::arithmetic_side_effects)]
+    if length < size_of::<CommonPacketHeader>() + 4 {
+        error!("Packet too short");
+        return;
+    }
+
+    match is_icrc_valid(received_data) {
+        Ok(is_valid) => {
+            if !is_valid {
+                error!("ICRC check failed {:?}", received_data);
+                return;
+            }
+        }
+        Err(e) => {
+            error!("ICRC check failed {:?}", e);
+            return;
+        }
+    }
+    let src_addr = IpUdpHeaders::from_bytes(received_data).ip_header.get_source();
+    // skip the ip header and udp header and the icrc
+    let offset = size_of::<IpUdpHeaders>();
+
+    #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
+    // if we pass the CRC check, it should be ok
+    let received_data = &received_data[offset..length - ICRC_SIZE];
+    if let Ok(mut message) = PacketProcessor::to_rdma_message(received_data) {
+        receiver.recv(&mut message, src_addr);
+    }
 }
@@ -1,5 +1,5 @@
 use std::{
-    mem::{size_of, MaybeUninit},
+    mem::MaybeUninit,
     net::{Ipv4Addr, SocketAddrV4},
     os::fd::AsRawFd,
     sync::{
@@ -7,21 +7,27 @@ use std::{
         Arc,
     },
     thread,
+    time::Duration,
 };
 
 use log::{error, info};
 use socket2::{Domain, Protocol, Socket, Type};
 
-use crate::device::software::{
-    packet::{CommonPacketHeader, IpUdpHeaders, ICRC_SIZE},
-    packet_processor::{is_icrc_valid, PacketProcessor, PacketWriter},
-    types::{PayloadInfo, RdmaMessage},
+use crate::{
+    device::software::{
+        packet_processor::PacketWriter,
+        types::{PayloadInfo, RdmaMessage},
+    },
+    pcap::PcapWriter,
 };
 
-use super::{NetAgentError, NetReceiveLogic, NetSendAgent};
+use super::{recv_packet, NetAgentError, NetReceiveLogic, NetSendAgent};
 
 pub(crate) const NET_SERVER_BUF_SIZE: usize = 8192;
 
+/// How long the receiving thread blocks on the socket before checking whether it should stop.
+const RECV_TIMEOUT: Duration = Duration::from_millis(100);
+
 /// A single thread udp server that listens to the corresponding port and calls the `recv` method of the receiver when a message is received.
 #[derive(Debug)]
 pub(crate) struct UDPReceiveAgent {
@@ -36,11 +42,16 @@ pub(crate) struct UDPSendAgent {
     sending_id_counter: AtomicU16,
     src_addr: Ipv4Addr,
     src_port: u16,
+    pcap: Option<Arc<PcapWriter>>,
 }
 
 impl UDPSendAgent {
     #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
-    pub(crate) fn new(src_addr: Ipv4Addr, src_port: u16) -> Result<Self, NetAgentError> {
+    pub(crate) fn new(
+        src_addr: Ipv4Addr,
+        src_port: u16,
+        pcap: Option<Arc<PcapWriter>>,
+    ) -> Result<Self, NetAgentError> {
         let sender = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP))?;
         let fd = sender.as_raw_fd();
         unsafe {
@@ -72,6 +83,7 @@ impl UDPSendAgent {
             sending_id_counter: sending_id,
             src_addr,
             src_port,
+            pcap,
         })
     }
 }
@@ -81,6 +93,7 @@ impl UDPReceiveAgent {
         receiver: Arc<dyn for<'a> NetReceiveLogic<'a>>,
         addr: Ipv4Addr,
         port: u16,
+        pcap: Option<Arc<PcapWriter>>,
     ) -> Result<Self, NetAgentError> {
         let stop_flag = Arc::new(AtomicBool::new(false));
         let thread_stop_flag = Arc::clone(&stop_flag);
@@ -88,42 +101,17 @@ impl UDPReceiveAgent {
         let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::UDP))?;
         let addr = SocketAddrV4::new(addr, port);
         socket.bind(&addr.into())?;
+        socket.set_read_timeout(Some(RECV_TIMEOUT))?;
         info!("UDP server started at {}:{}", addr.ip(), addr.port());
         let listen_thread = Some(thread::spawn(move || {
             let mut buf = [MaybeUninit::<u8>::uninit(); NET_SERVER_BUF_SIZE];
             while !thread_stop_flag.load(Ordering::Relaxed) {
                 if let Ok((length, _src)) = socket.recv_from(&mut buf) {
-                    #[allow(clippy::arithmetic_side_effects)]
-                    if length < size_of::<CommonPacketHeader>() + 4 {
-                        error!("Packet too short");
-                        continue;
-                    }
                     // SAFETY: `recv_from` ensures that the buffer is filled with `length` bytes.
                     let received_data = unsafe {
                         std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), length)
                     };
-
-                    match is_icrc_valid(received_data) {
-                        Ok(is_valid) =>{
-                            if !is_valid {
-                                error!("ICRC check failed {:?}", received_data);
-                                continue;
-                            }
-                        }
-                        Err(e) => {
-                            error!("ICRC check failed {:?}", e);
-                            continue;
-                        }
-                    }
-                    // skip the ip header and udp header and the icrc
-                    let offset = size_of::<IpUdpHeaders>();
-
-                    #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
-                    // if we pass the CRC check, it should be ok
-                    let received_data = &received_data[offset..length - ICRC_SIZE];
-                    if let Ok(mut message) = PacketProcessor::to_rdma_message(received_data) {
-                        receiver.recv(&mut message);
-                    }
+                    recv_packet(&*receiver, received_data, pcap.as_deref());
                 }
             }
         }));
@@ -137,11 +125,11 @@ impl UDPReceiveAgent {
 impl Drop for UDPReceiveAgent {
     fn drop(&mut self) {
         self.stop_flag.store(true, Ordering::Relaxed);
-        // if let Some(thread) =  self.listen_thread.take(){
-        //     if let Err(e) = thread.join(){
-        //         panic!("{}", format!("UDPReceiveAgent thread join failed: {e:?}"));
-        //     }
-        // }
+        if let Some(thread) = self.listen_thread.take() {
+            if let Err(e) = thread.join() {
+                error!("UDPReceiveAgent thread join failed: {e:?}");
+            }
+        }
     }
 }
 
@@ -169,14 +157,7 @@ impl NetSendAgent for UDPSendAgent {
             .write()?;
         #[allow(clippy::indexing_slicing)]
         // We are sure that the total_length is less than the buffer size.
-        let sended_size = self.sender.send_to(
-            &buf[0..total_length],
-            &SocketAddrV4::new(dest_addr, dest_port).into(),
-        )?;
-        if total_length != sended_size {
-            return Err(NetAgentError::WrongBytesSending(total_length, sended_size));
-        }
-        Ok(())
+        self.send_packet(dest_addr, dest_port, &buf[0..total_length])
     }
 
     fn send_raw(
@@ -190,11 +171,23 @@ impl NetSendAgent for UDPSendAgent {
             .ok_or(NetAgentError::InvalidRdmaMessage(
                 "PayloadInfo should have at least one item".to_owned(),
             ))?;
+        self.send_packet(dest_addr, dest_port, buf)
+    }
+
+    fn send_packet(
+        &self,
+        dest_addr: Ipv4Addr,
+        dest_port: u16,
+        packet: &[u8],
+    ) -> Result<(), NetAgentError> {
         let sended_size = self
             .sender
-            .send_to(buf, &SocketAddrV4::new(dest_addr, dest_port).into())?;
-        if buf.len() != sended_size {
-            return Err(NetAgentError::WrongBytesSending(buf.len(), sended_size));
+            .send_to(packet, &SocketAddrV4::new(dest_addr, dest_port).into())?;
+        if packet.len() != sended_size {
+            return Err(NetAgentError::WrongBytesSending(packet.len(), sended_size));
+        }
+        if let Some(ref pcap) = self.pcap {
+            pcap.write_ip(packet);
         }
         Ok(())
     }
@@ -202,7 +195,10 @@ impl NetSendAgent for UDPSendAgent {
 
 #[cfg(test)]
 mod tests {
-    use std::sync::{Arc, Mutex};
+    use std::{
+        net::Ipv4Addr,
+        sync::{Arc, Mutex},
+    };
 
     use crate::device::software::{net_agent::NetReceiveLogic, types::RdmaMessage};
     #[derive(Debug)]
@@ -213,7 +209,7 @@ mod tests {
     unsafe impl Send for DummyNetReceiveLogic {}
 
     impl NetReceiveLogic<'_> for DummyNetReceiveLogic {
-        fn recv(&self, msg: &mut RdmaMessage) {
+        fn recv(&self, msg: &mut RdmaMessage, _: Ipv4Addr) {
             let new_msg = msg.clone();
             self.packets.lock().unwrap().push(new_msg);
         }
@@ -15,8 +15,8 @@ use crate::{
 };
 
 use super::types::{
-    AethHeader, Metadata, PayloadInfo, RdmaGeneralMeta, RdmaMessage, RdmaMessageMetaCommon,
-    RethHeader,
+    AethHeader, AtomicAckHeader, AtomicEthHeader, DatagramMeta, Metadata, PayloadInfo,
+    RdmaGeneralMeta, RdmaMessage, RdmaMessageMetaCommon, RethHeader,
 };
 
 pub(crate) const ICRC_SIZE: usize = 4;
@@ -36,10 +36,13 @@ const BTH_FLAGS_PAD_CNT_SHIFT: usize = 5;
 const BTH_ACK_REQ_MASK: u8 = 0x80;
 const BTH_PSN_MASK: u32 = 0x00FF_FFFF;
 const MAX_AETH_CODE: u8 = 4;
-const AETH_CODE_MASK: u8 = 0x60;
-const AETH_CODE_SHIFT: usize = 5;
-const AETH_VALUE_MASK: u8 = 0x1F;
+// The syndrome of the AETH is packed as the driver does, see `crate::device::layout::Aeth`
+const AETH_CODE_MASK: u8 = 0x06;
+const AETH_CODE_SHIFT: usize = 1;
+const AETH_VALUE_MASK: u8 = 0xF8;
+const AETH_VALUE_SHIFT: usize = 3;
 const AETH_MSN_MASK: u32 = 0x00FF_FFFF;
+const DETH_SOURCE_QPN_MASK: u32 = 0x00FF_FFFF;
 
 /// Base Transport Header of RDMA over Ethernet
 #[derive(Clone, Copy)]
@@ -240,7 +243,7 @@ impl AETH {
     }
 
     pub(crate) fn get_aeth_value(&self) -> u8 {
-        self.value[0] & AETH_VALUE_MASK
+        (self.value[0] & AETH_VALUE_MASK) >> AETH_VALUE_SHIFT
     }
 
     pub(crate) fn get_msn(&self) -> u32 {
@@ -248,7 +251,7 @@ impl AETH {
     }
 
     pub(crate) fn set_aeth_code_and_value(&mut self, code: u8, value: u8) {
-        self.value[0] = (code % MAX_AETH_CODE) << AETH_CODE_SHIFT | value;
+        self.value[0] = (code % MAX_AETH_CODE) << AETH_CODE_SHIFT | value << AETH_VALUE_SHIFT;
     }
 
     pub(crate) fn set_msn(&mut self, msn: u32) {
@@ -258,6 +261,81 @@ impl AETH {
     }
 }
 
+/// Atomic Extended Transport Header
+#[repr(C, packed)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) struct AtomicETH {
+    va: [u8; 8],
+    rkey: [u8; 4],
+    swap_add: [u8; 8],
+    compare: [u8; 8],
+}
+
+impl AtomicETH {
+    pub(crate) fn get_va(&self) -> u64 {
+        u64::from_be_bytes(self.va)
+    }
+
+    pub(crate) fn get_rkey(&self) -> u32 {
+        u32::from_be_bytes(self.rkey)
+    }
+
+    pub(crate) fn get_swap_add(&self) -> u64 {
+        u64::from_be_bytes(self.swap_add)
+    }
+
+    pub(crate) fn get_compare(&self) -> u64 {
+        u64::from_be_bytes(self.compare)
+    }
+
+    pub(crate) fn set_from_atomic_eth_header(&mut self, header: &AtomicEthHeader) {
+        self.va = header.va.to_be_bytes();
+        self.rkey = header.rkey.get().to_be_bytes();
+        self.swap_add = header.swap_add.to_be_bytes();
+        self.compare = header.compare.to_be_bytes();
+    }
+}
+
+/// Atomic ACK Extended Transport Header, which carries the original value of the remote memory
+#[repr(C, packed)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) struct AtomicAckETH {
+    orig: [u8; 8],
+}
+
+impl AtomicAckETH {
+    pub(crate) fn get_orig(&self) -> u64 {
+        u64::from_be_bytes(self.orig)
+    }
+
+    pub(crate) fn set_orig(&mut self, orig: u64) {
+        self.orig = orig.to_be_bytes();
+    }
+}
+
+/// Datagram Extended Transport Header, which is carried by the UD requests
+#[repr(C, packed)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) struct DETH {
+    qkey: [u8; 4],
+    src_qpn: [u8; 4], // The higher 1 byte is reserved.
+}
+
+impl DETH {
+    pub(crate) fn get_qkey(&self) -> u32 {
+        u32::from_be_bytes(self.qkey)
+    }
+
+    pub(crate) fn get_src_qpn(&self) -> u32 {
+        u32::from_be_bytes(self.src_qpn) & DETH_SOURCE_QPN_MASK
+    }
+
+    pub(crate) fn set_from_datagram_meta(&mut self, header: &DatagramMeta) {
+        self.qkey = header.qkey.to_be_bytes();
+        self.src_qpn = (header.src_qpn.get() & DETH_SOURCE_QPN_MASK).to_be_bytes();
+    }
+}
+
 /// The `imm` of RDMA protocol
 pub(crate) struct Immediate([u8; 4]);
 
@@ -326,7 +404,86 @@ impl RdmaPacketHeader for RdmaHeaderReqBthReth {
                 self.reth.set_from_reth_header(&header.reth);
                 Ok(size_of::<Self>())
             }
-            Metadata::Acknowledge(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
+        }
+    }
+}
+
+/// A composite packet header layout that contains only the BTH, used by the send packets
+#[repr(C, packed)]
+pub(crate) struct RdmaHeaderReqBth {
+    pub(crate) bth: BTH,
+}
+
+impl RdmaPacketHeader for RdmaHeaderReqBth {
+    fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        let payload_length = self
+            .bth
+            .get_packet_real_length(buf_size.wrapping_sub(size_of::<Self>()));
+        Ok(RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta::new_from_send_packet(
+                &self.bth,
+                None,
+                payload_length,
+            )?),
+            payload: PayloadInfo::new_with_data(self.get_data_ptr(), payload_length),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::General(header) => {
+                self.bth
+                    .set_from_common_meta(&header.common_meta, message.payload.get_pad_cnt());
+                Ok(size_of::<Self>())
+            }
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
+        }
+    }
+}
+
+/// A composite packet header layout that contains the BTH and the Immediate, used by the send
+/// packets with immediate data
+#[repr(C, packed)]
+pub(crate) struct RdmaHeaderReqBthImm {
+    pub(crate) bth: BTH,
+    pub(crate) imm: Immediate,
+}
+
+impl RdmaPacketHeader for RdmaHeaderReqBthImm {
+    fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        let payload_length = self
+            .bth
+            .get_packet_real_length(buf_size.wrapping_sub(size_of::<Self>()));
+        Ok(RdmaMessage {
+            meta_data: Metadata::General(RdmaGeneralMeta::new_from_send_packet(
+                &self.bth,
+                Some(&self.imm),
+                payload_length,
+            )?),
+            payload: PayloadInfo::new_with_data(self.get_data_ptr(), payload_length),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::General(header) => {
+                self.bth
+                    .set_from_common_meta(&header.common_meta, message.payload.get_pad_cnt());
+                self.imm
+                    .set(header.imm.ok_or(PacketError::InvalidMetadataType)?);
+                Ok(size_of::<Self>())
+            }
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
         }
     }
 }
@@ -368,7 +525,10 @@ impl RdmaPacketHeader for RdmaHeaderReqBthDoubleReth {
                 self.secondary_reth.set_from_reth_header(sec_reth);
                 Ok(size_of::<Self>())
             }
-            Metadata::Acknowledge(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
         }
     }
 }
@@ -407,7 +567,10 @@ impl RdmaPacketHeader for RdmaHeaderReqBthRethImm {
                     .set(header.imm.ok_or(PacketError::InvalidMetadataType)?);
                 Ok(size_of::<Self>())
             }
-            Metadata::Acknowledge(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
         }
     }
 }
@@ -436,15 +599,130 @@ impl RdmaPacketHeader for RdmaHeaderRespBthAeth {
                 self.bth
                     .set_from_common_meta(&header.common_meta, message.payload.get_pad_cnt());
                 self.aeth
-                    .set_aeth_code_and_value(header.aeth_code.clone() as u8, header.aeth_value);
+                    .set_aeth_code_and_value(header.aeth_code as u8, header.aeth_value);
                 self.aeth.set_msn(header.msn);
                 Ok(size_of::<Self>())
             }
-            Metadata::General(_) => Err(PacketError::InvalidMetadataType),
+            Metadata::General(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
+        }
+    }
+}
+
+/// A composite packet header layout that contains the BTH and the `AtomicETH`.
+#[repr(C, packed)]
+pub(crate) struct RdmaHeaderReqBthAtomicEth {
+    pub(crate) bth: BTH,
+    pub(crate) atomic_eth: AtomicETH,
+}
+
+impl RdmaPacketHeader for RdmaHeaderReqBthAtomicEth {
+    fn to_rdma_message(&self, _buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        Ok(RdmaMessage {
+            meta_data: Metadata::Atomic(AtomicEthHeader::new_from_packet(
+                &self.bth,
+                &self.atomic_eth,
+            )?),
+            payload: PayloadInfo::new(),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::Atomic(header) => {
+                self.bth.set_from_common_meta(&header.common_meta, 0);
+                self.atomic_eth.set_from_atomic_eth_header(header);
+                Ok(size_of::<Self>())
+            }
+            Metadata::General(_)
+            | Metadata::Acknowledge(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
+        }
+    }
+}
+
+/// A composite packet header layout that contains the BTH and the DETH.
+#[repr(C, packed)]
+pub(crate) struct RdmaHeaderReqBthDeth {
+    pub(crate) bth: BTH,
+    pub(crate) deth: DETH,
+}
+
+impl RdmaPacketHeader for RdmaHeaderReqBthDeth {
+    fn to_rdma_message(&self, buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        let payload_length = self
+            .bth
+            .get_packet_real_length(buf_size.wrapping_sub(size_of::<Self>()));
+        Ok(RdmaMessage {
+            meta_data: Metadata::Datagram(DatagramMeta::new_from_packet(&self.bth, &self.deth)?),
+            payload: PayloadInfo::new_with_data(self.get_data_ptr(), payload_length),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::Datagram(header) => {
+                self.bth
+                    .set_from_common_meta(&header.common_meta, message.payload.get_pad_cnt());
+                self.deth.set_from_datagram_meta(header);
+                Ok(size_of::<Self>())
+            }
+            Metadata::General(_)
+            | Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_) => Err(PacketError::InvalidMetadataType),
         }
     }
 }
 
+/// A composite packet header layout that contains the BTH, the AETH and the `AtomicAckETH`.
+#[repr(C, packed)]
+pub(crate) struct RdmaHeaderRespBthAethAtomicAckEth {
+    pub(crate) bth: BTH,
+    pub(crate) aeth: AETH,
+    pub(crate) atomic_ack_eth: AtomicAckETH,
+}
+
+impl RdmaPacketHeader for RdmaHeaderRespBthAethAtomicAckEth {
+    fn to_rdma_message(&self, _buf_size: usize) -> Result<RdmaMessage, PacketError> {
+        Ok(RdmaMessage {
+            meta_data: Metadata::AtomicAcknowledge(AtomicAckHeader {
+                aeth: AethHeader::new_from_packet(&self.bth, &self.aeth)?,
+                orig: self.atomic_ack_eth.get_orig(),
+            }),
+            payload: PayloadInfo::new(),
+        })
+    }
+
+    fn set_from_rdma_message(&mut self, message: &RdmaMessage) -> Result<usize, PacketError> {
+        match &message.meta_data {
+            Metadata::AtomicAcknowledge(header) => {
+                self.bth.set_from_common_meta(&header.aeth.common_meta, 0);
+                self.aeth.set_aeth_code_and_value(
+                    header.aeth.aeth_code as u8,
+                    header.aeth.aeth_value,
+                );
+                self.aeth.set_msn(header.aeth.msn);
+                self.atomic_ack_eth.set_orig(header.orig);
+                Ok(size_of::<Self>())
+            }
+            Metadata::General(_)
+            | Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::Datagram(_) => Err(PacketError::InvalidMetadataType),
+        }
+    }
+}
+
+pub(crate) type RdmaSendFirstHeader = RdmaHeaderReqBth;
+pub(crate) type RdmaSendMiddleHeader = RdmaHeaderReqBth;
+pub(crate) type RdmaSendLastHeader = RdmaHeaderReqBth;
+pub(crate) type RdmaSendLastWithImmediateHeader = RdmaHeaderReqBthImm;
+pub(crate) type RdmaSendOnlyHeader = RdmaHeaderReqBth;
+pub(crate) type RdmaSendOnlyWithImmediateHeader = RdmaHeaderReqBthImm;
 pub(crate) type RdmaWriteFirstHeader = RdmaHeaderReqBthReth;
 pub(crate) type RdmaWriteMiddleHeader = RdmaHeaderReqBthReth;
 pub(crate) type RdmaWriteLastHeader = RdmaHeaderReqBthReth;
@@ -457,6 +735,10 @@ pub(crate) type RdmaReadResponseMiddleHeader = RdmaHeaderReqBthReth;
 pub(crate) type RdmaReadResponseLastHeader = RdmaHeaderReqBthReth;
 pub(crate) type RdmaReadResponseOnlyHeader = RdmaHeaderReqBthReth;
 pub(crate) type RdmaAcknowledgeHeader = RdmaHeaderRespBthAeth;
+pub(crate) type RdmaAtomicAcknowledgeHeader = RdmaHeaderRespBthAethAtomicAckEth;
+pub(crate) type RdmaCompareSwapHeader = RdmaHeaderReqBthAtomicEth;
+pub(crate) type RdmaFetchAddHeader = RdmaHeaderReqBthAtomicEth;
+pub(crate) type RdmaUdSendOnlyHeader = RdmaHeaderReqBthDeth;
 
 /// The IPv4 header
 #[derive(Clone, Copy)]
@@ -495,6 +777,9 @@ impl Ipv4Header {
     pub(crate) fn set_checksum(&mut self, checksum: u16) {
         self.checksum = checksum.to_be_bytes();
     }
+    pub(crate) fn get_source(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.source)
+    }
     pub(crate) fn set_source(&mut self, source: Ipv4Addr) {
         self.source = source.octets();
     }
@@ -2,26 +2,66 @@ use std::{mem::size_of, net::Ipv4Addr};
 
 use thiserror::Error;
 
-use crate::device::ToHostWorkRbDescOpcode;
+use crate::device::{ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType};
 
 use super::{
     packet::{
         CommonPacketHeader, IpUdpHeaders, Ipv4Header, PacketError, RdmaAcknowledgeHeader,
-        RdmaPacketHeader, RdmaReadRequestHeader, RdmaReadResponseFirstHeader,
+        RdmaAtomicAcknowledgeHeader, RdmaCompareSwapHeader, RdmaFetchAddHeader, RdmaPacketHeader, RdmaReadRequestHeader, RdmaReadResponseFirstHeader,
         RdmaReadResponseLastHeader, RdmaReadResponseMiddleHeader, RdmaReadResponseOnlyHeader,
+        RdmaSendFirstHeader, RdmaSendLastHeader, RdmaSendLastWithImmediateHeader,
+        RdmaSendMiddleHeader, RdmaSendOnlyHeader, RdmaSendOnlyWithImmediateHeader, RdmaUdSendOnlyHeader,
         RdmaWriteFirstHeader, RdmaWriteLastHeader, RdmaWriteLastWithImmediateHeader,
         RdmaWriteMiddleHeader, RdmaWriteOnlyHeader, RdmaWriteOnlyWithImmediateHeader, BTH,
         ICRC_SIZE,
     },
-    types::RdmaMessage,
+    types::{Metadata, RdmaMessage},
 };
 
 pub(crate) struct PacketProcessor;
 
 impl PacketProcessor {
     pub(crate) fn to_rdma_message(buf: &[u8]) -> Result<RdmaMessage, PacketError> {
-        let opcode = ToHostWorkRbDescOpcode::try_from(BTH::from_bytes(buf).get_opcode());
+        let bth = BTH::from_bytes(buf);
+        let opcode = ToHostWorkRbDescOpcode::try_from(bth.get_opcode());
+        // UD requests carry a DETH instead of a RETH, and only single packet sends are allowed
+        if matches!(
+            ToHostWorkRbDescTransType::try_from(bth.get_transaction_type()),
+            Ok(ToHostWorkRbDescTransType::Ud)
+        ) {
+            return match opcode {
+                Ok(ToHostWorkRbDescOpcode::SendOnly) => {
+                    let header = RdmaUdSendOnlyHeader::from_bytes(buf);
+                    Ok(header.to_rdma_message(buf.len())?)
+                }
+                Ok(_) | Err(_) => Err(PacketError::InvalidOpcode),
+            };
+        }
         match opcode {
+            Ok(ToHostWorkRbDescOpcode::SendFirst) => {
+                let header = RdmaSendFirstHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
+            Ok(ToHostWorkRbDescOpcode::SendMiddle) => {
+                let header = RdmaSendMiddleHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
+            Ok(ToHostWorkRbDescOpcode::SendLast) => {
+                let header = RdmaSendLastHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
+            Ok(ToHostWorkRbDescOpcode::SendLastWithImmediate) => {
+                let header = RdmaSendLastWithImmediateHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
+            Ok(ToHostWorkRbDescOpcode::SendOnly) => {
+                let header = RdmaSendOnlyHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
+            Ok(ToHostWorkRbDescOpcode::SendOnlyWithImmediate) => {
+                let header = RdmaSendOnlyWithImmediateHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
             Ok(ToHostWorkRbDescOpcode::RdmaWriteFirst) => {
                 let header = RdmaWriteFirstHeader::from_bytes(buf);
                 Ok(header.to_rdma_message(buf.len())?)
@@ -70,6 +110,18 @@ impl PacketProcessor {
                 let header = RdmaAcknowledgeHeader::from_bytes(buf);
                 Ok(header.to_rdma_message(buf.len())?)
             }
+            Ok(ToHostWorkRbDescOpcode::AtomicAcknowledge) => {
+                let header = RdmaAtomicAcknowledgeHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
+            Ok(ToHostWorkRbDescOpcode::CompareSwap) => {
+                let header = RdmaCompareSwapHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
+            Ok(ToHostWorkRbDescOpcode::FetchAdd) => {
+                let header = RdmaFetchAddHeader::from_bytes(buf);
+                Ok(header.to_rdma_message(buf.len())?)
+            }
             Err(_) => Err(PacketError::InvalidOpcode),
         }
     }
@@ -78,7 +130,35 @@ impl PacketProcessor {
         buf: &mut [u8],
         message: &RdmaMessage,
     ) -> Result<usize, PacketError> {
+        if matches!(message.meta_data, Metadata::Datagram(_)) {
+            let header = RdmaUdSendOnlyHeader::from_bytes(buf);
+            return header.set_from_rdma_message(message);
+        }
         match message.meta_data.get_opcode() {
+            ToHostWorkRbDescOpcode::SendFirst => {
+                let header = RdmaSendFirstHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
+            ToHostWorkRbDescOpcode::SendMiddle => {
+                let header = RdmaSendMiddleHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
+            ToHostWorkRbDescOpcode::SendLast => {
+                let header = RdmaSendLastHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
+            ToHostWorkRbDescOpcode::SendLastWithImmediate => {
+                let header = RdmaSendLastWithImmediateHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
+            ToHostWorkRbDescOpcode::SendOnly => {
+                let header = RdmaSendOnlyHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
+            ToHostWorkRbDescOpcode::SendOnlyWithImmediate => {
+                let header = RdmaSendOnlyWithImmediateHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
             ToHostWorkRbDescOpcode::RdmaWriteFirst => {
                 let header = RdmaWriteFirstHeader::from_bytes(buf);
                 Ok(header.set_from_rdma_message(message)?)
@@ -127,6 +207,18 @@ impl PacketProcessor {
                 let header = RdmaAcknowledgeHeader::from_bytes(buf);
                 Ok(header.set_from_rdma_message(message)?)
             }
+            ToHostWorkRbDescOpcode::AtomicAcknowledge => {
+                let header = RdmaAtomicAcknowledgeHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
+            ToHostWorkRbDescOpcode::CompareSwap => {
+                let header = RdmaCompareSwapHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
+            ToHostWorkRbDescOpcode::FetchAdd => {
+                let header = RdmaFetchAddHeader::from_bytes(buf);
+                Ok(header.set_from_rdma_message(message)?)
+            }
         }
     }
 }
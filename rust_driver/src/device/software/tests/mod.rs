@@ -6,8 +6,8 @@ use crate::{
     device::{
         ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescQpManagement,
         ToCardCtrlRbDescUpdateMrTable, ToCardWorkRbDesc, ToCardWorkRbDescCommon,
-        ToCardWorkRbDescOpcode, ToCardWorkRbDescRead, ToCardWorkRbDescWrite,
-        ToCardWorkRbDescWriteWithImm,
+        ToCardWorkRbDescAtomic, ToCardWorkRbDescOpcode, ToCardWorkRbDescRead,
+        ToCardWorkRbDescWrite, ToCardWorkRbDescWriteWithImm,
     },
     types::{MemAccessTypeFlag, Pmtu, QpType, WorkReqSendFlag},
 };
@@ -66,6 +66,7 @@ pub(crate) struct ToCardWorkRbDescBuilder {
     is_first: Option<bool>,
     is_last: Option<bool>,
     imm: Option<u32>,
+    atomic: Option<(u64, u64)>,
     sg_list: Option<SGList>,
 }
 
@@ -84,6 +85,7 @@ impl ToCardWorkRbDescBuilder {
             is_first: Some(true),
             is_last: Some(true),
             imm: None,
+            atomic: None,
             sg_list: None,
         }
     }
@@ -147,6 +149,11 @@ impl ToCardWorkRbDescBuilder {
         self
     }
 
+    pub(crate) fn with_atomic(&mut self, compare: u64, swap_add: u64) -> &mut Self {
+        self.atomic = Some((compare, swap_add));
+        self
+    }
+
     pub(crate) fn with_sg_list(&mut self, sg_list: SGList) -> &mut Self {
         self.sg_list = Some(sg_list);
         self
@@ -165,9 +172,11 @@ impl ToCardWorkRbDescBuilder {
             dqp_ip: Ipv4Addr::LOCALHOST,
             mac_addr: MacAddress::default(),
             msn: crate::types::Msn::new(0),
+            qkey: 0,
+            sqpn: crate::types::Qpn::new(self.dqpn.unwrap()),
         };
         let (sge0, sge1, sge2, sge3) = self.sg_list.take().unwrap().into_four_sges();
-        let desc = match self.opcode.clone().unwrap() {
+        let desc = match self.opcode.unwrap() {
             ToCardWorkRbDescOpcode::Write => ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
                 common,
                 is_first: self.is_first.unwrap(),
@@ -206,6 +215,29 @@ impl ToCardWorkRbDescBuilder {
                 sge2,
                 sge3,
             }),
+            ToCardWorkRbDescOpcode::Send => ToCardWorkRbDesc::Send(ToCardWorkRbDescWrite {
+                common,
+                is_first: self.is_first.unwrap(),
+                is_last: self.is_last.unwrap(),
+                sge0,
+                sge1,
+                sge2,
+                sge3,
+            }),
+            ToCardWorkRbDescOpcode::AtomicCmpSwap | ToCardWorkRbDescOpcode::AtomicFetchAdd => {
+                let (compare, swap_add) = self.atomic.unwrap();
+                let desc = ToCardWorkRbDescAtomic {
+                    common,
+                    compare,
+                    swap_add,
+                    sge: sge0,
+                };
+                if matches!(self.opcode, Some(ToCardWorkRbDescOpcode::AtomicCmpSwap)) {
+                    ToCardWorkRbDesc::AtomicCmpSwap(desc)
+                } else {
+                    ToCardWorkRbDesc::AtomicFetchAdd(desc)
+                }
+            }
         };
         Box::new(desc)
     }
@@ -337,6 +369,8 @@ impl ToCardCtrlRbDescBuilder {
                     rq_acc_flags: self.rq_acc_flags.unwrap(),
                     pmtu: self.pmtu.unwrap(),
                     peer_qpn: crate::Qpn::new(1234),
+                    expected_psn: crate::types::Psn::new(0),
+                    qkey: 0,
                 })
             }
         }
@@ -14,7 +14,7 @@ use crate::device::{
         logic::BlueRDMALogic,
         net_agent::udp_agent::{UDPReceiveAgent, UDPSendAgent},
     },
-    DeviceAdaptor, SoftwareDevice, ToCardWorkRbDescOpcode, ToHostWorkRbDesc,
+    software_device_attr, DeviceAdaptor, SoftwareDevice, ToCardWorkRbDescOpcode, ToHostWorkRbDesc,
 };
 use crate::types::{MemAccessTypeFlag, Pmtu, QpType, WorkReqSendFlag};
 
@@ -23,7 +23,7 @@ use super::ToCardCtrlRbDescBuilder;
 #[test]
 #[serial]
 fn test_loopback_software_device_write_and_read() {
-    let send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791).unwrap();
+    let send_agent = UDPSendAgent::new(Ipv4Addr::LOCALHOST, 4791, None).unwrap();
     let (ctrl_sender, _ctrl_receiver) = unbounded();
     let (work_sender, work_receiver) = unbounded();
     let device = Arc::new(BlueRDMALogic::new(
@@ -35,6 +35,7 @@ fn test_loopback_software_device_write_and_read() {
         Arc::<BlueRDMALogic>::clone(&device),
         Ipv4Addr::LOCALHOST,
         4791,
+        None,
     ).unwrap();
     let mr1_rkey = 1234_u32;
     let mr2_rkey = 4321_u32;
@@ -117,7 +118,10 @@ fn test_loopback_software_device_write_and_read() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         let q2 = work_receiver.recv().unwrap();
         match q2 {
@@ -129,7 +133,10 @@ fn test_loopback_software_device_write_and_read() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         // assert!(work_receiver.receiver_count() == 0);
         assert_eq!(
@@ -177,7 +184,10 @@ fn test_loopback_software_device_write_and_read() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         let q2 = work_receiver.recv().unwrap();
         match q2 {
@@ -189,7 +199,10 @@ fn test_loopback_software_device_write_and_read() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         let q3 = work_receiver.recv().unwrap();
         match q3 {
@@ -201,7 +214,10 @@ fn test_loopback_software_device_write_and_read() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         // assert!(work_receiver.receiver_count() == 0);
         assert_eq!(
@@ -220,7 +236,7 @@ fn test_loopback_software_device_write_and_read() {
 #[test]
 #[serial]
 fn test_loopback_software_device_with_scheudler() {
-    let device = SoftwareDevice::new(Ipv4Addr::LOCALHOST, 4791,RoundRobinStrategy::new()).unwrap();
+    let device = SoftwareDevice::new(Ipv4Addr::LOCALHOST, 4791,RoundRobinStrategy::new(),None,None,software_device_attr(None,None)).unwrap();
     let mr1_rkey = 1234_u32;
     let mr2_rkey = 4321_u32;
     let dqpn = 5;
@@ -304,7 +320,10 @@ fn test_loopback_software_device_with_scheudler() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         let q2 = to_host_work_rb.pop().unwrap();
         match q2 {
@@ -316,7 +335,10 @@ fn test_loopback_software_device_with_scheudler() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         // assert!(device.get_to_host_descriptor_queue().is_empty());
         assert_eq!(
@@ -365,7 +387,10 @@ fn test_loopback_software_device_with_scheudler() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         let q2 = to_host_work_rb.pop().unwrap();
         match q2 {
@@ -377,7 +402,10 @@ fn test_loopback_software_device_with_scheudler() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         let q3 = to_host_work_rb.pop().unwrap();
         match q3 {
@@ -389,7 +417,10 @@ fn test_loopback_software_device_with_scheudler() {
             ToHostWorkRbDesc::Read(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
             | ToHostWorkRbDesc::Ack(_)
-            | ToHostWorkRbDesc::Raw(_) => panic!("unexpected descriptor"),
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => panic!("unexpected descriptor"),
         }
         // assert!(device.get_to_host_descriptor_queue().is_empty());
         assert_eq!(
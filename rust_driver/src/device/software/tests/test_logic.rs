@@ -10,7 +10,7 @@ use crate::{
             tests::{SGListBuilder, ToCardWorkRbDescBuilder},
             types::{Metadata, PayloadInfo, RdmaMessage},
         },
-        ToCardWorkRbDescOpcode, ToHostWorkRbDescOpcode,
+        ToCardWorkRbDescOpcode, ToHostWorkRbDesc, ToHostWorkRbDescOpcode, ToHostWorkRbDescTransType,
     },
     types::{Pmtu, QpType},
 };
@@ -39,6 +39,10 @@ impl NetSendAgent for DummpyProxy {
         self.payload.borrow_mut().push_back(payload.clone());
         Ok(())
     }
+
+    fn send_packet(&self, _: Ipv4Addr, _: u16, _: &[u8]) -> Result<(), NetAgentError> {
+        Ok(())
+    }
 }
 
 unsafe impl Send for DummpyProxy {}
@@ -247,7 +251,10 @@ fn test_logic_send() {
             Metadata::General(meta) => {
                 assert_eq!(meta.imm.unwrap(), 0x1234);
             }
-            _ => unreachable!(),
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => unreachable!(),
         }
     }
 
@@ -284,7 +291,10 @@ fn test_logic_send() {
                 assert_eq!(secondary_reth.len, 1024);
                 assert_eq!(secondary_reth.rkey.get(), 4567);
             }
-            _ => unreachable!(),
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => unreachable!(),
         }
     }
 
@@ -332,7 +342,10 @@ fn test_logic_send() {
         assert_eq!(message.payload.get_length(), 4096);
         let meta = match message.meta_data {
             Metadata::General(meta) => meta,
-            _ => unreachable!(),
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => unreachable!(),
         };
         assert_eq!(meta.common_meta.psn.get(), 0,);
         assert_eq!(meta.reth.va, 0);
@@ -396,7 +409,10 @@ fn test_logic_send() {
         assert_eq!(message.payload.get_length(), 1024);
         let meta = match message.meta_data {
             Metadata::General(meta) => meta,
-            _ => unreachable!(),
+            Metadata::Acknowledge(_)
+            | Metadata::Atomic(_)
+            | Metadata::AtomicAcknowledge(_)
+            | Metadata::Datagram(_) => unreachable!(),
         };
         assert_eq!(meta.reth.va, 1024 * 31);
         assert_eq!(meta.reth.len, 1024 * 33);
@@ -456,3 +472,94 @@ fn test_logic_send_raw() {
         assert_eq!(payload.get_sg_list()[3].data, 7000 as *const u8);
     }
 }
+
+#[test]
+fn test_logic_send_atomic() {
+    let agent = Arc::new(DummpyProxy::new());
+    let (ctrl_sender, _ctrl_receiver) = unbounded();
+    let (work_sender, _work_receiver) = unbounded();
+    let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent), ctrl_sender, work_sender);
+    let desc = ToCardWorkRbDescBuilder::default()
+        .with_opcode(ToCardWorkRbDescOpcode::AtomicCmpSwap)
+        .with_total_len(8)
+        .with_raddr(0x2000)
+        .with_rkey(1234)
+        .with_pmtu(Pmtu::Mtu1024)
+        .with_psn(1234)
+        .with_dqpn(12)
+        .with_atomic(0x1111_2222_3333_4444, 0x5555_6666_7777_8888)
+        .with_sg_list(SGListBuilder::new().with_sge(0x1000, 8, 0x1234_u32).build())
+        .build();
+    logic.send(desc).unwrap();
+
+    // the operands are carried in the atomic ETH, without payload
+    let message = agent.message.borrow_mut().pop_front().unwrap();
+    assert_eq!(message.meta_data.get_opcode(), ToHostWorkRbDescOpcode::CompareSwap);
+    let Metadata::Atomic(header) = message.meta_data else {
+        panic!("should be an atomic request");
+    };
+    assert_eq!(header.va, 0x2000);
+    assert_eq!(header.compare, 0x1111_2222_3333_4444);
+    assert_eq!(header.swap_add, 0x5555_6666_7777_8888);
+    assert_eq!(message.payload.get_length(), 0);
+}
+
+#[test]
+fn test_logic_send_datagram() {
+    let agent = Arc::new(DummpyProxy::new());
+    let (ctrl_sender, _ctrl_receiver) = unbounded();
+    let (work_sender, work_receiver) = unbounded();
+    let logic = BlueRDMALogic::new(Arc::<DummpyProxy>::clone(&agent), ctrl_sender, work_sender);
+    let build_desc = |len| {
+        ToCardWorkRbDescBuilder::default()
+            .with_qp_type(QpType::Ud)
+            .with_opcode(ToCardWorkRbDescOpcode::Send)
+            .with_total_len(len)
+            .with_raddr(0)
+            .with_rkey(0)
+            .with_pmtu(Pmtu::Mtu1024)
+            .with_psn(1234)
+            .with_dqpn(12)
+            .with_sg_list(
+                SGListBuilder::new()
+                    .with_sge(1000, len / 2, 4567_u32)
+                    .with_sge(3000, len / 2, 4567_u32)
+                    .build(),
+            )
+            .build()
+    };
+
+    // a datagram is sent in a single packet
+    logic.send(build_desc(1024)).unwrap();
+    assert_eq!(agent.message.borrow().len(), 1);
+    let message = agent.message.borrow_mut().pop_front().unwrap();
+    assert_eq!(message.payload.get_length(), 1024);
+    match message.meta_data {
+        Metadata::Datagram(meta) => {
+            assert!(matches!(meta.common_meta.tran_type, ToHostWorkRbDescTransType::Ud));
+            assert!(matches!(meta.common_meta.opcode, ToHostWorkRbDescOpcode::SendOnly));
+            assert_eq!(meta.common_meta.dqpn.get(), 12);
+            assert_eq!(meta.common_meta.psn.get(), 1234);
+            assert_eq!(meta.qkey, 0);
+            assert_eq!(meta.src_qpn.get(), 12);
+        }
+        Metadata::General(_)
+        | Metadata::Acknowledge(_)
+        | Metadata::Atomic(_)
+        | Metadata::AtomicAcknowledge(_) => unreachable!(),
+    }
+    // the sent datagram is reported to the driver
+    let ToHostWorkRbDesc::Sent(sent) = work_receiver.try_recv().unwrap() else {
+        panic!("unexpected descriptor");
+    };
+    assert!(sent.common.status.is_ok());
+    assert_eq!(sent.common.dqpn.get(), 12);
+
+    // a datagram longer than the pmtu can't be sent
+    assert!(logic.send(build_desc(2048)).is_err());
+    assert!(agent.message.borrow().is_empty());
+    let ToHostWorkRbDesc::Sent(sent) = work_receiver.try_recv().unwrap() else {
+        panic!("unexpected descriptor");
+    };
+    assert!(!sent.common.status.is_ok());
+}
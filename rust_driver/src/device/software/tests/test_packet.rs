@@ -3,8 +3,10 @@ use std::mem::size_of;
 use crate::device::software::packet::Immediate;
 use crate::device::software::packet::AETH;
 use crate::device::software::packet::BTH;
+use crate::device::software::packet::DETH;
 use crate::device::software::packet::RETH;
 use crate::device::software::packet_processor::PacketProcessor;
+use crate::device::software::types::DatagramMeta;
 use crate::device::software::types::Key;
 use crate::device::software::types::Metadata;
 use crate::device::software::types::PKey;
@@ -22,6 +24,7 @@ const BTH_SIZE: usize = size_of::<BTH>();
 const RETH_SIZE: usize = size_of::<RETH>();
 const AETH_SIZE: usize = size_of::<AETH>();
 const IMM_SIZE: usize = size_of::<Immediate>();
+const DETH_SIZE: usize = size_of::<DETH>();
 
 #[test]
 fn test_header_bth_reth() {
@@ -63,7 +66,10 @@ fn test_header_bth_reth() {
             assert_eq!(header.reth.len, 1);
             assert_eq!(message.payload.get_length(), 512);
         }
-        _ => panic!("wrong meta data"),
+        Metadata::Acknowledge(_)
+        | Metadata::Atomic(_)
+        | Metadata::AtomicAcknowledge(_)
+        | Metadata::Datagram(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + RETH_SIZE + 512];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
@@ -113,7 +119,10 @@ fn test_header_bth_reth_imm() {
             assert_eq!(message.payload.get_length(), 512);
             assert_eq!(header.imm.unwrap(), u32::from_le_bytes([1u8; IMM_SIZE]));
         }
-        _ => panic!("wrong meta data"),
+        Metadata::Acknowledge(_)
+        | Metadata::Atomic(_)
+        | Metadata::AtomicAcknowledge(_)
+        | Metadata::Datagram(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + RETH_SIZE + IMM_SIZE + 512];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
@@ -121,6 +130,47 @@ fn test_header_bth_reth_imm() {
     assert!(buf[..size] == new_buf[..size]);
 }
 
+#[test]
+fn test_header_bth_imm() {
+    let mut buf = [0u8; BTH_SIZE + IMM_SIZE + 512];
+    let bth = BTH::from_bytes(&buf);
+    bth.set_opcode_and_type(
+        ToHostWorkRbDescOpcode::SendLastWithImmediate,
+        crate::device::ToHostWorkRbDescTransType::Rc,
+    );
+    bth.set_destination_qpn(1);
+    bth.set_psn(1);
+    bth.set_ack_req(false);
+    bth.set_flags_solicited(true);
+    bth.set_pkey(0x1234);
+    let imm = &mut buf[BTH_SIZE..BTH_SIZE + IMM_SIZE];
+    imm.copy_from_slice(&[1u8; IMM_SIZE]);
+    let message = PacketProcessor::to_rdma_message(&buf).unwrap();
+    let meta = &message.meta_data;
+    match meta {
+        Metadata::General(header) => {
+            assert_eq!(
+                header.common_meta.opcode.clone() as u8,
+                ToHostWorkRbDescOpcode::SendLastWithImmediate as u8
+            );
+            assert_eq!(header.common_meta.psn.get(), 1);
+            // a send packet has no RETH, the payload follows the immediate data
+            assert_eq!(header.reth.va, 0);
+            assert_eq!(header.reth.len, 512);
+            assert_eq!(message.payload.get_length(), 512);
+            assert_eq!(header.imm.unwrap(), u32::from_le_bytes([1u8; IMM_SIZE]));
+        }
+        Metadata::Acknowledge(_)
+        | Metadata::Atomic(_)
+        | Metadata::AtomicAcknowledge(_)
+        | Metadata::Datagram(_) => panic!("wrong meta data"),
+    }
+    let mut new_buf = [0u8; BTH_SIZE + IMM_SIZE + 512];
+    let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
+    assert!(size == BTH_SIZE + IMM_SIZE);
+    assert!(buf[..size] == new_buf[..size]);
+}
+
 #[test]
 fn test_header_bth_reth_reth() {
     let buf = [0u8; BTH_SIZE + RETH_SIZE + RETH_SIZE];
@@ -168,7 +218,10 @@ fn test_header_bth_reth_reth() {
             assert_eq!(secondary_reth.rkey.get(), 0x12345678);
             assert_eq!(secondary_reth.len, 0x12345678);
         }
-        _ => panic!("wrong meta data"),
+        Metadata::Acknowledge(_)
+        | Metadata::Atomic(_)
+        | Metadata::AtomicAcknowledge(_)
+        | Metadata::Datagram(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + RETH_SIZE + RETH_SIZE + 512];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
@@ -209,10 +262,13 @@ fn test_header_bth_aeth() {
             assert!(!header.common_meta.ack_req);
             assert_eq!(header.common_meta.psn.get(), 1);
             assert_eq!(header.msn, 0x123456);
-            assert_eq!(header.aeth_code.clone() as u8, 2);
+            assert_eq!(header.aeth_code as u8, 2);
             assert_eq!(header.aeth_value, 5);
         }
-        _ => panic!("wrong meta data"),
+        Metadata::General(_)
+        | Metadata::Atomic(_)
+        | Metadata::AtomicAcknowledge(_)
+        | Metadata::Datagram(_) => panic!("wrong meta data"),
     }
     let mut new_buf = [0u8; BTH_SIZE + AETH_SIZE];
     let size = PacketProcessor::set_from_rdma_message(&mut new_buf, &message).unwrap();
@@ -220,6 +276,63 @@ fn test_header_bth_aeth() {
     assert!(buf[..size] == new_buf[..size]);
 }
 
+#[test]
+fn test_header_bth_deth() {
+    let data_buf = [1u8; 510];
+    let msg = RdmaMessage {
+        meta_data: Metadata::Datagram(DatagramMeta {
+            common_meta: RdmaMessageMetaCommon {
+                tran_type: ToHostWorkRbDescTransType::Ud,
+                opcode: ToHostWorkRbDescOpcode::SendOnly,
+                solicited: false,
+                pkey: PKey::new(0),
+                dqpn: Qpn::new(3),
+                ack_req: false,
+                psn: Psn::new(0x123456),
+            },
+            qkey: 0x1234_5678,
+            src_qpn: Qpn::new(0xab_cdef),
+        }),
+        payload: PayloadInfo::new_with_data(data_buf.as_ptr(), data_buf.len()),
+    };
+    let mut buf = [0u8; BTH_SIZE + DETH_SIZE + 512];
+    let size = PacketProcessor::set_from_rdma_message(&mut buf, &msg).unwrap();
+    assert!(size == BTH_SIZE + DETH_SIZE);
+    buf[size..size + 510].copy_from_slice(&data_buf);
+
+    let message = PacketProcessor::to_rdma_message(&buf).unwrap();
+    match &message.meta_data {
+        Metadata::Datagram(header) => {
+            assert_eq!(
+                header.common_meta.tran_type as u8,
+                ToHostWorkRbDescTransType::Ud as u8
+            );
+            assert_eq!(
+                header.common_meta.opcode.clone() as u8,
+                ToHostWorkRbDescOpcode::SendOnly as u8
+            );
+            assert_eq!(header.common_meta.dqpn.get(), 3);
+            assert_eq!(header.common_meta.psn.get(), 0x123456);
+            assert_eq!(header.qkey, 0x1234_5678);
+            assert_eq!(header.src_qpn.get(), 0xab_cdef);
+            // the pad count is removed
+            assert_eq!(message.payload.get_length(), 510);
+        }
+        Metadata::General(_)
+        | Metadata::Acknowledge(_)
+        | Metadata::Atomic(_)
+        | Metadata::AtomicAcknowledge(_) => panic!("wrong meta data"),
+    }
+
+    // only the single packet send is allowed for a UD qp
+    let bth = BTH::from_bytes(&buf);
+    bth.set_opcode_and_type(
+        ToHostWorkRbDescOpcode::RdmaWriteOnly,
+        ToHostWorkRbDescTransType::Ud,
+    );
+    assert!(PacketProcessor::to_rdma_message(&buf).is_err());
+}
+
 #[test]
 fn test_payload_copy_to() {
     // test one source
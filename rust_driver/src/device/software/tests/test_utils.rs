@@ -82,3 +82,29 @@ fn test_helper_cut_sgl_all() {
     assert_eq!(sgl.data[3].len, 0);
     assert_eq!(payload.get_length(), 4096);
 }
+
+#[test]
+fn test_helper_cut_sgl_across_levels() {
+    // test has [256,512,256,0], request 384, then the rest
+    let mut sgl = SGListBuilder::new()
+        .with_sge(0x1000, 256, 0_u32)
+        .with_sge(0x2000, 512, 0_u32)
+        .with_sge(0x3000, 256, 0_u32)
+        .build();
+    let payload = sgl.cut(384).unwrap();
+    assert_eq!(payload.get_length(), 384);
+    assert_eq!(payload.get_sg_list().len(), 2);
+    assert_eq!(payload.get_sg_list()[0].data as u64, 0x1000);
+    assert_eq!(payload.get_sg_list()[1].data as u64, 0x2000);
+    assert_eq!(payload.get_sg_list()[1].len, 128);
+    assert_eq!(sgl.cur_level, 1);
+
+    // only the remaining buffers are gathered, and empty levels are skipped
+    let payload = sgl.cut_all_levels();
+    assert_eq!(payload.get_length(), 640);
+    assert_eq!(payload.get_sg_list().len(), 2);
+    assert_eq!(payload.get_sg_list()[0].data as u64, 0x2000 + 128);
+    assert_eq!(payload.get_sg_list()[0].len, 384);
+    assert_eq!(payload.get_sg_list()[1].data as u64, 0x3000);
+    assert_eq!(sgl.cur_level, sgl.len);
+}
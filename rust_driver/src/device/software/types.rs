@@ -8,7 +8,9 @@ use crate::{
 
 use super::{
     logic::BlueRdmaLogicError,
-    packet::{Immediate, PacketError, AETH, BTH, RDMA_PAYLOAD_ALIGNMENT, RETH},
+    packet::{
+        AtomicETH, Immediate, PacketError, AETH, BTH, DETH, RDMA_PAYLOAD_ALIGNMENT, RETH,
+    },
 };
 
 /// Queue-pair number
@@ -101,6 +103,15 @@ pub(crate) enum Metadata {
 
     /// Acknowledge message
     Acknowledge(AethHeader),
+
+    /// Atomic compare and swap, fetch and add request
+    Atomic(AtomicEthHeader),
+
+    /// Atomic acknowledge message
+    AtomicAcknowledge(AtomicAckHeader),
+
+    /// UD send
+    Datagram(DatagramMeta),
 }
 
 impl Metadata {
@@ -108,6 +119,9 @@ impl Metadata {
         match self {
             Metadata::General(header) => header.common_meta.opcode.clone(),
             Metadata::Acknowledge(header) => header.common_meta.opcode.clone(),
+            Metadata::Atomic(header) => header.common_meta.opcode.clone(),
+            Metadata::AtomicAcknowledge(header) => header.aeth.common_meta.opcode.clone(),
+            Metadata::Datagram(header) => header.common_meta.opcode.clone(),
         }
     }
 
@@ -115,6 +129,9 @@ impl Metadata {
         match self {
             Metadata::General(header) => &header.common_meta,
             Metadata::Acknowledge(header) => &header.common_meta,
+            Metadata::Atomic(header) => &header.common_meta,
+            Metadata::AtomicAcknowledge(header) => &header.aeth.common_meta,
+            Metadata::Datagram(header) => &header.common_meta,
         }
     }
 }
@@ -148,7 +165,6 @@ impl PayloadInfo {
         }
     }
 
-    #[cfg(test)]
     pub(crate) fn get_length(&self) -> usize {
         self.total_len
     }
@@ -279,6 +295,27 @@ impl RdmaGeneralMeta {
         })
     }
 
+    /// Build the metadata of a send packet, which carries no RETH.
+    ///
+    /// Only the `len` of the `reth` is set, to the length of the payload. The receiver places the
+    /// payload by the receive buffer bound to the message.
+    #[allow(clippy::cast_possible_truncation)] // the payload is no longer than a pmtu
+    pub(crate) fn new_from_send_packet(
+        bth: &BTH,
+        imm: Option<&Immediate>,
+        payload_length: usize,
+    ) -> Result<Self, PacketError> {
+        Ok(RdmaGeneralMeta {
+            common_meta: RdmaMessageMetaCommon::try_from(bth)?,
+            reth: RethHeader {
+                len: payload_length as u32,
+                ..RethHeader::default()
+            },
+            imm: imm.map(Immediate::get),
+            secondary_reth: None,
+        })
+    }
+
     pub(crate) fn is_read_request(&self) -> bool {
         matches!(
             self.common_meta.opcode,
@@ -286,10 +323,20 @@ impl RdmaGeneralMeta {
         )
     }
 
+    pub(crate) fn is_send(&self) -> bool {
+        self.common_meta.opcode.is_send()
+    }
+
     pub(crate) fn has_payload(&self) -> bool {
         matches!(
             self.common_meta.opcode,
-            ToHostWorkRbDescOpcode::RdmaWriteFirst
+            ToHostWorkRbDescOpcode::SendFirst
+                | ToHostWorkRbDescOpcode::SendMiddle
+                | ToHostWorkRbDescOpcode::SendLast
+                | ToHostWorkRbDescOpcode::SendLastWithImmediate
+                | ToHostWorkRbDescOpcode::SendOnly
+                | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+                | ToHostWorkRbDescOpcode::RdmaWriteFirst
                 | ToHostWorkRbDescOpcode::RdmaWriteMiddle
                 | ToHostWorkRbDescOpcode::RdmaWriteLast
                 | ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate
@@ -303,7 +350,10 @@ impl RdmaGeneralMeta {
     }
 
     pub(crate) fn needed_permissions(&self) -> MemAccessTypeFlag {
-        if self.has_payload() {
+        if self.is_send() {
+            // the receive buffer is a local buffer posted by the responder
+            MemAccessTypeFlag::IbvAccessLocalWrite
+        } else if self.has_payload() {
             MemAccessTypeFlag::IbvAccessRemoteWrite
         } else if self.is_read_request() {
             MemAccessTypeFlag::IbvAccessRemoteRead
@@ -335,6 +385,55 @@ impl AethHeader {
     }
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct AtomicEthHeader {
+    pub(crate) common_meta: RdmaMessageMetaCommon,
+    pub(crate) va: u64,
+    pub(crate) rkey: Key,
+    pub(crate) swap_add: u64,
+    pub(crate) compare: u64,
+}
+
+impl AtomicEthHeader {
+    pub(crate) fn new_from_packet(bth: &BTH, atomic_eth: &AtomicETH) -> Result<Self, PacketError> {
+        Ok(AtomicEthHeader {
+            common_meta: RdmaMessageMetaCommon::try_from(bth)?,
+            va: atomic_eth.get_va(),
+            rkey: Key::new(atomic_eth.get_rkey()),
+            swap_add: atomic_eth.get_swap_add(),
+            compare: atomic_eth.get_compare(),
+        })
+    }
+
+    pub(crate) fn is_compare_swap(&self) -> bool {
+        matches!(self.common_meta.opcode, ToHostWorkRbDescOpcode::CompareSwap)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AtomicAckHeader {
+    pub(crate) aeth: AethHeader,
+    /// The original value of the remote memory
+    pub(crate) orig: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DatagramMeta {
+    pub(crate) common_meta: RdmaMessageMetaCommon,
+    pub(crate) qkey: u32,
+    pub(crate) src_qpn: Qpn,
+}
+
+impl DatagramMeta {
+    pub(crate) fn new_from_packet(bth: &BTH, deth: &DETH) -> Result<Self, PacketError> {
+        Ok(DatagramMeta {
+            common_meta: RdmaMessageMetaCommon::try_from(bth)?,
+            qkey: deth.get_qkey(),
+            src_qpn: Qpn::new(deth.get_src_qpn()),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct SGListElementWithKey {
     pub(crate) addr: u64,
@@ -454,18 +553,22 @@ impl SGList {
         while (current_level as u32) < self.len {
             if self.data[current_level].len >= length {
                 let addr = self.data[current_level].addr as *mut u8;
-                payload.add(addr, length as usize);
+                if length > 0 {
+                    payload.add(addr, length as usize);
+                }
                 self.data[current_level].addr = self.data[current_level].addr.wrapping_add(u64::from(length));
                 self.data[current_level].len -= length;
                 if self.data[current_level].len == 0 {
                     current_level += 1;
-                    self.cur_level = current_level as u32;
                 }
+                self.cur_level = current_level as u32;
                 return Ok(payload);
             }
-            // check next level
-            let addr = self.data[current_level].addr as *mut u8;
-            payload.add(addr, self.data[current_level].len as usize);
+            // cut the rest of current level and check next level
+            if self.data[current_level].len > 0 {
+                let addr = self.data[current_level].addr as *mut u8;
+                payload.add(addr, self.data[current_level].len as usize);
+            }
             length -= self.data[current_level].len;
             self.data[current_level].len = 0;
             current_level += 1;
@@ -473,14 +576,18 @@ impl SGList {
         Err(BlueRdmaLogicError::Unreachable)
     }
 
+    /// Cut all the remaining buffers from the scatter-gather list
     pub(crate) fn cut_all_levels(&mut self) -> PayloadInfo {
         let mut payload = PayloadInfo::new();
-        for data in &mut self.data {
-            let addr = data.addr as *mut u8;
-            let length = data.len as usize;
-            payload.add(addr, length);
-            data.len = 0;
+        let cur_level = self.cur_level as usize;
+        let len = self.len as usize;
+        for data in self.data.iter_mut().take(len).skip(cur_level) {
+            if data.len > 0 {
+                payload.add(data.addr as *mut u8, data.len as usize);
+                data.len = 0;
+            }
         }
+        self.cur_level = self.len;
         payload
     }
 
@@ -529,6 +636,7 @@ impl SGList {
 pub(crate) enum ToCardDescriptor {
     Write(ToCardWriteDescriptor),
     Read(ToCardReadDescriptor),
+    Atomic(ToCardAtomicDescriptor),
 }
 
 impl ToCardDescriptor {
@@ -538,7 +646,7 @@ impl ToCardDescriptor {
                 matches!(desc.opcode, ToCardWorkRbDescOpcode::WriteWithImm)
                     && matches!(desc.common.qp_type, QpType::RawPacket)
             }
-            ToCardDescriptor::Read(_) => false,
+            ToCardDescriptor::Read(_) | ToCardDescriptor::Atomic(_) => false,
         }
     }
 
@@ -546,6 +654,7 @@ impl ToCardDescriptor {
         match self {
             ToCardDescriptor::Write(desc) => &desc.common,
             ToCardDescriptor::Read(desc) => &desc.common,
+            ToCardDescriptor::Atomic(desc) => &desc.common,
         }
     }
 
@@ -553,6 +662,7 @@ impl ToCardDescriptor {
         match self {
             ToCardDescriptor::Write(desc) => &mut desc.sg_list,
             ToCardDescriptor::Read(desc) => &mut desc.sge,
+            ToCardDescriptor::Atomic(desc) => &mut desc.sge,
         }
     }
 }
@@ -571,52 +681,64 @@ impl ToCardWriteDescriptor {
     pub(crate) fn write_only_opcode_with_imm(&self) -> (ToHostWorkRbDescOpcode, Option<u32>) {
         if self.is_first && self.is_last {
             // is_first = True and is_last = True, means only one packet
-            match (self.is_resp(), self.has_imm()) {
-                (true, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseOnly, None),
-                (false, true) => (ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate, self.imm),
-                (false, false) => (ToHostWorkRbDescOpcode::RdmaWriteOnly, None),
+            match (self.is_resp(), self.is_send(), self.has_imm()) {
+                (true, _, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseOnly, None),
+                (false, true, true) => (ToHostWorkRbDescOpcode::SendOnlyWithImmediate, self.imm),
+                (false, true, false) => (ToHostWorkRbDescOpcode::SendOnly, None),
+                (false, false, true) => (ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate, self.imm),
+                (false, false, false) => (ToHostWorkRbDescOpcode::RdmaWriteOnly, None),
             }
         } else if self.is_first {
             // self.is_last = False
-            if self.is_resp() {
-                (ToHostWorkRbDescOpcode::RdmaReadResponseFirst, None)
-            } else {
-                (ToHostWorkRbDescOpcode::RdmaWriteFirst, None)
+            match (self.is_resp(), self.is_send()) {
+                (true, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseFirst, None),
+                (false, true) => (ToHostWorkRbDescOpcode::SendFirst, None),
+                (false, false) => (ToHostWorkRbDescOpcode::RdmaWriteFirst, None),
             }
         } else {
             // self.is_last = True
-            match (self.is_resp(), self.has_imm()) {
-                (true, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseLast, None),
-                (false, true) => (ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate, self.imm),
-                (false, false) => (ToHostWorkRbDescOpcode::RdmaWriteLast, None),
+            match (self.is_resp(), self.is_send(), self.has_imm()) {
+                (true, _, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseLast, None),
+                (false, true, true) => (ToHostWorkRbDescOpcode::SendLastWithImmediate, self.imm),
+                (false, true, false) => (ToHostWorkRbDescOpcode::SendLast, None),
+                (false, false, true) => (ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate, self.imm),
+                (false, false, false) => (ToHostWorkRbDescOpcode::RdmaWriteLast, None),
             }
         }
     }
 
     pub(crate) fn write_first_opcode(&self) -> ToHostWorkRbDescOpcode {
-        match (self.is_first, self.is_resp()) {
-            (true, true) => ToHostWorkRbDescOpcode::RdmaReadResponseFirst,
-            (true, false) => ToHostWorkRbDescOpcode::RdmaWriteFirst,
-            (false, true) => ToHostWorkRbDescOpcode::RdmaReadResponseMiddle,
-            (false, false) => ToHostWorkRbDescOpcode::RdmaWriteMiddle,
+        if self.is_first {
+            match (self.is_resp(), self.is_send()) {
+                (true, _) => ToHostWorkRbDescOpcode::RdmaReadResponseFirst,
+                (false, true) => ToHostWorkRbDescOpcode::SendFirst,
+                (false, false) => ToHostWorkRbDescOpcode::RdmaWriteFirst,
+            }
+        } else {
+            self.write_middle_opcode()
         }
     }
 
     pub(crate) fn write_middle_opcode(&self) -> ToHostWorkRbDescOpcode {
-        if self.is_resp() {
-            ToHostWorkRbDescOpcode::RdmaReadResponseMiddle
-        } else {
-            ToHostWorkRbDescOpcode::RdmaWriteMiddle
+        match (self.is_resp(), self.is_send()) {
+            (true, _) => ToHostWorkRbDescOpcode::RdmaReadResponseMiddle,
+            (false, true) => ToHostWorkRbDescOpcode::SendMiddle,
+            (false, false) => ToHostWorkRbDescOpcode::RdmaWriteMiddle,
         }
     }
 
     pub(crate) fn write_last_opcode_with_imm(&self) -> (ToHostWorkRbDescOpcode, Option<u32>) {
-        match (self.is_last, self.is_resp(), self.has_imm()) {
-            (true, true, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseLast, None), // ignore read response last with imm
-            (true, false, true) => (ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate, self.imm),
-            (true, false, false) => (ToHostWorkRbDescOpcode::RdmaWriteLast, None),
-            (false, true, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseMiddle, None),
-            (false, false, _) => (ToHostWorkRbDescOpcode::RdmaWriteMiddle, None),
+        if self.is_last {
+            // ignore read response last with imm
+            match (self.is_resp(), self.is_send(), self.has_imm()) {
+                (true, _, _) => (ToHostWorkRbDescOpcode::RdmaReadResponseLast, None),
+                (false, true, true) => (ToHostWorkRbDescOpcode::SendLastWithImmediate, self.imm),
+                (false, true, false) => (ToHostWorkRbDescOpcode::SendLast, None),
+                (false, false, true) => (ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate, self.imm),
+                (false, false, false) => (ToHostWorkRbDescOpcode::RdmaWriteLast, None),
+            }
+        } else {
+            (self.write_middle_opcode(), None)
         }
     }
 
@@ -624,6 +746,10 @@ impl ToCardWriteDescriptor {
         matches!(self.opcode, ToCardWorkRbDescOpcode::ReadResp)
     }
 
+    pub(crate) fn is_send(&self) -> bool {
+        matches!(self.opcode, ToCardWorkRbDescOpcode::Send)
+    }
+
     pub(crate) fn has_imm(&self) -> bool {
         self.imm.is_some()
     }
@@ -635,6 +761,16 @@ pub(crate) struct ToCardReadDescriptor {
     pub(crate) sge: SGList,
 }
 
+#[derive(Debug)]
+pub(crate) struct ToCardAtomicDescriptor {
+    /// `CompareSwap` or `FetchAdd`
+    pub(crate) opcode: ToHostWorkRbDescOpcode,
+    pub(crate) common: ToCardWorkRbDescCommon,
+    pub(crate) compare: u64,
+    pub(crate) swap_add: u64,
+    pub(crate) sge: SGList,
+}
+
 impl From<Box<ToCardWorkRbDesc>> for ToCardDescriptor {
     fn from(desc: Box<ToCardWorkRbDesc>) -> Self {
         match *desc {
@@ -668,6 +804,32 @@ impl From<Box<ToCardWorkRbDesc>> for ToCardDescriptor {
                 imm: None,
                 sg_list: SGList::new_with_sge_list(desc.sge0, desc.sge1, desc.sge2, desc.sge3),
             }),
+            ToCardWorkRbDesc::Send(desc) => ToCardDescriptor::Write(ToCardWriteDescriptor {
+                opcode: ToCardWorkRbDescOpcode::Send,
+                common: desc.common,
+                is_first: desc.is_first,
+                is_last: desc.is_last,
+                imm: None,
+                sg_list: SGList::new_with_sge_list(desc.sge0, desc.sge1, desc.sge2, desc.sge3),
+            }),
+            ToCardWorkRbDesc::AtomicCmpSwap(desc) => {
+                ToCardDescriptor::Atomic(ToCardAtomicDescriptor {
+                    opcode: ToHostWorkRbDescOpcode::CompareSwap,
+                    common: desc.common,
+                    compare: desc.compare,
+                    swap_add: desc.swap_add,
+                    sge: SGList::new_with_sge(desc.sge),
+                })
+            }
+            ToCardWorkRbDesc::AtomicFetchAdd(desc) => {
+                ToCardDescriptor::Atomic(ToCardAtomicDescriptor {
+                    opcode: ToHostWorkRbDescOpcode::FetchAdd,
+                    common: desc.common,
+                    compare: desc.compare,
+                    swap_add: desc.swap_add,
+                    sge: SGList::new_with_sge(desc.sge),
+                })
+            }
         }
     }
 }
@@ -28,6 +28,7 @@ pub(crate) enum ToCardCtrlRbDesc {
     SetNetworkParam(ToCardCtrlRbDescSetNetworkParam),
     SetRawPacketReceiveMeta(ToCardCtrlRbDescSetRawPacketReceiveMeta),
     UpdateErrorPsnRecoverPoint(ToCardCtrlRbDescUpdateErrPsnRecoverPoint),
+    PostRecv(ToCardCtrlRbDescPostRecv),
 }
 
 impl ToCardCtrlRbDesc {
@@ -39,8 +40,16 @@ impl ToCardCtrlRbDesc {
             ToCardCtrlRbDesc::SetNetworkParam(desc) => desc.common.op_id = id,
             ToCardCtrlRbDesc::SetRawPacketReceiveMeta(desc) => desc.common.op_id = id,
             ToCardCtrlRbDesc::UpdateErrorPsnRecoverPoint(desc) => desc.common.op_id = id,
+            ToCardCtrlRbDesc::PostRecv(desc) => desc.common.op_id = id,
         }
     }
+
+    /// Whether the descriptor is only handled by the software device.
+    ///
+    /// The card holds no receive queue, so a `PostRecv` has no layout on the ring buffer.
+    pub(crate) fn is_software_only(&self) -> bool {
+        matches!(self, ToCardCtrlRbDesc::PostRecv(_))
+    }
 }
 
 #[derive(Debug)]
@@ -54,6 +63,9 @@ pub(crate) enum ToCardWorkRbDesc {
     Write(ToCardWorkRbDescWrite),
     WriteWithImm(ToCardWorkRbDescWriteWithImm),
     ReadResp(ToCardWorkRbDescWrite),
+    Send(ToCardWorkRbDescWrite),
+    AtomicCmpSwap(ToCardWorkRbDescAtomic),
+    AtomicFetchAdd(ToCardWorkRbDescAtomic),
 }
 
 #[derive(Debug)]
@@ -63,6 +75,9 @@ pub(crate) enum ToHostWorkRbDesc {
     WriteWithImm(ToHostWorkRbDescWriteWithImm),
     Ack(ToHostWorkRbDescAck),
     Raw(ToHostWorkRbDescRaw),
+    Atomic(ToHostWorkRbDescAtomic),
+    Datagram(ToHostWorkRbDescDatagram),
+    Sent(ToHostWorkRbDescSent),
 }
 
 impl ToHostWorkRbDesc {
@@ -73,6 +88,23 @@ impl ToHostWorkRbDesc {
             ToHostWorkRbDesc::WriteWithImm(desc) => &desc.common.status,
             ToHostWorkRbDesc::Ack(desc) => &desc.common.status,
             ToHostWorkRbDesc::Raw(desc) => &desc.common.status,
+            ToHostWorkRbDesc::Atomic(desc) => &desc.common.status,
+            ToHostWorkRbDesc::Datagram(desc) => &desc.common.status,
+            ToHostWorkRbDesc::Sent(desc) => &desc.common.status,
+        }
+    }
+
+    /// A request from the remote, which should be NAKed if it failed
+    pub(crate) fn is_request(&self) -> bool {
+        match self {
+            ToHostWorkRbDesc::Read(_)
+            | ToHostWorkRbDesc::WriteWithImm(_)
+            | ToHostWorkRbDesc::Atomic(_) => true,
+            ToHostWorkRbDesc::WriteOrReadResp(desc) => !desc.is_read_resp,
+            ToHostWorkRbDesc::Ack(_)
+            | ToHostWorkRbDesc::Raw(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => false,
         }
     }
 }
@@ -111,6 +143,10 @@ pub(crate) struct ToCardCtrlRbDescQpManagement {
     pub(crate) rq_acc_flags: MemAccessTypeFlag,
     pub(crate) pmtu: Pmtu,
     pub(crate) peer_qpn: Qpn,
+    /// The psn of the first packet expected to receive
+    pub(crate) expected_psn: Psn,
+    /// The Q_Key of a UD qp
+    pub(crate) qkey: u32,
 }
 
 #[derive(Debug)]
@@ -136,6 +172,14 @@ pub(crate) struct ToCardCtrlRbDescUpdateErrPsnRecoverPoint {
     pub(crate) recover_psn: Psn,
 }
 
+/// Post a receive buffer to the receive queue of `qpn` on the card.
+#[derive(Debug)]
+pub(crate) struct ToCardCtrlRbDescPostRecv {
+    pub(crate) common: ToCardCtrlRbDescCommon,
+    pub(crate) qpn: Qpn,
+    pub(crate) sge: DescSge,
+}
+
 #[derive(Debug)]
 pub(crate) struct ToHostCtrlRbDescCommon {
     pub(crate) op_id: u32, // user_data
@@ -156,6 +200,14 @@ pub(crate) struct ToCardWorkRbDescCommon {
     pub(crate) qp_type: QpType,
     pub(crate) psn: Psn,
     pub(crate) msn: Msn,
+    /// The Q_Key of a UD send
+    pub(crate) qkey: u32,
+    /// The source qpn of a UD send
+    ///
+    /// `qkey` and `sqpn` are the DETH of a datagram. The card has no field for them, so they are
+    /// written into the bits the card reserves in `SendQueueReqDescSeg1`: `qkey` in 192..224 and
+    /// `sqpn` in 224..248. Only the software device reads them, see `is_software_only`.
+    pub(crate) sqpn: Qpn,
 }
 
 impl Default for ToCardWorkRbDescCommon {
@@ -172,6 +224,8 @@ impl Default for ToCardWorkRbDescCommon {
             qp_type: QpType::Rc,
             psn: Psn::default(),
             msn: Msn::default(),
+            qkey: 0,
+            sqpn: Qpn::default(),
         }
     }
 }
@@ -205,6 +259,20 @@ pub(crate) struct ToCardWorkRbDescWriteWithImm {
     pub(crate) sge3: Option<DescSge>,
 }
 
+/// An atomic request on the 8 bytes at `common.raddr`.
+///
+/// The original value of the remote memory is written back into `sge`.
+/// It's only handled by the software device, see `ToCardWorkRbDesc::is_software_only`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ToCardWorkRbDescAtomic {
+    pub(crate) common: ToCardWorkRbDescCommon,
+    // Only used by compare and swap
+    pub(crate) compare: u64,
+    // The swap value of compare and swap, or the addend of fetch and add
+    pub(crate) swap_add: u64,
+    pub(crate) sge: DescSge,
+}
+
 #[derive(Debug, Default, Clone)]
 pub(crate) struct ToHostWorkRbDescCommon {
     pub(crate) status: ToHostWorkRbDescStatus,
@@ -228,11 +296,15 @@ pub(crate) struct ToHostWorkRbDescRead {
 pub(crate) struct ToHostWorkRbDescWriteOrReadResp {
     pub(crate) common: ToHostWorkRbDescCommon,
     pub(crate) is_read_resp: bool,
+    // For send, the `addr` is the address in the receive buffer that the card consumed.
+    pub(crate) is_send: bool,
     pub(crate) write_type: ToHostWorkRbDescWriteType,
     pub(crate) psn: Psn,
     pub(crate) addr: u64,
     pub(crate) len: u32,
     pub(crate) can_auto_ack: bool,
+    // The immediate data of the last packet of a write with immediate.
+    pub(crate) imm: Option<u32>,
 }
 
 impl Default for ToHostWorkRbDescWriteOrReadResp {
@@ -240,16 +312,17 @@ impl Default for ToHostWorkRbDescWriteOrReadResp {
         Self {
             common: ToHostWorkRbDescCommon::default(),
             is_read_resp: false,
+            is_send: false,
             write_type: ToHostWorkRbDescWriteType::Only,
             psn: Psn::default(),
             addr: 0,
             len: 0,
             can_auto_ack: false,
+            imm: None,
         }
     }
 }
 
-#[allow(unused)] // Currently we don't have write imm descriptor
 #[derive(Debug)]
 pub(crate) struct ToHostWorkRbDescWriteWithImm {
     pub(crate) common: ToHostWorkRbDescCommon,
@@ -258,7 +331,9 @@ pub(crate) struct ToHostWorkRbDescWriteWithImm {
     pub(crate) imm: u32,
     pub(crate) addr: u64,
     pub(crate) len: u32,
+    #[allow(unused)]
     pub(crate) key: Key,
+    pub(crate) can_auto_ack: bool,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -271,6 +346,42 @@ pub(crate) struct ToHostWorkRbDescAck {
     pub(crate) code: ToHostWorkRbDescAethCode,
     #[allow(unused)] // used in nack checking
     pub(crate) value: u8,
+    /// The last psn to retransmit of a PSN sequence error NAK, from the NRETH
+    ///
+    /// It is before `psn` if the NAK asks for all the packets from `psn`, see `make_nack`.
+    pub(crate) last_retry_psn: Psn,
+}
+
+/// An atomic request which has been executed by the card.
+///
+/// The driver should respond the original value of the remote memory to the requester.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ToHostWorkRbDescAtomic {
+    pub(crate) common: ToHostWorkRbDescCommon,
+    pub(crate) psn: Psn,
+    pub(crate) orig: u64,
+}
+
+/// A UD send which has been written to a receive buffer by the card.
+///
+/// The datagram is written without the GRH, so the source is reported here.
+#[derive(Debug, Clone)]
+pub(crate) struct ToHostWorkRbDescDatagram {
+    pub(crate) common: ToHostWorkRbDescCommon,
+    pub(crate) addr: u64,
+    pub(crate) len: u32,
+    pub(crate) src_qpn: Qpn,
+    pub(crate) src_ip: Ipv4Addr,
+}
+
+/// A UD send which has been sent to the network, or failed to be sent.
+///
+/// Nothing acknowledges a datagram, so the driver completes it on this report. `common.dqpn`
+/// is the qpn of the sender. Reported by the software device only, see
+/// `ToCardWorkRbDesc::is_software_only`.
+#[derive(Debug, Clone)]
+pub(crate) struct ToHostWorkRbDescSent {
+    pub(crate) common: ToHostWorkRbDescCommon,
 }
 
 #[derive(Debug, Default)]
@@ -307,6 +418,11 @@ pub(crate) enum ToHostWorkRbDescStatus {
     InvMrKey = 4,
     InvMrRegion = 5,
     Unknown = 6,
+    /// A send finds no receive buffer, which is answered by an RNR NAK
+    ///
+    /// Reported by the software device only, which holds the receive queues. The card has no
+    /// such status, so it is rejected when read from the ring buffer.
+    NoRecvBuf = 7,
 }
 
 impl Default for ToHostWorkRbDescStatus {
@@ -319,6 +435,24 @@ impl ToHostWorkRbDescStatus {
     pub(crate) fn is_ok(&self) -> bool {
         matches!(self, ToHostWorkRbDescStatus::Normal)
     }
+
+    /// The NAK code to tell the requester why its request failed
+    ///
+    /// Returns `None` if the request succeeded, or if it is answered by an RNR NAK.
+    pub(crate) fn nak_code(&self) -> Option<ToHostWorkRbDescNakCode> {
+        match self {
+            ToHostWorkRbDescStatus::Normal | ToHostWorkRbDescStatus::NoRecvBuf => None,
+            ToHostWorkRbDescStatus::InvAccFlag
+            | ToHostWorkRbDescStatus::InvMrKey
+            | ToHostWorkRbDescStatus::InvMrRegion => {
+                Some(ToHostWorkRbDescNakCode::RemoteAccessError)
+            }
+            ToHostWorkRbDescStatus::InvOpcode => Some(ToHostWorkRbDescNakCode::InvalidRequest),
+            ToHostWorkRbDescStatus::Unknown => {
+                Some(ToHostWorkRbDescNakCode::RemoteOperationalError)
+            }
+        }
+    }
 }
 
 #[derive(TryFromPrimitive, Debug, Clone, Copy)]
@@ -339,7 +473,7 @@ impl Default for ToHostWorkRbDescTransType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub(crate) enum ToHostWorkRbDescWriteType {
     First,
     Middle,
@@ -367,9 +501,11 @@ pub(crate) enum CtrlRbDescOpcode {
     SetNetworkParam = 0x03,
     SetRawPacketReceiveMeta = 0x04,
     UpdateErrorPsnRecoverPoint = 0x05,
+    /// Reported by the software device only, it's not an opcode of the card
+    PostRecv = 0x06,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub(crate) enum ToCardWorkRbDescOpcode {
     // IBV_WR_RDMA_WRITE           =  0,
     // IBV_WR_RDMA_WRITE_WITH_IMM  =  1,
@@ -388,7 +524,10 @@ pub(crate) enum ToCardWorkRbDescOpcode {
     // IBV_WR_ATOMIC_WRITE         = 15
     Write = 0,
     WriteWithImm = 1,
+    Send = 2,
     Read = 4,
+    AtomicCmpSwap = 5,
+    AtomicFetchAdd = 6,
     ReadResp = 12, // Not defined in rdma-core
 }
 
@@ -415,6 +554,12 @@ pub(crate) enum ToHostWorkRbDescOpcode {
     // Resync = 0x15,
     // SendLastWithInvalidate = 0x16,
     // SendOnlyWithInvalidate = 0x17,
+    SendFirst = 0x00,
+    SendMiddle = 0x01,
+    SendLast = 0x02,
+    SendLastWithImmediate = 0x03,
+    SendOnly = 0x04,
+    SendOnlyWithImmediate = 0x05,
     RdmaWriteFirst = 0x06,
     RdmaWriteMiddle = 0x07,
     RdmaWriteLast = 0x08,
@@ -427,14 +572,23 @@ pub(crate) enum ToHostWorkRbDescOpcode {
     RdmaReadResponseOnly = 0x10,
     RdmaReadRequest = 0x0c,
     Acknowledge = 0x11,
+    AtomicAcknowledge = 0x12,
+    CompareSwap = 0x13,
+    FetchAdd = 0x14,
 }
 
 impl ToHostWorkRbDescOpcode {
     pub(crate) fn is_first(&self) -> bool {
         match self {
-            ToHostWorkRbDescOpcode::RdmaWriteFirst
+            ToHostWorkRbDescOpcode::SendFirst
+            | ToHostWorkRbDescOpcode::RdmaWriteFirst
             | ToHostWorkRbDescOpcode::RdmaReadResponseFirst => true,
-            ToHostWorkRbDescOpcode::RdmaWriteMiddle
+            ToHostWorkRbDescOpcode::SendMiddle
+            | ToHostWorkRbDescOpcode::SendLast
+            | ToHostWorkRbDescOpcode::SendLastWithImmediate
+            | ToHostWorkRbDescOpcode::SendOnly
+            | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaWriteMiddle
             | ToHostWorkRbDescOpcode::RdmaWriteLast
             | ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate
             | ToHostWorkRbDescOpcode::RdmaWriteOnly
@@ -443,7 +597,10 @@ impl ToHostWorkRbDescOpcode {
             | ToHostWorkRbDescOpcode::RdmaReadResponseLast
             | ToHostWorkRbDescOpcode::RdmaReadResponseOnly
             | ToHostWorkRbDescOpcode::RdmaReadRequest
-            | ToHostWorkRbDescOpcode::Acknowledge => false,
+            | ToHostWorkRbDescOpcode::Acknowledge
+            | ToHostWorkRbDescOpcode::AtomicAcknowledge
+            | ToHostWorkRbDescOpcode::CompareSwap
+            | ToHostWorkRbDescOpcode::FetchAdd => false,
         }
     }
 
@@ -467,28 +624,50 @@ impl ToHostWorkRbDescOpcode {
         )
     }
 
+    pub(crate) fn is_send(&self) -> bool {
+        matches!(
+            self,
+            ToHostWorkRbDescOpcode::SendFirst
+                | ToHostWorkRbDescOpcode::SendMiddle
+                | ToHostWorkRbDescOpcode::SendLast
+                | ToHostWorkRbDescOpcode::SendLastWithImmediate
+                | ToHostWorkRbDescOpcode::SendOnly
+                | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+        )
+    }
+
     pub(crate) fn write_type(&self) -> Option<ToHostWorkRbDescWriteType> {
         match self {
-            ToHostWorkRbDescOpcode::RdmaWriteFirst
+            ToHostWorkRbDescOpcode::SendFirst
+            | ToHostWorkRbDescOpcode::RdmaWriteFirst
             | ToHostWorkRbDescOpcode::RdmaReadResponseFirst => {
                 Some(ToHostWorkRbDescWriteType::First)
             }
-            ToHostWorkRbDescOpcode::RdmaWriteMiddle
+            ToHostWorkRbDescOpcode::SendMiddle
+            | ToHostWorkRbDescOpcode::RdmaWriteMiddle
             | ToHostWorkRbDescOpcode::RdmaReadResponseMiddle => {
                 Some(ToHostWorkRbDescWriteType::Middle)
             }
-            ToHostWorkRbDescOpcode::RdmaWriteLast
+            ToHostWorkRbDescOpcode::SendLast
+            | ToHostWorkRbDescOpcode::SendLastWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaWriteLast
             | ToHostWorkRbDescOpcode::RdmaWriteLastWithImmediate
             | ToHostWorkRbDescOpcode::RdmaReadResponseLast => Some(ToHostWorkRbDescWriteType::Last),
-            ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate
+            ToHostWorkRbDescOpcode::SendOnly
+            | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaWriteOnlyWithImmediate
             | ToHostWorkRbDescOpcode::RdmaWriteOnly
             | ToHostWorkRbDescOpcode::RdmaReadResponseOnly => Some(ToHostWorkRbDescWriteType::Only),
-            ToHostWorkRbDescOpcode::RdmaReadRequest | ToHostWorkRbDescOpcode::Acknowledge => None,
+            ToHostWorkRbDescOpcode::RdmaReadRequest
+            | ToHostWorkRbDescOpcode::Acknowledge
+            | ToHostWorkRbDescOpcode::AtomicAcknowledge
+            | ToHostWorkRbDescOpcode::CompareSwap
+            | ToHostWorkRbDescOpcode::FetchAdd => None,
         }
     }
 }
 
-#[derive(TryFromPrimitive, Clone, PartialEq, Eq, Debug)]
+#[derive(TryFromPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub(crate) enum ToHostWorkRbDescAethCode {
     // AETH_CODE_ACK  = 2'b00,
@@ -507,6 +686,17 @@ impl Default for ToHostWorkRbDescAethCode {
     }
 }
 
+/// The AETH value of a NAK, which tells the reason of the NAK
+#[derive(TryFromPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub(crate) enum ToHostWorkRbDescNakCode {
+    PsnSeqErr = 0,
+    InvalidRequest = 1,
+    RemoteAccessError = 2,
+    RemoteOperationalError = 3,
+    InvalidRdRequest = 4,
+}
+
 impl ToCardCtrlRbDesc {
     pub(super) fn write(&self, dst: &mut [u8]) {
         fn write_common_header(dst: &mut [u8], opcode: CtrlRbDescOpcode, op_id: u32) {
@@ -571,7 +761,9 @@ impl ToCardCtrlRbDesc {
 
         fn write_qp_management(dst: &mut [u8], desc: &ToCardCtrlRbDescQpManagement) {
             // typedef struct {
-            //     ReservedZero#(80)              reserved1;       // 80  bits
+            //     ReservedZero#(24)              reserved1;       // 24  bits
+            //     QKEY                            qkey;           // 32  bits
+            //     PSN                             expectedPsn;    // 24  bits
             //     QPN                             qpn;            // 24  bits
             //     ReservedZero#(5)                reserved2;      // 5   bits
             //     PMTU                            pmtu;           // 3   bits
@@ -595,6 +787,8 @@ impl ToCardCtrlRbDesc {
             seg0.set_rq_access_flags(desc.rq_acc_flags.bits().into());
             seg0.set_pmtu(desc.pmtu as u64);
             seg0.set_peer_qpn(desc.peer_qpn.get().into());
+            seg0.set_expected_psn(desc.expected_psn.get().into());
+            seg0.set_qkey(desc.qkey.into());
         }
 
         fn write_set_network_param(dst: &mut [u8], desc: &ToCardCtrlRbDescSetNetworkParam) {
@@ -656,6 +850,8 @@ impl ToCardCtrlRbDesc {
                 );
                 write_update_err_psn_recover_point(dst, desc);
             }
+            // rejected before taking a slot of the ring buffer, see `is_software_only`
+            ToCardCtrlRbDesc::PostRecv(_) => {}
         }
     }
 }
@@ -704,6 +900,35 @@ impl ToHostCtrlRbDesc {
 }
 
 impl ToCardWorkRbDesc {
+    pub(crate) fn common(&self) -> &ToCardWorkRbDescCommon {
+        match self {
+            ToCardWorkRbDesc::Read(desc) => &desc.common,
+            ToCardWorkRbDesc::Write(desc)
+            | ToCardWorkRbDesc::ReadResp(desc)
+            | ToCardWorkRbDesc::Send(desc) => &desc.common,
+            ToCardWorkRbDesc::WriteWithImm(desc) => &desc.common,
+            ToCardWorkRbDesc::AtomicCmpSwap(desc) | ToCardWorkRbDesc::AtomicFetchAdd(desc) => {
+                &desc.common
+            }
+        }
+    }
+
+    /// Whether the descriptor is only handled by the software device.
+    ///
+    /// The layout of the atomic operands on the ring buffer is not defined by the card yet,
+    /// so an atomic request is only executed by the software device. So is a UD send, which
+    /// the card neither builds a DETH for nor reports the completion of.
+    pub(crate) fn is_software_only(&self) -> bool {
+        match self {
+            ToCardWorkRbDesc::AtomicCmpSwap(_) | ToCardWorkRbDesc::AtomicFetchAdd(_) => true,
+            ToCardWorkRbDesc::Send(desc) => matches!(desc.common.qp_type, QpType::Ud),
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::Write(_)
+            | ToCardWorkRbDesc::WriteWithImm(_)
+            | ToCardWorkRbDesc::ReadResp(_) => false,
+        }
+    }
+
     pub(super) fn write_0(&self, dst: &mut [u8]) {
         let (common, opcode, is_first, is_last) = match self {
             ToCardWorkRbDesc::Read(desc) => {
@@ -727,6 +952,24 @@ impl ToCardWorkRbDesc {
                 desc.is_first,
                 desc.is_last,
             ),
+            ToCardWorkRbDesc::Send(desc) => (
+                &desc.common,
+                ToCardWorkRbDescOpcode::Send,
+                desc.is_first,
+                desc.is_last,
+            ),
+            ToCardWorkRbDesc::AtomicCmpSwap(desc) => (
+                &desc.common,
+                ToCardWorkRbDescOpcode::AtomicCmpSwap,
+                true,
+                true,
+            ),
+            ToCardWorkRbDesc::AtomicFetchAdd(desc) => (
+                &desc.common,
+                ToCardWorkRbDescOpcode::AtomicFetchAdd,
+                true,
+                true,
+            ),
         };
 
         let mut head = SendQueueDescCommonHead(dst);
@@ -760,7 +1003,9 @@ impl ToCardWorkRbDesc {
 
     pub(super) fn write_1(&self, dst: &mut [u8]) {
         // typedef struct {
-        //     ReservedZero#(64)       reserved1;          // 64 bits
+        //     ReservedZero#(8)        reserved1;          // 8  bits
+        //     QPN                     sqpn;               // 24 bits
+        //     QKEY                    qkey;               // 32 bits
 
         //     IMM                     imm;                // 32 bits
 
@@ -789,7 +1034,12 @@ impl ToCardWorkRbDesc {
         #[allow(clippy::arithmetic_side_effects)]
         let (common, sge_cnt) = match self {
             ToCardWorkRbDesc::Read(desc) => (&desc.common, 1),
-            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => (
+            ToCardWorkRbDesc::AtomicCmpSwap(desc) | ToCardWorkRbDesc::AtomicFetchAdd(desc) => {
+                (&desc.common, 1)
+            }
+            ToCardWorkRbDesc::Write(desc)
+            | ToCardWorkRbDesc::ReadResp(desc)
+            | ToCardWorkRbDesc::Send(desc) => (
                 &desc.common,
                 1 + u8::from(desc.sge1.is_some())
                     + u8::from(desc.sge2.is_some())
@@ -811,6 +1061,9 @@ impl ToCardWorkRbDesc {
         desc_common.set_mac_addr(u8_slice_to_u64(common.mac_addr.as_bytes()));
 
         desc_common.set_dqpn(common.dqpn.get().into());
+        // the DETH of a UD send
+        desc_common.set_qkey(common.qkey.into());
+        desc_common.set_sqpn(common.sqpn.get().into());
 
         if let ToCardWorkRbDesc::WriteWithImm(desc) = self {
             desc_common.set_imm(u64::from(desc.imm));
@@ -834,10 +1087,14 @@ impl ToCardWorkRbDesc {
 
         let (sge0, sge1) = match self {
             ToCardWorkRbDesc::Read(desc) => (&desc.sge, None),
-            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => {
-                (&desc.sge0, desc.sge1.as_ref())
-            }
+            ToCardWorkRbDesc::Write(desc)
+            | ToCardWorkRbDesc::ReadResp(desc)
+            | ToCardWorkRbDesc::Send(desc) => (&desc.sge0, desc.sge1.as_ref()),
             ToCardWorkRbDesc::WriteWithImm(desc) => (&desc.sge0, desc.sge1.as_ref()),
+            // rejected before being scheduled to the card, see `is_software_only`
+            ToCardWorkRbDesc::AtomicCmpSwap(desc) | ToCardWorkRbDesc::AtomicFetchAdd(desc) => {
+                (&desc.sge, None)
+            }
         };
         // Note that the order of the sges is reversed in the struct
         let mut frag_sge = SendQueueReqDescFragSGE(&mut dst[16..32]);
@@ -869,8 +1126,12 @@ impl ToCardWorkRbDesc {
         // } SendQueueReqDescVariableLenSGE deriving(Bits, FShow);
 
         let (sge2, sge3) = match self {
-            ToCardWorkRbDesc::Read(_) => (None, None),
-            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => {
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::AtomicCmpSwap(_)
+            | ToCardWorkRbDesc::AtomicFetchAdd(_) => (None, None),
+            ToCardWorkRbDesc::Write(desc)
+            | ToCardWorkRbDesc::ReadResp(desc)
+            | ToCardWorkRbDesc::Send(desc) => {
                 (desc.sge2.as_ref(), desc.sge3.as_ref())
             }
             ToCardWorkRbDesc::WriteWithImm(desc) => (desc.sge2.as_ref(), desc.sge3.as_ref()),
@@ -902,8 +1163,12 @@ impl ToCardWorkRbDesc {
     #[allow(clippy::arithmetic_side_effects)]
     pub(super) fn serialized_desc_cnt(&self) -> u32 {
         let sge_desc_cnt = match self {
-            ToCardWorkRbDesc::Read(_) => 1,
-            ToCardWorkRbDesc::Write(desc) | ToCardWorkRbDesc::ReadResp(desc) => {
+            ToCardWorkRbDesc::Read(_)
+            | ToCardWorkRbDesc::AtomicCmpSwap(_)
+            | ToCardWorkRbDesc::AtomicFetchAdd(_) => 1,
+            ToCardWorkRbDesc::Write(desc)
+            | ToCardWorkRbDesc::ReadResp(desc)
+            | ToCardWorkRbDesc::Send(desc) => {
                 1 + u32::from(desc.sge2.is_some())
             }
             ToCardWorkRbDesc::WriteWithImm(desc) => 1 + u32::from(desc.sge2.is_some()),
@@ -999,6 +1264,11 @@ impl ToHostWorkRbDesc {
                     desc_bth.get_req_status()
                 )))
             })?;
+        if matches!(status, ToHostWorkRbDescStatus::NoRecvBuf) {
+            return Err(ToHostWorkRbDescError::DeviceError(DeviceError::ParseDesc(
+                "ToHostWorkRbDescStatus::NoRecvBuf is not reported by the card".to_owned(),
+            )));
+        }
 
         // typedef struct {
         //     ReservedZero#(4)                reserved1;    // 4
@@ -1038,13 +1308,20 @@ impl ToHostWorkRbDesc {
             expected_psn,
         };
         let is_read_resp = opcode.is_read_resp();
+        let is_send = opcode.is_send();
         // The default value will not be used since the `write_type` will only appear
         // in those write related opcodes.
         let write_type = opcode
             .write_type()
             .unwrap_or(ToHostWorkRbDescWriteType::Only);
         match opcode {
-            ToHostWorkRbDescOpcode::RdmaWriteFirst
+            ToHostWorkRbDescOpcode::SendFirst
+            | ToHostWorkRbDescOpcode::SendMiddle
+            | ToHostWorkRbDescOpcode::SendLast
+            | ToHostWorkRbDescOpcode::SendLastWithImmediate
+            | ToHostWorkRbDescOpcode::SendOnly
+            | ToHostWorkRbDescOpcode::SendOnlyWithImmediate
+            | ToHostWorkRbDescOpcode::RdmaWriteFirst
             | ToHostWorkRbDescOpcode::RdmaWriteMiddle
             | ToHostWorkRbDescOpcode::RdmaWriteLast
             | ToHostWorkRbDescOpcode::RdmaWriteOnly
@@ -1058,11 +1335,13 @@ impl ToHostWorkRbDesc {
                     ToHostWorkRbDescWriteOrReadResp {
                         common,
                         is_read_resp,
+                        is_send,
                         write_type,
                         psn,
                         addr,
                         len,
                         can_auto_ack,
+                        imm: None,
                     },
                 ))
             }
@@ -1083,6 +1362,7 @@ impl ToHostWorkRbDesc {
                     ))
                 } else {
                     let imm = Self::read_imm(src);
+                    let can_auto_ack = desc_bth.get_can_auto_ack();
                     Ok(ToHostWorkRbDesc::WriteWithImm(
                         ToHostWorkRbDescWriteWithImm {
                             common,
@@ -1092,6 +1372,7 @@ impl ToHostWorkRbDesc {
                             addr,
                             len,
                             key,
+                            can_auto_ack,
                         },
                     ))
                 }
@@ -1114,7 +1395,7 @@ impl ToHostWorkRbDesc {
                 ))
             }
             ToHostWorkRbDescOpcode::Acknowledge => {
-                let (_last_psn, msn_in_ack, value, code) =
+                let (last_retry_psn, msn_in_ack, value, code) =
                     Self::read_aeth(src).map_err(ToHostWorkRbDescError::DeviceError)?;
                 Ok(ToHostWorkRbDesc::Ack(ToHostWorkRbDescAck {
                     common,
@@ -1122,8 +1403,16 @@ impl ToHostWorkRbDesc {
                     value,
                     psn,
                     code,
+                    last_retry_psn,
                 }))
             }
+            ToHostWorkRbDescOpcode::AtomicAcknowledge
+            | ToHostWorkRbDescOpcode::CompareSwap
+            | ToHostWorkRbDescOpcode::FetchAdd => Err(ToHostWorkRbDescError::DeviceError(
+                DeviceError::ParseDesc(format!(
+                    "ToHostWorkRbDescOpcode = {opcode:?} is not supported by hardware"
+                )),
+            )),
         }
     }
 }
@@ -1153,7 +1442,10 @@ impl IncompleteToHostWorkRbDesc {
             ToHostWorkRbDesc::Raw(desc) => Ok(ToHostWorkRbDesc::Raw(desc)), // ignore the redundant imm
             ToHostWorkRbDesc::WriteOrReadResp(_)
             | ToHostWorkRbDesc::WriteWithImm(_)
-            | ToHostWorkRbDesc::Ack(_) => unreachable!(),
+            | ToHostWorkRbDesc::Ack(_)
+            | ToHostWorkRbDesc::Atomic(_)
+            | ToHostWorkRbDesc::Datagram(_)
+            | ToHostWorkRbDesc::Sent(_) => unreachable!(),
         }
     }
 }
@@ -1163,6 +1455,8 @@ pub(crate) struct ToCardWorkRbDescBuilder {
     common: Option<ToCardWorkRbDescCommon>,
     seg_list: Vec<Sge>,
     imm: Option<u32>,
+    // (compare, swap_add)
+    atomic: Option<(u64, u64)>,
 }
 
 impl ToCardWorkRbDescBuilder {
@@ -1172,6 +1466,7 @@ impl ToCardWorkRbDescBuilder {
             common: None,
             seg_list: Vec::new(),
             imm: None,
+            atomic: None,
         }
     }
 
@@ -1190,19 +1485,25 @@ impl ToCardWorkRbDescBuilder {
         self
     }
 
-    pub(crate) fn build(mut self) -> Result<Box<ToCardWorkRbDesc>, Error> {
+    pub(crate) fn with_atomic(mut self, compare: u64, swap_add: u64) -> Self {
+        self.atomic = Some((compare, swap_add));
+        self
+    }
+
+    pub(crate) fn build(self) -> Result<Box<ToCardWorkRbDesc>, Error> {
         let common = self
             .common
             .ok_or_else(|| Error::BuildDescFailed("common"))?;
+        // the sges are taken in the order they are added
+        let mut sges = self.seg_list.into_iter();
         let desc = match self.type_ {
             ToCardWorkRbDescOpcode::Write => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+                let sge0 = sges
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
-                let sge1 = self.seg_list.pop();
-                let sge2 = self.seg_list.pop();
-                let sge3 = self.seg_list.pop();
+                let sge1 = sges.next();
+                let sge2 = sges.next();
+                let sge3 = sges.next();
                 ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
                     common,
                     is_last: true,
@@ -1214,13 +1515,12 @@ impl ToCardWorkRbDescBuilder {
                 })
             }
             ToCardWorkRbDescOpcode::WriteWithImm => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+                let sge0 = sges
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
-                let sge1 = self.seg_list.pop();
-                let sge2 = self.seg_list.pop();
-                let sge3 = self.seg_list.pop();
+                let sge1 = sges.next();
+                let sge2 = sges.next();
+                let sge3 = sges.next();
                 let imm = self.imm.ok_or_else(|| Error::BuildDescFailed("imm"))?;
                 ToCardWorkRbDesc::WriteWithImm(ToCardWorkRbDescWriteWithImm {
                     common,
@@ -1234,24 +1534,22 @@ impl ToCardWorkRbDescBuilder {
                 })
             }
             ToCardWorkRbDescOpcode::Read => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+                let sge0 = sges
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
                 ToCardWorkRbDesc::Read(ToCardWorkRbDescRead {
                     common,
                     sge: sge0.into(),
                 })
             }
-            ToCardWorkRbDescOpcode::ReadResp => {
-                let sge0 = self
-                    .seg_list
-                    .pop()
+            ToCardWorkRbDescOpcode::ReadResp | ToCardWorkRbDescOpcode::Send => {
+                let sge0 = sges
+                    .next()
                     .ok_or_else(|| Error::BuildDescFailed("sge"))?;
-                let sge1 = self.seg_list.pop();
-                let sge2 = self.seg_list.pop();
-                let sge3 = self.seg_list.pop();
-                ToCardWorkRbDesc::ReadResp(ToCardWorkRbDescWrite {
+                let sge1 = sges.next();
+                let sge2 = sges.next();
+                let sge3 = sges.next();
+                let desc = ToCardWorkRbDescWrite {
                     common,
                     is_last: true,
                     is_first: true,
@@ -1259,7 +1557,31 @@ impl ToCardWorkRbDescBuilder {
                     sge1: sge1.map(bitfield::Into::into),
                     sge2: sge2.map(bitfield::Into::into),
                     sge3: sge3.map(bitfield::Into::into),
-                })
+                };
+                if matches!(self.type_, ToCardWorkRbDescOpcode::Send) {
+                    ToCardWorkRbDesc::Send(desc)
+                } else {
+                    ToCardWorkRbDesc::ReadResp(desc)
+                }
+            }
+            ToCardWorkRbDescOpcode::AtomicCmpSwap | ToCardWorkRbDescOpcode::AtomicFetchAdd => {
+                let sge = sges
+                    .next()
+                    .ok_or_else(|| Error::BuildDescFailed("sge"))?;
+                let (compare, swap_add) = self
+                    .atomic
+                    .ok_or_else(|| Error::BuildDescFailed("atomic"))?;
+                let desc = ToCardWorkRbDescAtomic {
+                    common,
+                    compare,
+                    swap_add,
+                    sge: sge.into(),
+                };
+                if matches!(self.type_, ToCardWorkRbDescOpcode::AtomicCmpSwap) {
+                    ToCardWorkRbDesc::AtomicCmpSwap(desc)
+                } else {
+                    ToCardWorkRbDesc::AtomicFetchAdd(desc)
+                }
             }
         };
         Ok(Box::new(desc))
@@ -141,7 +141,7 @@
 )]
 use crate::{
     device::{
-        DeviceAdaptor, EmulatedDevice, HardwareDevice, SoftwareDevice, ToCardCtrlRbDesc,
+        DeviceAdaptor, EmulatedDevice, HardwareDevice, software_device_attr, SoftwareDevice, ToCardCtrlRbDesc,
         ToCardWorkRbDescCommon,
     },
     mr::{MrCtx, MrPgt,ACKNOWLEDGE_BUFFER_SIZE,NIC_BUFFER_SIZE},
@@ -151,28 +151,36 @@ use buf::{PacketBuf,NIC_PACKET_BUFFER_SLOT_SIZE};
 use core_affinity::CoreId;
 use derive_builder::Builder;
 use device::{
-    ToCardCtrlRbDescCommon, ToCardCtrlRbDescSetNetworkParam, ToCardCtrlRbDescSetRawPacketReceiveMeta, ToCardWorkRbDesc, ToCardWorkRbDescBuilder, ToCardWorkRbDescOpcode
+    ToCardCtrlRbDescCommon, ToCardCtrlRbDescPostRecv, ToCardCtrlRbDescSetNetworkParam, ToCardCtrlRbDescSetRawPacketReceiveMeta, ToCardWorkRbDesc, ToCardWorkRbDescBuilder, ToCardWorkRbDescOpcode
 };
 use eui48::MacAddress;
 use flume::unbounded;
 use nic::NicInterface;
-use op_ctx::{CtrlOpCtx, OpCtx};
-use checker::{PacketChecker, PacketCheckerContext, RecvContextMap};
+use op_ctx::{CtrlOpCtx, CtxStatus, OpCtx};
+use cq::{WorkCompletion, WorkCompletionOpcode, WorkCompletionStatus};
+use checker::{PacketChecker, PacketCheckerContext, ReadRespContext, RecvContextMap};
 use ctrl_poller::{ControlPoller, ControlPollerContext};
 use work_poller::{WorkDescPoller, WorkDescPollerContext};
-use qp::QpContext;
-use retry::{RetryEvent, RetryMonitor, RetryMonitorContext, RetryRecord};
+use qp::{QpContext, QpManager, RecvWqe};
+use retry::{RetryCancel, RetryEvent, RetryMonitor, RetryMonitorContext, RetryRecord};
 use std::{
-    collections::HashMap, fmt::Debug, net::{Ipv4Addr, SocketAddr}, sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc, OnceLock,
+    collections::HashMap, fmt::Debug, net::{Ipv4Addr, SocketAddr}, path::PathBuf, sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
     }
 };
 use thiserror::Error;
-use types::{Key, Msn, Psn, Qpn, RdmaDeviceNetworkParam, Sge, WorkReqSendFlag};
+use types::{DeviceAttr, Imm, Key, Msn, Psn, QpType, Qpn, RdmaDeviceNetworkParam, Sge, WorkReqSendFlag};
 use utils::{calculate_packet_cnt, Buffer};
 use parking_lot::{Mutex,RwLock};
+use pcap::PcapWriter;
 
+/// address handle
+pub mod ah;
+/// completion channel
+pub mod comp_channel;
+/// completion queue
+pub mod cq;
 /// memory region
 pub mod mr;
 /// op context for user to track the status of the write/read/control operation
@@ -202,23 +210,42 @@ mod nic;
 mod retry;
 /// utility functions
 mod utils;
+/// pcap capture of the packets on the wire
+mod pcap;
 
 /// unit test
 #[cfg(test)]
 mod tests;
 
-pub use crate::{mr::Mr, pd::Pd};
+pub use crate::{
+    ah::Ah,
+    comp_channel::CompChannel,
+    cq::Cq,
+    mr::{Mr, MrHandle},
+    pd::{Pd, PdHandle},
+    qp::QpHandle,
+};
 pub use device::scheduler::{SchedulerStrategy,SealedDesc,POP_BATCH_SIZE,BatchDescs};
 pub use device::scheduler::{round_robin::RoundRobinStrategy,testing::{TestingStrategy,TestingHandler}};
 pub use types::Error;
-pub use retry::RetryConfig;
+pub use retry::{RetryBackoff, RetryConfig};
 pub use utils::{MmapMemory,AlignedMemory};
+pub use device::{LoopbackFabric, NetImpairment, NetImpairmentBuilder};
 
-const MR_KEY_IDX_BIT_CNT: usize = 8;
+/// The card takes the index of the MR table from the highest 8 bits of the key, a larger table
+/// takes more bits.
+const MIN_MR_KEY_IDX_BIT_CNT: usize = 8;
+/// The key keeps at least 8 random bits, so the MR table can not be larger than this.
+const MAX_MR_TABLE_SIZE: usize = 1 << 24;
 const MR_TABLE_SIZE: usize = 64;
 const MR_PGT_LENGTH: usize = 1024;
 const MR_PGT_ENTRY_SIZE: usize = 8;
 const DEFAULT_RMDA_PORT : u16 = 4791;
+const ATOMIC_OPERAND_SIZE: u32 = 8;
+const MAX_SGE_CNT: usize = 4;
+// A message is at most 2^23 packets, half of the PSN space, which is 2^31 bytes of the smallest
+// PMTU. It fits the 32 bits length of the descriptor and the RETH, and is the limit of the spec.
+const MAX_MSG_SIZE: u32 = 1 << 31;
 
 type ThreadSafeHashmap<K,V> = Arc<RwLock<HashMap<K,V>>>;
 
@@ -232,25 +259,36 @@ pub struct Device(Arc<DeviceInner<dyn DeviceAdaptor>>);
 
 struct DeviceInner<D: ?Sized> {
     pd: Mutex<HashMap<Pd, PdCtx>>,
-    mr_table: Mutex<[Option<MrCtx>; MR_TABLE_SIZE]>,
+    mr_table: Mutex<Box<[Option<MrCtx>]>>,
+    /// The number of the highest bits of a MR key which are the index of the MR table
+    mr_key_idx_bit_cnt: usize,
     qp_table: ThreadSafeHashmap<Qpn, QpContext>,
+    qp_manager: QpManager,
+    // serializes `modify_qp`, which doesn't hold `qp_table` while waiting for the card
+    qp_modify_lock: Mutex<()>,
+    attr: DeviceAttr,
     mr_pgt: Mutex<MrPgt>,
     user_op_ctx_map: ThreadSafeHashmap<(Qpn,Msn), OpCtx<()>>,
+    read_resp_map: ThreadSafeHashmap<(Qpn,Msn), ReadRespContext>,
     ctrl_op_ctx_map: ThreadSafeHashmap<u32, CtrlOpCtx>,
     next_ctrl_op_id: AtomicU32,
-    work_desc_poller: OnceLock<WorkDescPoller>,
-    pkt_checker_thread: OnceLock<PacketChecker>,
-    retry_monitor: OnceLock<RetryMonitor>,
-    ctrl_desc_poller : OnceLock<ControlPoller>,
+    // The background threads are taken out and joined when the device is closed
+    work_desc_poller: Mutex<Option<WorkDescPoller>>,
+    pkt_checker_thread: Mutex<Option<PacketChecker>>,
+    retry_monitor: RwLock<Option<RetryMonitor>>,
+    ctrl_desc_poller : Mutex<Option<ControlPoller>>,
     local_network : RdmaDeviceNetworkParam,
     nic_device : Mutex<Option<NicInterface>>,
     buffer_keeper : Mutex<Vec<Buffer>>,
+    // captures the raw packets of the NIC, unless the adaptor captures them itself
+    pcap : Option<Arc<PcapWriter>>,
+    closed : AtomicBool,
     adaptor: D,
 }
 
 impl<D: ?Sized> Debug for DeviceInner<D>{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("DeviceInner").field("pd", &self.pd).field("mr_table", &self.mr_table).field("qp_table", &self.qp_table).field("mr_pgt", &self.mr_pgt).field("user_op_ctx_map", &self.user_op_ctx_map).field("ctrl_op_ctx_map", &self.ctrl_op_ctx_map).field("next_ctrl_op_id", &self.next_ctrl_op_id).field("work_desc_poller", &self.work_desc_poller).field("pkt_checker_thread", &self.pkt_checker_thread).field("retry_monitor", &self.retry_monitor).field("ctrl_desc_poller", &self.ctrl_desc_poller).field("local_network", &self.local_network).field("nic_device", &self.nic_device).field("buffer_keeper", &self.buffer_keeper).finish()
+        f.debug_struct("DeviceInner").field("pd", &self.pd).field("mr_table", &self.mr_table).field("mr_key_idx_bit_cnt", &self.mr_key_idx_bit_cnt).field("qp_table", &self.qp_table).field("qp_manager", &self.qp_manager).field("qp_modify_lock", &self.qp_modify_lock).field("attr", &self.attr).field("mr_pgt", &self.mr_pgt).field("user_op_ctx_map", &self.user_op_ctx_map).field("read_resp_map", &self.read_resp_map).field("ctrl_op_ctx_map", &self.ctrl_op_ctx_map).field("next_ctrl_op_id", &self.next_ctrl_op_id).field("work_desc_poller", &self.work_desc_poller).field("pkt_checker_thread", &self.pkt_checker_thread).field("retry_monitor", &self.retry_monitor).field("ctrl_desc_poller", &self.ctrl_desc_poller).field("local_network", &self.local_network).field("nic_device", &self.nic_device).field("buffer_keeper", &self.buffer_keeper).field("pcap", &self.pcap).field("closed", &self.closed).finish()
     }
 }
 
@@ -275,7 +313,15 @@ pub enum DeviceType {
     },
 
     /// Pure software device, might be different from the hardware device
-    Software
+    Software,
+
+    /// A software device which exchanges packets with other devices in the same process
+    /// through an in-memory fabric instead of raw sockets. It needs no privilege, so it's
+    /// suitable for tests.
+    Loopback{
+        /// The fabric shared by the devices that talk to each other
+        fabric: LoopbackFabric,
+    },
 }
 
 /// Configuration of the device
@@ -292,84 +338,63 @@ pub struct DeviceConfig<Strat:SchedulerStrategy>{
     device_type : DeviceType,
 
     /// The scheduler strategy
-    strategy : Strat
+    strategy : Strat,
+
+    /// The impairments applied to the packets sent by a software or loopback device.
+    /// Other devices ignore it.
+    #[builder(default, setter(strip_option))]
+    impairment : Option<NetImpairment>,
+
+    /// The pcap file to capture the packets sent and received by a software or loopback device,
+    /// including the raw packets of the NIC. Other devices capture the raw packets of the NIC only.
+    #[builder(default, setter(into, strip_option))]
+    pcap_path : Option<PathBuf>,
+
+    /// The number of entries of the MR table, which is the max number of MRs registered at the
+    /// same time. The number reported by the device is used if it's not set.
+    #[builder(default, setter(strip_option))]
+    mr_table_size : Option<usize>,
+
+    /// The number of entries of the MR page table, each of which maps a page of the MRs.
+    /// The number reported by the device is used if it's not set.
+    #[builder(default, setter(strip_option))]
+    mr_pgt_length : Option<usize>,
 }
 
 impl Device {
-    const MR_TABLE_EMPTY_ELEM: Option<MrCtx> = None;
-
     /// # Errors
     ///
-    /// Will return `Err` if the device failed to create the `adaptor` or the device failed to init.
+    /// Will return `Err` if:
+    /// * the device failed to create the `adaptor` or the device failed to init
+    /// * the MR table or the MR page table in the config is larger than the device supports
     pub fn new<Strat:SchedulerStrategy>(config : DeviceConfig<Strat>) -> Result<Self, Error> {
         let mut core_ids = core_affinity::get_core_ids();
         let scheduler_core = core_ids.as_mut().and_then(|v|v.pop());
+        let pcap = match config.pcap_path {
+            Some(path) => Some(Arc::new(PcapWriter::create(&path).map_err(|e| Error::Device(Box::new(e)))?)),
+            None => None,
+        };
+        let mr_tables = (config.mr_table_size, config.mr_pgt_length);
         let dev  = match config.device_type{
             DeviceType::Hardware{device_path} => {
                 let adaptor = HardwareDevice::new(device_path,config.strategy,scheduler_core).map_err(|e| Error::Device(Box::new(e)))?;
-                    let use_hugepage =  adaptor.use_hugepage();
-                let pg_table_buf = Buffer::new(MR_PGT_LENGTH * MR_PGT_ENTRY_SIZE,use_hugepage).map_err(|e| Error::ResourceNoAvailable(format!("hugepage {e}")))?;
-                Self(Arc::new(DeviceInner {
-                    pd: Mutex::new(HashMap::new()),
-                    mr_table: Mutex::new([Self::MR_TABLE_EMPTY_ELEM; MR_TABLE_SIZE]),
-                    qp_table:  Arc::new(RwLock::new(HashMap::new())),
-                    mr_pgt: Mutex::new(MrPgt::new(pg_table_buf)),
-                    user_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-                    ctrl_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-                    next_ctrl_op_id: AtomicU32::new(0),
-                    adaptor,
-                    retry_monitor: OnceLock::new(),
-                    pkt_checker_thread: OnceLock::new(),
-                    work_desc_poller: OnceLock::new(),
-                    ctrl_desc_poller : OnceLock::new(),
-                    nic_device : Mutex::new(None),
-                    buffer_keeper : Vec::new().into(),
-                    local_network : config.network_config,
-                }))
+                Self::from_adaptor(adaptor, config.network_config, pcap, mr_tables)?
             },
             DeviceType::Emulated{rpc_server_addr,heap_mem_start_addr} => {
                 let adaptor = EmulatedDevice::new(rpc_server_addr, heap_mem_start_addr,config.strategy).map_err(|e| Error::Device(Box::new(e)))?;
-                let use_hugepage =  adaptor.use_hugepage();
-                let pg_table_buf = Buffer::new(MR_PGT_LENGTH * MR_PGT_ENTRY_SIZE,use_hugepage).map_err(|e| Error::ResourceNoAvailable(format!("hugepage {e}")))?;
-                Self(Arc::new(DeviceInner {
-                    pd: Mutex::new(HashMap::new()),
-                    mr_table: Mutex::new([Self::MR_TABLE_EMPTY_ELEM; MR_TABLE_SIZE]),
-                    qp_table:  Arc::new(RwLock::new(HashMap::new())),
-                    mr_pgt: Mutex::new(MrPgt::new(pg_table_buf)),
-                    user_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-                    ctrl_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-                    next_ctrl_op_id: AtomicU32::new(0),
-                    adaptor,
-                    retry_monitor: OnceLock::new(),
-                    pkt_checker_thread: OnceLock::new(),
-                    work_desc_poller: OnceLock::new(),
-                    ctrl_desc_poller : OnceLock::new(),
-                    nic_device : Mutex::new(None),
-                    buffer_keeper : Vec::new().into(),
-                    local_network : config.network_config,
-                }))
+                Self::from_adaptor(adaptor, config.network_config, pcap, mr_tables)?
             }
             DeviceType::Software => {
-                let adaptor = SoftwareDevice::new(config.network_config.ipaddr,DEFAULT_RMDA_PORT,config.strategy).map_err(Error::Device)?;
-                let use_hugepage =  adaptor.use_hugepage();
-                let pg_table_buf = Buffer::new(MR_PGT_LENGTH * MR_PGT_ENTRY_SIZE,use_hugepage).map_err(|e| Error::ResourceNoAvailable(format!("hugepage {e}")))?;
-                Self(Arc::new(DeviceInner {
-                    pd: Mutex::new(HashMap::new()),
-                    mr_table: Mutex::new([Self::MR_TABLE_EMPTY_ELEM; MR_TABLE_SIZE]),
-                    qp_table:  Arc::new(RwLock::new(HashMap::new())),
-                    mr_pgt: Mutex::new(MrPgt::new(pg_table_buf)),
-                    user_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-                    ctrl_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
-                    next_ctrl_op_id: AtomicU32::new(0),
-                    adaptor,
-                    retry_monitor: OnceLock::new(),
-                    pkt_checker_thread: OnceLock::new(),
-                    work_desc_poller: OnceLock::new(),
-                    ctrl_desc_poller : OnceLock::new(),
-                    nic_device : Mutex::new(None),
-                    buffer_keeper : Vec::new().into(),
-                    local_network : config.network_config,
-                }))
+                let attr = software_device_attr(config.mr_table_size, config.mr_pgt_length);
+                let adaptor = SoftwareDevice::new(config.network_config.ipaddr,DEFAULT_RMDA_PORT,config.strategy,config.impairment,pcap,attr).map_err(Error::Device)?;
+                // the software device captures every packet it sends or receives, so the NIC doesn't
+                Self::from_adaptor(adaptor, config.network_config, None, mr_tables)?
+            }
+            DeviceType::Loopback{fabric} => {
+                let attr = software_device_attr(config.mr_table_size, config.mr_pgt_length);
+                let adaptor = SoftwareDevice::new_loopback(config.network_config.ipaddr,DEFAULT_RMDA_PORT,&fabric,config.strategy,config.impairment,pcap,attr).map_err(Error::Device)?;
+                // the software device captures every packet it sends or receives, so the NIC doesn't
+                Self::from_adaptor(adaptor, config.network_config, None, mr_tables)?
             }
         };
         dev.init(config.retry_config,core_ids)?;
@@ -377,18 +402,76 @@ impl Device {
         Ok(dev)
     }
 
-    fn write_or_read(
+    /// Create a device on the `adaptor`.
+    ///
+    /// `mr_tables` are the configured sizes of the MR table and the MR page table.
+    fn from_adaptor<D: DeviceAdaptor + 'static>(adaptor: D, local_network : RdmaDeviceNetworkParam, pcap: Option<Arc<PcapWriter>>, mr_tables: (Option<usize>, Option<usize>)) -> Result<Self, Error> {
+        let use_hugepage =  adaptor.use_hugepage();
+        let mut attr = adaptor.device_attr().map_err(|e| Error::Device(Box::new(e)))?;
+        let (mr_table_size, mr_pgt_length) = mr_tables;
+        attr.max_mr = table_size("MR table", mr_table_size, attr.max_mr.min(MAX_MR_TABLE_SIZE))?;
+        attr.max_mr_pgt_entries = table_size("MR page table", mr_pgt_length, attr.max_mr_pgt_entries)?;
+        let pgt_buf_size = attr.max_mr_pgt_entries.checked_mul(MR_PGT_ENTRY_SIZE).ok_or_else(|| Error::Invalid(format!("MR page table length {}", attr.max_mr_pgt_entries)))?;
+        let pg_table_buf = Buffer::new(pgt_buf_size,use_hugepage).map_err(|e| Error::ResourceNoAvailable(format!("hugepage {e}")))?;
+        Ok(Self(Arc::new(DeviceInner {
+            pd: Mutex::new(HashMap::new()),
+            mr_table: Mutex::new((0..attr.max_mr).map(|_| None).collect()),
+            mr_key_idx_bit_cnt: mr_key_idx_bit_cnt(attr.max_mr),
+            qp_table:  Arc::new(RwLock::new(HashMap::new())),
+            qp_manager: QpManager::with_capacity(attr.max_qp),
+            qp_modify_lock: Mutex::new(()),
+            attr,
+            mr_pgt: Mutex::new(MrPgt::new(pg_table_buf)),
+            user_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
+            read_resp_map: Arc::new(RwLock::new(HashMap::new())),
+            ctrl_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
+            next_ctrl_op_id: AtomicU32::new(0),
+            adaptor,
+            retry_monitor: RwLock::new(None),
+            pkt_checker_thread: Mutex::new(None),
+            work_desc_poller: Mutex::new(None),
+            ctrl_desc_poller : Mutex::new(None),
+            nic_device : Mutex::new(None),
+            buffer_keeper : Vec::new().into(),
+            local_network,
+            pcap,
+            closed: AtomicBool::new(false),
+        })))
+    }
+
+    #[allow(clippy::too_many_arguments)] // the arguments are the fields of a work request
+    fn post_work_req(
         &self,
         dqpn: Qpn,
         raddr: u64,
         rkey: Key,
         flags: WorkReqSendFlag,
-        sge0: Sge,
-        is_read:bool)-> Result<OpCtx<()>, Error>{
-            let (common,key) = {
-                let total_len = sge0.len;
+        sges: &[Sge],
+        opcode: ToCardWorkRbDescOpcode,
+        imm: Option<Imm>,
+        atomic: Option<(u64, u64)>,
+        wr_id: u64,
+        ctx: Option<OpCtx<()>>)-> Result<(Msn, OpCtx<()>), Error>{
+            self.check_open()?;
+            let total_len = check_sgl(sges)?;
+            let (common,key,send_cq,max_retry,max_rnr_retry,backoff) = {
                 let qp_guard = self.0.qp_table.read();
                 let qp = qp_guard.get(&dqpn).ok_or(Error::Invalid(format!("Qpn :{dqpn:?}")))?;
+                let state = qp.state.load(Ordering::Acquire);
+                if !state.can_send() {
+                    return Err(Error::QpNotReady(state));
+                }
+                // by IB spec, the reads and atomics are supported by an RC qp only
+                if !matches!(qp.qp_type, QpType::Rc)
+                    && matches!(
+                        opcode,
+                        ToCardWorkRbDescOpcode::Read
+                            | ToCardWorkRbDescOpcode::AtomicCmpSwap
+                            | ToCardWorkRbDescOpcode::AtomicFetchAdd
+                    )
+                {
+                    return Err(Error::Invalid(format!("{opcode:?} on {:?} qp", qp.qp_type)));
+                }
                 let msn = qp.next_msn();
                 let mut common = ToCardWorkRbDescCommon {
                     total_len,
@@ -402,11 +485,18 @@ impl Device {
                     qp_type: qp.qp_type,
                     psn: Psn::default(),
                     msn,
+                    qkey: 0,
+                    sqpn: qp.qpn,
                 };
-                let packet_cnt = if !is_read{
-                    calculate_packet_cnt(qp.pmtu, raddr, total_len)
-                }else{
+                let packet_cnt = if matches!(
+                    opcode,
+                    ToCardWorkRbDescOpcode::Read
+                        | ToCardWorkRbDescOpcode::AtomicCmpSwap
+                        | ToCardWorkRbDescOpcode::AtomicFetchAdd
+                ) {
                     1
+                }else{
+                    calculate_packet_cnt(qp.pmtu, raddr, total_len)
                 };
                 let first_pkt_psn = {
                     let mut send_psn = qp.sending_psn.lock();
@@ -416,33 +506,89 @@ impl Device {
                 };
                 common.psn = first_pkt_psn;
                 let key = (common.dqpn,msn);
-                (common, key)    
-            };
-            let opcode = if !is_read{
-                ToCardWorkRbDescOpcode::Write
-            }else{
-                ToCardWorkRbDescOpcode::Read
+                (common, key, qp.send_cq.clone(), qp.retry_cnt, qp.rnr_retry, qp.retry_backoff)
             };
-            let desc = ToCardWorkRbDescBuilder::new(opcode)
-                .with_common(common)
-                .with_sge(sge0)
-                .build()?;
-            let clone_desc = desc.clone();
-            self.send_work_desc(desc)?;
-    
-            let ctx = OpCtx::new_running();
-    
+            let qp_type = common.qp_type;
+            let pmtu = common.pmtu;
+            let mut builder = sges
+                .iter()
+                .fold(ToCardWorkRbDescBuilder::new(opcode).with_common(common), |builder, sge| {
+                    builder.with_sge(*sge)
+                });
+            if let Some(imm) = imm {
+                builder = builder.with_imm(imm);
+            }
+            if let Some((compare, swap_add)) = atomic {
+                builder = builder.with_atomic(compare, swap_add);
+            }
+            let desc = builder.build()?;
+
+            // A context given by the caller already carries its own handler
+            let ctx = ctx.unwrap_or_else(|| {
+                let new_ctx = OpCtx::new_running();
+                if let Some(cq) = send_cq {
+                    let retry_cnt = new_ctx.retry_counter();
+                    new_ctx.set_handler(send_completion_handler(
+                        cq, flags, opcode, dqpn, wr_id, retry_cnt,
+                    ));
+                }
+                new_ctx
+            });
+
+            // nothing will acknowledge an unreliable request, so it is completed once sent
+            if matches!(qp_type, QpType::Uc) {
+                self.send_work_desc(desc)?;
+                if let Some(handler) = ctx.take_handler() {
+                    handler(WorkCompletionStatus::Success);
+                }
+                ctx.set_result(())?;
+                return Ok((key.1, ctx));
+            }
+
+            // The context and the retry record are tracked before sending, so that a quick
+            // acknowledgement always finds them
             self.0
                 .user_op_ctx_map
                 .write()
                 .insert(key, ctx.clone()).map_or_else(||Ok(()),|_|Err(Error::CreateOpCtxFailed))?;
-            if let Some(monitor) =self.0.retry_monitor.get(){
-                monitor.subscribe(RetryEvent::Retry(RetryRecord::new(clone_desc, dqpn, key.1)))?;
+            // the checker tracks the response packets landing in the local buffer
+            if let (ToCardWorkRbDescOpcode::Read, [sge]) = (opcode, sges) {
+                let read = ReadRespContext::new(sge.addr, sge.len, pmtu);
+                drop(self.0.read_resp_map.write().insert(key, read));
+            }
+            let monitor = self.0.retry_monitor.read();
+            if let Some(monitor) = monitor.as_ref() {
+                let record = RetryRecord::new(
+                    desc.clone(),
+                    dqpn,
+                    key.1,
+                    max_retry,
+                    max_rnr_retry,
+                    backoff,
+                );
+                monitor.subscribe(RetryEvent::Retry(record))?;
+            }
+            if let Err(e) = self.send_work_desc(desc) {
+                drop(monitor);
+                self.cancel_work_req(key)?;
+                return Err(e);
             }
-            Ok(ctx)
+            Ok((key.1, ctx))
+    }
+
+    /// Stop tracking and retransmitting a posted request, which is never reported to the user
+    fn cancel_work_req(&self, key: (Qpn, Msn)) -> Result<(), Error> {
+        drop(self.0.user_op_ctx_map.write().remove(&key));
+        drop(self.0.read_resp_map.write().remove(&key));
+        if let Some(monitor) = self.0.retry_monitor.read().as_ref() {
+            monitor.subscribe(RetryEvent::Cancel(RetryCancel::new(key.0, key.1)))?;
+        }
+        Ok(())
     }
     
     /// RDMA write operation
+    ///
+    /// If the QP is bound to a send CQ, a completion with `wr_id` is reported to it.
     /// 
     /// # Errors
     ///
@@ -457,9 +603,81 @@ impl Device {
         raddr: u64,
         rkey: Key,
         flags: WorkReqSendFlag,
-        sge0: Sge
+        sge0: Sge,
+        wr_id: u64,
+    ) -> Result<OpCtx<()>, Error> {
+        self.write_sgl(dqpn,raddr,rkey,flags,&[sge0],wr_id)
+    }
+
+    /// RDMA write operation, which finishes when the write is acknowledged
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * failed to post the request, see `write`
+    /// * the request failed
+    pub async fn write_async(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        flags: WorkReqSendFlag,
+        sge0: Sge,
+        wr_id: u64,
+    ) -> Result<(), Error> {
+        // the `Result` is not `Send`, so it must not live across the await
+        let ctx = self.write(dqpn, raddr, rkey, flags, sge0, wr_id)?;
+        ctx.await
+    }
+
+    /// RDMA write operation with a scatter-gather list
+    ///
+    /// The payload is gathered from `sges` in order, and written to the continuous remote memory
+    /// starting at `raddr`. At most 4 sges are supported in one request.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * `sges` is empty, has more than 4 elements or the total length overflows
+    /// * failed to create a descriptor
+    /// * failed to send a descriptor
+    /// * failed to create a operation context
+    pub fn write_sgl(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        flags: WorkReqSendFlag,
+        sges: &[Sge],
+        wr_id: u64,
     ) -> Result<OpCtx<()>, Error> {
-        self.write_or_read(dqpn,raddr,rkey,flags,sge0,false)
+        self.post_work_req(dqpn,raddr,rkey,flags,sges,ToCardWorkRbDescOpcode::Write,None,None,wr_id,None).map(|(_, ctx)| ctx)
+    }
+
+    /// RDMA write with immediate operation
+    ///
+    /// The peer is notified with the immediate data after the whole message is received,
+    /// see `imm_notification_receiver`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * failed to create a descriptor
+    /// * failed to send a descriptor
+    /// * failed to create a operation context
+    #[allow(clippy::too_many_arguments)] // the arguments are the fields of a work request
+    pub fn write_with_imm(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        flags: WorkReqSendFlag,
+        sge0: Sge,
+        imm: Imm,
+        wr_id: u64,
+    ) -> Result<OpCtx<()>, Error> {
+        self.post_work_req(dqpn,raddr,rkey,flags,&[sge0],ToCardWorkRbDescOpcode::WriteWithImm,Some(imm),None,wr_id,None).map(|(_, ctx)| ctx)
     }
 
     /// RDMA read operation
@@ -478,8 +696,416 @@ impl Device {
         rkey: Key,
         flags: WorkReqSendFlag,
         sge: Sge,
+        wr_id: u64,
     ) -> Result<OpCtx<()>, Error> {
-        self.write_or_read(dqpn,raddr,rkey,flags,sge,true)
+        self.post_work_req(dqpn,raddr,rkey,flags,&[sge],ToCardWorkRbDescOpcode::Read,None,None,wr_id,None).map(|(_, ctx)| ctx)
+    }
+
+    /// RDMA read operation, which finishes when the whole response is received
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * failed to post the request, see `read`
+    /// * the request failed
+    pub async fn read_async(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        flags: WorkReqSendFlag,
+        sge: Sge,
+        wr_id: u64,
+    ) -> Result<(), Error> {
+        // the `Result` is not `Send`, so it must not live across the await
+        let ctx = self.read(dqpn, raddr, rkey, flags, sge, wr_id)?;
+        ctx.await
+    }
+
+    /// RDMA read operation with a scatter-gather list
+    ///
+    /// The continuous remote memory starting at `raddr` is scattered into `sges` in order.
+    /// At most 4 sges are supported in one request.
+    ///
+    /// A read request can only carry one local buffer, so every sge is read by a separate request.
+    /// The returned context finishes after all of them finish, and only one completion with `wr_id`
+    /// is reported to the send CQ. If one of them fails to be posted, the ones already posted are
+    /// cancelled and nothing is reported.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * `sges` is empty, has more than 4 elements or the total length overflows
+    /// * failed to create a read descriptor
+    /// * failed to send a read descriptor
+    /// * failed to create a operation context
+    pub fn read_sgl(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        flags: WorkReqSendFlag,
+        sges: &[Sge],
+        wr_id: u64,
+    ) -> Result<OpCtx<()>, Error> {
+        let _: u32 = check_sgl(sges)?;
+        if let [sge] = sges {
+            return self.read(dqpn, raddr, rkey, flags, *sge, wr_id);
+        }
+        let send_cq = self
+            .0
+            .qp_table
+            .read()
+            .get(&dqpn)
+            .ok_or(Error::Invalid(format!("Qpn :{dqpn:?}")))?
+            .send_cq
+            .clone();
+        let ctx = OpCtx::new_running();
+        if let Some(cq) = send_cq {
+            ctx.set_handler(send_completion_handler(
+                cq,
+                flags,
+                ToCardWorkRbDescOpcode::Read,
+                dqpn,
+                wr_id,
+                ctx.retry_counter(),
+            ));
+        }
+
+        let remaining = Arc::new(AtomicUsize::new(sges.len()));
+        let mut offset = 0_u64;
+        let mut posted = Vec::with_capacity(sges.len());
+        for sge in sges {
+            let parent = ctx.clone();
+            let remaining = Arc::clone(&remaining);
+            let sub_ctx = OpCtx::new_running();
+            let sub_retry_cnt = sub_ctx.retry_counter();
+            sub_ctx.set_handler(Box::new(move |status| {
+                if !matches!(parent.status(), CtxStatus::Running) {
+                    return;
+                }
+                // the parent reports the retransmissions of all the sub requests
+                let _: u32 = parent
+                    .retry_counter()
+                    .fetch_add(sub_retry_cnt.load(Ordering::Relaxed), Ordering::Relaxed);
+                if !matches!(status, WorkCompletionStatus::Success) {
+                    if let Some(handler) = parent.take_handler() {
+                        handler(status);
+                    }
+                    parent.set_error(status);
+                } else if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                    if let Some(handler) = parent.take_handler() {
+                        handler(status);
+                    }
+                    if let Err(e) = parent.set_result(()) {
+                        log::error!("Set result failed {:?}", e);
+                    }
+                } else {
+                    // wait for the other sges
+                }
+            }));
+            // the sub request is tracked by the handler of the parent context
+            match self.post_work_req(dqpn,raddr.wrapping_add(offset),rkey,flags,&[*sge],ToCardWorkRbDescOpcode::Read,None,None,wr_id,Some(sub_ctx)) {
+                Ok((msn, _)) => posted.push(msn),
+                Err(e) => {
+                    // the request is not posted as a whole, so nothing of it is reported
+                    drop(ctx.take_handler());
+                    for msn in posted {
+                        self.cancel_work_req((dqpn, msn))?;
+                    }
+                    return Err(e);
+                }
+            }
+            offset = offset.wrapping_add(u64::from(sge.len));
+        }
+        Ok(ctx)
+    }
+
+    /// Atomic compare and swap operation
+    ///
+    /// If the 8 bytes at `raddr` equal to `compare`, they are replaced with `swap`.
+    /// The operation context finishes with the original value of the remote memory, which is
+    /// also written into the local buffer `sge`.
+    ///
+    /// `raddr` should be aligned to 8 bytes, and the memory region of `rkey` should be
+    /// registered with `IbvAccessRemoteAtomic`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * `raddr` is not aligned to 8 bytes or `sge` is shorter than 8 bytes
+    /// * the QP is not an RC QP
+    /// * failed to create a descriptor
+    /// * failed to send a descriptor
+    /// * failed to create a operation context
+    #[allow(clippy::too_many_arguments)] // the arguments are the fields of a work request
+    pub fn atomic_cmp_swap(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        compare: u64,
+        swap: u64,
+        flags: WorkReqSendFlag,
+        sge: Sge,
+        wr_id: u64,
+    ) -> Result<OpCtx<u64>, Error> {
+        self.post_atomic(dqpn,raddr,rkey,ToCardWorkRbDescOpcode::AtomicCmpSwap,(compare, swap),flags,sge,wr_id)
+    }
+
+    /// Atomic fetch and add operation
+    ///
+    /// `add` is added to the 8 bytes at `raddr`. The operation context finishes with the
+    /// original value of the remote memory, which is also written into the local buffer `sge`.
+    ///
+    /// `raddr` should be aligned to 8 bytes, and the memory region of `rkey` should be
+    /// registered with `IbvAccessRemoteAtomic`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * `raddr` is not aligned to 8 bytes or `sge` is shorter than 8 bytes
+    /// * the QP is not an RC QP
+    /// * failed to create a descriptor
+    /// * failed to send a descriptor
+    /// * failed to create a operation context
+    #[allow(clippy::too_many_arguments)] // the arguments are the fields of a work request
+    pub fn atomic_fetch_add(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        add: u64,
+        flags: WorkReqSendFlag,
+        sge: Sge,
+        wr_id: u64,
+    ) -> Result<OpCtx<u64>, Error> {
+        self.post_atomic(dqpn,raddr,rkey,ToCardWorkRbDescOpcode::AtomicFetchAdd,(0, add),flags,sge,wr_id)
+    }
+
+    /// Post an atomic request, whose context finishes with the original value of the remote memory
+    ///
+    /// The request is tracked by an inner context. Once it is acknowledged, the original value in
+    /// the atomic ack has been written back into `sge`, from where it's read.
+    #[allow(clippy::too_many_arguments)]
+    fn post_atomic(
+        &self,
+        dqpn: Qpn,
+        raddr: u64,
+        rkey: Key,
+        opcode: ToCardWorkRbDescOpcode,
+        operands: (u64, u64),
+        flags: WorkReqSendFlag,
+        sge: Sge,
+        wr_id: u64,
+    ) -> Result<OpCtx<u64>, Error> {
+        let sge = check_atomic_args(raddr, sge)?;
+        let send_cq = self
+            .0
+            .qp_table
+            .read()
+            .get(&dqpn)
+            .ok_or(Error::Invalid(format!("Qpn :{dqpn:?}")))?
+            .send_cq
+            .clone();
+        let ctx = OpCtx::new_running();
+        if let Some(cq) = send_cq {
+            ctx.set_handler(send_completion_handler(
+                cq,
+                flags,
+                opcode,
+                dqpn,
+                wr_id,
+                ctx.retry_counter(),
+            ));
+        }
+
+        let result = ctx.clone();
+        let request = OpCtx::new_running();
+        let request_retry_cnt = request.retry_counter();
+        request.set_handler(Box::new(move |status| {
+            result
+                .retry_counter()
+                .store(request_retry_cnt.load(Ordering::Relaxed), Ordering::Relaxed);
+            if let Some(handler) = result.take_handler() {
+                handler(status);
+            }
+            if !matches!(status, WorkCompletionStatus::Success) {
+                result.set_error(status);
+                return;
+            }
+            // SAFETY: the buffer is checked to hold 8 bytes, and the ack has been written into it
+            let orig = unsafe { (sge.addr as *const u64).read_unaligned() };
+            if let Err(e) = result.set_result(orig) {
+                log::error!("Set result failed {:?}", e);
+            }
+        }));
+        let _: (Msn, OpCtx<()>) = self.post_work_req(dqpn,raddr,rkey,flags,&[sge],opcode,None,Some(operands),wr_id,Some(request))?;
+        Ok(ctx)
+    }
+
+    /// RDMA send operation
+    ///
+    /// The message is written to a receive buffer which is posted by the peer with `post_recv`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * lock poisoned
+    /// * failed to create a send descriptor
+    /// * failed to send a send descriptor
+    /// * failed to create a operation context
+    pub fn post_send(
+        &self,
+        dqpn: Qpn,
+        flags: WorkReqSendFlag,
+        sge: Sge,
+        wr_id: u64,
+    ) -> Result<OpCtx<()>, Error> {
+        // The remote address of a send is the offset in the receive buffer
+        self.post_work_req(dqpn,0,Key::default(),flags,&[sge],ToCardWorkRbDescOpcode::Send,None,None,wr_id,None).map(|(_, ctx)| ctx)
+    }
+
+    /// Send a datagram from a UD QP to the destination described by `ah`.
+    ///
+    /// A datagram is neither acknowledged nor retransmitted, so the returned operation context
+    /// finishes once the device reports the datagram is sent. The message should fit in one packet.
+    /// Only the software device sends datagrams for now.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * the QP does not exist or is not a UD QP
+    /// * the QP is not ready to send
+    /// * the message is longer than the pmtu
+    /// * failed to send the descriptor to the device
+    pub fn post_send_ud(
+        &self,
+        qpn: Qpn,
+        ah: &Ah,
+        flags: WorkReqSendFlag,
+        sge: Sge,
+        wr_id: u64,
+    ) -> Result<OpCtx<()>, Error> {
+        self.check_open()?;
+        let (common, send_cq) = {
+            let qp_guard = self.0.qp_table.read();
+            let qp = qp_guard.get(&qpn).ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+            if !matches!(qp.qp_type, QpType::Ud) {
+                return Err(Error::Invalid(format!("Qp type :{:?}", qp.qp_type)));
+            }
+            let state = qp.state.load(Ordering::Acquire);
+            if !state.can_send() {
+                return Err(Error::QpNotReady(state));
+            }
+            if sge.len > u32::from(&qp.pmtu) {
+                return Err(Error::Invalid(format!("datagram length {}", sge.len)));
+            }
+            let psn = {
+                let mut send_psn = qp.sending_psn.lock();
+                let psn = *send_psn;
+                *send_psn = send_psn.wrapping_add(1);
+                psn
+            };
+            let common = ToCardWorkRbDescCommon {
+                total_len: sge.len,
+                raddr: 0,
+                rkey: Key::default(),
+                dqp_ip: ah.dqp_ip,
+                dqpn: ah.dqpn,
+                mac_addr: ah.dqp_mac,
+                pmtu: qp.pmtu,
+                flags,
+                qp_type: qp.qp_type,
+                psn,
+                msn: qp.next_msn(),
+                qkey: ah.qkey,
+                sqpn: qp.qpn,
+            };
+            (common, qp.send_cq.clone())
+        };
+        let key = (qpn, common.msn);
+        let desc = ToCardWorkRbDescBuilder::new(ToCardWorkRbDescOpcode::Send)
+            .with_common(common)
+            .with_sge(sge)
+            .build()?;
+        let ctx = OpCtx::new_running();
+        if let Some(cq) = send_cq {
+            let retry_cnt = ctx.retry_counter();
+            ctx.set_handler(send_completion_handler(
+                cq,
+                flags,
+                ToCardWorkRbDescOpcode::Send,
+                qpn,
+                wr_id,
+                retry_cnt,
+            ));
+        }
+
+        // the device reports the datagram is sent, and the checker completes the context
+        self.0
+            .user_op_ctx_map
+            .write()
+            .insert(key, ctx.clone())
+            .map_or_else(|| Ok(()), |_| Err(Error::CreateOpCtxFailed))?;
+        if let Err(e) = self.send_work_desc(desc) {
+            drop(self.0.user_op_ctx_map.write().remove(&key));
+            return Err(e);
+        }
+        Ok(ctx)
+    }
+
+    /// Post a receive buffer to the QP, which will be consumed by an incoming send.
+    ///
+    /// The buffers are consumed in the order they are posted. The returned operation context
+    /// finishes when a message is received into the buffer, and the result is the length of the message.
+    /// If the QP is bound to a receive CQ, a completion with `wr_id` is reported to it as well.
+    /// Only the software device holds a receive queue for now.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * the QP does not exist
+    /// * failed to send the descriptor to the device, e.g. the card has no receive queue
+    /// * the device failed to post the buffer
+    pub fn post_recv(&self, qpn: Qpn, sge: Sge, wr_id: u64) -> Result<OpCtx<u32>, Error> {
+        self.check_open()?;
+        let ctx = OpCtx::new_running();
+        {
+            let qp_guard = self.0.qp_table.read();
+            let qp = qp_guard.get(&qpn).ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+            let state = qp.state.load(Ordering::Acquire);
+            if !state.can_post_recv() {
+                return Err(Error::QpNotReady(state));
+            }
+            // The buffer should be known before the device consumes it
+            qp.recv_queue.lock().push_back(RecvWqe {
+                wr_id,
+                sge,
+                ctx: ctx.clone(),
+            });
+        }
+
+        let op_id = self.get_ctrl_op_id();
+        let desc = ToCardCtrlRbDesc::PostRecv(ToCardCtrlRbDescPostRecv {
+            common: ToCardCtrlRbDescCommon { op_id },
+            qpn,
+            sge: sge.into(),
+        });
+        let is_success = self
+            .do_ctrl_op(op_id, desc)
+            .and_then(|ctrl_ctx| ctrl_ctx.wait_result()?.copied().ok_or(Error::SetCtxResultFailed));
+        if !matches!(is_success, Ok(true)) {
+            // The device does not hold the buffer, so we are the last one posted with this address.
+            if let Some(qp) = self.0.qp_table.read().get(&qpn) {
+                let mut recv_queue = qp.recv_queue.lock();
+                if let Some(idx) = recv_queue.iter().rposition(|wqe| wqe.sge.addr == sge.addr) {
+                    let _: Option<RecvWqe> = recv_queue.remove(idx);
+                }
+            }
+            return Err(is_success.err().unwrap_or(Error::DeviceReturnFailed("post recv")));
+        }
+        Ok(ctx)
     }
 
     /// # Errors
@@ -493,6 +1119,12 @@ impl Device {
     }
 
     fn do_ctrl_op(&self, id: u32, desc: ToCardCtrlRbDesc) -> Result<CtrlOpCtx, Error> {
+        self.check_open()?;
+        self.push_ctrl_op(id, desc)
+    }
+
+    /// Like `do_ctrl_op`, but also works when the device is closing.
+    fn push_ctrl_op(&self, id: u32, desc: ToCardCtrlRbDesc) -> Result<CtrlOpCtx, Error> {
         // save operation context for unparking
         let ctrl_ctx = {
             let mut ctx = self.0.ctrl_op_ctx_map.write();
@@ -515,11 +1147,17 @@ impl Device {
         Ok(ctrl_ctx)
     }
 
+    fn check_open(&self) -> Result<(), Error> {
+        if self.0.closed.load(Ordering::SeqCst) {
+            return Err(Error::DeviceClosed);
+        }
+        Ok(())
+    }
+
     fn get_ctrl_op_id(&self) -> u32 {
         self.0.next_ctrl_op_id.fetch_add(1, Ordering::AcqRel)
     }
 
-    #[allow(clippy::expect_used,clippy::unwrap_in_result)]
     fn init(&self,retry_config:RetryConfig,mut core_ids : Option<Vec<CoreId>>) -> Result<(), Error> {
         // enable ctrl desc poller module
         let ctrl_thread_ctx = ControlPollerContext{
@@ -528,7 +1166,7 @@ impl Device {
         };
         let ctrl_queue_core = core_ids.as_mut().and_then(|v|v.pop());
         let ctrl_desc_poller = ControlPoller::new(ctrl_thread_ctx,ctrl_queue_core);
-        self.0.ctrl_desc_poller.set(ctrl_desc_poller).expect("ctrl_desc_poller has been set");
+        *self.0.ctrl_desc_poller.lock() = Some(ctrl_desc_poller);
 
         let use_hugepage = self.0.adaptor.use_hugepage();
         let mut buf = Buffer::new(ACKNOWLEDGE_BUFFER_SIZE, use_hugepage).map_err(|e| Error::ResourceNoAvailable(format!("hugepage {e}")))?;
@@ -546,16 +1184,19 @@ impl Device {
 
         let work_queue_core = core_ids.as_mut().and_then(|v|v.pop());
         let work_desc_poller = WorkDescPoller::new(work_desc_poller_ctx,work_queue_core);
-        self.0.work_desc_poller.set(work_desc_poller).expect("work descriptor poller has been set");
+        *self.0.work_desc_poller.lock() = Some(work_desc_poller);
 
         // create nic send device, but we don't prepare receive buffer. So it won't work now.
         let mut tx_slot_buf = Buffer::new(NIC_BUFFER_SIZE, use_hugepage).map_err(|e| Error::ResourceNoAvailable(format!("hugepage {e}")))?;
         let tx_buf = self.init_buf(&mut tx_slot_buf,NIC_BUFFER_SIZE)?;
         let self_device = self.clone();
-        let nic_interface = NicInterface::new(self_device, tx_buf, nic_notify_recv_queue,self.0.local_network.macaddr);
+        let nic_interface = NicInterface::new(self_device, tx_buf, nic_notify_recv_queue,self.0.local_network.macaddr,self.0.pcap.clone());
         let mut guard = self.0.nic_device.lock();
         *guard = Some(nic_interface);  
 
+        // the checker asks the retry monitor to retransmit on NAK
+        let (retry_send_channel, retry_recv_channel) = unbounded();
+
         // enable packet checker module
         let packet_checker_ctx = PacketCheckerContext{
             desc_poller_channel: checker_recv_queue,
@@ -565,21 +1206,24 @@ impl Device {
             ctrl_desc_sender: Arc::new(self.clone()),
             work_desc_sender: Arc::new(self.clone()),
             ack_buffers: ack_buf,
+            retry_sender: retry_send_channel.clone(),
+            read_resp_map: Arc::clone(&self.0.read_resp_map),
         };
         let pkt_checker_thread = PacketChecker::new(packet_checker_ctx);
-        self.0.pkt_checker_thread.set(pkt_checker_thread).expect("pkt_checker_thread has been set");
+        *self.0.pkt_checker_thread.lock() = Some(pkt_checker_thread);
 
         // install retry monitor
-        let (retry_send_channel, retry_recv_channel) = unbounded();
         let retry_context = RetryMonitorContext{
             map: HashMap::new(),
+            rnr_waits: HashMap::new(),
             receiver: retry_recv_channel,
             config: retry_config,
             user_op_ctx_map: Arc::clone(&self.0.user_op_ctx_map),
+            read_resp_map: Arc::clone(&self.0.read_resp_map),
             device: Arc::new(self.clone()),
         };  
         let retry_monitor = retry::RetryMonitor::new(retry_send_channel,retry_context);
-        self.0.retry_monitor.set(retry_monitor).expect("double init");
+        *self.0.retry_monitor.write() = Some(retry_monitor);
 
         // set card network
         self.set_network(&self.0.local_network)?;
@@ -587,6 +1231,77 @@ impl Device {
         Ok(())
     }
 
+    /// Query the capabilities of the device, like the number of QPs and MRs it supports.
+    ///
+    /// The hardware and the emulated device report them by the capability registers of the card,
+    /// and the software device by its config.
+    #[must_use]
+    pub fn query_device(&self) -> DeviceAttr {
+        self.0.attr
+    }
+
+    /// Close the device, and wait for all of its background threads to exit.
+    ///
+    /// The outstanding operations fail with `WorkCompletionStatus::DeviceClosed`, and the QPs and
+    /// MRs are removed from the card. After that, posting requests, querying the QPs or changing
+    /// the resources on the device or any of its clones returns `Error::DeviceClosed`, except
+    /// `Device::dealloc_pd`. Closing a closed device does nothing.
+    ///
+    /// The delivery thread of a `LoopbackFabric` is shared by the devices attached to it, so it is
+    /// joined once the fabric and all of those devices are dropped instead.
+    ///
+    /// It must not be called in a completion handler, which runs in one of the background threads.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the card failed to remove a QP or a MR. The device is closed anyway.
+    pub fn close(&self) -> Result<(), Error> {
+        if self.0.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // nothing will be retransmitted
+        let retry_monitor = self.0.retry_monitor.write().take();
+        drop(retry_monitor);
+
+        // The requests posted before the device is marked as closed are all in the map now
+        let user_op_ctxs: Vec<OpCtx<()>> = self.0.user_op_ctx_map.write().drain().map(|(_, ctx)| ctx).collect();
+        self.0.read_resp_map.write().clear();
+        for ctx in user_op_ctxs {
+            // a finished request keeps its result
+            if !matches!(ctx.status(), CtxStatus::Running) {
+                continue;
+            }
+            if let Some(handler) = ctx.take_handler() {
+                handler(WorkCompletionStatus::DeviceClosed);
+            }
+            ctx.set_error(WorkCompletionStatus::DeviceClosed);
+        }
+
+        // the control poller is still running to receive the results
+        let qp_result = self.close_qps();
+        let mr_result = self.close_mrs();
+
+        // Stop the threads in the order the descriptors flow through them
+        let work_desc_poller = self.0.work_desc_poller.lock().take();
+        drop(work_desc_poller);
+        let pkt_checker_thread = self.0.pkt_checker_thread.lock().take();
+        drop(pkt_checker_thread);
+        let nic_device = self.0.nic_device.lock().take();
+        drop(nic_device);
+        let ctrl_desc_poller = self.0.ctrl_desc_poller.lock().take();
+        drop(ctrl_desc_poller);
+        self.0.adaptor.close();
+
+        // no result will arrive for the control operations still waiting
+        let ctrl_op_ctxs: Vec<CtrlOpCtx> = self.0.ctrl_op_ctx_map.write().drain().map(|(_, ctx)| ctx).collect();
+        for ctx in ctrl_op_ctxs {
+            ctx.set_error(WorkCompletionStatus::DeviceClosed);
+        }
+
+        qp_result.and(mr_result)
+    }
+
     /// Enable the NIC interface so that hardware can send Arp, ICMP, etc.  
     pub fn enable_nic_interface(&self) -> Result<(),Error> {
         let mut guard = self.0.nic_device.lock();
@@ -641,6 +1356,79 @@ impl Device {
     }
 }
 
+/// Check the target address and the local buffer of an atomic operation.
+///
+/// Return the local buffer cut to the operand size.
+fn check_atomic_args(raddr: u64, sge: Sge) -> Result<Sge, Error> {
+    if raddr & u64::from(ATOMIC_OPERAND_SIZE - 1) != 0 {
+        return Err(Error::Invalid(format!("atomic raddr {raddr:#x} is not aligned to 8 bytes")));
+    }
+    if sge.len < ATOMIC_OPERAND_SIZE {
+        return Err(Error::Invalid(format!("atomic sge length {}", sge.len)));
+    }
+    Ok(Sge {
+        len: ATOMIC_OPERAND_SIZE,
+        ..sge
+    })
+}
+
+/// The size of a table, which is `configured` if it's set, otherwise `supported`.
+fn table_size(name: &str, configured: Option<usize>, supported: usize) -> Result<usize, Error> {
+    match configured {
+        Some(size) if size == 0 || size > supported => Err(Error::Invalid(format!(
+            "{name} size {size}, the device supports {supported}"
+        ))),
+        Some(size) => Ok(size),
+        None => Ok(supported),
+    }
+}
+
+/// The number of the highest bits of a MR key which index a MR table of `mr_table_size`.
+fn mr_key_idx_bit_cnt(mr_table_size: usize) -> usize {
+    let bit_cnt = mr_table_size.next_power_of_two().trailing_zeros() as usize;
+    bit_cnt.max(MIN_MR_KEY_IDX_BIT_CNT)
+}
+
+/// Check the scatter-gather list of a work request, and return the total length of it.
+fn check_sgl(sges: &[Sge]) -> Result<u32, Error> {
+    if sges.is_empty() || sges.len() > MAX_SGE_CNT {
+        return Err(Error::Invalid(format!("sge count {}", sges.len())));
+    }
+    sges.iter()
+        .try_fold(0_u32, |total_len, sge| total_len.checked_add(sge.len))
+        .filter(|total_len| *total_len <= MAX_MSG_SIZE)
+        .ok_or_else(|| Error::Invalid("sge total length exceeds the max message size".to_owned()))
+}
+
+/// Create a handler which reports the completion of a work request to the send CQ
+fn send_completion_handler(
+    cq: Cq,
+    flags: WorkReqSendFlag,
+    opcode: ToCardWorkRbDescOpcode,
+    dqpn: Qpn,
+    wr_id: u64,
+    retry_cnt: Arc<AtomicU32>,
+) -> Box<dyn Fn(WorkCompletionStatus) + Send + Sync> {
+    // Only the signaled request reports a successful completion
+    let is_signaled = flags.contains(WorkReqSendFlag::IbvSendSignaled);
+    Box::new(move |status| {
+        if matches!(status, WorkCompletionStatus::Success) && !is_signaled {
+            return;
+        }
+        cq.push(WorkCompletion {
+            wr_id,
+            status,
+            opcode: WorkCompletionOpcode::from(opcode),
+            qpn: dqpn,
+            byte_len: 0,
+            imm: None,
+            src_qpn: None,
+            src_ip: None,
+            retry_cnt: retry_cnt.load(Ordering::Relaxed),
+        });
+    })
+}
+
 /// A interface that allows `DescResponser` to push the work descriptor to the device
 pub(crate) trait WorkDescriptorSender: Send + Sync {
     fn send_work_desc(&self, desc_builder: Box<ToCardWorkRbDesc>) -> Result<(), Error>;
@@ -652,6 +1440,7 @@ pub(crate) trait CtrlDescriptorSender: Send + Sync {
 
 impl WorkDescriptorSender for Device {
     fn send_work_desc(&self, desc: Box<ToCardWorkRbDesc>) -> Result<(), Error> {
+        self.check_open()?;
         self.0
             .adaptor
             .to_card_work_rb()
@@ -665,18 +1454,8 @@ impl CtrlDescriptorSender for Device {
     fn send_ctrl_desc(&self, mut desc: ToCardCtrlRbDesc) -> Result<CtrlOpCtx, Error> {
         let id = self.get_ctrl_op_id();
         desc.set_id(id);
-        self.0
-            .adaptor
-            .to_card_ctrl_rb()
-            .push(desc)
-            .map_err(|_| Error::DeviceBusy)?;
-        let mut ctx = self.0.ctrl_op_ctx_map.write();
-        let ctrl_ctx = CtrlOpCtx::new_running();
-
-        if ctx.insert(id, ctrl_ctx.clone()).is_some() {
-            return Err(Error::CreateOpCtxFailed);
-        }
-        Ok(ctrl_ctx)
+        // the context is saved before sending, otherwise a quick result may find no context
+        self.do_ctrl_op(id, desc)
     }
 }
 
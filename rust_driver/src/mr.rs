@@ -5,8 +5,8 @@ use crate::{
         ToCardCtrlRbDescUpdatePageTable,
     },
     types::{Key, MemAccessTypeFlag, PAGE_SIZE},
-    utils::Buffer,
-    Device, Error, Pd, MR_PGT_ENTRY_SIZE,
+    utils::{block_on, Buffer},
+    Device, Error, Pd, PdHandle, MR_PGT_ENTRY_SIZE,
 };
 use rand::RngCore as _;
 use std::{
@@ -33,6 +33,79 @@ impl Mr {
     }
 }
 
+/// A memory region which is deregistered when dropped.
+///
+/// It borrows the `PdHandle` it is registered in, so the protection domain can not be dropped
+/// before it:
+///
+/// ```compile_fail
+/// # use open_rdma_driver::{types::MemAccessTypeFlag, Device, Error, PdHandle};
+/// # fn f(device: &Device, buf: &mut [u8]) -> Result<(), Error> {
+/// let pd = PdHandle::new(device)?;
+/// let flags = MemAccessTypeFlag::IbvAccessLocalWrite;
+/// let mr = pd.reg_mr(buf.as_mut_ptr() as u64, buf.len() as u32, 4096, flags)?;
+/// drop(pd); // `pd` is still borrowed by `mr`
+/// drop(mr);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MrHandle<'pd> {
+    pd: &'pd PdHandle,
+    mr: Mr,
+}
+
+impl MrHandle<'_> {
+    /// Get the memory region
+    #[must_use]
+    pub fn mr(&self) -> Mr {
+        self.mr
+    }
+
+    /// Get the key of the memory region
+    #[must_use]
+    pub fn get_key(&self) -> Key {
+        self.mr.key
+    }
+
+    /// Get the protection domain that the memory region is registered in
+    #[must_use]
+    pub fn pd(&self) -> &PdHandle {
+        self.pd
+    }
+}
+
+impl Drop for MrHandle<'_> {
+    fn drop(&mut self) {
+        match self.pd.device().dereg_mr(self.mr) {
+            // closing the device has deregistered it
+            Ok(()) | Err(Error::DeviceClosed) => {}
+            Err(e) => log::error!("failed to deregister {:?}: {e}", self.mr),
+        }
+    }
+}
+
+impl PdHandle {
+    /// Register a memory region in the protection domain, which is deregistered when the returned
+    /// handle is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Same as `Device::reg_mr`
+    pub fn reg_mr(
+        &self,
+        addr: u64,
+        len: u32,
+        pg_size: u32,
+        acc_flags: MemAccessTypeFlag,
+    ) -> Result<MrHandle<'_>, Error> {
+        let mr = self
+            .device()
+            .reg_mr(self.pd(), addr, len, pg_size, acc_flags)?;
+        Ok(MrHandle { pd: self, mr })
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct MrCtx {
     pub(crate) pd: Pd,
@@ -55,59 +128,65 @@ struct MrPgtFreeBlk {
 }
 
 impl Device {
-    fn register_page_table(&self, addr: u64, length: u32, pg_size: u32) -> Result<usize, Error> {
-        let mut mr_pgt = self.0.mr_pgt.lock();
+    async fn register_page_table(
+        &self,
+        addr: u64,
+        length: u32,
+        pg_size: u32,
+    ) -> Result<usize, Error> {
         let pgte_cnt = length.div_ceil(pg_size) as usize;
-        let pgt_offset = mr_pgt.alloc(pgte_cnt)?;
-        for pgt_idx in 0..pgte_cnt {
-            let va = addr.wrapping_add(((pg_size as usize).wrapping_mul(pgt_idx)) as u64);
-            // Should we support 32 bit system?
-            let va_in_usize =
-                usize::try_from(va).map_err(|_| Error::NotSupport("32 bit System"))?;
-            let pa = self
-                .0
-                .adaptor
-                .get_phys_addr(va_in_usize)
-                .map_err(|e| Error::GetPhysAddrFailed(e.to_string()))?;
-            // If we run with hardware DMA,
-            // we must make sure va and pa are all allign to pg_size
-            if va_in_usize & (PAGE_SIZE - 1) != 0 {
-                return Err(Error::AddressNotAlign("va", va_in_usize));
-            }
-            if pa & (PAGE_SIZE - 1) != 0 {
-                return Err(Error::AddressNotAlign("pa", pa));
-            }
-            // `mr_pgt.alloc(pgte_cnt)` has already checked that `pgt_offset + pgt_idx` is in range
-            #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
-            {
-                mr_pgt.table[pgt_idx] = pa as u64;
+        // the lock is released before waiting for the card
+        let (pgt_offset, update_pgt_ctx) = {
+            let mut mr_pgt = self.0.mr_pgt.lock();
+            let pgt_offset = mr_pgt.alloc(pgte_cnt)?;
+            for pgt_idx in 0..pgte_cnt {
+                let va = addr.wrapping_add(((pg_size as usize).wrapping_mul(pgt_idx)) as u64);
+                // Should we support 32 bit system?
+                let va_in_usize =
+                    usize::try_from(va).map_err(|_| Error::NotSupport("32 bit System"))?;
+                let pa = self
+                    .0
+                    .adaptor
+                    .get_phys_addr(va_in_usize)
+                    .map_err(|e| Error::GetPhysAddrFailed(e.to_string()))?;
+                // If we run with hardware DMA,
+                // we must make sure va and pa are all allign to pg_size
+                if va_in_usize & (PAGE_SIZE - 1) != 0 {
+                    return Err(Error::AddressNotAlign("va", va_in_usize));
+                }
+                if pa & (PAGE_SIZE - 1) != 0 {
+                    return Err(Error::AddressNotAlign("pa", pa));
+                }
+                // `mr_pgt.alloc(pgte_cnt)` has already checked that `pgt_offset + pgt_idx` is in range
+                #[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
+                {
+                    mr_pgt.table[pgt_idx] = pa as u64;
+                }
             }
-        }
 
-        let update_pgt_op_id = self.get_ctrl_op_id();
-        // `pgt_offset` and `pg_size` are both derived from pg_size, which is a u32. So it's safe to covert
-        #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
-        let update_pgt_desc = ToCardCtrlRbDesc::UpdatePageTable(ToCardCtrlRbDescUpdatePageTable {
-            common: ToCardCtrlRbDescCommon {
-                op_id: update_pgt_op_id,
-            },
-            start_addr: self
-                .0
-                .adaptor
-                .get_phys_addr(mr_pgt.table.as_ptr() as usize)
-                .map_err(|e| Error::GetPhysAddrFailed(e.to_string()))? as u64,
-            pgt_idx: pgt_offset as u32,
-            pgte_cnt: pgte_cnt as u32,
-        });
-
-        let update_pgt_ctx = self.do_ctrl_op(update_pgt_op_id, update_pgt_desc)?;
+            let update_pgt_op_id = self.get_ctrl_op_id();
+            // `pgt_offset` and `pg_size` are both derived from pg_size, which is a u32. So it's safe to covert
+            #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
+            let update_pgt_desc = ToCardCtrlRbDesc::UpdatePageTable(ToCardCtrlRbDescUpdatePageTable {
+                common: ToCardCtrlRbDescCommon {
+                    op_id: update_pgt_op_id,
+                },
+                start_addr: self
+                    .0
+                    .adaptor
+                    .get_phys_addr(mr_pgt.table.as_ptr() as usize)
+                    .map_err(|e| Error::GetPhysAddrFailed(e.to_string()))? as u64,
+                pgt_idx: pgt_offset as u32,
+                pgte_cnt: pgte_cnt as u32,
+            });
+
+            (pgt_offset, self.do_ctrl_op(update_pgt_op_id, update_pgt_desc)?)
+        };
 
-        let update_pgt_result = update_pgt_ctx
-            .wait_result()?
-            .ok_or(Error::SetCtxResultFailed)?;
+        let update_pgt_result = update_pgt_ctx.await?;
 
         if !update_pgt_result {
-            mr_pgt.dealloc(pgt_offset, pgte_cnt);
+            self.0.mr_pgt.lock().dealloc(pgt_offset, pgte_cnt);
             return Err(Error::DeviceReturnFailed("update page table"));
         }
         Ok(pgt_offset)
@@ -141,37 +220,84 @@ impl Device {
         pg_size: u32,
         acc_flags: MemAccessTypeFlag,
     ) -> Result<Mr, Error> {
-        let mut mr_table = self.0.mr_table.lock();
+        block_on(self.reg_mr_async(pd, addr, len, pg_size, acc_flags))
+    }
 
-        let mut pd_pool = self.0.pd.lock();
+    /// Register a Mr without blocking the thread while waiting for the card
+    ///
+    /// # Errors
+    ///
+    /// Same as `reg_mr`
+    pub async fn reg_mr_async(
+        &self,
+        pd: Pd,
+        addr: u64,
+        len: u32,
+        pg_size: u32,
+        acc_flags: MemAccessTypeFlag,
+    ) -> Result<Mr, Error> {
+        self.check_open()?;
+        // check the resources before touching the page table
+        {
+            let mr_table = self.0.mr_table.lock();
+            if mr_table.iter().all(Option::is_some) {
+                return Err(Error::ResourceNoAvailable("MR".to_owned()));
+            }
+            if !self.0.pd.lock().contains_key(&pd) {
+                return Err(Error::Invalid(format!("PD :{pd:?}")));
+            }
+        }
 
-        let Some(mr_idx) = mr_table
-            .iter()
-            .enumerate()
-            .find_map(|(idx, ctx)| ctx.is_none().then_some(idx))
-        else {
-            return Err(Error::ResourceNoAvailable("MR".to_owned()));
+        let pgt_offset = self.register_page_table(addr, len, pg_size).await?;
+
+        // The mr is inserted before the card is updated, so that the locks are not held
+        // while waiting. It is removed if the card fails.
+        let (mr, mr_idx) = {
+            let mut mr_table = self.0.mr_table.lock();
+            let mut pd_pool = self.0.pd.lock();
+
+            let Some(mr_idx) = mr_table
+                .iter()
+                .enumerate()
+                .find_map(|(idx, ctx)| ctx.is_none().then_some(idx))
+            else {
+                self.deregister_page_table(pgt_offset, len.div_ceil(pg_size))?;
+                return Err(Error::ResourceNoAvailable("MR".to_owned()));
+            };
+            let Some(pd_ctx) = pd_pool.get_mut(&pd) else {
+                self.deregister_page_table(pgt_offset, len.div_ceil(pg_size))?;
+                return Err(Error::Invalid(format!("PD :{pd:?}")));
+            };
+
+            let key = self.mr_key(mr_idx);
+
+            let mr = Mr { key };
+            if pd_ctx.mr.insert(mr) {
+                #[allow(clippy::indexing_slicing)]
+                // `mr_idx` is allocated by `find_map` above, so it's safe to index
+                {
+                    mr_table[mr_idx] = Some(MrCtx {
+                        pd,
+                        len,
+                        pgt_offset,
+                        pg_size,
+                    });
+                }
+                (mr, Some(mr_idx))
+            } else {
+                (mr, None)
+            }
         };
-
-        let pd_ctx = pd_pool
-            .get_mut(&pd)
-            .ok_or(Error::Invalid(format!("PD :{pd:?}")))?;
-
-        let pgt_offset = self.register_page_table(addr, len, pg_size)?;
-
-        // mr_idx is smaller than `MR_TABLE_SIZE`. Currently, it's a relatively small number.
-        // And it's expected to smaller than 2^32 during transimission
-        #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
-        let key_idx = (mr_idx as u32) << (mem::size_of::<u32>() * 8 - crate::MR_KEY_IDX_BIT_CNT);
-        let key_secret = rand::thread_rng().next_u32() >> crate::MR_KEY_IDX_BIT_CNT;
-        let key = Key::new(key_idx | key_secret);
-
-        let mr = Mr { key };
-        let mr_ctx = MrCtx {
-            pd,
-            len,
-            pgt_offset,
-            pg_size,
+        let Some(mr_idx) = mr_idx else {
+            // the pd is out of sync with the mr table, so leave neither the page table nor
+            // the key on the card
+            self.deregister_page_table(pgt_offset, len.div_ceil(pg_size))?;
+            let op_id = self.get_ctrl_op_id();
+            let invalidate_ctx = self.do_ctrl_op(op_id, invalidate_mr_desc(op_id, mr.key))?;
+            if !invalidate_ctx.await? {
+                log::error!("failed to invalidate {mr:?}");
+            }
+            return Err(Error::Invalid(format!("mr :{mr:?}")));
         };
 
         let update_mr_op_id = self.get_ctrl_op_id();
@@ -183,33 +309,45 @@ impl Device {
             },
             addr,
             len,
-            key,
-            pd_hdl: mr_ctx.pd.handle,
+            key: mr.key,
+            pd_hdl: pd.handle,
             acc_flags,
             pgt_offset: pgt_offset as u32,
         });
 
-        let update_mr_ctx = self.do_ctrl_op(update_mr_op_id, update_mr_desc)?;
-
-        let update_mr_result = update_mr_ctx
-            .wait_result()?
-            .ok_or(Error::SetCtxResultFailed)?;
+        // the `Result` is not `Send`, so it must not live across the await
+        let update_mr_ctx = match self.do_ctrl_op(update_mr_op_id, update_mr_desc) {
+            Ok(update_mr_ctx) => update_mr_ctx,
+            Err(e) => {
+                self.cancel_reg_mr(pd, mr, mr_idx)?;
+                return Err(e);
+            }
+        };
+        let update_mr_result = update_mr_ctx.await;
 
-        if !update_mr_result {
-            return Err(Error::DeviceReturnFailed("register mr table"));
+        match update_mr_result {
+            Ok(true) => Ok(mr),
+            Ok(false) => {
+                self.cancel_reg_mr(pd, mr, mr_idx)?;
+                Err(Error::DeviceReturnFailed("register mr table"))
+            }
+            Err(e) => {
+                self.cancel_reg_mr(pd, mr, mr_idx)?;
+                Err(e)
+            }
         }
+    }
 
-        #[allow(clippy::indexing_slicing)]
-        // `mr_idx` is allocated by `find_map` above, so it's safe to index
-        {
-            mr_table[mr_idx] = Some(mr_ctx);
+    /// Remove a Mr which is failed to register to the card
+    fn cancel_reg_mr(&self, pd: Pd, mr: Mr, mr_idx: usize) -> Result<(), Error> {
+        if let Some(pd_ctx) = self.0.pd.lock().get_mut(&pd) {
+            let _: bool = pd_ctx.mr.remove(&mr);
         }
-
-        if !pd_ctx.mr.insert(mr) {
-            return Err(Error::Invalid(format!("mr :{mr:?}")));
+        let mr_ctx = self.0.mr_table.lock().get_mut(mr_idx).and_then(Option::take);
+        if let Some(mr_ctx) = mr_ctx {
+            self.deregister_page_table(mr_ctx.pgt_offset, mr_ctx.len.div_ceil(mr_ctx.pg_size))?;
         }
-
-        Ok(mr)
+        Ok(())
     }
 
     pub(crate) fn init_buf<const SLOT_SIZE: usize>(
@@ -244,12 +382,12 @@ impl Device {
     /// * Operating system not support
     /// * Setted context result failed
     pub fn dereg_mr(&self, mr: Mr) -> Result<(), Error> {
+        self.check_open()?;
         let mut mr_table = self.0.mr_table.lock();
         let mut pd_pool = self.0.pd.lock();
-        #[allow(clippy::arithmetic_side_effects)]
-        let mr_idx = mr.key.get() >> (mem::size_of::<u32>() * 8 - crate::MR_KEY_IDX_BIT_CNT);
+        let mr_idx = self.mr_table_idx(mr.key);
         let ctx_option = mr_table
-            .get_mut(mr_idx as usize)
+            .get_mut(mr_idx)
             .ok_or(Error::Invalid(format!("MR :{mr_idx}")))?;
         let Some(mr_ctx) = ctx_option else {
             return Err(Error::Invalid(format!("MR :{mr_idx}")));
@@ -261,17 +399,7 @@ impl Device {
 
         let op_id = self.get_ctrl_op_id();
 
-        let desc = ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
-            common: ToCardCtrlRbDescCommon { op_id },
-            addr: 0,
-            len: 0,
-            key: mr.key,
-            pd_hdl: 0,
-            acc_flags: MemAccessTypeFlag::IbvAccessNoFlags,
-            pgt_offset: 0,
-        });
-
-        let ctx = self.do_ctrl_op(op_id, desc)?;
+        let ctx = self.do_ctrl_op(op_id, invalidate_mr_desc(op_id, mr.key))?;
 
         let res = ctx.wait_result()?.ok_or(Error::SetCtxResultFailed)?;
 
@@ -288,6 +416,73 @@ impl Device {
 
         Ok(())
     }
+
+    /// Remove all the mrs from the card. It's called when the device is closed.
+    ///
+    /// Every mr is removed even if removing a previous one fails, and the first error is returned.
+    pub(crate) fn close_mrs(&self) -> Result<(), Error> {
+        let mrs: Vec<Mr> = self
+            .0
+            .pd
+            .lock()
+            .values_mut()
+            .flat_map(|pd_ctx| pd_ctx.mr.drain())
+            .collect();
+        let mut mr_table = self.0.mr_table.lock();
+        let mut result = Ok(());
+        for mr in mrs {
+            let op_id = self.get_ctrl_op_id();
+            let is_success = self
+                .push_ctrl_op(op_id, invalidate_mr_desc(op_id, mr.key))
+                .and_then(|ctx| ctx.wait_result()?.copied().ok_or(Error::SetCtxResultFailed));
+            // the rest of the mrs are removed even if the page table of one is not
+            let is_pgt_deregistered = mr_table
+                .get_mut(self.mr_table_idx(mr.key))
+                .and_then(Option::take)
+                .map_or(Ok(()), |mr_ctx| {
+                    self.deregister_page_table(mr_ctx.pgt_offset, mr_ctx.len.div_ceil(mr_ctx.pg_size))
+                });
+            if result.is_ok() && !matches!(is_success, Ok(true)) {
+                result = Err(is_success.err().unwrap_or(Error::DeviceReturnFailed("deregister mr table")));
+            }
+            if result.is_ok() {
+                result = is_pgt_deregistered;
+            }
+        }
+        result
+    }
+
+    /// Create a key of the mr at `mr_idx` of the mr table, whose highest bits are the index and
+    /// the rest are random.
+    fn mr_key(&self, mr_idx: usize) -> Key {
+        let idx_bit_cnt = self.0.mr_key_idx_bit_cnt;
+        // mr_idx is smaller than the size of the mr table, which takes at most `idx_bit_cnt` bits.
+        // `idx_bit_cnt` is between 8 and 24.
+        #[allow(clippy::cast_possible_truncation, clippy::arithmetic_side_effects)]
+        let key_idx = (mr_idx as u32) << (mem::size_of::<u32>() * 8 - idx_bit_cnt);
+        let key_secret = rand::thread_rng().next_u32() >> idx_bit_cnt;
+        Key::new(key_idx | key_secret)
+    }
+
+    /// The index of the mr in the mr table, which is the highest bits of the key
+    fn mr_table_idx(&self, key: Key) -> usize {
+        #[allow(clippy::arithmetic_side_effects)]
+        let mr_idx = key.get() >> (mem::size_of::<u32>() * 8 - self.0.mr_key_idx_bit_cnt);
+        mr_idx as usize
+    }
+}
+
+/// The descriptor that removes the mr with `key` from the card
+fn invalidate_mr_desc(op_id: u32, key: Key) -> ToCardCtrlRbDesc {
+    ToCardCtrlRbDesc::UpdateMrTable(ToCardCtrlRbDescUpdateMrTable {
+        common: ToCardCtrlRbDescCommon { op_id },
+        addr: 0,
+        len: 0,
+        key,
+        pd_hdl: 0,
+        acc_flags: MemAccessTypeFlag::IbvAccessNoFlags,
+        pgt_offset: 0,
+    })
 }
 
 impl MrPgt {
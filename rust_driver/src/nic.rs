@@ -9,6 +9,7 @@ use crate::{
     buf::{PacketBuf, Slot, NIC_PACKET_BUFFER_SLOT_SIZE},
     device::{ToCardWorkRbDescBuilder, ToCardWorkRbDescCommon, ToCardWorkRbDescOpcode},
     types::QpType,
+    pcap::PcapWriter,
     Device as BlueRdmaDevice, WorkDescriptorSender,
 };
 use eui48::MacAddress;
@@ -46,6 +47,7 @@ pub(crate) struct BasicNicDeivce {
     tx_buf: PacketBuf<NIC_PACKET_BUFFER_SLOT_SIZE>,
     receiver: Receiver<NicRecvNotification>,
     neighbor_cache: Arc<Mutex<HashMap<Ipv4Addr, MacAddress>>>,
+    pcap: Option<Arc<PcapWriter>>,
 }
 
 #[derive(Debug)]
@@ -70,6 +72,7 @@ impl NicInterface {
         tx_buf: PacketBuf<NIC_PACKET_BUFFER_SLOT_SIZE>,
         receiver: Receiver<NicRecvNotification>,
         self_mac_addr: MacAddress,
+        pcap: Option<Arc<PcapWriter>>,
     ) -> Self {
         let (icmp_queries_sender, icmp_queries_receiver) = flume::unbounded();
         let cache = Arc::new(Mutex::new(HashMap::new()));
@@ -79,6 +82,7 @@ impl NicInterface {
             tx_buf,
             receiver,
             neighbor_cache: cache.clone(),
+            pcap,
         };
         let stop_flag = Arc::new(AtomicBool::new(false));
         let context = NicWorkingContext{
@@ -153,7 +157,7 @@ impl Device for BasicNicDeivce {
                 let len = notification.len as usize;
                 return Some((
                     NicRxToken(&mut buf[..len], self), // the length is guaranteed to be less than the buffer size
-                    NicTxToken(&self.device, &self.tx_buf),
+                    NicTxToken(self),
                 ));
             }
             Err(TryRecvError::Disconnected) => {
@@ -164,7 +168,7 @@ impl Device for BasicNicDeivce {
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        Some(NicTxToken(&self.device, &self.tx_buf))
+        Some(NicTxToken(self))
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
@@ -177,10 +181,7 @@ impl Device for BasicNicDeivce {
 }
 
 pub(crate) struct NicRxToken<'a>(&'static mut [u8], &'a BasicNicDeivce);
-pub(crate) struct NicTxToken<'a>(
-    &'a BlueRdmaDevice,
-    &'a PacketBuf<NIC_PACKET_BUFFER_SLOT_SIZE>,
-);
+pub(crate) struct NicTxToken<'a>(&'a BasicNicDeivce);
 
 impl RxToken for NicRxToken<'_> {
     #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
@@ -192,6 +193,9 @@ impl RxToken for NicRxToken<'_> {
         // 1. First check if it's an Ethernet frame, and the upper layer is IP.
         // 2. we distract the src IP and src MAC, store them into our cache.
         log::info!("Received packet: {:?}", self.0);
+        if let Some(ref pcap) = self.1.pcap {
+            pcap.write_ethernet(self.0);
+        }
         let type_ =
             u16::from(self.0[ETH_TYPE_START]) << 8_i32 | u16::from(self.0[ETH_TYPE_START + 1]);
         if type_ == ETH_TYPE_IP {
@@ -225,7 +229,7 @@ impl TxToken for NicTxToken<'_> {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buf = self.1.recycle_buf();
+        let mut buf = self.0.tx_buf.recycle_buf();
 
         let ret = f(buf.as_mut_slice()[0..len].as_mut());
 
@@ -233,6 +237,9 @@ impl TxToken for NicTxToken<'_> {
             "Sending packet to buffer: {:?}",
             &buf.as_mut_slice()[0..len]
         );
+        if let Some(ref pcap) = self.0.pcap {
+            pcap.write_ethernet(&buf.as_mut_slice()[0..len]);
+        }
         let sge = buf.into_sge(len as u32);
         let total_len = 64.min(len);
         let common = ToCardWorkRbDescCommon {
@@ -248,7 +255,7 @@ impl TxToken for NicTxToken<'_> {
             .with_sge(sge)
             .build()
             .unwrap();
-        if let Err(e) = self.0.send_work_desc(desc) {
+        if let Err(e) = self.0.device.send_work_desc(desc) {
             log::error!("Failed to send work desc: {:?}", e);
         }
         ret
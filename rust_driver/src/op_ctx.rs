@@ -1,12 +1,18 @@
 use std::{
     fmt::Debug,
-    sync::{Arc, OnceLock},
-    thread::{self, Thread},
+    future::{poll_fn, Future},
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll, Waker},
 };
 
 use parking_lot::Mutex;
 
-use crate::Error;
+use crate::{cq::WorkCompletionStatus, utils::block_on, Error};
 
 /// The status of operations.
 #[non_exhaustive]
@@ -18,7 +24,7 @@ pub enum CtxStatus {
     /// The operation is running.
     Running,
     /// The operation is stopped.
-    Failed(&'static str),
+    Failed(WorkCompletionStatus),
     /// The operation is finished.
     Finished,
 }
@@ -29,6 +35,7 @@ pub enum CtxStatus {
 /// When calling the `read`,`write` or some `control` command, you get an operation context.
 ///
 /// You can wait for the operation to finish by calling the `wait` method and get the result by calling the `get_result` method.
+/// The context is also a `Future` resolving to the result, which can be awaited by many tasks at the same time.
 #[derive(Debug, Clone)]
 pub struct OpCtx<Payload>(Arc<OpCtxWrapper<Payload>>);
 
@@ -36,7 +43,9 @@ pub struct OpCtx<Payload>(Arc<OpCtxWrapper<Payload>>);
 struct OpCtxWrapper<Payload> {
     inner: Mutex<OpCtxInner>,
     payload: OnceLock<Payload>,
-    handler: Mutex<Option<Box<dyn Fn(bool) + Sync + Send>>>,
+    handler: Mutex<Option<Box<dyn Fn(WorkCompletionStatus) + Sync + Send>>>,
+    // the number of retransmissions of the request, counted by the retry monitor
+    retry_cnt: Arc<AtomicU32>,
 }
 
 impl<Payload> Debug for OpCtxWrapper<Payload> {
@@ -49,7 +58,7 @@ impl<Payload> Debug for OpCtxWrapper<Payload> {
 
 #[derive(Debug)]
 struct OpCtxInner {
-    thread: Option<Thread>,
+    wakers: Vec<Waker>,
     status: CtxStatus,
 }
 
@@ -62,13 +71,14 @@ impl<Payload> OpCtx<Payload> {
     #[must_use]
     pub fn new_running() -> Self {
         let inner = OpCtxInner {
-            thread: None,
+            wakers: Vec::new(),
             status: CtxStatus::Running,
         };
         let wrapper = OpCtxWrapper {
             inner: Mutex::new(inner),
             payload: OnceLock::new(),
             handler: Mutex::new(None),
+            retry_cnt: Arc::new(AtomicU32::new(0)),
         };
         Self(Arc::new(wrapper))
     }
@@ -76,38 +86,53 @@ impl<Payload> OpCtx<Payload> {
     /// # Errors
     /// Returns an error if the operation context is poisoned.
     pub fn wait(&self) -> Result<(), Error> {
-        let mut guard = self.0.inner.lock();
-        if matches!(guard.status, CtxStatus::Running) {
-            guard.thread = Some(thread::current());
-            drop(guard);
-            thread::park();
-        }
+        let _: CtxStatus = block_on(poll_fn(|cx| self.poll_done(cx)));
         Ok(())
     }
 
-    // TODO: use enum rather than str
-    pub(crate) fn set_error(&self, cause: &'static str) {
-        // set only once
+    /// Poll whether the operation is done, and register the waker if it is still running.
+    fn poll_done(&self, cx: &Context<'_>) -> Poll<CtxStatus> {
         let mut guard = self.0.inner.lock();
-        guard.status = CtxStatus::Failed(cause);
-        if let Some(thread) = guard.thread.take() {
-            thread.unpark();
+        if !matches!(guard.status, CtxStatus::Running) {
+            return Poll::Ready(guard.status);
+        }
+        if !guard.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            guard.wakers.push(cx.waker().clone());
         }
+        Poll::Pending
     }
-    pub(crate) fn set_result(&self, result: Payload) -> Result<(), Error> {
-        self.0
-            .payload
-            .set(result)
-            .map_err(|_| Error::SetCtxResultFailed)?;
-        // set only once
-        let mut guard = self.0.inner.lock();
-        guard.status = CtxStatus::Finished;
-        if let Some(thread) = guard.thread.take() {
-            thread.unpark();
+
+    /// Change the status of a running operation and wake up its waiters, along with the result
+    /// of a finished one. The status is only set once, later changes are ignored.
+    fn set_status(&self, status: CtxStatus, result: Option<Payload>) -> Result<(), Error> {
+        let wakers = {
+            let mut guard = self.0.inner.lock();
+            if !matches!(guard.status, CtxStatus::Running) {
+                return Ok(());
+            }
+            if let Some(result) = result {
+                self.0
+                    .payload
+                    .set(result)
+                    .map_err(|_| Error::SetCtxResultFailed)?;
+            }
+            guard.status = status;
+            mem::take(&mut guard.wakers)
+        };
+        for waker in wakers {
+            waker.wake();
         }
         Ok(())
     }
 
+    pub(crate) fn set_error(&self, cause: WorkCompletionStatus) {
+        // no result to set, so it never fails
+        let _: Result<(), Error> = self.set_status(CtxStatus::Failed(cause), None);
+    }
+    pub(crate) fn set_result(&self, result: Payload) -> Result<(), Error> {
+        self.set_status(CtxStatus::Finished, Some(result))
+    }
+
     /// Get the result of the operation.
     ///
     /// Returns `None` if the operation is not finished.
@@ -128,14 +153,25 @@ impl<Payload> OpCtx<Payload> {
         self.0.inner.lock().status
     }
 
-    pub(crate) fn set_handler(&self, handler: Box<dyn Fn(bool) + Send + Sync>){
+    /// Get the number of times the request has been retransmitted.
+    #[must_use]
+    pub fn retry_cnt(&self) -> u32 {
+        self.0.retry_cnt.load(Ordering::Relaxed)
+    }
+
+    /// The counter of retransmissions, which is shared with the retry monitor and the handler
+    pub(crate) fn retry_counter(&self) -> Arc<AtomicU32> {
+        Arc::clone(&self.0.retry_cnt)
+    }
+
+    pub(crate) fn set_handler(&self, handler: Box<dyn Fn(WorkCompletionStatus) + Send + Sync>){
         let mut guard = self.0
             .handler
             .lock();
         *guard = Some(handler);
     }
 
-    pub(crate) fn take_handler(&self) -> Option<Box<(dyn Fn(bool) + Send + Sync)>> {
+    pub(crate) fn take_handler(&self) -> Option<Box<(dyn Fn(WorkCompletionStatus) + Send + Sync)>> {
         let mut guard = self.0
             .handler
             .lock();
@@ -143,6 +179,20 @@ impl<Payload> OpCtx<Payload> {
     }
 }
 
+impl<Payload: Clone> Future for OpCtx<Payload> {
+    type Output = Result<Payload, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_done(cx).map(|status| match status {
+            CtxStatus::Finished => self.get_result().cloned().ok_or(Error::SetCtxResultFailed),
+            CtxStatus::Failed(cause) => Err(Error::OpFailed(cause)),
+            CtxStatus::Invalid | CtxStatus::Running => {
+                Err(Error::Invalid(format!("operation context status :{status:?}")))
+            }
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -166,4 +216,78 @@ mod tests {
         let _ = ctx.wait_result();
         assert_eq!(ctx.get_result(), Some(false).as_ref());
     }
+
+    #[test]
+    fn test_op_ctx_future() {
+        let ctx = super::OpCtx::new_running();
+        // many waiters, both blocking and async ones
+        let waiters: Vec<_> = (0..4)
+            .map(|i| {
+                let ctx_clone = ctx.clone();
+                std::thread::spawn(move || {
+                    if i % 2 == 0 {
+                        crate::utils::block_on(ctx_clone).unwrap()
+                    } else {
+                        ctx_clone.wait().unwrap();
+                        *ctx_clone.get_result().unwrap()
+                    }
+                })
+            })
+            .collect();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        ctx.set_result(7_u32).unwrap();
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), 7);
+        }
+        // a finished context is ready immediately
+        assert_eq!(crate::utils::block_on(ctx).unwrap(), 7);
+
+        let ctx = super::OpCtx::<u32>::new_running();
+        ctx.set_error(crate::cq::WorkCompletionStatus::RetryExceeded);
+        assert!(matches!(
+            crate::utils::block_on(ctx),
+            Err(crate::Error::OpFailed(crate::cq::WorkCompletionStatus::RetryExceeded))
+        ));
+    }
+
+    #[test]
+    fn test_op_ctx_status_set_once() {
+        use super::CtxStatus;
+        use crate::cq::WorkCompletionStatus;
+
+        let ctx = super::OpCtx::new_running();
+        ctx.set_result(1_u32).unwrap();
+        ctx.set_error(WorkCompletionStatus::DeviceClosed);
+        ctx.set_result(2_u32).unwrap();
+        assert!(matches!(ctx.status(), CtxStatus::Finished));
+        assert_eq!(ctx.get_result(), Some(&1));
+
+        let ctx = super::OpCtx::new_running();
+        ctx.set_error(WorkCompletionStatus::FlushError);
+        ctx.set_error(WorkCompletionStatus::DeviceClosed);
+        ctx.set_result(1_u32).unwrap();
+        assert!(matches!(
+            ctx.status(),
+            CtxStatus::Failed(WorkCompletionStatus::FlushError)
+        ));
+        assert_eq!(ctx.get_result(), None);
+    }
+
+    #[test]
+    fn test_device_futures_are_send() {
+        use crate::{
+            types::{Key, MemAccessTypeFlag, Qp, Qpn, Sge, WorkReqSendFlag},
+            Device, Pd,
+        };
+
+        fn assert_send<T: Send>(_: &T) {}
+        fn check(device: &Device, qp: &Qp, pd: Pd, sge: Sge) {
+            assert_send(&device.reg_mr_async(pd, 0, 0, 0, MemAccessTypeFlag::IbvAccessNoFlags));
+            assert_send(&device.create_qp_async(qp));
+            let flags = WorkReqSendFlag::empty();
+            assert_send(&device.write_async(Qpn::new(3), 0, Key::new(0), flags, sge, 0));
+            assert_send(&device.read_async(Qpn::new(3), 0, Key::new(0), flags, sge, 0));
+        }
+        let _: fn(&Device, &Qp, Pd, Sge) = check;
+    }
 }
@@ -0,0 +1,125 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use parking_lot::Mutex;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 0xFFFF;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+const ETH_TYPE_IP: [u8; 2] = [0x08, 0x00];
+
+/// The Ethernet header put in front of the IP packets sent by the software device,
+/// which doesn't know the MAC addresses.
+const DUMMY_ETH_HEADER: [u8; 14] = [
+    0, 0, 0, 0, 0, 0, // destination mac
+    0, 0, 0, 0, 0, 0, // source mac
+    ETH_TYPE_IP[0], ETH_TYPE_IP[1],
+];
+
+/// Writes the frames on the wire to a pcap file, which can be opened by Wireshark.
+///
+/// The RoCEv2 packets are recognized by their UDP destination port 4791.
+#[derive(Debug)]
+pub(crate) struct PcapWriter {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl PcapWriter {
+    /// Create the pcap file at `path` and write its global header.
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        file.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+        file.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+        file.write_all(&0_i32.to_le_bytes())?; // thiszone
+        file.write_all(&0_u32.to_le_bytes())?; // sigfigs
+        file.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        file.flush()?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record an Ethernet frame.
+    pub(crate) fn write_ethernet(&self, frame: &[u8]) {
+        self.write_record(&[frame]);
+    }
+
+    /// Record an IP packet, with a dummy Ethernet header in front of it.
+    pub(crate) fn write_ip(&self, packet: &[u8]) {
+        self.write_record(&[&DUMMY_ETH_HEADER, packet]);
+    }
+
+    fn write_record(&self, parts: &[&[u8]]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        #[allow(clippy::cast_possible_truncation)] // pcap timestamps are 32 bits
+        let ts_sec = now.as_secs() as u32;
+        let Ok(len) = u32::try_from(len) else {
+            error!("pcap: frame of {len} bytes is too long");
+            return;
+        };
+
+        let mut file = self.file.lock();
+        let result = (|| -> io::Result<()> {
+            file.write_all(&ts_sec.to_le_bytes())?;
+            file.write_all(&now.subsec_micros().to_le_bytes())?;
+            file.write_all(&len.to_le_bytes())?; // incl_len
+            file.write_all(&len.to_le_bytes())?; // orig_len
+            for part in parts {
+                file.write_all(part)?;
+            }
+            // flush every frame, so that the capture is complete even if the process crashes
+            file.flush()
+        })();
+        if let Err(e) = result {
+            error!("pcap: failed to write frame: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::PcapWriter;
+
+    #[test]
+    fn test_pcap_writer() {
+        let path = std::env::temp_dir().join(format!("pcap_writer_{}.pcap", std::process::id()));
+        let writer = PcapWriter::create(&path).unwrap();
+        writer.write_ethernet(&[0xaa; 20]);
+        writer.write_ip(&[0x45; 28]);
+        drop(writer);
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 24 + (16 + 20) + (16 + 14 + 28));
+        // global header
+        assert_eq!(data[0..4], 0xa1b2_c3d4_u32.to_le_bytes());
+        assert_eq!(data[4..6], 2_u16.to_le_bytes());
+        assert_eq!(data[6..8], 4_u16.to_le_bytes());
+        assert_eq!(data[20..24], 1_u32.to_le_bytes());
+        // the ethernet frame
+        assert_eq!(data[32..36], 20_u32.to_le_bytes());
+        assert_eq!(data[36..40], 20_u32.to_le_bytes());
+        assert_eq!(data[40..60], [0xaa; 20]);
+        // the ip packet with a dummy ethernet header
+        assert_eq!(data[68..72], 42_u32.to_le_bytes());
+        assert_eq!(data[72..76], 42_u32.to_le_bytes());
+        assert_eq!(data[76..88], [0; 12]);
+        assert_eq!(data[88..90], [0x08, 0x00]);
+        assert_eq!(data[90..118], [0x45; 28]);
+    }
+}
@@ -12,6 +12,51 @@ pub struct Pd {
     pub(crate) handle: u32,
 }
 
+/// A protection domain which is deallocated when dropped.
+///
+/// The memory regions and queue pairs created through it borrow the handle, so the protection
+/// domain always outlives them.
+#[derive(Debug)]
+pub struct PdHandle {
+    device: Device,
+    pd: Pd,
+}
+
+impl PdHandle {
+    /// Allocate a protection domain on `device`
+    ///
+    /// # Errors
+    ///
+    /// Same as `Device::alloc_pd`
+    pub fn new(device: &Device) -> Result<Self, Error> {
+        let pd = device.alloc_pd()?;
+        Ok(Self {
+            device: device.clone(),
+            pd,
+        })
+    }
+
+    /// Get the protection domain
+    #[must_use]
+    pub fn pd(&self) -> Pd {
+        self.pd
+    }
+
+    /// Get the device that the protection domain belongs to
+    #[must_use]
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl Drop for PdHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.device.dealloc_pd(self.pd) {
+            log::error!("failed to deallocate {:?}: {e}", self.pd);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct PdCtx {
     pub(crate) mr: HashSet<Mr>,
@@ -26,6 +71,7 @@ impl Device {
     /// Will return `Err` if:
     /// * lock poisoned
     pub fn alloc_pd(&self) -> Result<Pd, Error> {
+        self.check_open()?;
         let mut pool = self.0.pd.lock();
 
         let pd = Pd {
@@ -55,6 +101,9 @@ impl Device {
     /// * lock poisoned
     /// * invalid Pd
     /// * mr or qp is in use
+    ///
+    /// It also works on a closed device, whose mrs and qps are all removed, so the pds can be
+    /// released after closing, e.g. by dropping their `PdHandle`.
     pub fn dealloc_pd(&self, pd: Pd) -> Result<(), Error> {
         let mut pool = self.0.pd.lock();
         let pd_ctx = pool.get(&pd).ok_or(Error::Invalid(format!("PD :{pd:?}")))?;
@@ -1,21 +1,30 @@
 use atomic_enum::atomic_enum;
+use bitflags::bitflags;
 use eui48::MacAddress;
 
 use crate::{
-    device::{ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescQpManagement},
-    types::{MemAccessTypeFlag, Msn, Pmtu, Psn, Qp, QpType, Qpn},
-    Device, Error, Pd,
+    cq::{WorkCompletion, WorkCompletionOpcode, WorkCompletionStatus},
+    device::{
+        ToCardCtrlRbDesc, ToCardCtrlRbDescCommon, ToCardCtrlRbDescQpManagement, QP_MAX_CNT,
+    },
+    op_ctx::{CtxStatus, OpCtx},
+    retry::{RetryBackoff, RetryCancel, RetryEvent},
+    types::{
+        ImmNotification, MemAccessTypeFlag, Msn, Pmtu, Psn, Qp, QpAttr, QpType, Qpn, Sge,
+        DEFAULT_MIN_RNR_TIMER, IMM_NOTIFICATION_DEPTH, INFINITE_RNR_RETRY, MAX_MIN_RNR_TIMER,
+    },
+    utils::block_on,
+    Cq, Device, Error, Pd, PdHandle,
 };
 use std::{
-    hash::{Hash, Hasher},
+    collections::VecDeque,
     net::Ipv4Addr,
-    sync::atomic::{AtomicBool, AtomicU16, Ordering},
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
 };
 
+use flume::{bounded, Receiver, Sender};
 use parking_lot::Mutex;
 
-const QP_MAX_CNT: usize = 1024;
-
 /// The status of current QP
 #[atomic_enum]
 #[non_exhaustive]
@@ -39,6 +48,143 @@ impl Default for QpStatus {
     }
 }
 
+/// The state of a QP, as defined by the IB spec
+#[atomic_enum]
+#[non_exhaustive]
+#[derive(PartialEq, Eq)]
+pub enum QpState {
+    /// The QP is just created or reset, it can neither send nor receive
+    Reset = 0,
+    /// The QP is initialized, receive buffers can be posted
+    Init = 1,
+    /// Ready to receive
+    Rtr = 2,
+    /// Ready to send
+    Rts = 3,
+    /// Send queue drained, new send requests are not processed
+    Sqd = 4,
+    /// Send queue error
+    Sqe = 5,
+    /// The QP is in error, all the outstanding receive requests are flushed
+    Error = 6,
+}
+
+impl QpState {
+    /// Whether the transition from `self` to `next` is allowed
+    pub(crate) fn can_transit_to(self, next: QpState) -> bool {
+        matches!(
+            (self, next),
+            (_, QpState::Reset | QpState::Error)
+                | (QpState::Reset | QpState::Init, QpState::Init)
+                | (QpState::Init, QpState::Rtr)
+                | (QpState::Rtr | QpState::Rts | QpState::Sqd | QpState::Sqe, QpState::Rts)
+                | (QpState::Rts | QpState::Sqd, QpState::Sqd)
+        )
+    }
+
+    /// The attributes which can be modified in the transition from `self` to `next`
+    fn allowed_attrs(self, next: QpState) -> QpAttrMask {
+        match (self, next) {
+            (QpState::Reset | QpState::Init, QpState::Init) => {
+                QpAttrMask::RQ_ACC_FLAGS | QpAttrMask::QKEY
+            }
+            (QpState::Init, QpState::Rtr) => {
+                QpAttrMask::PEER_QPN
+                    | QpAttrMask::DQP_IP
+                    | QpAttrMask::DQP_MAC
+                    | QpAttrMask::PMTU
+                    | QpAttrMask::RQ_PSN
+                    | QpAttrMask::RQ_ACC_FLAGS
+                    | QpAttrMask::MIN_RNR_TIMER
+            }
+            (QpState::Rtr, QpState::Rts) => {
+                QpAttrMask::SQ_PSN
+                    | QpAttrMask::RETRY_CNT
+                    | QpAttrMask::RNR_RETRY
+                    | QpAttrMask::RETRY_BACKOFF
+                    | QpAttrMask::RQ_ACC_FLAGS
+                    | QpAttrMask::QKEY
+            }
+            (QpState::Sqd, QpState::Sqd) => {
+                QpAttrMask::PMTU
+                    | QpAttrMask::RETRY_CNT
+                    | QpAttrMask::RNR_RETRY
+                    | QpAttrMask::RETRY_BACKOFF
+                    | QpAttrMask::RQ_ACC_FLAGS
+                    | QpAttrMask::QKEY
+                    | QpAttrMask::MIN_RNR_TIMER
+            }
+            (QpState::Rts | QpState::Sqd | QpState::Sqe, QpState::Rts) => {
+                QpAttrMask::RQ_ACC_FLAGS | QpAttrMask::QKEY | QpAttrMask::MIN_RNR_TIMER
+            }
+            // moving to reset or error takes no attributes
+            _ => QpAttrMask::empty(),
+        }
+    }
+
+    /// Whether the QP can post send requests
+    pub(crate) fn can_send(self) -> bool {
+        matches!(self, QpState::Rts)
+    }
+
+    /// Whether the QP can post receive buffers
+    pub(crate) fn can_post_recv(self) -> bool {
+        !matches!(self, QpState::Reset | QpState::Error)
+    }
+
+    /// Whether the QP accepts the incoming packets
+    pub(crate) fn can_recv(self) -> bool {
+        matches!(
+            self,
+            QpState::Rtr | QpState::Rts | QpState::Sqd | QpState::Sqe
+        )
+    }
+}
+
+impl Default for QpState {
+    fn default() -> Self {
+        Self::Reset
+    }
+}
+
+bitflags! {
+    /// The attributes set in a `QpAttr`
+    #[derive(Debug, Clone, Copy)]
+    struct QpAttrMask: u32 {
+        const PEER_QPN = 1;
+        const DQP_IP = 1 << 1;
+        const DQP_MAC = 1 << 2;
+        const PMTU = 1 << 3;
+        const RQ_PSN = 1 << 4;
+        const SQ_PSN = 1 << 5;
+        const RETRY_CNT = 1 << 6;
+        const RNR_RETRY = 1 << 7;
+        const RQ_ACC_FLAGS = 1 << 8;
+        const QKEY = 1 << 9;
+        const MIN_RNR_TIMER = 1 << 10;
+        const RETRY_BACKOFF = 1 << 11;
+    }
+}
+
+impl From<&QpAttr> for QpAttrMask {
+    fn from(attr: &QpAttr) -> Self {
+        let mut mask = QpAttrMask::empty();
+        mask.set(QpAttrMask::PEER_QPN, attr.peer_qpn.is_some());
+        mask.set(QpAttrMask::DQP_IP, attr.dqp_ip.is_some());
+        mask.set(QpAttrMask::DQP_MAC, attr.dqp_mac.is_some());
+        mask.set(QpAttrMask::PMTU, attr.pmtu.is_some());
+        mask.set(QpAttrMask::RQ_PSN, attr.rq_psn.is_some());
+        mask.set(QpAttrMask::SQ_PSN, attr.sq_psn.is_some());
+        mask.set(QpAttrMask::RETRY_CNT, attr.retry_cnt.is_some());
+        mask.set(QpAttrMask::RNR_RETRY, attr.rnr_retry.is_some());
+        mask.set(QpAttrMask::RQ_ACC_FLAGS, attr.rq_acc_flags.is_some());
+        mask.set(QpAttrMask::QKEY, attr.qkey.is_some());
+        mask.set(QpAttrMask::MIN_RNR_TIMER, attr.min_rnr_timer.is_some());
+        mask.set(QpAttrMask::RETRY_BACKOFF, attr.retry_backoff.is_some());
+        mask
+    }
+}
+
 /// QP context
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug)]
@@ -56,18 +202,47 @@ pub struct QpContext {
     pub(crate) dqp_mac_addr: MacAddress,
     pub(crate) sending_psn: Mutex<Psn>,
     pub(crate) status: AtomicQpStatus,
+    pub(crate) state: AtomicQpState,
+    pub(crate) rq_psn: Psn,
+    pub(crate) retry_cnt: Option<u32>,
+    pub(crate) rnr_retry: Option<u32>,
+    /// The backoff policy of the retransmission, use the one of `RetryConfig` if it's `None`
+    pub(crate) retry_backoff: Option<RetryBackoff>,
+    /// The RNR NAK timer in the IB encoding, sent when there is no receive buffer
+    pub(crate) min_rnr_timer: u8,
+    pub(crate) qkey: u32,
+    /// Set when `rq_psn` changes, so that the packet checker drops the receiving contexts of the qp
+    pub(crate) recv_ctx_outdated: AtomicBool,
     pub(crate) _next_msn: AtomicU16,
+    pub(crate) recv_queue: Mutex<VecDeque<RecvWqe>>,
+    pub(crate) imm_sender: Sender<ImmNotification>,
+    pub(crate) imm_receiver: Receiver<ImmNotification>,
+    /// The number of immediate notifications dropped since the channel is full
+    pub(crate) imm_dropped: AtomicU64,
+    pub(crate) send_cq: Option<Cq>,
+    pub(crate) recv_cq: Option<Cq>,
+}
+
+/// A receive buffer posted by `post_recv`, waiting to be consumed by an incoming send.
+#[derive(Debug)]
+pub(crate) struct RecvWqe {
+    pub(crate) wr_id: u64,
+    pub(crate) sge: Sge,
+    /// The result is the length of the received message
+    pub(crate) ctx: OpCtx<u32>,
 }
 
 impl QpContext {
     /// create a qp context
     ///
-    /// currently, `sending_psn` is set to 0 at begining
+    /// The qp is in `Reset` state, `sending_psn` and `rq_psn` are set to the `sq_psn` and `rq_psn` of `qp`.
+    /// `qpn` is the number allocated to the qp, which overrides `qp.qpn`.
     #[must_use]
-    pub fn new(qp: &Qp, local_ip: Ipv4Addr, local_mac: MacAddress) -> Self {
+    pub(crate) fn new(qp: &Qp, qpn: Qpn, local_ip: Ipv4Addr, local_mac: MacAddress) -> Self {
+        let (imm_sender, imm_receiver) = bounded(IMM_NOTIFICATION_DEPTH);
         Self {
             pd: qp.pd,
-            qpn: qp.qpn,
+            qpn,
             peer_qpn: qp.peer_qpn,
             qp_type: qp.qp_type,
             rq_acc_flags: qp.rq_acc_flags,
@@ -76,19 +251,212 @@ impl QpContext {
             local_mac,
             dqp_ip: qp.dqp_ip,
             dqp_mac_addr: qp.dqp_mac,
-            sending_psn: Mutex::new(Psn::new(0)),
+            sending_psn: Mutex::new(qp.sq_psn),
             status: AtomicQpStatus::new(QpStatus::Normal),
+            state: AtomicQpState::new(QpState::Reset),
+            rq_psn: qp.rq_psn,
+            retry_cnt: None,
+            rnr_retry: None,
+            retry_backoff: None,
+            min_rnr_timer: DEFAULT_MIN_RNR_TIMER,
+            qkey: 0,
+            recv_ctx_outdated: AtomicBool::new(true),
             _next_msn: AtomicU16::default(),
+            recv_queue: Mutex::new(VecDeque::new()),
+            imm_sender,
+            imm_receiver,
+            imm_dropped: AtomicU64::new(0),
+            send_cq: qp.send_cq.clone(),
+            recv_cq: qp.recv_cq.clone(),
         }
     }
 
     pub(crate) fn next_msn(&self) -> Msn {
         Msn::new(self._next_msn.fetch_add(1, Ordering::Relaxed))
     }
+
+    /// Take the posted receive buffer which starts at `addr`.
+    ///
+    /// The card consumes the buffers in order, so the first matched one is taken.
+    pub(crate) fn take_recv_wqe(&self, addr: u64) -> Option<RecvWqe> {
+        let mut recv_queue = self.recv_queue.lock();
+        let idx = recv_queue.iter().position(|wqe| wqe.sge.addr == addr)?;
+        recv_queue.remove(idx)
+    }
+
+    /// Fail all the posted receive buffers with `status`, and report them to the receive CQ.
+    fn flush_recv_queue(&self, status: WorkCompletionStatus) {
+        let wqes: Vec<RecvWqe> = self.recv_queue.lock().drain(..).collect();
+        for wqe in wqes {
+            wqe.ctx.set_error(status);
+            if let Some(cq) = self.recv_cq.as_ref() {
+                cq.push(WorkCompletion {
+                    wr_id: wqe.wr_id,
+                    status,
+                    opcode: WorkCompletionOpcode::Recv,
+                    qpn: self.qpn,
+                    byte_len: 0,
+                    imm: None,
+                    src_qpn: None,
+                    src_ip: None,
+                    retry_cnt: 0,
+                });
+            }
+        }
+    }
+
+    /// Get the attributes and state of the qp
+    fn attr(&self) -> QpAttr {
+        QpAttr {
+            qp_state: self.state.load(Ordering::Acquire),
+            peer_qpn: Some(self.peer_qpn),
+            dqp_ip: Some(self.dqp_ip),
+            dqp_mac: Some(self.dqp_mac_addr),
+            pmtu: Some(self.pmtu),
+            rq_psn: Some(self.rq_psn),
+            sq_psn: Some(*self.sending_psn.lock()),
+            retry_cnt: self.retry_cnt,
+            rnr_retry: self.rnr_retry,
+            retry_backoff: self.retry_backoff,
+            min_rnr_timer: Some(self.min_rnr_timer),
+            rq_acc_flags: Some(self.rq_acc_flags),
+            qkey: Some(self.qkey),
+        }
+    }
+
+    /// Check the values of the attributes to be modified from `cur_state`
+    fn validate_attr(&self, cur_state: QpState, attr: &QpAttr) -> Result<(), Error> {
+        // a connected qp must know its peer before receiving, while a datagram carries the peer
+        let is_connecting = matches!((cur_state, attr.qp_state), (QpState::Init, QpState::Rtr))
+            && matches!(self.qp_type, QpType::Rc | QpType::Uc);
+        if is_connecting
+            && (attr.peer_qpn.is_none() || attr.rq_psn.is_none() || attr.pmtu.is_none())
+        {
+            return Err(Error::Invalid(format!(
+                "peer qpn, rq psn and pmtu are required from {cur_state:?} to {:?}",
+                attr.qp_state
+            )));
+        }
+        // by IB spec, the RNR timer is encoded in 5 bits, and the RNR retry in 3 bits
+        if let Some(min_rnr_timer) = attr.min_rnr_timer {
+            if min_rnr_timer > MAX_MIN_RNR_TIMER {
+                return Err(Error::Invalid(format!("min rnr timer :{min_rnr_timer}")));
+            }
+        }
+        if let Some(rnr_retry) = attr.rnr_retry {
+            if rnr_retry > INFINITE_RNR_RETRY {
+                return Err(Error::Invalid(format!("rnr retry :{rnr_retry}")));
+            }
+        }
+        if let Some(peer_qpn) = attr.peer_qpn {
+            // by IB spec, QP0 and QP1 are reserved
+            if peer_qpn.get() < 2 {
+                return Err(Error::Invalid(format!("peer qpn :{peer_qpn:?}")));
+            }
+        }
+        if let Some(dqp_ip) = attr.dqp_ip {
+            if dqp_ip.is_unspecified() || dqp_ip.is_broadcast() || dqp_ip.is_multicast() {
+                return Err(Error::Invalid(format!("destination ip :{dqp_ip:?}")));
+            }
+        }
+        if let Some(dqp_mac) = attr.dqp_mac {
+            if dqp_mac.is_nil() || !dqp_mac.is_unicast() {
+                return Err(Error::Invalid(format!("destination mac :{dqp_mac:?}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// The attributes of the qp after modified by `attr`, whose state is `attr.qp_state`
+    ///
+    /// Moving to `Reset` clears the psns, which should be given again when connecting.
+    fn modified_attr(&self, attr: &QpAttr) -> QpAttr {
+        let mut next = self.attr();
+        next.qp_state = attr.qp_state;
+        if matches!(attr.qp_state, QpState::Reset) {
+            next.sq_psn = Some(Psn::new(0));
+            next.rq_psn = Some(Psn::new(0));
+        }
+        next.peer_qpn = attr.peer_qpn.or(next.peer_qpn);
+        next.dqp_ip = attr.dqp_ip.or(next.dqp_ip);
+        next.dqp_mac = attr.dqp_mac.or(next.dqp_mac);
+        next.pmtu = attr.pmtu.or(next.pmtu);
+        next.rq_psn = attr.rq_psn.or(next.rq_psn);
+        next.sq_psn = attr.sq_psn.or(next.sq_psn);
+        next.retry_cnt = attr.retry_cnt.or(next.retry_cnt);
+        next.rnr_retry = attr.rnr_retry.or(next.rnr_retry);
+        next.retry_backoff = attr.retry_backoff.or(next.retry_backoff);
+        next.min_rnr_timer = attr.min_rnr_timer.or(next.min_rnr_timer);
+        next.rq_acc_flags = attr.rq_acc_flags.or(next.rq_acc_flags);
+        next.qkey = attr.qkey.or(next.qkey);
+        next
+    }
+
+    /// Apply the attributes, except the state
+    fn apply_attr(&mut self, attr: &QpAttr) {
+        if let Some(peer_qpn) = attr.peer_qpn {
+            self.peer_qpn = peer_qpn;
+        }
+        if let Some(dqp_ip) = attr.dqp_ip {
+            self.dqp_ip = dqp_ip;
+        }
+        if let Some(dqp_mac) = attr.dqp_mac {
+            self.dqp_mac_addr = dqp_mac;
+        }
+        if let Some(pmtu) = attr.pmtu {
+            self.pmtu = pmtu;
+        }
+        if let Some(rq_psn) = attr.rq_psn {
+            self.rq_psn = rq_psn;
+            self.recv_ctx_outdated.store(true, Ordering::Release);
+        }
+        if let Some(sq_psn) = attr.sq_psn {
+            *self.sending_psn.lock() = sq_psn;
+        }
+        if attr.retry_cnt.is_some() {
+            self.retry_cnt = attr.retry_cnt;
+        }
+        if attr.rnr_retry.is_some() {
+            self.rnr_retry = attr.rnr_retry;
+        }
+        if attr.retry_backoff.is_some() {
+            self.retry_backoff = attr.retry_backoff;
+        }
+        if let Some(rq_acc_flags) = attr.rq_acc_flags {
+            self.rq_acc_flags = rq_acc_flags;
+        }
+        if let Some(qkey) = attr.qkey {
+            self.qkey = qkey;
+        }
+        if let Some(min_rnr_timer) = attr.min_rnr_timer {
+            self.min_rnr_timer = min_rnr_timer;
+        }
+    }
+
+    fn management_desc(&self, op_id: u32, is_valid: bool) -> ToCardCtrlRbDesc {
+        self.management_desc_of(&self.attr(), op_id, is_valid)
+    }
+
+    /// The descriptor to update the qp on the card with the attributes `attr`
+    fn management_desc_of(&self, attr: &QpAttr, op_id: u32, is_valid: bool) -> ToCardCtrlRbDesc {
+        ToCardCtrlRbDesc::QpManagement(ToCardCtrlRbDescQpManagement {
+            common: ToCardCtrlRbDescCommon { op_id },
+            is_valid,
+            qpn: self.qpn,
+            pd_hdl: self.pd.handle,
+            qp_type: self.qp_type,
+            rq_acc_flags: attr.rq_acc_flags.unwrap_or(self.rq_acc_flags),
+            pmtu: attr.pmtu.unwrap_or(self.pmtu),
+            peer_qpn: attr.peer_qpn.unwrap_or(self.peer_qpn),
+            expected_psn: attr.rq_psn.unwrap_or(self.rq_psn),
+            qkey: attr.qkey.unwrap_or(self.qkey),
+        })
+    }
 }
 
 impl Default for QpContext {
     fn default() -> Self {
+        let (imm_sender, imm_receiver) = bounded(IMM_NOTIFICATION_DEPTH);
         Self {
             pd: Pd::default(),
             qpn: Default::default(),
@@ -102,65 +470,117 @@ impl Default for QpContext {
             dqp_mac_addr: Default::default(),
             sending_psn: Default::default(),
             status: AtomicQpStatus::new(QpStatus::Normal),
+            state: AtomicQpState::new(QpState::Reset),
+            rq_psn: Default::default(),
+            retry_cnt: None,
+            rnr_retry: None,
+            retry_backoff: None,
+            min_rnr_timer: DEFAULT_MIN_RNR_TIMER,
+            qkey: 0,
+            recv_ctx_outdated: AtomicBool::new(false),
             _next_msn: Default::default(),
+            recv_queue: Mutex::new(VecDeque::new()),
+            imm_sender,
+            imm_receiver,
+            imm_dropped: AtomicU64::new(0),
+            send_cq: None,
+            recv_cq: None,
         }
     }
 }
 
 impl Device {
-    /// create a qp
+    /// create a qp, and return its qp number
+    ///
+    /// The qp number is allocated by the device if `qp.qpn` is `None`, otherwise the given one
+    /// is used. The number is freed when the qp is destroyed.
+    ///
+    /// The qp is created in `Reset` state, use `modify_qp` to move it to `Rts` before using it.
     ///
     /// # Errors
     ///
     /// Will return `Err` if:
     /// * lock poisoned
+    /// * the given qp number is in use or out of range
+    /// * no qp number is available
     /// * opeartion failed
     /// * Operating system not support
     /// * Setted context result failed
-    pub fn create_qp(&self, qp: &Qp) -> Result<(), Error> {
-        let mut qp_pool = self.0.qp_table.write();
-        let mut pd_pool = self.0.pd.lock();
+    pub fn create_qp(&self, qp: &Qp) -> Result<Qpn, Error> {
+        block_on(self.create_qp_async(qp))
+    }
+
+    /// create a qp without blocking the thread while waiting for the card
+    ///
+    /// # Errors
+    ///
+    /// Same as `create_qp`
+    pub async fn create_qp_async(&self, qp: &Qp) -> Result<Qpn, Error> {
+        self.check_open()?;
+        let qpn = match qp.qpn {
+            Some(qpn) => {
+                if !self.0.qp_manager.reserve(qpn) {
+                    return Err(Error::Invalid(format!("qp :{qpn:?}")));
+                }
+                qpn
+            }
+            None => self.0.qp_manager.alloc()?,
+        };
+        let mut guard = CreateQpGuard {
+            dev: self,
+            pd: qp.pd,
+            qpn,
+            is_in_table: false,
+            is_on_card: false,
+            is_created: false,
+        };
+        self.create_qp_with_qpn(qp, &mut guard).await?;
+        guard.is_created = true;
+        Ok(qpn)
+    }
+
+    async fn create_qp_with_qpn(
+        &self,
+        qp: &Qp,
+        guard: &mut CreateQpGuard<'_>,
+    ) -> Result<(), Error> {
         let pd = &qp.pd;
-        let pd_ctx = pd_pool
-            .get_mut(pd)
-            .ok_or(Error::Invalid(format!("PD :{pd:?}")))?;
-
-        let qpc = QpContext::new(
-            qp,
-            self.0.local_network.ipaddr,
-            self.0.local_network.macaddr,
-        );
-        let op_id = self.get_ctrl_op_id();
+        let qpn = guard.qpn;
+        // The slot is reserved before the card is updated, so that the locks are not held
+        // while waiting. The qp stays in `Reset` state until it is modified.
+        let (op_id, desc) = {
+            let mut qp_pool = self.0.qp_table.write();
+            let mut pd_pool = self.0.pd.lock();
+            let pd_ctx = pd_pool
+                .get_mut(pd)
+                .ok_or(Error::Invalid(format!("PD :{pd:?}")))?;
+            if qp_pool.contains_key(&qpn) || !pd_ctx.qp.insert(qpn) {
+                return Err(Error::Invalid(format!("qp :{qpn:?}")));
+            }
 
-        let desc = ToCardCtrlRbDesc::QpManagement(ToCardCtrlRbDescQpManagement {
-            common: ToCardCtrlRbDescCommon { op_id },
-            is_valid: true,
-            qpn: qp.qpn,
-            pd_hdl: qp.pd.handle,
-            qp_type: qp.qp_type,
-            rq_acc_flags: qp.rq_acc_flags,
-            pmtu: qp.pmtu,
-            peer_qpn: qp.peer_qpn
-        });
+            let qpc = QpContext::new(
+                qp,
+                qpn,
+                self.0.local_network.ipaddr,
+                self.0.local_network.macaddr,
+            );
+            let op_id = self.get_ctrl_op_id();
+            let desc = qpc.management_desc(op_id, true);
+            let _: Option<QpContext> = qp_pool.insert(qpn, qpc);
+            guard.is_in_table = true;
+            (op_id, desc)
+        };
 
         let ctx = self.do_ctrl_op(op_id, desc)?;
+        guard.is_on_card = true;
 
-        let res = ctx.wait_result()?.ok_or(Error::SetCtxResultFailed)?;
+        let res = ctx.await?;
 
         if !res {
+            guard.is_on_card = false;
             return Err(Error::DeviceReturnFailed("create qp"));
         }
 
-        let pd_res = pd_ctx.qp.insert(qp.qpn);
-        if !pd_res {
-            return Err(Error::Invalid(format!("qp :{0:?}", qp.qpn)));
-        }
-
-        let qp_res = qp_pool.insert(qp.qpn, qpc);
-        if qp_res.is_some() {
-            return Err(Error::Invalid(format!("qp :{0:?}", qp.qpn)));
-        }
-
         Ok(())
     }
 
@@ -173,6 +593,7 @@ impl Device {
     /// * opeartion failed
     /// * Setted context result failed
     pub fn destroy_qp(&self, qp: Qpn) -> Result<(), Error> {
+        self.check_open()?;
         let mut qp_pool = self.0.qp_table.write();
         let mut pd_pool = self.0.pd.lock();
 
@@ -190,7 +611,9 @@ impl Device {
                 qp_type: qp_ctx.qp_type,
                 rq_acc_flags: MemAccessTypeFlag::IbvAccessNoFlags,
                 pmtu: qp_ctx.pmtu,
-                peer_qpn: qp_ctx.peer_qpn
+                peer_qpn: qp_ctx.peer_qpn,
+                expected_psn: qp_ctx.rq_psn,
+                qkey: qp_ctx.qkey,
             });
             (pd_ctx, desc)
         } else {
@@ -207,30 +630,323 @@ impl Device {
 
         let _: bool = pd_ctx.qp.remove(&qp);
         let _: Option<QpContext> = qp_pool.remove(&qp);
+        self.0.qp_manager.free(qp);
+
+        Ok(())
+    }
+
+    /// Modify the state and attributes of a qp
+    ///
+    /// The transitions and the attributes allowed in each of them follow the IB spec:
+    /// * `Reset` -> `Init`: `rq_acc_flags`, `qkey`
+    /// * `Init` -> `Rtr`: `peer_qpn`, `dqp_ip`, `dqp_mac`, `pmtu`, `rq_psn`, `rq_acc_flags`,
+    ///   `min_rnr_timer`, of which `peer_qpn`, `rq_psn` and `pmtu` are required by an RC or UC qp
+    /// * `Rtr` -> `Rts`: `sq_psn`, `retry_cnt`, `rnr_retry`, `retry_backoff`, `rq_acc_flags`,
+    ///   `qkey`
+    /// * `Rts` -> `Sqd`, `Sqd` -> `Rts`, `Sqe` -> `Rts`
+    /// * any state -> `Reset` or `Error`
+    ///
+    /// Moving to `Reset` or `Error` flushes the posted receive buffers and fails the outstanding requests.
+    /// The qp is left unchanged if the device fails to update it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * the qp does not exist
+    /// * the transition is not allowed, or an attribute is not allowed in the transition
+    /// * a required attribute of the transition is missing
+    /// * the value of an attribute is invalid
+    /// * failed to update the qp on the device
+    pub fn modify_qp(&self, qpn: Qpn, attr: &QpAttr) -> Result<(), Error> {
+        self.check_open()?;
+        let _modifying = self.0.qp_modify_lock.lock();
+        let next_state = attr.qp_state;
+        let mask = QpAttrMask::from(attr);
+        // The modified attributes are pushed to the card before being applied, so the qp is
+        // left unchanged if the card fails, and the table is not locked while waiting for it.
+        let (cur_state, descs) = {
+            let qp_pool = self.0.qp_table.read();
+            let qp_ctx = qp_pool
+                .get(&qpn)
+                .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+            let cur_state = qp_ctx.state.load(Ordering::Acquire);
+            if !cur_state.can_transit_to(next_state) {
+                return Err(Error::InvalidQpStateTransition(cur_state, next_state));
+            }
+            let not_allowed = mask.difference(cur_state.allowed_attrs(next_state));
+            if !not_allowed.is_empty() {
+                return Err(Error::Invalid(format!(
+                    "qp attributes {not_allowed:?} from {cur_state:?} to {next_state:?}"
+                )));
+            }
+            qp_ctx.validate_attr(cur_state, attr)?;
+            let next_attr = qp_ctx.modified_attr(attr);
 
+            let is_reset = matches!(next_state, QpState::Reset) && !matches!(cur_state, QpState::Reset);
+            let mut descs = vec![];
+            if is_reset {
+                // recreate the qp on the device to drop everything it holds
+                for is_valid in [false, true] {
+                    let op_id = self.get_ctrl_op_id();
+                    descs.push((op_id, qp_ctx.management_desc_of(&next_attr, op_id, is_valid)));
+                }
+            } else if mask.intersects(
+                QpAttrMask::PEER_QPN
+                    | QpAttrMask::PMTU
+                    | QpAttrMask::RQ_PSN
+                    | QpAttrMask::RQ_ACC_FLAGS
+                    | QpAttrMask::QKEY,
+            ) {
+                let op_id = self.get_ctrl_op_id();
+                descs.push((op_id, qp_ctx.management_desc_of(&next_attr, op_id, true)));
+            } else {
+                // the device holds nothing changed
+            }
+            (cur_state, descs)
+        };
+        for (op_id, desc) in descs {
+            self.update_qp_on_card(op_id, desc)?;
+        }
+
+        {
+            let mut qp_pool = self.0.qp_table.write();
+            let qp_ctx = qp_pool
+                .get_mut(&qpn)
+                .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))?;
+            qp_ctx.apply_attr(attr);
+            if matches!(next_state, QpState::Reset) && !matches!(cur_state, QpState::Reset) {
+                *qp_ctx.sending_psn.lock() = Psn::new(0);
+                qp_ctx.rq_psn = Psn::new(0);
+                qp_ctx.recv_ctx_outdated.store(true, Ordering::Release);
+                qp_ctx.status.store(QpStatus::Normal, Ordering::Release);
+            }
+            if matches!(next_state, QpState::Reset | QpState::Error) {
+                qp_ctx.flush_recv_queue(WorkCompletionStatus::FlushError);
+            }
+            qp_ctx.state.store(next_state, Ordering::Release);
+        }
+        if matches!(next_state, QpState::Reset | QpState::Error) {
+            self.flush_user_op_ctx(qpn)?;
+        }
         Ok(())
     }
+
+    /// Query the state and attributes of a qp
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the qp does not exist, or the device is closed.
+    pub fn query_qp(&self, qpn: Qpn) -> Result<QpAttr, Error> {
+        self.check_open()?;
+        self.0
+            .qp_table
+            .read()
+            .get(&qpn)
+            .map(QpContext::attr)
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))
+    }
+
+    fn update_qp_on_card(&self, op_id: u32, desc: ToCardCtrlRbDesc) -> Result<(), Error> {
+        let ctx = self.do_ctrl_op(op_id, desc)?;
+        let res = ctx.wait_result()?.ok_or(Error::SetCtxResultFailed)?;
+        if !res {
+            return Err(Error::DeviceReturnFailed("modify qp"));
+        }
+        Ok(())
+    }
+
+    /// Fail the outstanding requests of a qp, and stop retrying them
+    fn flush_user_op_ctx(&self, qpn: Qpn) -> Result<(), Error> {
+        let mut removed = vec![];
+        self.0.user_op_ctx_map.write().retain(|key, ctx| {
+            if key.0 != qpn {
+                return true;
+            }
+            removed.push((key.1, ctx.clone()));
+            false
+        });
+        self.0.read_resp_map.write().retain(|key, _| key.0 != qpn);
+        // a request finishing meanwhile keeps its result, and is not retried anymore
+        let flushed: Vec<Msn> = removed
+            .into_iter()
+            .filter(|(_, ctx)| matches!(ctx.status(), CtxStatus::Running))
+            .map(|(msn, ctx)| {
+                if let Some(handler) = ctx.take_handler() {
+                    handler(WorkCompletionStatus::FlushError);
+                }
+                ctx.set_error(WorkCompletionStatus::FlushError);
+                msn
+            })
+            .collect();
+        if let Some(monitor) = self.0.retry_monitor.read().as_ref() {
+            for msn in flushed {
+                monitor.subscribe(RetryEvent::Cancel(RetryCancel::new(qpn, msn)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all the qps from the card, and fail their posted receive buffers with
+    /// `WorkCompletionStatus::DeviceClosed`. It's called when the device is closed.
+    ///
+    /// Every qp is removed even if removing a previous one fails, and the first error is returned.
+    pub(crate) fn close_qps(&self) -> Result<(), Error> {
+        let qps: Vec<QpContext> = self.0.qp_table.write().drain().map(|(_, qp)| qp).collect();
+        for pd_ctx in self.0.pd.lock().values_mut() {
+            pd_ctx.qp.clear();
+        }
+        let mut result = Ok(());
+        for qp_ctx in qps {
+            self.0.qp_manager.free(qp_ctx.qpn);
+            qp_ctx.flush_recv_queue(WorkCompletionStatus::DeviceClosed);
+            let op_id = self.get_ctrl_op_id();
+            let is_success = self
+                .push_ctrl_op(op_id, qp_ctx.management_desc(op_id, false))
+                .and_then(|ctx| ctx.wait_result()?.copied().ok_or(Error::SetCtxResultFailed));
+            if result.is_ok() && !matches!(is_success, Ok(true)) {
+                result = Err(is_success.err().unwrap_or(Error::DeviceReturnFailed("destroy qp")));
+            }
+        }
+        result
+    }
+
+    /// Get the notification stream of RDMA write with immediate received by a qp
+    ///
+    /// Each notification is yielded after the whole message has landed in the local memory.
+    /// The notifications of a qp are shared by all of its receivers. At most 1024 notifications
+    /// are held, the later ones are dropped and counted by `imm_notification_dropped`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the qp does not exist.
+    pub fn imm_notification_receiver(&self, qpn: Qpn) -> Result<Receiver<ImmNotification>, Error> {
+        self.0
+            .qp_table
+            .read()
+            .get(&qpn)
+            .map(|qp| qp.imm_receiver.clone())
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))
+    }
+
+    /// Get the number of immediate notifications of a qp dropped since nobody received them in time
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the qp does not exist.
+    pub fn imm_notification_dropped(&self, qpn: Qpn) -> Result<u64, Error> {
+        self.0
+            .qp_table
+            .read()
+            .get(&qpn)
+            .map(|qp| qp.imm_dropped.load(Ordering::Relaxed))
+            .ok_or(Error::Invalid(format!("Qpn :{qpn:?}")))
+    }
+}
+
+/// Undo the creation of a qp which is failed, or cancelled while waiting for the card.
+struct CreateQpGuard<'a> {
+    dev: &'a Device,
+    pd: Pd,
+    /// The qp number reserved or allocated for the qp, which is freed on failure
+    qpn: Qpn,
+    /// Whether the qp is in the qp table and its pd
+    is_in_table: bool,
+    /// Whether the card may have created the qp
+    is_on_card: bool,
+    is_created: bool,
+}
+
+impl Drop for CreateQpGuard<'_> {
+    fn drop(&mut self) {
+        if self.is_created {
+            return;
+        }
+        if !self.is_in_table {
+            self.dev.0.qp_manager.free(self.qpn);
+            return;
+        }
+        if let Some(pd_ctx) = self.dev.0.pd.lock().get_mut(&self.pd) {
+            let _: bool = pd_ctx.qp.remove(&self.qpn);
+        }
+        // closing the device may have removed it and freed the qp number
+        let Some(qp_ctx) = self.dev.0.qp_table.write().remove(&self.qpn) else {
+            return;
+        };
+        if self.is_on_card {
+            // nobody waits for the result. The destroy is queued before any later creation
+            // which reuses the qp number.
+            let op_id = self.dev.get_ctrl_op_id();
+            if let Err(e) = self.dev.push_ctrl_op(op_id, qp_ctx.management_desc(op_id, false)) {
+                log::error!("failed to destroy qp {:?}: {e}", self.qpn);
+            }
+        }
+        self.dev.0.qp_manager.free(self.qpn);
+    }
+}
+
+/// A queue pair which is destroyed when dropped.
+///
+/// It borrows the `PdHandle` it is created in, so the protection domain can not be dropped
+/// before it.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Debug)]
+pub struct QpHandle<'pd> {
+    pd: &'pd PdHandle,
+    qpn: Qpn,
 }
 
-impl Hash for Qp {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.qpn.hash(state);
+impl QpHandle<'_> {
+    /// Get the queue pair number
+    #[must_use]
+    pub fn qpn(&self) -> Qpn {
+        self.qpn
+    }
+
+    /// Get the protection domain that the queue pair is created in
+    #[must_use]
+    pub fn pd(&self) -> &PdHandle {
+        self.pd
     }
 }
 
-impl PartialEq for Qp {
-    fn eq(&self, other: &Self) -> bool {
-        self.qpn == other.qpn
+impl Drop for QpHandle<'_> {
+    fn drop(&mut self) {
+        match self.pd.device().destroy_qp(self.qpn) {
+            // closing the device has destroyed it
+            Ok(()) | Err(Error::DeviceClosed) => {}
+            Err(e) => log::error!("failed to destroy qp {:?}: {e}", self.qpn),
+        }
     }
 }
 
-impl Eq for Qp {}
+impl PdHandle {
+    /// Create a queue pair in the protection domain, which is destroyed when the returned handle
+    /// is dropped.
+    ///
+    /// The qp number is allocated by the device if `qp.qpn` is `None`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if:
+    /// * `qp.pd` is not the protection domain of the handle
+    /// * failed to create the qp, see `Device::create_qp`
+    pub fn create_qp(&self, qp: &Qp) -> Result<QpHandle<'_>, Error> {
+        if qp.pd != self.pd() {
+            return Err(Error::Invalid(format!("PD :{:?}", qp.pd)));
+        }
+        let qpn = self.device().create_qp(qp)?;
+        Ok(QpHandle { pd: self, qpn })
+    }
+}
 
 /// QP manager
 ///
 /// The QP manager is used to allocate and free QP numbers(QPN).
 ///
-/// The max QP number is `QP_MAX_CNT`, and QP0 and QP1 are reserved.
+/// The max QP number is `QP_MAX_CNT` by default, and QP0 and QP1 are reserved.
+///
+/// Each `Device` owns a QP manager sized by the number of QPs its adaptor supports, which
+/// allocates the QPN in `create_qp` if the caller does not choose one.
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug)]
 pub struct QpManager {
@@ -241,14 +957,18 @@ impl QpManager {
     /// create a QP manager
     #[must_use]
     pub fn new() -> Self {
+        Self::with_capacity(QP_MAX_CNT)
+    }
+
+    /// create a QP manager which manages the qp numbers below `max_qp_cnt`
+    #[must_use]
+    pub fn with_capacity(max_qp_cnt: usize) -> Self {
         let qp_availability: Vec<AtomicBool> =
-            (0..QP_MAX_CNT).map(|_| AtomicBool::new(true)).collect();
+            (0..max_qp_cnt).map(|_| AtomicBool::new(true)).collect();
 
         // by IB spec, QP0 and QP1 are reserved, so qpn should start with 2
-        #[allow(clippy::indexing_slicing)]
-        {
-            qp_availability[0].store(false, Ordering::Relaxed);
-            qp_availability[1].store(false, Ordering::Relaxed);
+        for reserved in qp_availability.iter().take(2) {
+            reserved.store(false, Ordering::Relaxed);
         }
 
         Self {
@@ -275,6 +995,15 @@ impl QpManager {
             .ok_or_else(|| Error::ResourceNoAvailable("QP".to_owned()))
     }
 
+    /// allocate the given qp number
+    ///
+    /// Return `false` if the qp number is in use, reserved or out of range.
+    pub fn reserve(&self, qpn: Qpn) -> bool {
+        self.qp_availability
+            .get(qpn.get() as usize)
+            .is_some_and(|n| n.swap(false, Ordering::AcqRel))
+    }
+
     /// free a qp number
     ///
     /// If the QP number is not allocated, it will be ignored.
@@ -290,3 +1019,194 @@ impl Default for QpManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use eui48::MacAddress;
+
+    use crate::{
+        cq::{WorkCompletion, WorkCompletionStatus},
+        op_ctx::{CtxStatus, OpCtx},
+        retry::RetryBackoff,
+        types::{Key, Pmtu, QpAttr, QpAttrBuilder, QpType, Qpn, Sge},
+        Cq,
+    };
+
+    use super::{QpAttrMask, QpContext, QpManager, QpState, RecvWqe};
+
+    #[test]
+    fn test_qp_manager() {
+        let manager = QpManager::with_capacity(4);
+        assert!(!manager.reserve(Qpn::new(0)));
+        assert!(!manager.reserve(Qpn::new(1)));
+        assert!(manager.reserve(Qpn::new(3)));
+        assert!(!manager.reserve(Qpn::new(3)));
+        assert!(!manager.reserve(Qpn::new(4)));
+        assert_eq!(manager.alloc().unwrap().get(), 2);
+        assert!(manager.alloc().is_err());
+
+        manager.free(Qpn::new(3));
+        assert_eq!(manager.alloc().unwrap().get(), 3);
+        // freeing a qp number out of range is ignored
+        manager.free(Qpn::new(4));
+        assert!(manager.alloc().is_err());
+    }
+
+    #[test]
+    fn test_qp_state_transition() {
+        let connect = [QpState::Reset, QpState::Init, QpState::Rtr, QpState::Rts];
+        for pair in connect.windows(2) {
+            if let [cur, next] = pair {
+                assert!(cur.can_transit_to(*next));
+            }
+        }
+        assert!(QpState::Rts.can_transit_to(QpState::Sqd));
+        assert!(QpState::Sqd.can_transit_to(QpState::Rts));
+        assert!(QpState::Sqe.can_transit_to(QpState::Rts));
+        assert!(QpState::Error.can_transit_to(QpState::Reset));
+        assert!(QpState::Rtr.can_transit_to(QpState::Error));
+
+        assert!(!QpState::Reset.can_transit_to(QpState::Rtr));
+        assert!(!QpState::Init.can_transit_to(QpState::Rts));
+        assert!(!QpState::Error.can_transit_to(QpState::Rts));
+        assert!(!QpState::Rts.can_transit_to(QpState::Init));
+
+        assert!(QpState::Rts.can_send());
+        assert!(!QpState::Rtr.can_send());
+        assert!(QpState::Rtr.can_recv());
+        assert!(!QpState::Init.can_recv());
+        assert!(QpState::Init.can_post_recv());
+        assert!(!QpState::Reset.can_post_recv());
+    }
+
+    #[test]
+    fn test_qp_attr_mask() {
+        let attr = QpAttrBuilder::default()
+            .qp_state(QpState::Rtr)
+            .peer_qpn(Qpn::new(3))
+            .dqp_ip(Ipv4Addr::new(192, 168, 0, 2))
+            .rq_psn(crate::types::Psn::new(100))
+            .build()
+            .unwrap();
+        let mask = QpAttrMask::from(&attr);
+        assert!(QpState::Init.allowed_attrs(QpState::Rtr).contains(mask));
+        // the destination can only be set when moving to RTR
+        assert!(!QpState::Rtr.allowed_attrs(QpState::Rts).contains(mask));
+        assert!(QpState::Rts.allowed_attrs(QpState::Error).is_empty());
+
+        // the qkey of a UD qp is set when moving to INIT or RTS
+        let attr = QpAttrBuilder::default().qkey(0x1111).build().unwrap();
+        let mask = QpAttrMask::from(&attr);
+        assert!(QpState::Reset.allowed_attrs(QpState::Init).contains(mask));
+        assert!(QpState::Rtr.allowed_attrs(QpState::Rts).contains(mask));
+        assert!(!QpState::Init.allowed_attrs(QpState::Rtr).contains(mask));
+    }
+
+    #[test]
+    fn test_qp_validate_and_apply_attr() {
+        let invalid_attrs = [
+            QpAttrBuilder::default().peer_qpn(Qpn::new(1)).build().unwrap(),
+            QpAttrBuilder::default()
+                .dqp_ip(Ipv4Addr::UNSPECIFIED)
+                .build()
+                .unwrap(),
+            QpAttrBuilder::default()
+                .dqp_mac(MacAddress::nil())
+                .build()
+                .unwrap(),
+        ];
+        let mut qp = QpContext::default();
+        for attr in &invalid_attrs {
+            assert!(qp.validate_attr(QpState::Rtr, attr).is_err());
+        }
+
+        // a connected qp requires its peer when moving to RTR
+        let mut builder = QpAttrBuilder::default();
+        let _: &mut QpAttrBuilder = builder
+            .qp_state(QpState::Rtr)
+            .peer_qpn(Qpn::new(2))
+            .rq_psn(crate::types::Psn::new(0));
+        assert!(qp.validate_attr(QpState::Init, &builder.build().unwrap()).is_err());
+        let connect = builder.pmtu(Pmtu::Mtu1024).build().unwrap();
+        assert!(qp.validate_attr(QpState::Init, &connect).is_ok());
+        let ud_qp = QpContext {
+            qp_type: QpType::Ud,
+            ..Default::default()
+        };
+        let datagram = QpAttrBuilder::default().qp_state(QpState::Rtr).build().unwrap();
+        assert!(ud_qp.validate_attr(QpState::Init, &datagram).is_ok());
+
+        // the RNR timer and retry should fit in their IB encodings
+        let rnr = |timer, retry| {
+            let attr = QpAttrBuilder::default()
+                .qp_state(QpState::Rts)
+                .min_rnr_timer(timer)
+                .rnr_retry(retry)
+                .build()
+                .unwrap();
+            qp.validate_attr(QpState::Rtr, &attr)
+        };
+        assert!(rnr(31, 7).is_ok());
+        assert!(rnr(32, 7).is_err());
+        assert!(rnr(31, 8).is_err());
+
+        let attr = QpAttrBuilder::default()
+            .qp_state(QpState::Rts)
+            .sq_psn(crate::types::Psn::new(1000))
+            .retry_cnt(3)
+            .pmtu(Pmtu::Mtu1024)
+            .qkey(0x1111)
+            .min_rnr_timer(14)
+            .retry_backoff(RetryBackoff::Exponential { max: Duration::from_secs(1) })
+            .build()
+            .unwrap();
+        assert!(qp.validate_attr(QpState::Rtr, &attr).is_ok());
+        qp.apply_attr(&attr);
+        let QpAttr {
+            qp_state,
+            sq_psn,
+            retry_cnt,
+            rnr_retry,
+            pmtu,
+            qkey,
+            min_rnr_timer,
+            retry_backoff,
+            ..
+        } = qp.attr();
+        // the state is changed by `modify_qp` only
+        assert!(matches!(qp_state, QpState::Reset));
+        assert_eq!(sq_psn.unwrap().get(), 1000);
+        assert_eq!(retry_cnt, Some(3));
+        assert_eq!(rnr_retry, None);
+        assert!(matches!(pmtu, Some(Pmtu::Mtu1024)));
+        assert_eq!(qkey, Some(0x1111));
+        assert_eq!(min_rnr_timer, Some(14));
+        assert_eq!(retry_backoff, Some(RetryBackoff::Exponential { max: Duration::from_secs(1) }));
+    }
+
+    #[test]
+    fn test_qp_flush_recv_queue() {
+        let cq = Cq::new(4);
+        let qp = QpContext {
+            qpn: Qpn::new(3),
+            recv_cq: Some(cq.clone()),
+            ..Default::default()
+        };
+        let ctx = OpCtx::new_running();
+        qp.recv_queue.lock().push_back(RecvWqe {
+            wr_id: 7,
+            sge: Sge::new(0x1000, 64, Key::new(1)),
+            ctx: ctx.clone(),
+        });
+        qp.flush_recv_queue(WorkCompletionStatus::FlushError);
+
+        assert!(qp.recv_queue.lock().is_empty());
+        assert!(matches!(ctx.status(), CtxStatus::Failed(_)));
+        let mut wc = [WorkCompletion::default(); 2];
+        assert_eq!(cq.poll(&mut wc).unwrap(), 1);
+        assert_eq!(wc[0].wr_id, 7);
+        assert_eq!(wc[0].status, WorkCompletionStatus::FlushError);
+    }
+}
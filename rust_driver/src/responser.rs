@@ -8,7 +8,8 @@ use eui48::MacAddress;
 
 use crate::device::{
     ToCardWorkRbDesc, ToCardWorkRbDescBuilder, ToCardWorkRbDescCommon, ToCardWorkRbDescOpcode,
-    ToHostWorkRbDescAethCode, ToHostWorkRbDescOpcode, ToHostWorkRbDescRead,
+    ToHostWorkRbDescAethCode, ToHostWorkRbDescNakCode, ToHostWorkRbDescOpcode,
+    ToHostWorkRbDescRead,
 };
 use crate::utils::calculate_packet_cnt;
 use crate::{Error, Sge, ThreadSafeHashmap};
@@ -23,11 +24,17 @@ pub(crate) fn make_ack(
     msn: Msn,
     psn: Psn,
 ) -> Result<Box<ToCardWorkRbDesc>, Error> {
-    make_ack_or_nack(ack_buf, qp_table, qpn, msn, psn, None)
+    make_ack_packet(ack_buf, qp_table, qpn, msn, psn, AckExtension::Nreth(None))
 }
 
 /// make a nack packet in the buffer, and return a work descriptor
 ///
+/// As the IB spec, the psn of a PSN sequence error NAK is the expected psn of the responder,
+/// from which the requester should retransmit. The psn of other NAKs is the one of the bad request.
+/// A PSN sequence error NAK with `last_retry_psn` asks for the packets from `psn` to it only.
+/// It's carried in the NRETH, while the AETH is the same as the IB spec, so a requester which
+/// ignores it retransmits all the packets from `psn`. Without it, the NRETH carries the psn
+/// before `psn`, which asks for all the packets as well.
 /// The slot can be allocated by `PacketBuf::recycle_buf`
 pub(crate) fn make_nack(
     ack_buf: Slot<RDMA_ACK_BUFFER_SLOT_SIZE>,
@@ -35,19 +42,85 @@ pub(crate) fn make_nack(
     qpn: Qpn,
     msn: Msn,
     psn: Psn,
-    expected_psn: Psn,
+    last_retry_psn: Option<Psn>,
+    code: ToHostWorkRbDescNakCode,
 ) -> Result<Box<ToCardWorkRbDesc>, Error> {
-    make_ack_or_nack(ack_buf, qp_table, qpn, msn, psn, Some(expected_psn))
+    let last_retry_psn = last_retry_psn.unwrap_or_else(|| psn.wrapping_sub(1));
+    make_ack_packet(
+        ack_buf,
+        qp_table,
+        qpn,
+        msn,
+        psn,
+        AckExtension::Nreth(Some((last_retry_psn, ToHostWorkRbDescAethCode::Nak, code as u8))),
+    )
+}
+
+/// make an RNR nack packet in the buffer, and return a work descriptor
+///
+/// The psn is the one of the request which finds no receive buffer, from which the requester
+/// should retransmit after the time of `rnr_timer`, in the 5 bits encoding of the IB spec.
+/// The slot can be allocated by `PacketBuf::recycle_buf`
+pub(crate) fn make_rnr_nack(
+    ack_buf: Slot<RDMA_ACK_BUFFER_SLOT_SIZE>,
+    qp_table: &ThreadSafeHashmap<Qpn, QpContext>,
+    qpn: Qpn,
+    msn: Msn,
+    psn: Psn,
+    rnr_timer: u8,
+) -> Result<Box<ToCardWorkRbDesc>, Error> {
+    make_ack_packet(
+        ack_buf,
+        qp_table,
+        qpn,
+        msn,
+        psn,
+        AckExtension::Nreth(Some((psn, ToHostWorkRbDescAethCode::Rnr, rnr_timer))),
+    )
+}
+
+/// make an atomic ack packet in the buffer, and return a work descriptor
+///
+/// `orig` is the original value of the remote memory before the atomic operation.
+/// The slot can be allocated by `PacketBuf::recycle_buf`
+pub(crate) fn make_atomic_ack(
+    ack_buf: Slot<RDMA_ACK_BUFFER_SLOT_SIZE>,
+    qp_table: &ThreadSafeHashmap<Qpn, QpContext>,
+    qpn: Qpn,
+    msn: Msn,
+    psn: Psn,
+    orig: u64,
+) -> Result<Box<ToCardWorkRbDesc>, Error> {
+    make_ack_packet(ack_buf, qp_table, qpn, msn, psn, AckExtension::AtomicAck(orig))
 }
 
-fn make_ack_or_nack(
+/// The extended transport header behind the AETH
+#[derive(Debug, Clone, Copy)]
+enum AckExtension {
+    /// The NRETH of an ack, or of a nack with the retry psn, the AETH code and value
+    Nreth(Option<(Psn, ToHostWorkRbDescAethCode, u8)>),
+    /// The `AtomicAckETH` with the original value of the remote memory
+    AtomicAck(u64),
+}
+
+impl AckExtension {
+    fn packet_size(self) -> usize {
+        match self {
+            AckExtension::Nreth(_) => ACKPACKET_SIZE,
+            AckExtension::AtomicAck(_) => ATOMIC_ACKPACKET_SIZE,
+        }
+    }
+}
+
+fn make_ack_packet(
     mut ack_buf: Slot<RDMA_ACK_BUFFER_SLOT_SIZE>,
     qp_table: &ThreadSafeHashmap<Qpn, QpContext>,
     qpn: Qpn,
     msn: Msn,
     psn: Psn,
-    expected_psn: Option<Psn>,
+    ext: AckExtension,
 ) -> Result<Box<ToCardWorkRbDesc>, Error> {
+    let packet_size = ext.packet_size();
     #[allow(clippy::unwrap_used)]
     let (src_mac, src_ip, dst_mac, dst_ip, common) = {
         let table = qp_table.read();
@@ -58,7 +131,7 @@ fn make_ack_or_nack(
             let src_ip = qp.local_ip;
             #[allow(clippy::cast_possible_truncation)]
             let common = ToCardWorkRbDescCommon {
-                total_len: ACKPACKET_SIZE as u32,
+                total_len: packet_size as u32,
                 rkey: Key::default(),
                 raddr: 0,
                 dqp_ip: dst_ip,
@@ -69,6 +142,8 @@ fn make_ack_or_nack(
                 qp_type: QpType::RawPacket,
                 psn: Psn::default(),
                 msn,
+                qkey: 0,
+                sqpn: qpn,
             };
             (src_mac, src_ip, dst_mac, dst_ip, common)
         } else {
@@ -82,10 +157,10 @@ fn make_ack_or_nack(
         qpn,
         msn,
         psn,
-        expected_psn,
+        ext,
     );
     #[allow(clippy::cast_possible_truncation)]
-    let sge = ack_buf.into_sge(ACKPACKET_SIZE as u32);
+    let sge = ack_buf.into_sge(packet_size as u32);
     ToCardWorkRbDescBuilder::new(ToCardWorkRbDescOpcode::WriteWithImm)
         .with_common(common)
         .with_sge(sge)
@@ -118,6 +193,8 @@ pub(crate) fn make_read_resp(
             qp_type: qp.qp_type,
             psn: Psn::default(),
             msn,
+            qkey: 0,
+            sqpn: dqpn,
         };
         let packet_cnt = calculate_packet_cnt(qp.pmtu, raddr, len);
         let first_pkt_psn = {
@@ -161,8 +238,8 @@ fn mac_to_be64(mac: MacAddress) -> u64 {
 /// If the
 /// # Panic
 /// We assume the `buf` is large enough to hold the packet, in other words, the length should
-/// at least be `ACKPACKET_SIZE`. If the length is less than `ACKPACKET_SIZE`, it will panic.
-#[allow(clippy::indexing_slicing)]
+/// at least be `ext.packet_size()`. If the length is less than it, it will panic.
+#[allow(clippy::indexing_slicing, clippy::arithmetic_side_effects)]
 fn write_packet(
     buf: &mut [u8],
     src: (MacAddress, Ipv4Addr),
@@ -170,9 +247,10 @@ fn write_packet(
     dpqn: Qpn,
     msg_seq_num: Msn,
     psn: Psn,
-    expected_psn: Option<Psn>,
+    ext: AckExtension,
 ) {
-    let buf = &mut buf[..ACKPACKET_SIZE];
+    let packet_size = ext.packet_size();
+    let buf = &mut buf[..packet_size];
     let (src_mac, src_ip) = src;
     let (dst_mac, dst_ip) = dst;
 
@@ -189,12 +267,12 @@ fn write_packet(
     ip_header.set_dscp_ecn(0);
 
     // The `total_length` take a 16 bits **big-endian** number as input.
-    // Only the third and forth bytes are used, so the we put the `ACKPACKET_SIZE` into the third byte.
+    // Only the third and forth bytes are used, so the we put the packet size into the third byte.
     #[allow(clippy::cast_possible_truncation)]
     ip_header.set_total_length(u32::from_be_bytes([
         0,
         0,
-        ACKPACKET_SIZE_WITHOUT_MAC as u8,
+        (packet_size - MAC_HEADER_SIZE) as u8,
         0,
     ]));
     ip_header.set_identification(0x27);
@@ -215,13 +293,17 @@ fn write_packet(
     udp_header.set_src_port(RDMA_DEFAULT_PORT.to_be());
     udp_header.set_dst_port(RDMA_DEFAULT_PORT.to_be());
     #[allow(clippy::cast_possible_truncation)]
-    udp_header.set_length((ACKPACKET_SIZE_WITHOUT_MAC_AND_IPV4 as u16).to_be());
+    udp_header.set_length(((packet_size - MAC_HEADER_SIZE - IPV4_HEADER_SIZE) as u16).to_be());
     // It might redundant to calculate checksum, as the ICRC will calculate the another checksum
     udp_header.set_checksum(0);
 
     let bth_hdr_buf = &mut mac_header.0[MAC_HEADER_SIZE + IPV4_HEADER_SIZE + UDP_HEADER_SIZE..];
     let mut bth_header = Bth(bth_hdr_buf);
-    bth_header.set_opcode(ToHostWorkRbDescOpcode::Acknowledge as u32);
+    let opcode = match ext {
+        AckExtension::Nreth(_) => ToHostWorkRbDescOpcode::Acknowledge,
+        AckExtension::AtomicAck(_) => ToHostWorkRbDescOpcode::AtomicAcknowledge,
+    };
+    bth_header.set_opcode(opcode as u32);
     bth_header.set_pad_count(0);
     bth_header.set_pkey(0);
     bth_header.set_ecn_and_resv6(0);
@@ -229,37 +311,34 @@ fn write_packet(
     bth_header.set_dqpn(dpqn.into_be());
     bth_header.set_psn(psn.into_be());
 
-    let is_nak = expected_psn.is_some();
     let aeth_hdr_buf =
         &mut mac_header.0[MAC_HEADER_SIZE + IPV4_HEADER_SIZE + UDP_HEADER_SIZE + BTH_HEADER_SIZE..];
     let mut aeth_header = Aeth(aeth_hdr_buf);
-    if is_nak {
-        aeth_header.set_aeth_code(ToHostWorkRbDescAethCode::Nak as u32);
+    if let AckExtension::Nreth(Some((_, code, value))) = ext {
+        aeth_header.set_aeth_code(code as u32);
+        aeth_header.set_aeth_value(value.into());
     } else {
         aeth_header.set_aeth_code(ToHostWorkRbDescAethCode::Ack as u32);
+        aeth_header.set_aeth_value(0);
     }
-    aeth_header.set_aeth_value(0);
     aeth_header.set_msn(msg_seq_num.into_be().into());
 
-    let mut nreth_header = NReth(
-        &mut mac_header.0[MAC_HEADER_SIZE
-            + IPV4_HEADER_SIZE
-            + UDP_HEADER_SIZE
-            + BTH_HEADER_SIZE
-            + AETH_HEADER_SIZE..],
-    );
-    if is_nak {
-        // we have checked the option before.
-        #[allow(clippy::unwrap_used)]
-        let expected_psn = expected_psn.unwrap().into_be();
-        nreth_header.set_last_retry_psn(expected_psn);
-    } else {
-        nreth_header.set_last_retry_psn(0);
+    let ext_hdr_buf = &mut mac_header.0
+        [MAC_HEADER_SIZE + IPV4_HEADER_SIZE + UDP_HEADER_SIZE + BTH_HEADER_SIZE + AETH_HEADER_SIZE..];
+    match ext {
+        AckExtension::Nreth(nak) => {
+            let mut nreth_header = NReth(ext_hdr_buf);
+            let last_retry_psn = nak.map_or(0, |(retry_psn, _, _)| retry_psn.into_be());
+            nreth_header.set_last_retry_psn(last_retry_psn);
+        }
+        AckExtension::AtomicAck(orig) => {
+            ext_hdr_buf[..ATOMIC_ACK_ETH_HEADER_SIZE].copy_from_slice(&orig.to_be_bytes());
+        }
     }
     // calculate the ICRC
-    let total_buf = &mut mac_header.0[..ACKPACKET_SIZE];
+    let total_buf = &mut mac_header.0[..packet_size];
     let icrc = calculate_icrc(&total_buf[MAC_HEADER_SIZE..]);
-    total_buf[ACKPACKET_SIZE - ICRC_SIZE..].copy_from_slice(&icrc.to_le_bytes());
+    total_buf[packet_size - ICRC_SIZE..].copy_from_slice(&icrc.to_le_bytes());
 }
 
 const MAC_HEADER_SIZE: usize = 14;
@@ -269,6 +348,7 @@ const BTH_HEADER_SIZE: usize = 12;
 const IPV4_UDP_BTH_HEADER_SIZE: usize = IPV4_HEADER_SIZE + UDP_HEADER_SIZE + BTH_HEADER_SIZE;
 const AETH_HEADER_SIZE: usize = 4;
 const NRETH_HEADER_SIZE: usize = 4;
+const ATOMIC_ACK_ETH_HEADER_SIZE: usize = 8;
 const ICRC_SIZE: usize = 4;
 const ACKPACKET_SIZE_WITHOUT_MAC_AND_IPV4: usize =
     UDP_HEADER_SIZE + BTH_HEADER_SIZE + AETH_HEADER_SIZE + NRETH_HEADER_SIZE + ICRC_SIZE;
@@ -276,9 +356,14 @@ const ACKPACKET_SIZE_WITHOUT_MAC_AND_IPV4: usize =
 const ACKPACKET_SIZE_WITHOUT_MAC: usize = IPV4_HEADER_SIZE + ACKPACKET_SIZE_WITHOUT_MAC_AND_IPV4;
 
 pub(crate)const ACKPACKET_SIZE: usize = MAC_HEADER_SIZE + ACKPACKET_SIZE_WITHOUT_MAC;
+pub(crate) const ATOMIC_ACKPACKET_SIZE: usize = MAC_HEADER_SIZE
+    + IPV4_UDP_BTH_HEADER_SIZE
+    + AETH_HEADER_SIZE
+    + ATOMIC_ACK_ETH_HEADER_SIZE
+    + ICRC_SIZE;
 #[allow(clippy::assertions_on_constants)]
 const _: () = assert!(
-    RDMA_ACK_BUFFER_SLOT_SIZE >= ACKPACKET_SIZE,
+    RDMA_ACK_BUFFER_SLOT_SIZE >= ACKPACKET_SIZE && RDMA_ACK_BUFFER_SLOT_SIZE >= ATOMIC_ACKPACKET_SIZE,
     "RDMA_ACK_BUFFER_SLOT_SIZE too small"
 );
 
@@ -2,20 +2,24 @@ use std::{
     collections::HashMap,
     fmt::Debug,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
         Arc,
     },
     thread::sleep,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     u128,
 };
 
 use flume::{Receiver, Sender};
+use rand::Rng as _;
 
 use crate::{
-    device::ToCardWorkRbDesc,
-    op_ctx::OpCtx,
-    types::{Msn, Qpn},
+    checker::ReadRespContext,
+    cq::WorkCompletionStatus,
+    device::{scheduler::slice_descriptor, ToCardWorkRbDesc},
+    op_ctx::{CtxStatus, OpCtx},
+    types::{Msn, Psn, Qpn, INFINITE_RNR_RETRY},
+    utils::calculate_packet_cnt,
     Error, ThreadSafeHashmap, WorkDescriptorSender,
 };
 
@@ -23,7 +27,67 @@ use crate::{
 pub(crate) struct RetryContext {
     descriptor: Box<ToCardWorkRbDesc>,
     retry_counter: u32,
+    rnr_retry_counter: u32,
     next_timeout: u128,
+    backoff: RetryBackoff,
+    // the number of retransmissions, shared with the operation context of user
+    retransmitted: Arc<AtomicU32>,
+}
+
+impl RetryContext {
+    /// Count a retransmission, and restart the timer by the backoff policy
+    #[allow(clippy::arithmetic_side_effects)]
+    fn retransmit(&mut self, base_timeout: u128, now: u128) {
+        let retransmitted = self.retransmitted.fetch_add(1, Ordering::Relaxed).saturating_add(1);
+        self.next_timeout = now + self.backoff.timeout(base_timeout, retransmitted);
+    }
+}
+
+/// The policy of the timeout between the retransmissions of a request
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryBackoff {
+    /// Every retransmission waits for the same `retry_timeout`
+    #[default]
+    Fixed,
+    /// The timeout doubles after every retransmission, until it reaches `max`
+    Exponential {
+        /// The max timeout
+        max: Duration,
+    },
+    /// Like `Exponential`, but the timeout is picked randomly from the upper half of it,
+    /// so that the requests timing out together are not retransmitted together again
+    Jittered {
+        /// The max timeout
+        max: Duration,
+    },
+}
+
+impl RetryBackoff {
+    /// The timeout in ms after `retransmitted` retransmissions, where the first one is `base`
+    fn timeout(self, base: u128, retransmitted: u32) -> u128 {
+        let exponential = |max: Duration| {
+            let factor = 1_u128.checked_shl(retransmitted).unwrap_or(u128::MAX);
+            base.saturating_mul(factor).min(max.as_millis().max(base))
+        };
+        match self {
+            RetryBackoff::Fixed => base,
+            RetryBackoff::Exponential { max } => exponential(max),
+            RetryBackoff::Jittered { max } => {
+                let timeout = exponential(max);
+                rand::thread_rng().gen_range(timeout.div_ceil(2)..=timeout)
+            }
+        }
+    }
+}
+
+/// A qp which is waiting to retransmit from `psn` after an RNR NAK
+///
+/// The RNR timers are as short as 10 us, so the deadline is not kept in ms like the timeouts.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RnrWait {
+    psn: Psn,
+    deadline: Instant,
 }
 
 /// Typically the checking_interval should at most 1% of retry_timeout
@@ -34,10 +98,11 @@ pub struct RetryConfig {
     max_retry: u32,
     retry_timeout: u128,
     checking_interval: Duration,
+    backoff: RetryBackoff,
 }
 
 impl RetryConfig {
-    /// Create a new retry config
+    /// Create a new retry config, which retransmits at the fixed `retry_timeout`
     pub fn new(
         is_enable: bool,
         max_retry: u32,
@@ -49,8 +114,18 @@ impl RetryConfig {
             max_retry,
             retry_timeout: retry_timeout.as_millis(),
             checking_interval,
+            backoff: RetryBackoff::Fixed,
         }
     }
+
+    /// Set the backoff policy of the qps which don't set their own one.
+    ///
+    /// The `retry_timeout` is the timeout of the first transmission.
+    #[must_use]
+    pub fn with_backoff(mut self, backoff: RetryBackoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
 }
 
 // Main thread will send a retry record to retry monitor
@@ -58,33 +133,120 @@ pub(crate) struct RetryRecord {
     descriptor: Box<ToCardWorkRbDesc>,
     qpn: Qpn,
     msn: Msn,
+    // the max retry of the qp, use the one in `RetryConfig` if it's `None`
+    max_retry: Option<u32>,
+    // the max RNR retry of the qp, retry infinitely if it's `None`
+    max_rnr_retry: Option<u32>,
+    // the backoff policy of the qp, use the one in `RetryConfig` if it's `None`
+    backoff: Option<RetryBackoff>,
 }
 
 impl RetryRecord {
-    pub(crate) fn new(descriptor: Box<ToCardWorkRbDesc>, qpn: Qpn, msn: Msn) -> Self {
+    pub(crate) fn new(
+        descriptor: Box<ToCardWorkRbDesc>,
+        qpn: Qpn,
+        msn: Msn,
+        max_retry: Option<u32>,
+        max_rnr_retry: Option<u32>,
+        backoff: Option<RetryBackoff>,
+    ) -> Self {
         Self {
             descriptor,
             qpn,
             msn,
+            max_retry,
+            max_rnr_retry,
+            backoff,
         }
     }
 }
 
+/// Stop retransmitting a message, e.g. it is acknowledged or failed
 pub(crate) struct RetryCancel {
     qpn: Qpn,
     msn: Msn,
+    is_cumulative: bool,
+}
+
+impl RetryCancel {
+    pub(crate) fn new(qpn: Qpn, msn: Msn) -> Self {
+        Self {
+            qpn,
+            msn,
+            is_cumulative: false,
+        }
+    }
+
+    /// An acknowledgement of `msn`, which also acknowledges the earlier messages of the qp
+    pub(crate) fn cumulative(qpn: Qpn, msn: Msn) -> Self {
+        Self {
+            qpn,
+            msn,
+            is_cumulative: true,
+        }
+    }
+}
+
+/// A PSN sequence error NAK, which asks to retransmit from `psn`
+pub(crate) struct RetryGoBackN {
+    qpn: Qpn,
+    psn: Psn,
+}
+
+impl RetryGoBackN {
+    pub(crate) fn new(qpn: Qpn, psn: Psn) -> Self {
+        Self { qpn, psn }
+    }
+}
+
+/// A PSN sequence error NAK bounded by its NRETH, which asks to retransmit the packets from
+/// `from` to `to` only
+pub(crate) struct RetrySelective {
+    qpn: Qpn,
+    from: Psn,
+    to: Psn,
+}
+
+impl RetrySelective {
+    pub(crate) fn new(qpn: Qpn, from: Psn, to: Psn) -> Self {
+        Self { qpn, from, to }
+    }
+}
+
+/// An RNR NAK, which asks to retransmit from `psn` after `timer`
+pub(crate) struct RetryRnr {
+    qpn: Qpn,
+    msn: Msn,
+    psn: Psn,
+    timer: Duration,
+}
+
+impl RetryRnr {
+    pub(crate) fn new(qpn: Qpn, msn: Msn, psn: Psn, timer: Duration) -> Self {
+        Self {
+            qpn,
+            msn,
+            psn,
+            timer,
+        }
+    }
 }
 
 pub(crate) enum RetryEvent {
     Retry(RetryRecord),
     Cancel(RetryCancel),
+    GoBackN(RetryGoBackN),
+    Selective(RetrySelective),
+    Rnr(RetryRnr),
 }
 
 pub(crate) struct RetryMonitorContext {
     pub(crate) map: HashMap<(Qpn, Msn), RetryContext>,
+    pub(crate) rnr_waits: HashMap<Qpn, RnrWait>,
     pub(crate) receiver: Receiver<RetryEvent>,
     pub(crate) device: Arc<dyn WorkDescriptorSender>,
     pub(crate) user_op_ctx_map: ThreadSafeHashmap<(Qpn, Msn), OpCtx<()>>,
+    pub(crate) read_resp_map: ThreadSafeHashmap<(Qpn, Msn), ReadRespContext>,
     pub(crate) config: RetryConfig,
 }
 
@@ -125,6 +287,18 @@ impl RetryMonitor {
     }
 }
 
+impl Drop for RetryMonitor {
+    fn drop(&mut self) {
+        self.stop_flag.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if let Err(e) = thread.join() {
+                panic!("{}", format!("RetryMonitor thread join failed: {e:?}"));
+            }
+            log::info!("RetryMonitor thread is normally stopped");
+        }
+    }
+}
+
 impl RetryMonitorContext {
     fn check_receive(&mut self) {
         while let Ok(record) = self.receiver.try_recv() {
@@ -132,6 +306,9 @@ impl RetryMonitorContext {
                 match record {
                     RetryEvent::Retry(record) => self.handle_retry(record),
                     RetryEvent::Cancel(cancel) => self.handle_cancel(&cancel),
+                    RetryEvent::GoBackN(go_back_n) => self.handle_go_back_n(&go_back_n),
+                    RetryEvent::Selective(selective) => self.handle_selective(&selective),
+                    RetryEvent::Rnr(rnr) => self.handle_rnr(&rnr),
                 }
             }
         }
@@ -140,10 +317,18 @@ impl RetryMonitorContext {
     #[allow(clippy::arithmetic_side_effects)]
     fn handle_retry(&mut self, record: RetryRecord) {
         let key = (record.qpn, record.msn);
+        let retransmitted = self
+            .user_op_ctx_map
+            .read()
+            .get(&key)
+            .map_or_else(|| Arc::new(AtomicU32::new(0)), OpCtx::retry_counter);
         let ctx = RetryContext {
             descriptor: record.descriptor,
-            retry_counter: self.config.max_retry,
+            retry_counter: record.max_retry.unwrap_or(self.config.max_retry),
+            rnr_retry_counter: record.max_rnr_retry.unwrap_or(INFINITE_RNR_RETRY),
             next_timeout: get_current_time() + self.config.retry_timeout,
+            backoff: record.backoff.unwrap_or(self.config.backoff),
+            retransmitted,
         };
         if self.map.insert(key, ctx).is_some() {
             // receive same record more than once
@@ -151,11 +336,224 @@ impl RetryMonitorContext {
         }
     }
 
+    /// Remove the retry record of the canceled message.
+    ///
+    /// A cumulative cancel also removes and completes the earlier messages of the qp, except
+    /// the ones which are completed by their own responses, i.e. the reads and atomics.
     fn handle_cancel(&mut self, cancel: &RetryCancel) {
         let key = (cancel.qpn, cancel.msn);
-        if self.map.remove(&key).is_none() {
-            // remove failed
-            log::warn!("Remove retry record failed.");
+        let is_removed = self.map.remove(&key).is_some();
+        if !cancel.is_cumulative {
+            if !is_removed {
+                log::warn!("Remove retry record failed: Can not find {key:?}");
+            }
+            return;
+        }
+        let acked: Vec<(Qpn, Msn)> = self
+            .map
+            .iter()
+            .filter(|((qpn, msn), ctx)| {
+                *qpn == cancel.qpn
+                    && cancel.msn.larger_in_msn(*msn)
+                    && !expects_response(&ctx.descriptor)
+            })
+            .map(|(acked_key, _)| *acked_key)
+            .collect();
+        for acked_key in acked {
+            let _: Option<RetryContext> = self.map.remove(&acked_key);
+            self.report_success(&acked_key);
+        }
+    }
+
+    /// Retransmit the messages of the qp from the NAKed psn, in the psn order.
+    ///
+    /// The descriptor is retransmitted as a whole, so the message containing the NAKed psn is
+    /// sent from its first packet. Every retransmission consumes a retry of the message.
+    fn handle_go_back_n(&mut self, go_back_n: &RetryGoBackN) {
+        // the retransmission after the RNR wait will cover the NAKed psn
+        if let Some(wait) = self.rnr_waits.get(&go_back_n.qpn) {
+            if go_back_n.psn.larger_in_psn(wait.psn) {
+                return;
+            }
+        }
+        self.retransmit_from(go_back_n.qpn, go_back_n.psn, true);
+    }
+
+    /// Retransmit only the NAKed packets of the qp, which are cut out of the stored descriptors.
+    ///
+    /// Every message containing the NAKed packets consumes a retry.
+    #[allow(clippy::arithmetic_side_effects)]
+    fn handle_selective(&mut self, selective: &RetrySelective) {
+        // the retransmission after the RNR wait will cover the NAKed packets
+        if let Some(wait) = self.rnr_waits.get(&selective.qpn) {
+            if selective.to.larger_in_psn(wait.psn) {
+                return;
+            }
+        }
+        let now = get_current_time();
+        let mut to_retry: Vec<(u32, (Qpn, Msn))> = self
+            .map
+            .iter()
+            .filter(|((msg_qpn, _), _)| *msg_qpn == selective.qpn)
+            .filter(|(_, ctx)| {
+                let common = ctx.descriptor.common();
+                selective.to.larger_in_psn(common.psn)
+                    && last_psn_of(&ctx.descriptor).larger_in_psn(selective.from)
+            })
+            .map(|(key, ctx)| (selective.to.wrapping_abs(ctx.descriptor.common().psn), *key))
+            .collect();
+        // the message with the smallest psn has the largest distance to the end of the range
+        to_retry.sort_unstable_by_key(|(distance, _)| std::cmp::Reverse(*distance));
+
+        for (_, key) in to_retry {
+            let Some(ctx) = self.map.get_mut(&key) else {
+                continue;
+            };
+            if ctx.retry_counter == 0 {
+                let _: Option<RetryContext> = self.map.remove(&key);
+                self.report_failure(&key, WorkCompletionStatus::RetryExceeded);
+                continue;
+            }
+            ctx.retry_counter -= 1;
+            ctx.retransmit(self.config.retry_timeout, now);
+            let desc = match slice_descriptor(&ctx.descriptor, selective.from, selective.to) {
+                Ok(Some(desc)) => desc,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!("Slice descriptor of {key:?} failed: {e}");
+                    let _: Option<RetryContext> = self.map.remove(&key);
+                    self.report_failure(&key, WorkCompletionStatus::GeneralError);
+                    continue;
+                }
+            };
+            if self.device.send_work_desc(desc).is_err() {
+                log::error!("Retry send work descriptor failed");
+            }
+        }
+    }
+
+    /// Hold the messages of the qp from the NAKed psn, until the RNR timer expires.
+    ///
+    /// Every RNR NAK consumes an RNR retry of the message, and the `RetryConfig::max_retry`
+    /// is not consumed by the retransmission after waiting.
+    #[allow(clippy::arithmetic_side_effects)]
+    fn handle_rnr(&mut self, rnr: &RetryRnr) {
+        let key = (rnr.qpn, rnr.msn);
+        let Some(ctx) = self.map.get_mut(&key) else {
+            log::warn!("Receive RNR NAK of unknown request {key:?}");
+            return;
+        };
+        if ctx.rnr_retry_counter == 0 {
+            let _: Option<RetryContext> = self.map.remove(&key);
+            self.report_failure(&key, WorkCompletionStatus::RnrRetryExceeded);
+            return;
+        }
+        if ctx.rnr_retry_counter != INFINITE_RNR_RETRY {
+            ctx.rnr_retry_counter -= 1;
+        }
+        let deadline = Instant::now() + rnr.timer;
+        // the timeout of the waiting messages starts after the retransmission
+        let next_timeout =
+            get_current_time() + rnr.timer.as_micros().div_ceil(1000) + self.config.retry_timeout;
+        for ((qpn, _), waiting) in &mut self.map {
+            if *qpn == rnr.qpn && last_psn_of(&waiting.descriptor).larger_in_psn(rnr.psn) {
+                waiting.next_timeout = next_timeout;
+            }
+        }
+        let wait = RnrWait {
+            psn: rnr.psn,
+            deadline,
+        };
+        // the earliest psn is retransmitted if more than one message are NAKed
+        let is_earlier = self
+            .rnr_waits
+            .get(&rnr.qpn)
+            .map_or(true, |prev| prev.psn.larger_in_psn(rnr.psn));
+        if is_earlier {
+            let _: Option<RnrWait> = self.rnr_waits.insert(rnr.qpn, wait);
+        }
+    }
+
+    /// Retransmit the qps whose RNR timer expires
+    fn check_rnr_waits(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<(Qpn, Psn)> = self
+            .rnr_waits
+            .iter()
+            .filter(|(_, wait)| wait.deadline <= now)
+            .map(|(qpn, wait)| (*qpn, wait.psn))
+            .collect();
+        for (qpn, psn) in expired {
+            let _: Option<RnrWait> = self.rnr_waits.remove(&qpn);
+            self.retransmit_from(qpn, psn, false);
+        }
+    }
+
+    /// Retransmit the messages of the qp which end at or after `psn`, in the psn order
+    #[allow(clippy::arithmetic_side_effects)]
+    fn retransmit_from(&mut self, qpn: Qpn, psn: Psn, consume_retry: bool) {
+        let now = get_current_time();
+        // the distance from `psn` to the last psn of a message, the ones end before it are received
+        let mut to_retry: Vec<(u32, (Qpn, Msn))> = self
+            .map
+            .iter()
+            .filter(|((msg_qpn, _), _)| *msg_qpn == qpn)
+            .filter_map(|(key, ctx)| {
+                let last_psn = last_psn_of(&ctx.descriptor);
+                last_psn
+                    .larger_in_psn(psn)
+                    .then(|| (last_psn.wrapping_abs(psn), *key))
+            })
+            .collect();
+        to_retry.sort_unstable_by_key(|(distance, _)| *distance);
+
+        for (_, key) in to_retry {
+            let Some(ctx) = self.map.get_mut(&key) else {
+                continue;
+            };
+            if consume_retry && ctx.retry_counter == 0 {
+                let _: Option<RetryContext> = self.map.remove(&key);
+                self.report_failure(&key, WorkCompletionStatus::RetryExceeded);
+                continue;
+            }
+            if consume_retry {
+                ctx.retry_counter -= 1;
+            }
+            ctx.retransmit(self.config.retry_timeout, now);
+            let desc = reissued_descriptor(&self.read_resp_map, &key, &ctx.descriptor);
+            if self.device.send_work_desc(desc).is_err() {
+                log::error!("Retry send work descriptor failed");
+            }
+        }
+    }
+
+    /// Tell user that the operation is finished, whose acknowledgement is covered by a later one
+    fn report_success(&self, key: &(Qpn, Msn)) {
+        let Some(user_op_ctx) = self.user_op_ctx_map.write().remove(key) else {
+            return;
+        };
+        if !matches!(user_op_ctx.status(), CtxStatus::Running) {
+            return;
+        }
+        if let Some(handler) = user_op_ctx.take_handler() {
+            handler(WorkCompletionStatus::Success);
+        }
+        if let Err(e) = user_op_ctx.set_result(()) {
+            log::error!("Set result failed {:?}", e);
+        }
+    }
+
+    /// Tell user that the operation is failed after reaching the max retry
+    fn report_failure(&self, key: &(Qpn, Msn), status: WorkCompletionStatus) {
+        let _: Option<ReadRespContext> = self.read_resp_map.write().remove(key);
+        let user_op_ctx = self.user_op_ctx_map.write().remove(key);
+        if let Some(user_op_ctx) = user_op_ctx {
+            if let Some(handler) = user_op_ctx.take_handler() {
+                handler(status);
+            }
+            user_op_ctx.set_error(status);
+        } else {
+            log::warn!("Remove retry record failed: Can not find {key:?}");
         }
     }
 
@@ -163,35 +561,91 @@ impl RetryMonitorContext {
     fn check_timeout(&mut self) {
         let now = get_current_time();
         let mut has_removed = false;
+        let mut expired = Vec::new();
         for (key, ctx) in self.map.iter_mut() {
             if ctx.next_timeout <= now {
                 if ctx.retry_counter > 0 {
                     ctx.retry_counter -= 1;
-                    ctx.next_timeout = now + self.config.retry_timeout;
-                    if self.device.send_work_desc(ctx.descriptor.clone()).is_err() {
+                    ctx.retransmit(self.config.retry_timeout, now);
+                    let desc = reissued_descriptor(&self.read_resp_map, key, &ctx.descriptor);
+                    if self.device.send_work_desc(desc).is_err() {
                         log::error!("Retry send work descriptor failed")
                     }
                 } else {
                     // Encounter max retry, remove it and tell user the error
                     has_removed = true;
-                    let guard = self.user_op_ctx_map.write();
-                    if let Some(user_op_ctx) = guard.get(key) {
-                        user_op_ctx.set_error("exceed max retry count");
-                    } else {
-                        log::warn!("Remove retry record failed: Can not find {key:?}");
-                    }
+                    expired.push(*key);
                 }
             }
         }
+        for key in &expired {
+            self.report_failure(key, WorkCompletionStatus::RetryExceeded);
+        }
         if has_removed {
             self.map.retain(|_, ctx| ctx.retry_counter != 0);
         }
     }
 }
 
+/// The descriptor to retransmit, a read only fetches the bytes which have not landed yet
+fn reissued_descriptor(
+    read_resp_map: &ThreadSafeHashmap<(Qpn, Msn), ReadRespContext>,
+    key: &(Qpn, Msn),
+    desc: &ToCardWorkRbDesc,
+) -> Box<ToCardWorkRbDesc> {
+    let mut desc = Box::new(desc.clone());
+    if let ToCardWorkRbDesc::Read(read) = desc.as_mut() {
+        let received = read_resp_map
+            .read()
+            .get(key)
+            .map_or(0, ReadRespContext::received_len);
+        // a fully received read is being completed by the checker
+        if received < read.common.total_len {
+            let offset = u64::from(received);
+            read.common.raddr = read.common.raddr.wrapping_add(offset);
+            read.common.total_len = read.common.total_len.saturating_sub(received);
+            read.sge.addr = read.sge.addr.wrapping_add(offset);
+            read.sge.len = read.sge.len.saturating_sub(received);
+        }
+    }
+    desc
+}
+
+/// Whether the request is completed by its own response rather than an acknowledgement
+fn expects_response(desc: &ToCardWorkRbDesc) -> bool {
+    match desc {
+        ToCardWorkRbDesc::Read(_)
+        | ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | ToCardWorkRbDesc::AtomicFetchAdd(_) => true,
+        ToCardWorkRbDesc::Write(_)
+        | ToCardWorkRbDesc::WriteWithImm(_)
+        | ToCardWorkRbDesc::ReadResp(_)
+        | ToCardWorkRbDesc::Send(_) => false,
+    }
+}
+
+/// The psn of the last packet of a descriptor
+#[allow(clippy::arithmetic_side_effects)] // the packet count is at least 1
+fn last_psn_of(desc: &ToCardWorkRbDesc) -> Psn {
+    let common = desc.common();
+    let packet_cnt = match desc {
+        ToCardWorkRbDesc::Read(_)
+        | ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | ToCardWorkRbDesc::AtomicFetchAdd(_) => 1,
+        ToCardWorkRbDesc::Write(_)
+        | ToCardWorkRbDesc::WriteWithImm(_)
+        | ToCardWorkRbDesc::ReadResp(_)
+        | ToCardWorkRbDesc::Send(_) => {
+            calculate_packet_cnt(common.pmtu, common.raddr, common.total_len)
+        }
+    };
+    common.psn.wrapping_add(packet_cnt - 1)
+}
+
 fn retry_monitor_working_thread(stop_flag: &AtomicBool, monitor: &mut RetryMonitorContext) {
     while !stop_flag.load(Ordering::Relaxed) {
         monitor.check_receive();
+        monitor.check_rnr_waits();
         monitor.check_timeout();
         // sleep for an interval
         sleep(monitor.config.checking_interval);
@@ -200,18 +654,26 @@ fn retry_monitor_working_thread(stop_flag: &AtomicBool, monitor: &mut RetryMonit
 
 #[cfg(test)]
 mod test {
-    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
 
-    use parking_lot::{lock_api::RwLock, Mutex, RawRwLock};
+    use parking_lot::{lock_api::RwLock, Mutex};
 
     use crate::{
-        device::{DescSge, ToCardWorkRbDesc, ToCardWorkRbDescCommon, ToCardWorkRbDescWrite},
+        checker::ReadRespContext,
+        cq::WorkCompletionStatus,
+        device::{
+            DescSge, ToCardWorkRbDesc, ToCardWorkRbDescCommon, ToCardWorkRbDescRead,
+            ToCardWorkRbDescWrite,
+        },
         op_ctx::{self, CtxStatus},
-        types::{Key, Msn, Qpn, ThreeBytesStruct},
+        types::{Key, Msn, Pmtu, Psn, Qpn},
         Error, WorkDescriptorSender,
     };
 
-    use super::{RetryConfig, RetryEvent, RetryMonitorContext};
+    use super::{
+        RetryBackoff, RetryCancel, RetryConfig, RetryEvent, RetryGoBackN, RetryMonitorContext,
+        RetrySelective,
+    };
     struct MockDevice(Mutex<Vec<ToCardWorkRbDesc>>);
 
     impl WorkDescriptorSender for MockDevice {
@@ -221,29 +683,29 @@ mod test {
         }
     }
 
-    #[test]
-    fn test_retry_monitor() {
-        let map = Arc::new(RwLock::new(HashMap::new()));
+    /// A context sending the descriptors to a `MockDevice`, and receiving the events from the
+    /// returned sender.
+    fn test_context(
+        retry_timeout: Duration,
+    ) -> (RetryMonitorContext, flume::Sender<RetryEvent>, Arc<MockDevice>) {
         let (sender, receiver) = flume::unbounded();
         let device = Arc::new(MockDevice(Vec::new().into()));
         let context = RetryMonitorContext {
             map: HashMap::new(),
+            rnr_waits: HashMap::new(),
             receiver,
             device: Arc::<MockDevice>::clone(&device),
-            user_op_ctx_map: Arc::<
-                RwLock<RawRwLock, HashMap<(ThreeBytesStruct, Msn), op_ctx::OpCtx<()>>>,
-            >::clone(&map),
-            config: RetryConfig::new(
-                true,
-                3,
-                Duration::from_millis(1000),
-                std::time::Duration::from_millis(10),
-            ),
+            user_op_ctx_map: Arc::new(RwLock::new(HashMap::new())),
+            read_resp_map: Arc::new(RwLock::new(HashMap::new())),
+            config: RetryConfig::new(true, 3, retry_timeout, Duration::from_millis(10)),
         };
-        map.write().insert(
-            (Qpn::default(), Msn::default()),
-            op_ctx::OpCtx::new_running(),
-        );
+        (context, sender, device)
+    }
+
+    #[test]
+    fn test_retry_monitor() {
+        let (context, sender, device) = test_context(Duration::from_millis(1000));
+        let map = Arc::clone(&context.user_op_ctx_map);
         let _monitor = super::RetryMonitor::new(sender.clone(),context);
         let desc = Box::new(ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
             common: ToCardWorkRbDescCommon {
@@ -261,11 +723,16 @@ mod test {
             sge3: None,
         }));
         for _i in 0..4 {
+            let user_ctx = op_ctx::OpCtx::new_running();
+            map.write().insert((Qpn::default(), Msn::default()), user_ctx.clone());
             sender
                 .send(RetryEvent::Retry(super::RetryRecord {
                     descriptor: desc.clone(),
                     qpn: Qpn::default(),
                     msn: Msn::default(),
+                    max_retry: None,
+                    max_rnr_retry: None,
+                    backoff: None,
                 }))
                 .unwrap();
             // should send first retry
@@ -280,13 +747,8 @@ mod test {
 
             std::thread::sleep(std::time::Duration::from_millis(1020));
             // should remove the record
-            matches!(
-                map.read()
-                    .get(&(Qpn::default(), Msn::default()))
-                    .unwrap()
-                    .status(),
-                CtxStatus::Failed(_)
-            );
+            assert!(matches!(user_ctx.status(), CtxStatus::Failed(_)));
+            assert!(!map.read().contains_key(&(Qpn::default(), Msn::default())));
             device.0.lock().clear();
             std::thread::sleep(std::time::Duration::from_millis(1000));
         }
@@ -311,4 +773,303 @@ mod test {
         // std::thread::sleep(std::time::Duration::from_millis(105));
         // assert_eq!(device.0.lock().len(), 1);
     }
+
+    #[test]
+    fn test_retry_backoff() {
+        let base = 100;
+        for retransmitted in 0..4 {
+            assert_eq!(RetryBackoff::Fixed.timeout(base, retransmitted), base);
+        }
+
+        let exponential = RetryBackoff::Exponential { max: Duration::from_millis(500) };
+        let timeouts: Vec<u128> = (0..5).map(|n| exponential.timeout(base, n)).collect();
+        assert_eq!(timeouts, vec![100, 200, 400, 500, 500]);
+        assert_eq!(exponential.timeout(base, u32::MAX), 500);
+        // the cap never shortens the base timeout
+        let exponential = RetryBackoff::Exponential { max: Duration::from_millis(10) };
+        assert_eq!(exponential.timeout(base, 3), base);
+
+        let jittered = RetryBackoff::Jittered { max: Duration::from_millis(500) };
+        for _ in 0..100 {
+            let timeout = jittered.timeout(base, 2);
+            assert!((200..=400).contains(&timeout));
+            let timeout = jittered.timeout(base, 10);
+            assert!((250..=500).contains(&timeout));
+        }
+    }
+
+    #[test]
+    fn test_retry_cancel_cumulative() {
+        let (mut context, _sender, device) = test_context(Duration::from_millis(1000));
+        let qpn = Qpn::new(3);
+        let write = |qpn| {
+            Box::new(ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+                common: ToCardWorkRbDescCommon {
+                    dqpn: qpn,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }))
+        };
+        let read = Box::new(ToCardWorkRbDesc::Read(ToCardWorkRbDescRead {
+            common: ToCardWorkRbDescCommon {
+                dqpn: qpn,
+                ..Default::default()
+            },
+            ..Default::default()
+        }));
+        // the msn wraps around between the earlier messages
+        let records = [
+            (write(qpn), qpn, u16::MAX),
+            (read, qpn, 0),
+            (write(qpn), qpn, 1),
+            (write(qpn), qpn, 2),
+            (write(qpn), qpn, 3),
+            (write(Qpn::new(4)), Qpn::new(4), 1),
+        ];
+        let mut user_ctxs = HashMap::new();
+        for (desc, qpn, msn) in records {
+            let key = (qpn, Msn::new(msn));
+            let user_ctx = op_ctx::OpCtx::new_running();
+            drop(context.user_op_ctx_map.write().insert(key, user_ctx.clone()));
+            drop(user_ctxs.insert(key, user_ctx));
+            let record = super::RetryRecord::new(desc, qpn, key.1, None, None, None);
+            context.handle_retry(record);
+        }
+
+        // only the acknowledged message is canceled
+        context.handle_cancel(&RetryCancel::new(qpn, Msn::new(1)));
+        assert!(!context.map.contains_key(&(qpn, Msn::new(1))));
+        assert!(context.map.contains_key(&(qpn, Msn::new(u16::MAX))));
+        assert!(matches!(user_ctxs[&(qpn, Msn::new(u16::MAX))].status(), CtxStatus::Running));
+
+        // the earlier writes of the qp are acknowledged as well, but not the read
+        context.handle_cancel(&RetryCancel::cumulative(qpn, Msn::new(2)));
+        let mut remaining: Vec<(u32, u16)> =
+            context.map.keys().map(|(qpn, msn)| (qpn.get(), msn.get())).collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, vec![(3, 0), (3, 3), (4, 1)]);
+        for (msn, is_finished) in [(u16::MAX, true), (0, false), (3, false)] {
+            let status = user_ctxs[&(qpn, Msn::new(msn))].status();
+            assert_eq!(matches!(status, CtxStatus::Finished), is_finished);
+            let is_tracked = context.user_op_ctx_map.read().contains_key(&(qpn, Msn::new(msn)));
+            assert_eq!(is_tracked, !is_finished);
+        }
+        assert!(device.0.lock().is_empty());
+    }
+
+    #[test]
+    fn test_retry_partial_read() {
+        let (mut context, _sender, device) = test_context(Duration::ZERO);
+        let key = (Qpn::new(3), Msn::new(1));
+        let read = Box::new(ToCardWorkRbDesc::Read(ToCardWorkRbDescRead {
+            common: ToCardWorkRbDescCommon {
+                total_len: 4000,
+                raddr: 0x8000,
+                pmtu: Pmtu::Mtu1024,
+                dqpn: key.0,
+                msn: key.1,
+                ..Default::default()
+            },
+            sge: DescSge {
+                addr: 0x1000,
+                len: 4000,
+                key: Key::new(0x1234_u32),
+            },
+        }));
+        let record = super::RetryRecord::new(read, key.0, key.1, None, None, None);
+        context.handle_retry(record);
+        let mut resp = ReadRespContext::new(0x1000, 4000, Pmtu::Mtu1024);
+        resp.insert(0x1000);
+        resp.insert(0x1800);
+        drop(context.read_resp_map.write().insert(key, resp));
+
+        // only the bytes after the first missing packet are read again
+        context.check_timeout();
+        let reissued: Vec<(u64, u64, u32)> = device
+            .0
+            .lock()
+            .drain(..)
+            .map(|desc| match desc {
+                ToCardWorkRbDesc::Read(read) => {
+                    (read.common.raddr, read.sge.addr, read.common.total_len)
+                }
+                ToCardWorkRbDesc::Write(_)
+                | ToCardWorkRbDesc::WriteWithImm(_)
+                | ToCardWorkRbDesc::ReadResp(_)
+                | ToCardWorkRbDesc::AtomicCmpSwap(_)
+                | ToCardWorkRbDesc::AtomicFetchAdd(_)
+                | ToCardWorkRbDesc::Send(_) => panic!("should be a read"),
+            })
+            .collect();
+        assert_eq!(reissued, vec![(0x8400, 0x1400, 2976)]);
+
+        // the next reissue starts from the original read
+        if let Some(resp) = context.read_resp_map.write().get_mut(&key) {
+            resp.insert(0x1400);
+        }
+        context.check_timeout();
+        let desc = device.0.lock().pop().map(|desc| (desc.common().raddr, desc.common().total_len));
+        assert_eq!(desc, Some((0x8c00, 928)));
+    }
+
+    #[test]
+    fn test_retry_go_back_n() {
+        let (mut context, _sender, device) = test_context(Duration::from_millis(1000));
+        let qpn = Qpn::new(3);
+        let user_ctx = op_ctx::OpCtx::new_running();
+        drop(context.user_op_ctx_map.write().insert((qpn, Msn::new(2)), user_ctx.clone()));
+        // three messages of qp 3, each has 2 packets, and a message of another qp
+        let write = |qpn, msn: u16, psn| {
+            Box::new(ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+                common: ToCardWorkRbDescCommon {
+                    total_len: 2048,
+                    pmtu: Pmtu::Mtu1024,
+                    dqpn: qpn,
+                    psn: Psn::new(psn),
+                    msn: Msn::new(msn),
+                    ..Default::default()
+                },
+                is_last: true,
+                is_first: true,
+                sge0: DescSge {
+                    addr: 0x1000,
+                    len: 2048,
+                    key: Key::new(0x1234_u32),
+                },
+                sge1: None,
+                sge2: None,
+                sge3: None,
+            }))
+        };
+        for (qpn, msn, psn) in [(qpn, 2, 2), (qpn, 1, 0), (qpn, 3, 4), (Qpn::new(4), 1, 0)] {
+            let desc = write(qpn, msn, psn);
+            let record = super::RetryRecord::new(desc, qpn, Msn::new(msn), None, None, None);
+            context.handle_retry(record);
+        }
+
+        // the message with the NAKed psn and the later ones are retransmitted in order
+        context.handle_go_back_n(&RetryGoBackN::new(qpn, Psn::new(3)));
+        let psns: Vec<u32> = device
+            .0
+            .lock()
+            .iter()
+            .map(|desc| desc.common().psn.get())
+            .collect();
+        assert_eq!(psns, vec![2, 4]);
+        // the retransmission is reported to the user context
+        assert_eq!(user_ctx.retry_cnt(), 1);
+        let counter = context.map.get(&(qpn, Msn::new(2))).map(|ctx| ctx.retry_counter);
+        assert_eq!(counter, Some(2));
+        let counter = context.map.get(&(qpn, Msn::new(1))).map(|ctx| ctx.retry_counter);
+        assert_eq!(counter, Some(3));
+    }
+
+    #[test]
+    fn test_retry_selective() {
+        let (mut context, _sender, device) = test_context(Duration::from_millis(1000));
+        let qpn = Qpn::new(3);
+        // three messages of qp 3, each has 4 packets
+        let write = |msn: u16, psn| {
+            Box::new(ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+                common: ToCardWorkRbDescCommon {
+                    total_len: 4096,
+                    pmtu: Pmtu::Mtu1024,
+                    dqpn: qpn,
+                    psn: Psn::new(psn),
+                    msn: Msn::new(msn),
+                    ..Default::default()
+                },
+                is_last: true,
+                is_first: true,
+                sge0: DescSge {
+                    addr: 0x1000,
+                    len: 4096,
+                    key: Key::new(0x1234_u32),
+                },
+                sge1: None,
+                sge2: None,
+                sge3: None,
+            }))
+        };
+        for (msn, psn) in [(2, 4), (1, 0), (3, 8)] {
+            let desc = write(msn, psn);
+            let record = super::RetryRecord::new(desc, qpn, Msn::new(msn), None, None, None);
+            context.handle_retry(record);
+        }
+
+        // only the packets 3 - 5 are retransmitted, which are in the first two messages
+        context.handle_selective(&RetrySelective::new(qpn, Psn::new(3), Psn::new(5)));
+        let slices: Vec<(u32, u64, u32)> = device
+            .0
+            .lock()
+            .iter()
+            .map(|desc| {
+                let common = desc.common();
+                let ToCardWorkRbDesc::Write(req) = desc else {
+                    panic!("should be a write");
+                };
+                (common.psn.get(), req.sge0.addr, req.sge0.len)
+            })
+            .collect();
+        assert_eq!(slices, vec![(3, 0x1000 + 3 * 1024, 1024), (4, 0x1000, 2048)]);
+        let counter = context.map.get(&(qpn, Msn::new(1))).map(|ctx| ctx.retry_counter);
+        assert_eq!(counter, Some(2));
+        let counter = context.map.get(&(qpn, Msn::new(3))).map(|ctx| ctx.retry_counter);
+        assert_eq!(counter, Some(3));
+    }
+
+    #[test]
+    fn test_retry_rnr() {
+        let (mut context, _sender, device) = test_context(Duration::from_millis(1000));
+        let map = Arc::clone(&context.user_op_ctx_map);
+        let qpn = Qpn::new(3);
+        let msn = Msn::new(1);
+        let desc = Box::new(ToCardWorkRbDesc::Write(ToCardWorkRbDescWrite {
+            common: ToCardWorkRbDescCommon {
+                total_len: 512,
+                dqpn: qpn,
+                psn: Psn::new(5),
+                msn,
+                ..Default::default()
+            },
+            is_last: true,
+            is_first: true,
+            sge0: DescSge {
+                addr: 0x1000,
+                len: 512,
+                key: Key::new(0x1234_u32),
+            },
+            sge1: None,
+            sge2: None,
+            sge3: None,
+        }));
+        let user_ctx = op_ctx::OpCtx::new_running();
+        map.write().insert((qpn, msn), user_ctx.clone());
+        context.handle_retry(super::RetryRecord::new(desc, qpn, msn, None, Some(1), None));
+
+        // the message is held until the RNR timer expires
+        let rnr = super::RetryRnr::new(qpn, msn, Psn::new(5), Duration::from_secs(60));
+        context.handle_rnr(&rnr);
+        context.check_rnr_waits();
+        context.check_timeout();
+        assert!(device.0.lock().is_empty());
+
+        // then it is retransmitted without consuming the retry
+        context.rnr_waits.get_mut(&qpn).unwrap().deadline = Instant::now();
+        context.check_rnr_waits();
+        assert_eq!(device.0.lock().len(), 1);
+        assert!(context.rnr_waits.is_empty());
+        let counter = context.map.get(&(qpn, msn)).map(|ctx| ctx.retry_counter);
+        assert_eq!(counter, Some(3));
+
+        // the second RNR NAK exceeds the max RNR retry
+        context.handle_rnr(&rnr);
+        assert!(context.map.is_empty());
+        assert!(matches!(
+            user_ctx.status(),
+            CtxStatus::Failed(WorkCompletionStatus::RnrRetryExceeded)
+        ));
+        assert!(map.read().is_empty());
+    }
 }
@@ -9,19 +9,25 @@ use parking_lot::{lock_api::RwLock, Mutex};
 
 use crate::{
     buf::{PacketBuf, RDMA_ACK_BUFFER_SLOT_SIZE},
-    checker::{PacketCheckEvent, PacketCheckerContext, RecvContextMap},
+    checker::{PacketCheckEvent, PacketCheckerContext, ReadRespContext, RecvContextMap},
+    cq::{Cq, WorkCompletion, WorkCompletionOpcode, WorkCompletionStatus},
     device::{
-        ToCardCtrlRbDesc, ToCardWorkRbDesc, ToHostWorkRbDescCommon, ToHostWorkRbDescRead,
-        ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType,
+        ToCardCtrlRbDesc, ToCardWorkRbDesc, ToHostWorkRbDescAck, ToHostWorkRbDescAethCode,
+        ToHostWorkRbDescAtomic, ToHostWorkRbDescCommon, ToHostWorkRbDescNakCode, ToHostWorkRbDescRead,
+        ToHostWorkRbDescStatus, ToHostWorkRbDescWriteOrReadResp, ToHostWorkRbDescWriteType,
     },
-    op_ctx::CtrlOpCtx,
-    qp::{QpContext, QpStatus},
-    types::{Key, Msn, Pmtu, Psn, QpType, Qpn},
+    op_ctx::{CtrlOpCtx, CtxStatus, OpCtx},
+    qp::{QpContext, QpState, QpStatus, RecvWqe},
+    retry::RetryEvent,
+    types::{Key, Msn, Pmtu, Psn, QpType, Qpn, Sge, IMM_NOTIFICATION_DEPTH},
     utils::{calculate_packet_cnt, get_first_packet_max_length},
     CtrlDescriptorSender, WorkDescriptorSender,
 };
 macro_rules! construct_context {
     ($context : ident,$device: ident, $qpn : ident = $qpn_val : expr) => {
+        construct_context!($context, $device, _retry_receiver, $qpn = $qpn_val);
+    };
+    ($context : ident,$device: ident, $retry_receiver: ident, $qpn : ident = $qpn_val : expr) => {
         let $device = Arc::new(MockCtrlDescSender::default());
         let ctrl_desc_sender: Arc<dyn CtrlDescriptorSender> = $device.clone();
         let work_desc_sender: Arc<dyn WorkDescriptorSender> = $device.clone();
@@ -35,6 +41,7 @@ macro_rules! construct_context {
 
         // we don't use the channel, so we don't care if it is closed
         let (_send_channel, desc_poller_channel) = unbounded();
+        let (retry_sender, $retry_receiver) = unbounded();
         let $context = PacketCheckerContext {
             desc_poller_channel,
             recv_ctx_map: RecvContextMap::default(),
@@ -43,12 +50,15 @@ macro_rules! construct_context {
             ctrl_desc_sender,
             work_desc_sender,
             ack_buffers,
+            retry_sender,
+            read_resp_map: Arc::new(RwLock::new(HashMap::new())),
         };
         let $qpn = Qpn::new($qpn_val);
         $context.qp_table.write().insert(
             $qpn,
             QpContext {
                 $qpn,
+                state: QpState::Rts.into(),
                 ..Default::default()
             },
         );
@@ -174,6 +184,336 @@ fn test_checker_normal() {
     }
 }
 
+#[test]
+fn test_checker_complete_recv_on_send() {
+    construct_context!(context, device, qpn = 0x1234);
+    let cq = Cq::new(16);
+    context.qp_table.write().get_mut(&qpn).unwrap().recv_cq = Some(cq.clone());
+    let post_recv = |addr: u64| {
+        let ctx = OpCtx::new_running();
+        context
+            .qp_table
+            .read()
+            .get(&qpn)
+            .unwrap()
+            .recv_queue
+            .lock()
+            .push_back(RecvWqe {
+                wr_id: addr,
+                sge: Sge::new(addr, 0x10000, Key::new(0x1000)),
+                ctx: ctx.clone(),
+            });
+        ctx
+    };
+    let first_buf = post_recv(0x1000_0000);
+    let second_buf = post_recv(0x2000_0000);
+
+    // a multi-packet send consumes the first buffer, and its length is known by the last packet
+    let start_psn = Psn::new(0x10);
+    let msn = Msn::new(0x20);
+    let packet_first: PacketCheckEvent = PacketWriteBuilder::create_empty()
+        .dqpn(qpn)
+        .msn(msn)
+        .psn(start_psn)
+        .write_type(ToHostWorkRbDescWriteType::First)
+        .is_send(true)
+        .addr(0x1000_0000_u64)
+        .len(0x1000_u32)
+        .build()
+        .unwrap()
+        .into();
+    context.handle_check_event(packet_first.clone());
+    assert!(first_buf.get_result().is_none());
+
+    let mut packet_last = packet_first.clone();
+    update(&mut packet_last, |e| {
+        e.write_type = ToHostWorkRbDescWriteType::Last;
+        e.psn = start_psn.wrapping_add(1);
+        e.common.expected_psn = e.psn;
+        e.addr = 0x1000_1000;
+        e.len = 0x1000;
+    });
+    context.handle_check_event(packet_last.clone());
+    assert_eq!(*first_buf.get_result().unwrap(), 0x2000);
+    assert!(device.work_pop().is_some(), "should get a ack");
+
+    // the retransmitted last packet should not complete the buffer again
+    context.handle_check_event(packet_last);
+    assert!(second_buf.get_result().is_none());
+
+    // a single packet send with immediate data consumes the second buffer
+    let mut packet_only = packet_first;
+    update(&mut packet_only, |e| {
+        e.write_type = ToHostWorkRbDescWriteType::Only;
+        e.common.msn = Msn::new(0x21);
+        e.psn = start_psn.wrapping_add(2);
+        e.common.expected_psn = e.psn;
+        e.addr = 0x2000_0000;
+        e.len = 0x100;
+        e.imm = Some(0x55);
+    });
+    context.handle_check_event(packet_only);
+    assert_eq!(*second_buf.get_result().unwrap(), 0x100);
+    let mut wc = [WorkCompletion::default(); 4];
+    assert_eq!(cq.poll(&mut wc).unwrap(), 2);
+    assert_eq!(wc[0].wr_id, 0x1000_0000);
+    assert_eq!(wc[0].byte_len, 0x2000);
+    assert_eq!(wc[1].wr_id, 0x2000_0000);
+    assert_eq!(wc[1].byte_len, 0x100);
+    assert!(wc[0].imm.is_none());
+    assert_eq!(wc[1].imm.map(|imm| imm.get()), Some(0x55));
+    assert!(wc[..2]
+        .iter()
+        .all(|c| c.opcode == WorkCompletionOpcode::Recv && c.qpn == qpn));
+    assert!(context
+        .qp_table
+        .read()
+        .get(&qpn)
+        .unwrap()
+        .recv_queue
+        .lock()
+        .is_empty());
+}
+
+#[test]
+fn test_checker_uc_drop_incomplete_message() {
+    construct_context!(context, device, qpn = 0x1234);
+    let cq = Cq::new(16);
+    if let Some(qp) = context.qp_table.write().get_mut(&qpn) {
+        qp.qp_type = QpType::Uc;
+        qp.recv_cq = Some(cq.clone());
+    }
+    let ctx = OpCtx::new_running();
+    context
+        .qp_table
+        .read()
+        .get(&qpn)
+        .unwrap()
+        .recv_queue
+        .lock()
+        .push_back(RecvWqe {
+            wr_id: 0x1000_0000,
+            sge: Sge::new(0x1000_0000, 0x10000, Key::new(0x1000)),
+            ctx: ctx.clone(),
+        });
+
+    let start_psn = Psn::new(0x10);
+    let packet_first: PacketCheckEvent = PacketWriteBuilder::create_empty()
+        .dqpn(qpn)
+        .msn(Msn::new(0x20))
+        .psn(start_psn)
+        .write_type(ToHostWorkRbDescWriteType::First)
+        .is_send(true)
+        .addr(0x1000_0000_u64)
+        .len(0x2000_u32)
+        .build()
+        .unwrap()
+        .into();
+    context.handle_check_event(packet_first.clone());
+
+    // the middle packet is lost, so the message is dropped without entering the error status
+    let mut packet_last = packet_first.clone();
+    update(&mut packet_last, |e| {
+        e.write_type = ToHostWorkRbDescWriteType::Last;
+        e.psn = start_psn.wrapping_add(2);
+        e.common.expected_psn = start_psn.wrapping_add(1);
+        e.addr = 0x1000_2000;
+        e.len = 0x1000;
+    });
+    context.handle_check_event(packet_last);
+    assert!(ctx.get_result().is_none());
+    assert!(context
+        .qp_table
+        .read()
+        .get(&qpn)
+        .unwrap()
+        .status
+        .load(Ordering::Acquire)
+        .is_normal());
+
+    // the buffer is reused by the next message, and nothing is acknowledged
+    let mut packet_only = packet_first;
+    update(&mut packet_only, |e| {
+        e.write_type = ToHostWorkRbDescWriteType::Only;
+        e.common.msn = Msn::new(0x21);
+        e.psn = start_psn.wrapping_add(3);
+        e.common.expected_psn = e.psn;
+        e.len = 0x100;
+    });
+    context.handle_check_event(packet_only);
+    assert_eq!(*ctx.get_result().unwrap(), 0x100);
+    let mut wc = [WorkCompletion::default(); 2];
+    assert_eq!(cq.poll(&mut wc).unwrap(), 1);
+    assert_eq!(wc[0].byte_len, 0x100);
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_notify_write_with_imm() {
+    construct_context!(context, device, qpn = 0x1234);
+    let receiver = context
+        .qp_table
+        .read()
+        .get(&qpn)
+        .unwrap()
+        .imm_receiver
+        .clone();
+
+    // only the last packet carries the immediate data
+    let start_psn = Psn::new(0x10);
+    let packet_first: PacketCheckEvent = PacketWriteBuilder::create_empty()
+        .dqpn(qpn)
+        .msn(Msn::new(0x20))
+        .psn(start_psn)
+        .write_type(ToHostWorkRbDescWriteType::First)
+        .addr(0x1000_0000_u64)
+        .len(0x2000_u32)
+        .build()
+        .unwrap()
+        .into();
+    context.handle_check_event(packet_first.clone());
+    assert!(receiver.is_empty());
+
+    let mut packet_last = packet_first.clone();
+    update(&mut packet_last, |e| {
+        e.write_type = ToHostWorkRbDescWriteType::Last;
+        e.psn = start_psn.wrapping_add(1);
+        e.common.expected_psn = e.psn;
+        e.addr = 0x1000_1000;
+        e.len = 0x1000;
+        e.imm = Some(0xdead_beef);
+    });
+    context.handle_check_event(packet_last.clone());
+    let notification = receiver.try_recv().unwrap();
+    assert_eq!(notification.qpn, qpn);
+    assert_eq!(notification.imm.get(), 0xdead_beef);
+    assert_eq!(notification.addr, 0x1000_0000);
+    assert_eq!(notification.len, 0x2000);
+    assert!(device.work_pop().is_some(), "should get a ack");
+
+    // the retransmitted last packet should not be notified again
+    context.handle_check_event(packet_last);
+    assert!(receiver.is_empty());
+
+    let mut packet_only = packet_first;
+    update(&mut packet_only, |e| {
+        e.write_type = ToHostWorkRbDescWriteType::Only;
+        e.common.msn = Msn::new(0x21);
+        e.psn = start_psn.wrapping_add(2);
+        e.common.expected_psn = e.psn;
+        e.addr = 0x2000_0000;
+        e.len = 0x100;
+        e.imm = Some(0x1234);
+    });
+    context.handle_check_event(packet_only);
+    let notification = receiver.try_recv().unwrap();
+    assert_eq!(notification.imm.get(), 0x1234);
+    assert_eq!(notification.addr, 0x2000_0000);
+    assert_eq!(notification.len, 0x100);
+}
+
+#[test]
+fn test_checker_drop_imm_notification_on_overflow() {
+    construct_context!(context, device, qpn = 0x1234);
+    let receiver = context
+        .qp_table
+        .read()
+        .get(&qpn)
+        .unwrap()
+        .imm_receiver
+        .clone();
+
+    let start_psn = Psn::new(0x10);
+    for i in 0..=IMM_NOTIFICATION_DEPTH {
+        let psn = start_psn.wrapping_add(i as u32);
+        let packet_only: PacketCheckEvent = PacketWriteBuilder::create_empty()
+            .dqpn(qpn)
+            .msn(Msn::new(i as u16))
+            .psn(psn)
+            .expected_psn(psn)
+            .write_type(ToHostWorkRbDescWriteType::Only)
+            .addr(0x1000_0000_u64)
+            .len(0x100_u32)
+            .can_auto_ack(true)
+            .imm(i as u32)
+            .build()
+            .unwrap()
+            .into();
+        context.handle_check_event(packet_only);
+    }
+
+    // the notifications beyond the depth are dropped and counted
+    assert_eq!(receiver.len(), IMM_NOTIFICATION_DEPTH);
+    let qp_table = context.qp_table.read();
+    let qp = qp_table.get(&qpn).unwrap();
+    assert_eq!(qp.imm_dropped.load(Ordering::Relaxed), 1);
+    assert_eq!(receiver.try_recv().unwrap().imm.get(), 0);
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_reset_on_new_rq_psn() {
+    construct_context!(context, device, qpn = 0x1234);
+    let receiver = context
+        .qp_table
+        .read()
+        .get(&qpn)
+        .unwrap()
+        .imm_receiver
+        .clone();
+    let set_rq_psn = |psn: Psn| {
+        let mut qp_table = context.qp_table.write();
+        let qp = qp_table.get_mut(&qpn).unwrap();
+        qp.rq_psn = psn;
+        qp.recv_ctx_outdated.store(true, Ordering::Release);
+    };
+
+    set_rq_psn(Psn::new(100));
+    let mut packet: PacketCheckEvent = PacketWriteBuilder::create_empty()
+        .dqpn(qpn)
+        .msn(Msn::new(1))
+        .psn(Psn::new(100))
+        .write_type(ToHostWorkRbDescWriteType::Only)
+        .addr(0x1000_u64)
+        .len(0x100_u32)
+        .imm(0x1_u32)
+        .build()
+        .unwrap()
+        .into();
+    update(&mut packet, |e| e.common.expected_psn = e.psn);
+    context.handle_check_event(packet.clone());
+    assert!(receiver.try_recv().is_ok());
+    assert_eq!(
+        context
+            .recv_ctx_map
+            .get_per_qp_ctx_mut(qpn)
+            .unwrap()
+            .largest_psn_recved(),
+        Psn::new(99)
+    );
+    // the retransmitted packet is ignored
+    context.handle_check_event(packet.clone());
+    assert!(receiver.is_empty());
+
+    // after reconnecting, the same msn is a new message
+    set_rq_psn(Psn::new(500));
+    update(&mut packet, |e| {
+        e.psn = Psn::new(500);
+        e.common.expected_psn = e.psn;
+    });
+    context.handle_check_event(packet);
+    assert!(receiver.try_recv().is_ok());
+    assert_eq!(
+        context
+            .recv_ctx_map
+            .get_per_qp_ctx_mut(qpn)
+            .unwrap()
+            .largest_psn_recved(),
+        Psn::new(499)
+    );
+    while device.work_pop().is_some() {}
+}
+
 #[test]
 fn test_checker_miss_and_then_recover() {
     construct_context!(context, device, qpn = 0x1234);
@@ -452,6 +792,283 @@ fn test_checker_miss_packet_recover_and_miss_again() {
 
     context.handle_check_event(packets[10].clone());
     check_qp_status(&context, qpn, QpStatus::Normal);
+
+    // every time entering the error status, a NAK asks to retransmit the missing packet only
+    let nak = device.work_pop().expect("should get a nak");
+    assert_eq!(parse_nak(&nak), Some((Psn::new(6), ToHostWorkRbDescNakCode::PsnSeqErr)));
+    assert_eq!(parse_last_retry_psn(&nak), Psn::new(6));
+    let nak = device.work_pop().expect("should get a nak");
+    assert_eq!(parse_nak(&nak), Some((Psn::new(3), ToHostWorkRbDescNakCode::PsnSeqErr)));
+    assert_eq!(parse_last_retry_psn(&nak), Psn::new(3));
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_report_missing_ranges() {
+    construct_context!(context, device, qpn = 0x1234);
+    make_ref_packet_event!(
+        packet_ref,
+        qpn,
+        start_psn = 0,
+        msn = 0x1235,
+        addr = 0u32,
+        len = 4096 * 11
+    );
+    let mut packets = generate_range_of_packet(packet_ref, Pmtu::Mtu4096);
+
+    // case:
+    // psn = 0,expected_psn=0
+    // psn = 5,expected_psn=1, missing 1 - 4
+    // psn = 6,expected_psn=1
+    // psn = 8,expected_psn=1, missing 7
+    // psn = 2,expected_psn=1, a retransmitted packet
+    reset_packet_psn!(packets, psn = 0, expected = 0);
+    reset_packet_psn!(packets, psn = 5, expected = 1);
+    reset_packet_psn!(packets, psn = 6, expected = 1);
+    reset_packet_psn!(packets, psn = 8, expected = 1);
+    reset_packet_psn!(packets, psn = 2, expected = 1);
+    for idx in [0, 5, 6, 8, 2] {
+        context.handle_check_event(packets[idx].clone());
+    }
+    check_qp_status(&context, qpn, QpStatus::OutOfOrder);
+
+    // every missing range is reported once, popped in reverse order
+    let nak = device.work_pop().expect("should get a nak");
+    assert_eq!(parse_nak(&nak), Some((Psn::new(7), ToHostWorkRbDescNakCode::PsnSeqErr)));
+    assert_eq!(parse_last_retry_psn(&nak), Psn::new(7));
+    let nak = device.work_pop().expect("should get a nak");
+    assert_eq!(parse_nak(&nak), Some((Psn::new(1), ToHostWorkRbDescNakCode::PsnSeqErr)));
+    assert_eq!(parse_last_retry_psn(&nak), Psn::new(4));
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_nak_failed_request() {
+    construct_context!(context, device, qpn = 0x1234);
+    make_ref_packet_event!(
+        packet_ref,
+        qpn,
+        start_psn = 0,
+        msn = 0x1235,
+        addr = 0u32,
+        len = 4096_u64
+    );
+    let mut packets = generate_range_of_packet(packet_ref, Pmtu::Mtu4096);
+    update(&mut packets[0], |pkt| {
+        pkt.common.status = ToHostWorkRbDescStatus::InvMrKey;
+    });
+    context.handle_check_event(packets[0].clone());
+    let nak = device.work_pop().expect("should get a nak");
+    assert_eq!(
+        parse_nak(&nak),
+        Some((Psn::new(0), ToHostWorkRbDescNakCode::RemoteAccessError))
+    );
+    check_recv_ctx_exist(&context, qpn, msn, false);
+    check_qp_status(&context, qpn, QpStatus::Normal);
+}
+
+#[test]
+fn test_checker_nak_failed_atomic() {
+    construct_context!(context, device, qpn = 0x1234);
+    // the card failed to execute the atomic request, so the original value is meaningless
+    context.handle_check_event(PacketCheckEvent::AtomicReq(ToHostWorkRbDescAtomic {
+        common: ToHostWorkRbDescCommon {
+            status: ToHostWorkRbDescStatus::InvAccFlag,
+            dqpn: qpn,
+            msn: Msn::new(3),
+            expected_psn: Psn::new(6),
+            ..Default::default()
+        },
+        psn: Psn::new(5),
+        orig: 0,
+    }));
+    let nak = device.work_pop().expect("should get a nak");
+    assert_eq!(
+        parse_nak(&nak),
+        Some((Psn::new(5), ToHostWorkRbDescNakCode::RemoteAccessError))
+    );
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_rnr_nak_without_recv_buffer() {
+    construct_context!(context, device, qpn = 0x1234);
+    if let Some(qp) = context.qp_table.write().get_mut(&qpn) {
+        qp.min_rnr_timer = 14;
+    }
+    make_ref_packet_event!(
+        packet_ref,
+        qpn,
+        start_psn = 0,
+        msn = 0x1235,
+        addr = 0u32,
+        len = 4096 * 2
+    );
+    let mut packets = generate_range_of_packet(packet_ref, Pmtu::Mtu4096);
+    for packet in &mut packets {
+        update(packet, |pkt| {
+            pkt.is_send = true;
+            pkt.common.status = ToHostWorkRbDescStatus::NoRecvBuf;
+        });
+    }
+    // the card doesn't advance the expected psn of a request without receive buffer
+    set_expected_psn(&mut packets[0], Psn::new(0));
+    set_expected_psn(&mut packets[1], Psn::new(0));
+
+    context.handle_check_event(packets[0].clone());
+    let rnr = device.work_pop().expect("should get a rnr nak");
+    assert_eq!(parse_ack(&rnr), (Psn::new(0), ToHostWorkRbDescAethCode::Rnr, 14));
+
+    // the rest of the message is dropped silently
+    context.handle_check_event(packets[1].clone());
+    assert!(device.work_pop().is_none());
+    check_recv_ctx_exist(&context, qpn, msn, false);
+    check_qp_status(&context, qpn, QpStatus::Normal);
+}
+
+#[test]
+fn test_checker_recv_rnr_nak() {
+    construct_context!(context, device, retry_receiver, qpn = 0x1234);
+    context.handle_check_event(PacketCheckEvent::Ack(ToHostWorkRbDescAck {
+        common: ToHostWorkRbDescCommon {
+            dqpn: qpn,
+            ..Default::default()
+        },
+        msn: Msn::new(0x1235),
+        psn: Psn::new(0x100),
+        code: ToHostWorkRbDescAethCode::Rnr,
+        value: 12,
+        last_retry_psn: Psn::new(0x100),
+    }));
+    assert!(matches!(retry_receiver.try_recv(), Ok(RetryEvent::Rnr(_))));
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_recv_nak() {
+    construct_context!(context, device, retry_receiver, qpn = 0x1234);
+    let msn = Msn::new(0x1235);
+    let ctx = OpCtx::new_running();
+    let failed_status = Arc::new(Mutex::new(None));
+    let handler_status = Arc::clone(&failed_status);
+    ctx.set_handler(Box::new(move |status| {
+        *handler_status.lock() = Some(status);
+    }));
+    let _: Option<OpCtx<()>> = context.user_op_ctx_map.write().insert((qpn, msn), ctx.clone());
+    let nak = |value: ToHostWorkRbDescNakCode, last_retry_psn: u32| {
+        PacketCheckEvent::Ack(ToHostWorkRbDescAck {
+            common: ToHostWorkRbDescCommon {
+                dqpn: qpn,
+                ..Default::default()
+            },
+            msn,
+            psn: Psn::new(0x100),
+            code: ToHostWorkRbDescAethCode::Nak,
+            value: value as u8,
+            last_retry_psn: Psn::new(last_retry_psn),
+        })
+    };
+
+    // a sequence error asks the retry monitor to go back
+    context.handle_check_event(nak(ToHostWorkRbDescNakCode::PsnSeqErr, 0xff));
+    assert!(matches!(retry_receiver.try_recv(), Ok(RetryEvent::GoBackN(_))));
+    assert!(matches!(ctx.status(), CtxStatus::Running));
+
+    // unless the NRETH bounds the range to retransmit
+    context.handle_check_event(nak(ToHostWorkRbDescNakCode::PsnSeqErr, 0x102));
+    assert!(matches!(retry_receiver.try_recv(), Ok(RetryEvent::Selective(_))));
+    assert!(matches!(ctx.status(), CtxStatus::Running));
+
+    // even to a single packet
+    context.handle_check_event(nak(ToHostWorkRbDescNakCode::PsnSeqErr, 0x100));
+    assert!(matches!(retry_receiver.try_recv(), Ok(RetryEvent::Selective(_))));
+
+    // a reserved NAK code is ignored
+    let mut reserved = nak(ToHostWorkRbDescNakCode::PsnSeqErr, 0x102);
+    if let PacketCheckEvent::Ack(ref mut ack) = reserved {
+        ack.value = 5;
+    }
+    context.handle_check_event(reserved);
+    assert!(retry_receiver.try_recv().is_err());
+    assert!(matches!(ctx.status(), CtxStatus::Running));
+
+    // an access error fails the request without retrying
+    context.handle_check_event(nak(ToHostWorkRbDescNakCode::RemoteAccessError, 0x100));
+    assert!(matches!(retry_receiver.try_recv(), Ok(RetryEvent::Cancel(_))));
+    assert!(matches!(
+        ctx.status(),
+        CtxStatus::Failed(WorkCompletionStatus::RemoteAccessError)
+    ));
+    assert!(matches!(
+        *failed_status.lock(),
+        Some(WorkCompletionStatus::RemoteAccessError)
+    ));
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_recv_ack() {
+    construct_context!(context, device, retry_receiver, qpn = 0x1234);
+    let msn = Msn::new(0x1235);
+    let ctx = OpCtx::new_running();
+    let _: Option<OpCtx<()>> = context.user_op_ctx_map.write().insert((qpn, msn), ctx.clone());
+    context.handle_check_event(PacketCheckEvent::Ack(ToHostWorkRbDescAck {
+        common: ToHostWorkRbDescCommon {
+            dqpn: qpn,
+            ..Default::default()
+        },
+        msn,
+        psn: Psn::new(0x100),
+        code: ToHostWorkRbDescAethCode::Ack,
+        value: 0,
+        last_retry_psn: Psn::new(0x100),
+    }));
+
+    // the acknowledged request is completed and no longer retransmitted
+    assert!(matches!(ctx.status(), CtxStatus::Finished));
+    assert!(matches!(retry_receiver.try_recv(), Ok(RetryEvent::Cancel(_))));
+    assert!(device.work_pop().is_none());
+}
+
+#[test]
+fn test_checker_read_resp_out_of_order() {
+    construct_context!(context, device, retry_receiver, qpn = 0x1234);
+    let msn = Msn::new(7);
+    let ctx = OpCtx::new_running();
+    let _: Option<OpCtx<()>> = context.user_op_ctx_map.write().insert((qpn, msn), ctx.clone());
+    // a read of 3 packets, whose responses are in the psn space of the responder
+    let read = ReadRespContext::new(0x1000, 2500, Pmtu::Mtu1024);
+    let _: Option<ReadRespContext> = context.read_resp_map.write().insert((qpn, msn), read);
+    let resp = |psn, write_type, addr: u64, len: u32| -> PacketCheckEvent {
+        PacketWriteBuilder::create_empty()
+            .dqpn(qpn)
+            .msn(msn)
+            .expected_psn(Psn::new(0))
+            .psn(Psn::new(psn))
+            .write_type(write_type)
+            .is_read_resp(true)
+            .addr(addr)
+            .len(len)
+            .build()
+            .unwrap()
+            .into()
+    };
+
+    context.handle_check_event(resp(102, ToHostWorkRbDescWriteType::Last, 0x1800, 452));
+    context.handle_check_event(resp(100, ToHostWorkRbDescWriteType::First, 0x1000, 2500));
+    assert!(matches!(ctx.status(), CtxStatus::Running));
+    assert_eq!(context.read_resp_map.read()[&(qpn, msn)].received_len(), 1024);
+
+    // the missing middle packet completes the read
+    context.handle_check_event(resp(101, ToHostWorkRbDescWriteType::Middle, 0x1400, 1024));
+    assert!(matches!(ctx.status(), CtxStatus::Finished));
+    assert!(matches!(retry_receiver.try_recv(), Ok(RetryEvent::Cancel(_))));
+    assert!(context.read_resp_map.read().is_empty());
+
+    // the responses neither change the qp status nor are acknowledged
+    check_qp_status(&context, qpn, QpStatus::Normal);
+    context.handle_check_event(resp(101, ToHostWorkRbDescWriteType::Middle, 0x1400, 1024));
+    assert!(retry_receiver.try_recv().is_err());
     assert!(device.work_pop().is_none());
 }
 
@@ -746,7 +1363,11 @@ impl MockCtrlDescSender {
     fn ctrl_pop_and_exec_handler(&self, is_succ: bool) {
         if let Some((_, ctx)) = self.ctrl_queue.lock().pop() {
             if let Some(handler) = ctx.take_handler() {
-                handler(is_succ);
+                handler(if is_succ {
+                    WorkCompletionStatus::Success
+                } else {
+                    WorkCompletionStatus::GeneralError
+                });
             }
         }
     }
@@ -786,11 +1407,15 @@ struct PacketWrite {
     #[builder(setter(into, strip_option), default)]
     is_read_resp: Option<bool>,
     #[builder(setter(into, strip_option), default)]
+    is_send: Option<bool>,
+    #[builder(setter(into, strip_option), default)]
     can_auto_ack: Option<bool>,
     #[builder(setter(into, strip_option), default)]
     addr: Option<u64>,
     #[builder(setter(into, strip_option), default)]
     len: Option<u32>,
+    #[builder(setter(into, strip_option), default)]
+    imm: Option<u32>,
 }
 
 impl From<PacketWrite> for PacketCheckEvent {
@@ -808,10 +1433,46 @@ impl From<PacketWrite> for PacketCheckEvent {
             addr: value.addr.unwrap_or(0),
             len: value.len.unwrap_or(0),
             is_read_resp: value.is_read_resp.unwrap_or(false),
+            is_send: value.is_send.unwrap_or(false),
+            imm: value.imm,
         })
     }
 }
 
+/// The psn, AETH code and value of an ack packet
+fn parse_ack(desc: &ToCardWorkRbDesc) -> (Psn, ToHostWorkRbDescAethCode, u8) {
+    let ToCardWorkRbDesc::WriteWithImm(desc) = desc else {
+        panic!("should be an ack packet");
+    };
+    // SAFETY: the ack buffer is leaked in `construct_context`
+    let packet = unsafe {
+        std::slice::from_raw_parts(desc.sge0.addr as *const u8, desc.sge0.len as usize)
+    };
+    let psn = Psn::new(u32::from_be_bytes([0, packet[51], packet[52], packet[53]]));
+    let aeth = packet[54];
+    let code = ToHostWorkRbDescAethCode::try_from((aeth >> 1) & 0b11).unwrap();
+    (psn, code, aeth >> 3)
+}
+
+/// The psn and the code of a NAK packet, or `None` if it is not a NAK
+fn parse_nak(desc: &ToCardWorkRbDesc) -> Option<(Psn, ToHostWorkRbDescNakCode)> {
+    let (psn, code, value) = parse_ack(desc);
+    (code == ToHostWorkRbDescAethCode::Nak)
+        .then(|| (psn, ToHostWorkRbDescNakCode::try_from(value).unwrap()))
+}
+
+/// The last retry psn in the NRETH of a NAK packet
+fn parse_last_retry_psn(desc: &ToCardWorkRbDesc) -> Psn {
+    let ToCardWorkRbDesc::WriteWithImm(desc) = desc else {
+        panic!("should be an ack packet");
+    };
+    // SAFETY: the ack buffer is leaked in `construct_context`
+    let packet = unsafe {
+        std::slice::from_raw_parts(desc.sge0.addr as *const u8, desc.sge0.len as usize)
+    };
+    Psn::new(u32::from_be_bytes([0, packet[58], packet[59], packet[60]]))
+}
+
 fn set_expected_psn(pkt: &mut PacketCheckEvent, psn: Psn) {
     update(pkt, |desc| {
         desc.common.expected_psn = psn;
@@ -1,7 +1,7 @@
 use eui48::MacAddress;
 use parking_lot::lock_api::{Mutex, RwLock};
 
-use crate::{buf::{PacketBuf, RDMA_ACK_BUFFER_SLOT_SIZE}, device::{ToHostWorkRbDescCommon, ToHostWorkRbDescRead}, qp::QpContext, responser::{make_ack, make_nack, make_read_resp, ACKPACKET_SIZE}, types::{Key, Msn, Pmtu, Psn, Qpn, WorkReqSendFlag}};
+use crate::{buf::{PacketBuf, RDMA_ACK_BUFFER_SLOT_SIZE}, device::{ToHostWorkRbDescCommon, ToHostWorkRbDescNakCode, ToHostWorkRbDescRead}, qp::QpContext, responser::{make_ack, make_nack, make_read_resp, ACKPACKET_SIZE}, types::{Key, Msn, Pmtu, Psn, Qpn, WorkReqSendFlag}};
 
 const BUFFER_SIZE: usize = 1024 * RDMA_ACK_BUFFER_SLOT_SIZE;
 
@@ -46,7 +46,10 @@ fn test_make_ack() {
         }
         crate::device::ToCardWorkRbDesc::Read(_)
         | crate::device::ToCardWorkRbDesc::Write(_)
-        | crate::device::ToCardWorkRbDesc::ReadResp(_) => {
+        | crate::device::ToCardWorkRbDesc::ReadResp(_)
+        | crate::device::ToCardWorkRbDesc::Send(_)
+        | crate::device::ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | crate::device::ToCardWorkRbDesc::AtomicFetchAdd(_) => {
             panic!("Unexpected desc type");
         }
     }
@@ -87,7 +90,16 @@ fn test_make_nack() {
         },
     );
     let ack_buf = ack_buffers.recycle_buf();
-    let desc = make_nack(ack_buf, &qp_table, qpn, msn, psn, expeceted_psn).unwrap();
+    let desc = make_nack(
+        ack_buf,
+        &qp_table,
+        qpn,
+        msn,
+        psn,
+        Some(expeceted_psn),
+        ToHostWorkRbDescNakCode::PsnSeqErr,
+    )
+    .unwrap();
     // check the desc
     match *desc {
         crate::device::ToCardWorkRbDesc::WriteWithImm(desc) => {
@@ -104,7 +116,10 @@ fn test_make_nack() {
         }
         crate::device::ToCardWorkRbDesc::Read(_)
         | crate::device::ToCardWorkRbDesc::Write(_)
-        | crate::device::ToCardWorkRbDesc::ReadResp(_) => {
+        | crate::device::ToCardWorkRbDesc::ReadResp(_)
+        | crate::device::ToCardWorkRbDesc::Send(_)
+        | crate::device::ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | crate::device::ToCardWorkRbDesc::AtomicFetchAdd(_) => {
             panic!("Unexpected desc type");
         }
     }
@@ -119,6 +134,24 @@ fn test_make_nack() {
         0x89, 0x00, 0xa6, 0xad, 0xef, 0xcc,
     ];
     assert_eq!(&buffer[0..ACKPACKET_SIZE], &expected_buffer);
+
+    // the code of the nack is in the AETH value
+    let ack_buf = ack_buffers.recycle_buf();
+    let desc = make_nack(
+        ack_buf,
+        &qp_table,
+        qpn,
+        msn,
+        psn,
+        None,
+        ToHostWorkRbDescNakCode::RemoteAccessError,
+    )
+    .unwrap();
+    let crate::device::ToCardWorkRbDesc::WriteWithImm(desc) = *desc else {
+        panic!("Unexpected desc type");
+    };
+    let offset = desc.sge0.addr as usize - buffer.as_ptr() as usize;
+    assert_eq!(buffer[offset + 54], 0x16);
 }
 
 #[test]
@@ -171,7 +204,10 @@ fn test_make_read_resp() {
         }
         crate::device::ToCardWorkRbDesc::Read(_)
         | crate::device::ToCardWorkRbDesc::Write(_)
-        | crate::device::ToCardWorkRbDesc::WriteWithImm(_) => {
+        | crate::device::ToCardWorkRbDesc::WriteWithImm(_)
+        | crate::device::ToCardWorkRbDesc::Send(_)
+        | crate::device::ToCardWorkRbDesc::AtomicCmpSwap(_)
+        | crate::device::ToCardWorkRbDesc::AtomicFetchAdd(_) => {
             panic!("Unexpected desc type");
         }
     }
@@ -31,6 +31,14 @@ impl ToHostRb<ToHostWorkRbDesc> for MockToHostRb {
         }
         Ok(self.rb.lock().pop().unwrap())
     }
+
+    fn pop_timeout(&self, timeout: std::time::Duration) -> Result<ToHostWorkRbDesc, DeviceError> {
+        let desc = self.rb.lock().pop();
+        desc.ok_or_else(|| {
+            sleep(timeout);
+            DeviceError::Timeout
+        })
+    }
 }
 #[test]
 fn test_work_desc_poller() {
@@ -93,6 +101,7 @@ fn test_work_desc_poller() {
             msn: Msn::default(),
             psn: Psn::new(2),
             code: ToHostWorkRbDescAethCode::Ack,
+            last_retry_psn: Psn::new(2),
         }),
         ToHostWorkRbDesc::Raw(ToHostWorkRbDescRaw {
             common: ToHostWorkRbDescCommon {
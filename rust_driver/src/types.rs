@@ -7,11 +7,20 @@ use num_enum::TryFromPrimitive;
 use serde::ser::StdError;
 use thiserror::Error;
 
-use crate::Pd;
+use crate::{cq::WorkCompletionStatus, qp::QpState, retry::RetryBackoff, Cq, Pd};
 
 /// page size is 2MB.
 pub const PAGE_SIZE: usize = 1024 * 1024 * 2;
 pub(crate) const PSN_MAX_WINDOW_SIZE: u32 = 1 << 23_i32;
+pub(crate) const MSN_MAX_WINDOW_SIZE: u16 = 1 << 15_i32;
+/// As the IB spec, a `rnr_retry` of 7 means retrying infinitely
+pub(crate) const INFINITE_RNR_RETRY: u32 = 7;
+/// The default RNR NAK timer of a qp, which is 0.64 ms
+pub(crate) const DEFAULT_MIN_RNR_TIMER: u8 = 12;
+/// The largest encoding of the RNR NAK timer, which is 491.52 ms
+pub(crate) const MAX_MIN_RNR_TIMER: u8 = 31;
+/// The number of immediate notifications a qp holds before they are received
+pub(crate) const IMM_NOTIFICATION_DEPTH: usize = 1024;
 
 
 /// Type for `Imm`
@@ -70,6 +79,12 @@ impl Msn {
     pub fn into_be(self) -> u16 {
         self.0.to_be()
     }
+
+    /// Check if the current MSN is larger or equal to the MSN in the argument
+    pub(crate) fn larger_in_msn(self, rhs: Msn) -> bool {
+        // if diff < 2^15, then self is larger or equal to rhs
+        self.0.wrapping_sub(rhs.0) < MSN_MAX_WINDOW_SIZE
+    }
 }
 
 impl From<u16> for Msn {
@@ -367,6 +382,22 @@ impl Sge {
     }
 }
 
+/// Notification of a received RDMA write with immediate
+///
+/// It is reported after the whole message has been written to the local memory.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct ImmNotification {
+    /// The local QP which receives the message
+    pub qpn: Qpn,
+    /// The immediate data
+    pub imm: Imm,
+    /// The start address of the message
+    pub addr: u64,
+    /// The length of the message
+    pub len: u32,
+}
+
 /// RDMA network param
 #[derive(Debug, Builder, Clone, Copy)]
 #[non_exhaustive]
@@ -383,12 +414,13 @@ pub struct RdmaDeviceNetworkParam {
 
 /// Queue Pair imuutable context
 #[non_exhaustive]
-#[derive(Builder, Debug, Clone, Copy)]
+#[derive(Builder, Debug, Clone)]
 pub struct Qp {
     /// Protection Domain
     pub pd: Pd,
-    /// Queue Pair Number
-    pub qpn: Qpn,
+    /// Queue Pair Number, allocated by the device when the qp is created if it's `None`
+    #[builder(default, setter(strip_option))]
+    pub qpn: Option<Qpn>,
     /// Peer Queue Pair Number
     pub peer_qpn: Qpn,
     /// Queue Pair Type
@@ -401,6 +433,92 @@ pub struct Qp {
     pub dqp_ip: Ipv4Addr,
     /// Destination MAC
     pub dqp_mac: MacAddress,
+    /// The PSN of the first packet to send
+    #[builder(default)]
+    pub sq_psn: Psn,
+    /// The PSN of the first packet expected from the peer, which should be the `sq_psn` of the peer
+    #[builder(default)]
+    pub rq_psn: Psn,
+    /// The completion queue of send work requests, no completion is reported if it's `None`
+    #[builder(default)]
+    pub send_cq: Option<Cq>,
+    /// The completion queue of receive work requests, no completion is reported if it's `None`
+    #[builder(default)]
+    pub recv_cq: Option<Cq>,
+}
+
+/// The attributes of a QP, used by `modify_qp` and `query_qp`
+///
+/// The attributes which are `None` are left unchanged when modifying a QP.
+#[non_exhaustive]
+#[derive(Builder, Debug, Clone, Copy, Default)]
+#[builder(default)]
+pub struct QpAttr {
+    /// The state to move to, or the current state when queried
+    pub qp_state: QpState,
+    /// Peer Queue Pair Number
+    #[builder(setter(strip_option))]
+    pub peer_qpn: Option<Qpn>,
+    /// Destination IP
+    #[builder(setter(strip_option))]
+    pub dqp_ip: Option<Ipv4Addr>,
+    /// Destination MAC
+    #[builder(setter(strip_option))]
+    pub dqp_mac: Option<MacAddress>,
+    /// Packet MTU
+    #[builder(setter(strip_option))]
+    pub pmtu: Option<Pmtu>,
+    /// The PSN expected by the receive queue
+    #[builder(setter(strip_option))]
+    pub rq_psn: Option<Psn>,
+    /// The PSN of the first packet to send
+    #[builder(setter(strip_option))]
+    pub sq_psn: Option<Psn>,
+    /// The max retry times of a request which is not acknowledged
+    #[builder(setter(strip_option))]
+    pub retry_cnt: Option<u32>,
+    /// The max retry times of a request which is responded with RNR NAK
+    ///
+    /// As the IB spec, it's at most 7, which means retrying infinitely.
+    #[builder(setter(strip_option))]
+    pub rnr_retry: Option<u32>,
+    /// The backoff policy of the retransmission, overriding the one of `RetryConfig`
+    #[builder(setter(strip_option))]
+    pub retry_backoff: Option<RetryBackoff>,
+    /// The RNR NAK timer advertised to the requester when there is no receive buffer,
+    /// in the 5 bits encoding of the IB spec, e.g. 12 for 0.64 ms and 0 for 655.36 ms.
+    /// It's at most 31.
+    #[builder(setter(strip_option))]
+    pub min_rnr_timer: Option<u8>,
+    /// Receive Queue Access Flags
+    #[builder(setter(strip_option))]
+    pub rq_acc_flags: Option<MemAccessTypeFlag>,
+    /// The Q_Key of a UD QP, the datagrams with a different Q_Key are dropped
+    #[builder(setter(strip_option))]
+    pub qkey: Option<u32>,
+}
+
+/// The capabilities of a device, returned by `Device::query_device`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceAttr {
+    /// The number of QPs, QP0 and QP1 included
+    pub max_qp: usize,
+    /// The number of MRs that can be registered at the same time
+    pub max_mr: usize,
+    /// The number of entries of the MR page table, each of which maps a page of `page_size`
+    pub max_mr_pgt_entries: usize,
+    /// The size of the pages mapped by the MR page table
+    pub page_size: usize,
+    /// The max number of scatter-gather elements of a work request
+    pub max_sge: usize,
+    /// The max length of a message
+    pub max_msg_size: u32,
+    /// The largest path MTU supported, all the smaller ones are supported as well
+    pub max_pmtu: Pmtu,
+    /// The depth of the ring buffers between the driver and the device,
+    /// `None` if the descriptors are queued without a limit
+    pub ringbuf_depth: Option<usize>,
 }
 
 /// Error type for RDMA user space driver library
@@ -457,7 +575,27 @@ pub enum Error {
 
     /// Pipe broken
     #[error("Pipe brocken : {0}")]
-    PipeBroken(&'static str)
+    PipeBroken(&'static str),
+
+    /// The QP can not move from the first state to the second one
+    #[error("invalid qp state transition from {0:?} to {1:?}")]
+    InvalidQpStateTransition(QpState, QpState),
+
+    /// The operation is not allowed in the current state of QP
+    #[error("operation not allowed in qp state {0:?}")]
+    QpNotReady(QpState),
+
+    /// The completion queue overflowed, and the completions after it are lost
+    #[error("completion queue overflow")]
+    CqOverflow,
+
+    /// The operation is finished with a failure
+    #[error("operation failed : {0:?}")]
+    OpFailed(WorkCompletionStatus),
+
+    /// The device is closed
+    #[error("device closed")]
+    DeviceClosed,
 }
 
 #[cfg(test)]
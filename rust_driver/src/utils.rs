@@ -1,11 +1,17 @@
 use std::{
     alloc::{alloc, dealloc, Layout},
     fs::{File, OpenOptions},
+    future::Future,
     io,
     ops::{Deref, DerefMut, Index, IndexMut},
     os::fd::AsRawFd,
     path::Path,
+    pin::pin,
     slice::from_raw_parts_mut,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+    time::Duration,
 };
 
 use log::error;
@@ -35,6 +41,22 @@ pub(crate) fn calculate_packet_cnt(pmtu: Pmtu, raddr: u64, total_len: u32) -> u3
     1 + (total_len - first_pkt_len).div_ceil(u32::from(&pmtu))
 }
 
+/// Decode the 5 bits RNR NAK timer of the IB spec into the time to wait before retrying
+pub(crate) fn rnr_timer_to_duration(timer: u8) -> Duration {
+    // the time in microseconds of each encoding, 0 is the longest one
+    #[allow(clippy::decimal_literal_representation)] // the values are listed in decimal by the spec
+    const RNR_TIMER_US: [u64; 32] = [
+        655_360, 10, 20, 30, 40, 60, 80, 120, 160, 240, 320, 480, 640, 960, 1280, 1920, 2560,
+        3840, 5120, 7680, 10240, 15360, 20480, 30720, 40960, 61440, 81920, 122_880, 163_840,
+        245_760, 327_680, 491_520,
+    ];
+    let micros = RNR_TIMER_US
+        .get(usize::from(timer))
+        .copied()
+        .unwrap_or(RNR_TIMER_US[0]);
+    Duration::from_micros(micros)
+}
+
 #[allow(clippy::arithmetic_side_effects)]
 pub(crate) fn u8_slice_to_u64(slice: &[u8]) -> u64 {
     // this operation convert a [u8;8] to a u64. So it's safe to left shift
@@ -46,6 +68,29 @@ pub(crate) fn align_up<const PAGE: usize>(addr: usize) -> usize {
     (((addr) + ((PAGE) - 1)) / PAGE) * PAGE
 }
 
+/// Wake a thread parked in `block_on`
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread, parking the thread while it is pending.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        // a spurious wakeup only leads to another poll
+        thread::park();
+    }
+}
+
 /// A struct to manage hugepage memory
 #[derive(Debug)]
 pub struct MmapMemory {
@@ -313,6 +358,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_rnr_timer_to_duration() {
+        assert_eq!(super::rnr_timer_to_duration(0).as_micros(), 655_360);
+        assert_eq!(super::rnr_timer_to_duration(1).as_micros(), 10);
+        assert_eq!(super::rnr_timer_to_duration(12).as_micros(), 640);
+        assert_eq!(super::rnr_timer_to_duration(31).as_micros(), 491_520);
+        // only the lower 5 bits are valid
+        assert_eq!(super::rnr_timer_to_duration(32).as_micros(), 655_360);
+    }
+
     #[test]
     fn align_up_test() {
         let a = align_up::<{ 1024 * 1024 * 2 }>(1024);
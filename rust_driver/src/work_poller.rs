@@ -11,7 +11,8 @@ use crate::{
     buf::Slot,
     checker::PacketCheckEvent,
     device::{
-        DeviceError, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescRaw, ToHostWorkRbDescStatus, ToHostWorkRbDescWriteWithImm
+        DeviceError, ToHostRb, ToHostWorkRbDesc, ToHostWorkRbDescRaw, ToHostWorkRbDescStatus,
+        TO_HOST_RB_POLL_TIMEOUT,
     },
     nic::NicRecvNotification,
     Error,
@@ -56,8 +57,9 @@ impl WorkDescPoller {
 impl WorkDescPollerContext {
     pub(crate) fn poll_working_thread(ctx: &Self, stop_flag: &AtomicBool) {
         while !stop_flag.load(Ordering::Relaxed) {
-            let desc = match ctx.work_rb.pop() {
+            let desc = match ctx.work_rb.pop_timeout(TO_HOST_RB_POLL_TIMEOUT) {
                 Ok(desc) => desc,
+                Err(DeviceError::Timeout) => continue,
                 Err(DeviceError::ParseDesc(e)) => {
                     error!("parse descriptor failed : {:?}", e);
                     continue;
@@ -68,7 +70,12 @@ impl WorkDescPollerContext {
                 }
             };
             debug!("driver read from card RQ: {:?}", &desc);
-            if !matches!(desc.status(), ToHostWorkRbDescStatus::Normal) {
+            // a failed request is passed to the checker, which NAKs it, and so is a failed
+            // datagram, which fails the user's context
+            if !matches!(desc.status(), ToHostWorkRbDescStatus::Normal)
+                && !desc.is_request()
+                && !matches!(desc, ToHostWorkRbDesc::Sent(_))
+            {
                 error!("desc status is {:?}", desc.status());
                 continue;
             }
@@ -76,9 +83,12 @@ impl WorkDescPollerContext {
             let result = match desc {
                 ToHostWorkRbDesc::Read(desc) => ctx.handle_work_desc_to_checker(desc),
                 ToHostWorkRbDesc::WriteOrReadResp(desc) => ctx.handle_work_desc_to_checker(desc),
-                ToHostWorkRbDesc::WriteWithImm(desc) => ctx.handle_work_desc_write_with_imm(&desc),
+                ToHostWorkRbDesc::WriteWithImm(desc) => ctx.handle_work_desc_to_checker(desc),
                 ToHostWorkRbDesc::Ack(desc) => ctx.handle_work_desc_to_checker(desc),
                 ToHostWorkRbDesc::Raw(desc) => ctx.handle_work_desc_raw(&desc),
+                ToHostWorkRbDesc::Atomic(desc) => ctx.handle_work_desc_to_checker(desc),
+                ToHostWorkRbDesc::Datagram(desc) => ctx.handle_work_desc_to_checker(desc),
+                ToHostWorkRbDesc::Sent(desc) => ctx.handle_work_desc_to_checker(desc),
             };
             if let Err(reason) = result {
                 error!("poll_work_rb stopped: {}", reason);
@@ -98,13 +108,6 @@ impl WorkDescPollerContext {
             .map_err(|_| Error::PipeBroken("work polling thread to responser"))
     }
 
-    fn handle_work_desc_write_with_imm(
-        &self,
-        _desc: &ToHostWorkRbDescWriteWithImm,
-    ) -> Result<(), Error> {
-        todo!()
-    }
-
     #[inline]
     fn handle_work_desc_raw(&self, desc: &ToHostWorkRbDescRaw) -> Result<(), Error> {
         let slot = unsafe { Slot::from_raw_parts_mut(desc.addr as *mut u8, desc.key) };
@@ -1,8 +1,8 @@
 use eui48::MacAddress;
 use log::info;
 use open_rdma_driver::{
-    qp::QpManager, types::{
-        MemAccessTypeFlag, Msn, Pmtu, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParam,
+    qp::{QpManager, QpState}, types::{
+        MemAccessTypeFlag, Msn, Pmtu, QpAttrBuilder, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParam,
         RdmaDeviceNetworkParamBuilder, Sge, WorkReqSendFlag, PAGE_SIZE,
     }, AlignedMemory, Device, DeviceConfigBuilder, DeviceType, Mr, Pd, RetryConfig, SealedDesc, TestingHandler, TestingStrategy
 };
@@ -105,7 +105,15 @@ fn create_and_init_card<'a>(
         .dqp_mac(remote_network.macaddr)
         .build()
         .unwrap();
-    dev.create_qp(&qp).unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    for state in [QpState::Init, QpState::Rtr, QpState::Rts] {
+        let mut builder = QpAttrBuilder::default();
+        let _ = builder.qp_state(state);
+        if matches!(state, QpState::Rtr) {
+            let _ = builder.peer_qpn(qp.peer_qpn).rq_psn(qp.rq_psn).pmtu(qp.pmtu);
+        }
+        dev.modify_qp(qpn, &builder.build().unwrap()).unwrap();
+    }
     info!("[{}] QP created", card_id);
 
     (dev, pd, mr, mr_buffer)
@@ -158,6 +166,7 @@ fn test_emulated() {
             mr_b.get_key(),
             WorkReqSendFlag::IbvSendSignaled,
             sge0,
+            0,
         )
         .unwrap();
 
@@ -0,0 +1,681 @@
+use eui48::MacAddress;
+use log::info;
+use open_rdma_driver::{
+    cq::WorkCompletionStatus,
+    op_ctx::CtxStatus,
+    qp::{QpManager, QpState},
+    types::{
+        MemAccessTypeFlag, Pmtu, QpAttrBuilder, QpBuilder, QpType, Qpn, RdmaDeviceNetworkParam,
+        RdmaDeviceNetworkParamBuilder, Sge, WorkReqSendFlag, PAGE_SIZE,
+    },
+    AlignedMemory, Device, DeviceConfigBuilder, DeviceType, Error, LoopbackFabric, Mr,
+    NetImpairment, NetImpairmentBuilder, Pd, PdHandle, RetryConfig, RoundRobinStrategy,
+};
+use std::{
+    fs,
+    future::Future,
+    net::Ipv4Addr,
+    path::PathBuf,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    time::Duration,
+};
+
+const BUFFER_LENGTH: usize = 1024 * 128;
+const SEND_CNT: usize = 1024 * 16;
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+fn network(host: u8) -> RdmaDeviceNetworkParam {
+    RdmaDeviceNetworkParamBuilder::default()
+        .gateway(Ipv4Addr::new(10, 0, 0, 0x1))
+        .netmask(Ipv4Addr::new(255, 255, 255, 0))
+        .ipaddr(Ipv4Addr::new(10, 0, 0, host))
+        .macaddr(MacAddress::new([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, host]))
+        .build()
+        .unwrap()
+}
+
+fn config_builder(
+    fabric: &LoopbackFabric,
+    local_network: RdmaDeviceNetworkParam,
+) -> DeviceConfigBuilder<RoundRobinStrategy> {
+    let mut builder = DeviceConfigBuilder::default();
+    let _ = builder
+        .network_config(local_network)
+        .retry_config(RetryConfig::new(
+            true,
+            10,
+            Duration::from_millis(200),
+            Duration::from_millis(10),
+        ))
+        .device_type(DeviceType::Loopback {
+            fabric: fabric.clone(),
+        })
+        .strategy(RoundRobinStrategy::new());
+    builder
+}
+
+fn create_device(
+    fabric: &LoopbackFabric,
+    local_network: RdmaDeviceNetworkParam,
+    impairment: Option<NetImpairment>,
+    pcap_path: Option<PathBuf>,
+) -> Device {
+    let mut builder = config_builder(fabric, local_network);
+    if let Some(impairment) = impairment {
+        let _ = builder.impairment(impairment);
+    }
+    if let Some(path) = pcap_path {
+        let _ = builder.pcap_path(path);
+    }
+    let config = builder.build().unwrap();
+    Device::new(config).unwrap()
+}
+
+fn create_and_init_card<'a>(
+    fabric: &LoopbackFabric,
+    card_id: usize,
+    qpn: Qpn,
+    local_network: RdmaDeviceNetworkParam,
+    remote_network: &RdmaDeviceNetworkParam,
+    impairment: Option<NetImpairment>,
+    pcap_path: Option<PathBuf>,
+) -> (Device, Pd, Mr, AlignedMemory<'a>) {
+    let dev = create_device(fabric, local_network, impairment, pcap_path);
+    info!("[{}] Device created", card_id);
+
+    let pd = dev.alloc_pd().unwrap();
+    let mut mr_buffer = AlignedMemory::new(BUFFER_LENGTH).unwrap();
+    let access_flag = MemAccessTypeFlag::IbvAccessRemoteRead
+        | MemAccessTypeFlag::IbvAccessRemoteWrite
+        | MemAccessTypeFlag::IbvAccessRemoteAtomic
+        | MemAccessTypeFlag::IbvAccessLocalWrite;
+    let mr = dev
+        .reg_mr(
+            pd,
+            mr_buffer.as_mut_ptr() as u64,
+            mr_buffer.len() as u32,
+            PAGE_SIZE as u32,
+            access_flag,
+        )
+        .unwrap();
+    let qp = QpBuilder::default()
+        .pd(pd)
+        .qpn(qpn)
+        .qp_type(QpType::Rc)
+        .rq_acc_flags(access_flag)
+        .pmtu(Pmtu::Mtu1024)
+        .dqp_ip(remote_network.ipaddr)
+        .dqp_mac(remote_network.macaddr)
+        .peer_qpn(qpn)
+        .build()
+        .unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    for state in [QpState::Init, QpState::Rtr, QpState::Rts] {
+        let mut builder = QpAttrBuilder::default();
+        let _ = builder.qp_state(state);
+        if matches!(state, QpState::Rtr) {
+            let _ = builder.peer_qpn(qp.peer_qpn).rq_psn(qp.rq_psn).pmtu(qp.pmtu);
+        }
+        dev.modify_qp(qpn, &builder.build().unwrap()).unwrap();
+    }
+    info!("[{}] QP created", card_id);
+
+    (dev, pd, mr, mr_buffer)
+}
+
+#[test]
+fn test_loopback_write_and_read() {
+    write_and_read(None, None);
+}
+
+#[test]
+fn test_loopback_impaired_write_and_read() {
+    let impairment = NetImpairmentBuilder::default()
+        .loss_rate(0.05)
+        .duplicate_rate(0.01)
+        .corrupt_rate(0.01)
+        .reorder_window(4)
+        .seed(0x5eed)
+        .build()
+        .unwrap();
+    write_and_read(Some(impairment), None);
+}
+
+#[test]
+fn test_loopback_pcap_capture() {
+    let path = std::env::temp_dir().join(format!("loopback_{}.pcap", std::process::id()));
+    write_and_read(None, Some(path.clone()));
+    let data = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // pcap global header, with Ethernet link type
+    assert_eq!(data[0..4], 0xa1b2_c3d4_u32.to_le_bytes());
+    assert_eq!(data[20..24], 1_u32.to_le_bytes());
+    let mut offset = 24;
+    let mut frame_cnt = 0;
+    while offset < data.len() {
+        let len = u32::from_le_bytes(data[offset + 8..offset + 12].try_into().unwrap()) as usize;
+        let frame = &data[offset + 16..offset + 16 + len];
+        // Ethernet/IPv4/UDP to the RoCEv2 port
+        assert_eq!(frame[12..14], [0x08, 0x00]);
+        assert_eq!(frame[14 + 9], 17);
+        assert_eq!(frame[14 + 20 + 2..14 + 20 + 4], 4791_u16.to_be_bytes());
+        offset += 16 + len;
+        frame_cnt += 1;
+    }
+    assert_eq!(offset, data.len());
+    // both the written packets sent by device A and the read responses it received are captured
+    assert!(frame_cnt >= 3 * (SEND_CNT / 1024));
+}
+
+#[test]
+fn test_loopback_close() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let qpn = QpManager::new().alloc().unwrap();
+    // every packet sent by device A is lost, so its write stays outstanding
+    let lossy = NetImpairmentBuilder::default().loss_rate(1.0).build().unwrap();
+    let (dev_a, pd_a, mr_a, mr_buffer_a) =
+        create_and_init_card(&fabric, 0, qpn, a_network, &b_network, Some(lossy), None);
+    let (dev_b, _pd_b, mr_b, mr_buffer_b) =
+        create_and_init_card(&fabric, 1, qpn, b_network, &a_network, None, None);
+
+    let sge = Sge::new(mr_buffer_a.as_ptr() as u64, 1024, mr_a.get_key());
+    let ctx = dev_a
+        .write(
+            qpn,
+            mr_buffer_b.as_ptr() as u64,
+            mr_b.get_key(),
+            WorkReqSendFlag::empty(),
+            sge,
+            0,
+        )
+        .unwrap();
+    assert!(matches!(ctx.status(), CtxStatus::Running));
+
+    dev_a.close().unwrap();
+    ctx.wait().unwrap();
+    assert!(matches!(
+        ctx.status(),
+        CtxStatus::Failed(WorkCompletionStatus::DeviceClosed)
+    ));
+    assert!(matches!(
+        dev_a.write(
+            qpn,
+            mr_buffer_b.as_ptr() as u64,
+            mr_b.get_key(),
+            WorkReqSendFlag::empty(),
+            sge,
+            0,
+        ),
+        Err(Error::DeviceClosed)
+    ));
+    assert!(matches!(dev_a.alloc_pd(), Err(Error::DeviceClosed)));
+    assert!(matches!(dev_a.dereg_mr(mr_a), Err(Error::DeviceClosed)));
+    assert!(matches!(dev_a.query_qp(qpn), Err(Error::DeviceClosed)));
+    // the resources are removed by closing
+    dev_a.dealloc_pd(pd_a).unwrap();
+    // closing again does nothing
+    dev_a.close().unwrap();
+
+    // a clone shares the closed state
+    let dev_b_clone = dev_b.clone();
+    dev_b_clone.close().unwrap();
+    assert!(matches!(dev_b.alloc_pd(), Err(Error::DeviceClosed)));
+}
+
+#[test]
+fn test_loopback_flush_on_error() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let qpn = QpManager::new().alloc().unwrap();
+    let (dev_a, _pd_a, mr_a, mr_buffer_a) =
+        create_and_init_card(&fabric, 0, qpn, a_network, &b_network, None, None);
+    let (dev_b, _pd_b, mr_b, mr_buffer_b) =
+        create_and_init_card(&fabric, 1, qpn, b_network, &a_network, None, None);
+
+    let sge = Sge::new(mr_buffer_a.as_ptr() as u64, 1024, mr_a.get_key());
+    let write = || {
+        dev_a
+            .write(
+                qpn,
+                mr_buffer_b.as_ptr() as u64,
+                mr_b.get_key(),
+                WorkReqSendFlag::empty(),
+                sge,
+                0,
+            )
+            .unwrap()
+    };
+    let finished = write();
+    finished.wait().unwrap();
+    // nothing acknowledges the requests once the responder stops receiving
+    dev_b.close().unwrap();
+    let outstanding = write();
+
+    let mut builder = QpAttrBuilder::default();
+    let _ = builder.qp_state(QpState::Error);
+    dev_a.modify_qp(qpn, &builder.build().unwrap()).unwrap();
+    outstanding.wait().unwrap();
+    assert!(matches!(
+        outstanding.status(),
+        CtxStatus::Failed(WorkCompletionStatus::FlushError)
+    ));
+    // the finished request keeps its result
+    assert!(matches!(finished.status(), CtxStatus::Finished));
+    dev_a.close().unwrap();
+}
+
+#[test]
+fn test_loopback_atomic() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let qpn = QpManager::new().alloc().unwrap();
+    let (dev_a, _pd_a, mr_a, mr_buffer_a) =
+        create_and_init_card(&fabric, 0, qpn, a_network, &b_network, None, None);
+    let (dev_b, _pd_b, mr_b, mut mr_buffer_b) =
+        create_and_init_card(&fabric, 1, qpn, b_network, &a_network, None, None);
+    mr_buffer_b[0..8].copy_from_slice(&5_u64.to_ne_bytes());
+
+    let raddr = mr_buffer_b.as_ptr() as u64;
+    let sge = Sge::new(mr_buffer_a.as_ptr() as u64, 8, mr_a.get_key());
+    let flags = WorkReqSendFlag::empty();
+    let remote = |buffer: &[u8]| u64::from_ne_bytes(buffer[0..8].try_into().unwrap());
+
+    let ctx = dev_a
+        .atomic_fetch_add(qpn, raddr, mr_b.get_key(), 3, flags, sge, 0)
+        .unwrap();
+    assert_eq!(ctx.wait_result().unwrap(), Some(&5));
+    assert_eq!(remote(&mr_buffer_b), 8);
+
+    let ctx = dev_a
+        .atomic_cmp_swap(qpn, raddr, mr_b.get_key(), 8, 42, flags, sge, 0)
+        .unwrap();
+    assert_eq!(ctx.wait_result().unwrap(), Some(&8));
+    assert_eq!(remote(&mr_buffer_b), 42);
+
+    // a failed comparison leaves the remote memory unchanged
+    let ctx = dev_a
+        .atomic_cmp_swap(qpn, raddr, mr_b.get_key(), 0, 1, flags, sge, 0)
+        .unwrap();
+    assert_eq!(ctx.wait_result().unwrap(), Some(&42));
+    assert_eq!(remote(&mr_buffer_b), 42);
+    // the original value is written into the local buffer as well
+    assert_eq!(remote(&mr_buffer_a), 42);
+
+    dev_a.close().unwrap();
+    dev_b.close().unwrap();
+}
+
+fn create_ud_qp(dev: &Device, pd: Pd, qpn: Qpn, qkey: u32) {
+    let qp = QpBuilder::default()
+        .pd(pd)
+        .qpn(qpn)
+        .qp_type(QpType::Ud)
+        .rq_acc_flags(MemAccessTypeFlag::IbvAccessLocalWrite)
+        .pmtu(Pmtu::Mtu1024)
+        .dqp_ip(Ipv4Addr::UNSPECIFIED)
+        .dqp_mac(MacAddress::nil())
+        .peer_qpn(qpn)
+        .build()
+        .unwrap();
+    let qpn = dev.create_qp(&qp).unwrap();
+    for state in [QpState::Init, QpState::Rtr, QpState::Rts] {
+        let mut builder = QpAttrBuilder::default();
+        let _ = builder.qp_state(state);
+        if matches!(state, QpState::Init) {
+            let _ = builder.qkey(qkey);
+        }
+        dev.modify_qp(qpn, &builder.build().unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn test_loopback_ud_qkey() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let qp_manager = QpManager::new();
+    let (rc_qpn, ud_qpn) = (qp_manager.alloc().unwrap(), qp_manager.alloc().unwrap());
+    let (dev_a, pd_a, mr_a, mr_buffer_a) =
+        create_and_init_card(&fabric, 0, rc_qpn, a_network, &b_network, None, None);
+    let (dev_b, pd_b, mr_b, mr_buffer_b) =
+        create_and_init_card(&fabric, 1, rc_qpn, b_network, &a_network, None, None);
+    create_ud_qp(&dev_a, pd_a, ud_qpn, 0x1111);
+    create_ud_qp(&dev_b, pd_b, ud_qpn, 0x2222);
+
+    let recv_sge = Sge::new(mr_buffer_b.as_ptr() as u64, 1024, mr_b.get_key());
+    let recv_ctx = dev_b.post_recv(ud_qpn, recv_sge, 0).unwrap();
+    let flags = WorkReqSendFlag::empty();
+
+    // the qkey carried by the datagram does not match the one of the receiver, so it is dropped
+    // without consuming the receive buffer
+    let wrong_ah = dev_a
+        .create_ah(b_network.ipaddr, b_network.macaddr, ud_qpn, 0x1111)
+        .unwrap();
+    let sge = Sge::new(mr_buffer_a.as_ptr() as u64, 16, mr_a.get_key());
+    let ctx = dev_a.post_send_ud(ud_qpn, &wrong_ah, flags, sge, 0).unwrap();
+    ctx.wait().unwrap();
+    assert!(matches!(ctx.status(), CtxStatus::Finished));
+
+    let ah = dev_a
+        .create_ah(b_network.ipaddr, b_network.macaddr, ud_qpn, 0x2222)
+        .unwrap();
+    let sge = Sge::new(mr_buffer_a.as_ptr() as u64, 32, mr_a.get_key());
+    let ctx = dev_a.post_send_ud(ud_qpn, &ah, flags, sge, 0).unwrap();
+    ctx.wait().unwrap();
+    assert!(matches!(ctx.status(), CtxStatus::Finished));
+    // the datagrams are delivered in order, so the buffer is consumed by the second one
+    assert_eq!(recv_ctx.wait_result().unwrap(), Some(&32));
+
+    // an atomic is supported by an RC qp only
+    let sge = Sge::new(mr_buffer_a.as_ptr() as u64, 8, mr_a.get_key());
+    let raddr = mr_buffer_b.as_ptr() as u64;
+    let result = dev_a.atomic_fetch_add(ud_qpn, raddr, mr_b.get_key(), 1, flags, sge, 0);
+    assert!(matches!(result, Err(Error::Invalid(_))));
+
+    dev_a.close().unwrap();
+    dev_b.close().unwrap();
+}
+
+#[test]
+fn test_loopback_resource_handles() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let dev = create_device(&fabric, a_network, None, None);
+    let qpn = QpManager::new().alloc().unwrap();
+    let mut buffer = AlignedMemory::new(BUFFER_LENGTH).unwrap();
+
+    let pd = PdHandle::new(&dev).unwrap();
+    let qp = QpBuilder::default()
+        .pd(pd.pd())
+        .qpn(qpn)
+        .qp_type(QpType::Rc)
+        .rq_acc_flags(MemAccessTypeFlag::IbvAccessLocalWrite)
+        .pmtu(Pmtu::Mtu1024)
+        .dqp_ip(b_network.ipaddr)
+        .dqp_mac(b_network.macaddr)
+        .peer_qpn(qpn)
+        .build()
+        .unwrap();
+    let mr = {
+        let mr_handle = pd
+            .reg_mr(
+                buffer.as_mut_ptr() as u64,
+                buffer.len() as u32,
+                PAGE_SIZE as u32,
+                MemAccessTypeFlag::IbvAccessLocalWrite,
+            )
+            .unwrap();
+        let _qp_handle = pd.create_qp(&qp).unwrap();
+        assert!(dev.query_qp(qpn).is_ok());
+        assert!(matches!(dev.dealloc_pd(pd.pd()), Err(Error::PdInUse(_))));
+        // a qp can only be created in its own pd
+        let other_pd = PdHandle::new(&dev).unwrap();
+        assert!(matches!(other_pd.create_qp(&qp), Err(Error::Invalid(_))));
+        mr_handle.mr()
+    };
+
+    // the handles have released the mr and the qp, and then the pd
+    assert!(dev.query_qp(qpn).is_err());
+    assert!(matches!(dev.dereg_mr(mr), Err(Error::Invalid(_))));
+    let raw_pd = pd.pd();
+    drop(pd);
+    assert!(matches!(dev.dealloc_pd(raw_pd), Err(Error::Invalid(_))));
+    dev.close().unwrap();
+}
+
+#[test]
+fn test_loopback_qpn_allocation() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let dev = create_device(&fabric, a_network, None, None);
+    let pd = dev.alloc_pd().unwrap();
+    let mut builder = QpBuilder::default();
+    let _: &mut QpBuilder = builder
+        .pd(pd)
+        .qp_type(QpType::Rc)
+        .rq_acc_flags(MemAccessTypeFlag::IbvAccessLocalWrite)
+        .pmtu(Pmtu::Mtu1024)
+        .dqp_ip(b_network.ipaddr)
+        .dqp_mac(b_network.macaddr)
+        .peer_qpn(Qpn::new(2));
+
+    // the qpn is allocated by the device if not given, QP0 and QP1 are reserved
+    let auto_qp = builder.build().unwrap();
+    let qpn_a = dev.create_qp(&auto_qp).unwrap();
+    let qpn_b = dev.create_qp(&auto_qp).unwrap();
+    assert_eq!((qpn_a.get(), qpn_b.get()), (2, 3));
+
+    // a given qpn must not collide with the allocated ones
+    let fixed_qp = builder.qpn(Qpn::new(3)).build().unwrap();
+    assert!(matches!(dev.create_qp(&fixed_qp), Err(Error::Invalid(_))));
+    let reserved_qp = builder.qpn(Qpn::new(1)).build().unwrap();
+    assert!(matches!(dev.create_qp(&reserved_qp), Err(Error::Invalid(_))));
+    let out_of_range_qp = builder.qpn(Qpn::new(1 << 20)).build().unwrap();
+    assert!(matches!(dev.create_qp(&out_of_range_qp), Err(Error::Invalid(_))));
+
+    // the qpn is freed when the qp is destroyed
+    dev.destroy_qp(qpn_b).unwrap();
+    assert_eq!(dev.create_qp(&fixed_qp).unwrap(), qpn_b);
+
+    // and when the creation is cancelled
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut creating = Box::pin(dev.create_qp_async(&auto_qp));
+    if let Poll::Ready(created) = creating.as_mut().poll(&mut Context::from_waker(&waker)) {
+        dev.destroy_qp(created.unwrap()).unwrap();
+    }
+    drop(creating);
+    assert_eq!(dev.create_qp(&auto_qp).unwrap().get(), 4);
+    dev.close().unwrap();
+}
+
+#[test]
+fn test_loopback_query_device() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let dev = create_device(&fabric, a_network, None, None);
+    let attr = dev.query_device();
+    assert_eq!(attr.max_qp, 1024);
+    assert_eq!(attr.max_sge, 4);
+    assert_eq!(attr.max_msg_size, 1 << 31);
+    assert_eq!(attr.page_size, PAGE_SIZE);
+    assert!(matches!(attr.max_pmtu, Pmtu::Mtu4096));
+    // the software device queues the descriptors without a limit
+    assert_eq!(attr.ringbuf_depth, None);
+
+    // the qp numbers are limited by `max_qp`
+    let pd = dev.alloc_pd().unwrap();
+    let mut builder = QpBuilder::default();
+    let _: &mut QpBuilder = builder
+        .pd(pd)
+        .qp_type(QpType::Rc)
+        .rq_acc_flags(MemAccessTypeFlag::IbvAccessLocalWrite)
+        .pmtu(attr.max_pmtu)
+        .dqp_ip(b_network.ipaddr)
+        .dqp_mac(b_network.macaddr)
+        .peer_qpn(Qpn::new(2));
+    let max_qpn = u32::try_from(attr.max_qp).unwrap();
+    let last_qp = builder.qpn(Qpn::new(max_qpn - 1)).build().unwrap();
+    assert!(dev.create_qp(&last_qp).is_ok());
+    let beyond_qp = builder.qpn(Qpn::new(max_qpn)).build().unwrap();
+    assert!(matches!(dev.create_qp(&beyond_qp), Err(Error::Invalid(_))));
+    dev.close().unwrap();
+}
+
+#[test]
+fn test_loopback_mr_table_size() {
+    let fabric = LoopbackFabric::new();
+    // the table is larger than the 64 entries of the card
+    let mr_table_size = 300;
+    let config = config_builder(&fabric, network(2))
+        .mr_table_size(mr_table_size)
+        .mr_pgt_length(512)
+        .build()
+        .unwrap();
+    let dev = Device::new(config).unwrap();
+    let attr = dev.query_device();
+    assert_eq!((attr.max_mr, attr.max_mr_pgt_entries), (mr_table_size, 512));
+
+    // the device registers its packet buffers, the rest of the table is for the user
+    let mut buffer = AlignedMemory::new(PAGE_SIZE).unwrap();
+    let pd = dev.alloc_pd().unwrap();
+    let flags = MemAccessTypeFlag::IbvAccessLocalWrite;
+    let addr = buffer.as_mut_ptr() as u64;
+    let mut mrs = Vec::new();
+    let err = loop {
+        match dev.reg_mr(pd, addr, 4096, PAGE_SIZE as u32, flags) {
+            Ok(mr) => mrs.push(mr),
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(err, Error::ResourceNoAvailable(_)));
+    assert!(mrs.len() > 64 && mrs.len() < mr_table_size);
+    // the key takes 9 bits to index the table
+    for mr in mrs {
+        assert!((mr.get_key().get() >> 23) < mr_table_size as u32);
+        dev.dereg_mr(mr).unwrap();
+    }
+    dev.close().unwrap();
+
+    // a page table of 0 entries is rejected
+    let config = config_builder(&fabric, network(2))
+        .mr_pgt_length(0)
+        .build()
+        .unwrap();
+    assert!(matches!(Device::new(config), Err(Error::Invalid(_))));
+}
+
+#[test]
+fn test_loopback_msn_wraparound() {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let qpn = QpManager::new().alloc().unwrap();
+    let (dev_a, _pd_a, mr_a, mr_buffer_a) =
+        create_and_init_card(&fabric, 0, qpn, a_network, &b_network, None, None);
+    let (dev_b, _pd_b, mr_b, mr_buffer_b) =
+        create_and_init_card(&fabric, 1, qpn, b_network, &a_network, None, None);
+
+    // the msn is reused after 65536 messages, which must not collide with a finished request
+    let sge = Sge::new(mr_buffer_a.as_ptr() as u64, 8, mr_a.get_key());
+    let batch_size = 1024;
+    for _ in 0..=u32::from(u16::MAX) / batch_size + 1 {
+        let ctxs: Vec<_> = (0..batch_size)
+            .map(|_| {
+                dev_a
+                    .write(
+                        qpn,
+                        mr_buffer_b.as_ptr() as u64,
+                        mr_b.get_key(),
+                        WorkReqSendFlag::empty(),
+                        sge,
+                        0,
+                    )
+                    .unwrap()
+            })
+            .collect();
+        for ctx in ctxs {
+            ctx.wait().unwrap();
+            assert!(matches!(ctx.status(), CtxStatus::Finished));
+        }
+    }
+    dev_a.close().unwrap();
+    dev_b.close().unwrap();
+}
+
+fn write_and_read(impairment: Option<NetImpairment>, pcap_path: Option<PathBuf>) {
+    let fabric = LoopbackFabric::new();
+    let a_network = network(2);
+    let b_network = network(3);
+    let qp_manager = QpManager::new();
+    let qpn = qp_manager.alloc().unwrap();
+    let (dev_a, _pd_a, mr_a, mut mr_buffer_a) =
+        create_and_init_card(&fabric, 0, qpn, a_network, &b_network, impairment, pcap_path);
+    let (dev_b, _pd_b, mr_b, mut mr_buffer_b) =
+        create_and_init_card(&fabric, 1, qpn, b_network, &a_network, impairment, None);
+
+    for (idx, item) in mr_buffer_a.iter_mut().enumerate() {
+        *item = idx as u8;
+    }
+    for item in mr_buffer_b.iter_mut() {
+        *item = 0;
+    }
+
+    // test write
+    let sge0 = Sge::new(
+        &mr_buffer_a[0] as *const u8 as u64,
+        SEND_CNT.try_into().unwrap(),
+        mr_a.get_key(),
+    );
+    let sge1 = Sge::new(
+        &mr_buffer_a[SEND_CNT] as *const u8 as u64,
+        SEND_CNT.try_into().unwrap(),
+        mr_a.get_key(),
+    );
+    let ctx1 = dev_a
+        .write(
+            qpn,
+            &mr_buffer_b[0] as *const u8 as u64,
+            mr_b.get_key(),
+            WorkReqSendFlag::empty(),
+            sge0,
+            0,
+        )
+        .unwrap();
+    let ctx2 = dev_a
+        .write(
+            qpn,
+            &mr_buffer_b[SEND_CNT] as *const u8 as u64,
+            mr_b.get_key(),
+            WorkReqSendFlag::empty(),
+            sge1,
+            0,
+        )
+        .unwrap();
+    ctx1.wait().unwrap();
+    ctx2.wait().unwrap();
+    assert!(matches!(ctx1.status(), CtxStatus::Finished));
+    assert!(matches!(ctx2.status(), CtxStatus::Finished));
+    assert!(mr_buffer_a[0..SEND_CNT * 2] == mr_buffer_b[0..SEND_CNT * 2]);
+
+    // test read: fetch the written data back into another part of the local buffer
+    let sge_read = Sge::new(
+        &mr_buffer_a[SEND_CNT * 2] as *const u8 as u64,
+        SEND_CNT.try_into().unwrap(),
+        mr_a.get_key(),
+    );
+    let ctx = dev_a
+        .read(
+            qpn,
+            &mr_buffer_b[0] as *const u8 as u64,
+            mr_b.get_key(),
+            WorkReqSendFlag::empty(),
+            sge_read,
+            0,
+        )
+        .unwrap();
+    ctx.wait().unwrap();
+    assert!(matches!(ctx.status(), CtxStatus::Finished));
+    assert!(mr_buffer_a[SEND_CNT * 2..SEND_CNT * 3] == mr_buffer_b[0..SEND_CNT]);
+
+    // the impairment may still deliver duplicated packets, don't let them touch the freed buffers
+    dev_a.close().unwrap();
+    dev_b.close().unwrap();
+    // closing keeps the result of the finished requests
+    for ctx in [ctx1, ctx2, ctx] {
+        assert!(matches!(ctx.status(), CtxStatus::Finished));
+    }
+}